@@ -4,15 +4,36 @@ use std::rc::Rc;
 
 pub struct File {
     pub span: Span,
+    pub module: Rc<str>,
+
+    /// if set (with 'target <name>'), the file is only
+    /// included when building for the named target
+    pub target: Option<(Span, Rc<str>)>,
+
+    pub imports: Vec<RawImport>,
     pub constants: Vec<RawConstant>,
     pub externs: Vec<RawExtern>,
+    pub extern_globals: Vec<RawExternGlobal>,
+    pub extern_memories: Vec<RawExternMemory>,
     pub records: Vec<RawRecord>,
     pub globals: Vec<RawGlobal>,
     pub funcs: Vec<RawFunc>,
 }
 
+pub struct RawImport {
+    pub span: Span,
+
+    /// the full name of the imported module (e.g. "foo.bar")
+    pub module: Rc<str>,
+
+    /// the name the module is referred to by in the importing file
+    /// (e.g. "bar" for 'import foo.bar', or "b" for 'import foo.bar as b')
+    pub alias: Rc<str>,
+}
+
 pub struct RawConstant {
     pub span: Span,
+    pub public: bool,
     pub name: Rc<str>,
     pub type_: Option<TypeExpr>,
     pub expr: RawExpr,
@@ -20,19 +41,40 @@ pub struct RawConstant {
 
 pub struct RawExtern {
     pub span: Span,
+    pub public: bool,
     pub path: (Rc<str>, Rc<str>),
     pub name: Rc<str>,
     pub type_: FuncTypeExpr,
 }
 
+/// A global variable provided by the host
+/// e.g. extern "env" "counter" var counter i32
+pub struct RawExternGlobal {
+    pub span: Span,
+    pub public: bool,
+    pub path: (Rc<str>, Rc<str>),
+    pub name: Rc<str>,
+    pub type_: TypeExpr,
+}
+
+/// Imports the linear memory from the host instead of defining it
+/// e.g. extern "env" "memory" memory
+/// (the runtime still lays out its data in it, see ir.rs's Memory)
+pub struct RawExternMemory {
+    pub span: Span,
+    pub path: (Rc<str>, Rc<str>),
+}
+
 pub struct RawRecord {
     pub span: Span,
+    pub public: bool,
     pub name: Rc<str>,
     pub fields: Vec<(Rc<str>, TypeExpr)>,
 }
 
 pub struct RawGlobal {
     pub span: Span,
+    pub public: bool,
     pub name: Rc<str>,
     pub type_: Option<TypeExpr>,
     pub init: RawExpr,
@@ -40,6 +82,11 @@ pub struct RawGlobal {
 
 pub struct RawFunc {
     pub span: Span,
+    pub public: bool,
+
+    /// the name the function is exported under, if it is exported
+    pub export: Option<Rc<str>>,
+
     pub name: Rc<str>,
     pub type_: FuncTypeExpr,
     pub body: RawStmt,
@@ -105,10 +152,11 @@ pub enum ByteCount {
     N8,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum Unop {
     Negative,
     Positive,
+    Not,
 }
 
 impl Unop {
@@ -116,6 +164,7 @@ impl Unop {
         Some(match token {
             Token::Plus => Self::Positive,
             Token::Minus => Self::Negative,
+            Token::Name("not") => Self::Not,
             _ => return None,
         })
     }
@@ -130,6 +179,15 @@ pub enum Binop {
     TruncDivide,
     Remainder,
 
+    BitwiseAnd,
+    BitwiseOr,
+    BitwiseXor,
+    ShiftLeft,
+    ShiftRight,
+
+    LogicalAnd,
+    LogicalOr,
+
     Equal,
     NotEqual,
 
@@ -151,6 +209,17 @@ impl Binop {
             Token::Slash2 => Self::TruncDivide,
             Token::Percent => Self::Remainder,
 
+            Token::Ampersand => Self::BitwiseAnd,
+            Token::VerticalBar => Self::BitwiseOr,
+            Token::Caret => Self::BitwiseXor,
+            Token::Lt2 => Self::ShiftLeft,
+            Token::Gt2 => Self::ShiftRight,
+
+            Token::Name("and") => Self::LogicalAnd,
+            Token::Name("or") => Self::LogicalOr,
+
+            Token::Eq2 => Self::Equal,
+            Token::Ne => Self::NotEqual,
             Token::Lt => Self::LessThan,
             Token::Le => Self::LessThanOrEqual,
             Token::Gt => Self::GreaterThan,
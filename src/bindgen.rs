@@ -0,0 +1,644 @@
+//! Rust bindings for wac programs (wac bindgen)
+//!
+//! The generated module has an Imports trait, with a method for each
+//! extern the program declares (except for the prelude's, which imports.rs
+//! provides) and a function registering them with an Engine, and an Exports
+//! struct wrapping an Instance, with a method for each exported function.
+//! Values have the Rust types of engine.rs, except for strs, which are
+//! Strings (or &strs going into the guest), and records, which are structs
+//! generated for each record type, with the same fields.
+//!
+//! Strs and records are copied in and out of the guest's memory, and
+//! the references that exports return are released once copied. Imports
+//! can only be given them: as a host function has no way of allocating,
+//! externs that return them are refused.
+use crate::translate_to_program;
+use crate::wasm_path;
+use crate::Error;
+use crate::FuncType;
+use crate::Program;
+use crate::Record;
+use crate::ReturnType;
+use crate::RunConfig;
+use crate::Source;
+use crate::Type;
+use crate::PRELUDE_MODULE;
+use std::fmt::Write;
+use std::rc::Rc;
+
+/// Names that cannot be used as is in the generated code
+/// (Rust's keywords, and the names the generated code uses itself)
+const RUST_RESERVED_NAMES: &[&str] = &[
+    "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum", "extern",
+    "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub",
+    "ref", "return", "self", "Self", "static", "struct", "super", "trait", "true", "type",
+    "unsafe", "use", "where", "while", "abstract", "become", "box", "do", "final", "macro",
+    "override", "priv", "try", "typeof", "unsized", "virtual", "yield", "memory", "imports",
+    "instance", "new", "read", "alloc",
+];
+
+/// The types the generated code names, which the records cannot shadow
+const RUST_TYPE_NAMES: &[&str] = &[
+    "Imports",
+    "Exports",
+    "Engine",
+    "EngineError",
+    "Instance",
+    "MemoryRef",
+    "Value",
+    "Rc",
+    "String",
+    "Box",
+    "Option",
+    "Some",
+    "None",
+    "Result",
+    "Ok",
+    "Err",
+];
+
+/// Parses and checks the sources, and generates bindings for the program
+pub fn translate_to_bindings(
+    sources: Vec<Rc<Source>>,
+    config: &RunConfig,
+) -> Result<String, Error> {
+    bindgen(&translate_to_program(sources, config)?)
+}
+
+pub fn bindgen(program: &Program) -> Result<String, Error> {
+    let mut out = String::new();
+    writeln!(out, "// Generated by wac bindgen")?;
+    writeln!(out, "use std::rc::Rc;")?;
+    if !program.records.is_empty() {
+        writeln!(out, "use wac::wasm::Value;")?;
+    }
+    writeln!(out, "use wac::Engine;")?;
+    writeln!(out, "use wac::EngineError;")?;
+    writeln!(out, "use wac::Instance;")?;
+    writeln!(out, "use wac::MemoryRef;")?;
+
+    // records from different modules may share a name
+    let mut names = vec![];
+    for rec in &program.records {
+        let mut name = rust_name(&rec.name);
+        if names.contains(&name) {
+            name = rust_name(&format!("{}_{}", rec.module, rec.name));
+        }
+        names.push(unique_name(&names, name));
+    }
+    let types = Types {
+        records: program.records.iter().cloned().zip(names).collect(),
+    };
+    for (rec, name) in &types.records {
+        gen_record(&mut out, &types, rec, name)?;
+    }
+
+    let externs: Vec<_> = program
+        .externs
+        .iter()
+        .filter(|ext| &*ext.module != PRELUDE_MODULE)
+        .collect();
+    let mut names = vec![];
+    for ext in &externs {
+        if let ReturnType::Type(type_) = &ext.type_.return_type {
+            if is_object(type_) {
+                return Err(Error {
+                    span: vec![ext.span.clone()],
+                    message: format!(
+                        "Imports cannot return {} in bindings, as host functions cannot allocate",
+                        type_
+                    ),
+                });
+            }
+        }
+        // externs from different modules may share a name
+        let mut name = rust_name(&ext.name);
+        if names.contains(&name) {
+            name = rust_name(&format!("{}_{}", ext.module, ext.name));
+        }
+        names.push(unique_name(&names, name));
+    }
+
+    writeln!(out)?;
+    writeln!(out, "/// The functions the program imports")?;
+    writeln!(out, "#[allow(non_snake_case)]")?;
+    writeln!(out, "pub trait Imports {{")?;
+    for (ext, name) in externs.iter().zip(&names) {
+        writeln!(
+            out,
+            "    /// extern {:?} {:?} {}",
+            ext.path.0,
+            ext.path.1,
+            wac_signature(&ext.name, &ext.type_)
+        )?;
+        write!(out, "    fn {}(&self, memory: MemoryRef", name)?;
+        for (param, type_) in &ext.type_.parameters {
+            write!(out, ", {}: {}", rust_name(param), types.owned(type_))?;
+        }
+        match &ext.type_.return_type {
+            // (never a str or record, see above)
+            ReturnType::Type(type_) => writeln!(out, ") -> {};", rust_type(type_))?,
+            ReturnType::Void | ReturnType::NoReturn => writeln!(out, ");")?,
+        }
+    }
+    writeln!(out, "}}")?;
+
+    writeln!(out)?;
+    writeln!(
+        out,
+        "/// Provides the functions of imports to the programs the engine runs"
+    )?;
+    writeln!(out, "///")?;
+    writeln!(
+        out,
+        "/// (this panics if the program passes a str or record that is not"
+    )?;
+    writeln!(
+        out,
+        "/// in its memory, which only a corrupted program can do)"
+    )?;
+    writeln!(out, "#[allow(unused_variables)]")?;
+    writeln!(
+        out,
+        "pub fn register_imports<T: Imports + 'static>(engine: &mut Engine, imports: Rc<T>) {{"
+    )?;
+    for (ext, name) in externs.iter().zip(&names) {
+        let args: Vec<_> = (0..ext.type_.parameters.len())
+            .map(|i| format!("a{}", i))
+            .collect();
+        writeln!(out, "    {{")?;
+        writeln!(out, "        let imports = imports.clone();")?;
+        write!(
+            out,
+            "        engine.func({:?}, {:?}, move |memory: MemoryRef",
+            ext.path.0, ext.path.1
+        )?;
+        for (arg, (_, type_)) in args.iter().zip(&ext.type_.parameters) {
+            write!(out, ", {}: {}", arg, rust_type(type_))?;
+        }
+        let pointers = ext
+            .type_
+            .parameters
+            .iter()
+            .any(|(_, type_)| is_object(type_));
+        if pointers {
+            // the strs and records are copied out first
+            writeln!(out, "| {{")?;
+            for (arg, (_, type_)) in args.iter().zip(&ext.type_.parameters) {
+                if is_object(type_) {
+                    let value = types.read(type_, arg, ".unwrap()");
+                    writeln!(out, "            let {} = {};", arg, value)?;
+                }
+            }
+            write!(out, "            imports.{}(memory", name)?;
+        } else {
+            write!(out, "| imports.{}(memory", name)?;
+        }
+        for arg in &args {
+            write!(out, ", {}", arg)?;
+        }
+        if pointers {
+            writeln!(out, ")")?;
+            writeln!(out, "        }});")?;
+        } else {
+            writeln!(out, "));")?;
+        }
+        writeln!(out, "    }}")?;
+    }
+    writeln!(out, "}}")?;
+
+    writeln!(out)?;
+    writeln!(out, "/// The program's exported functions")?;
+    writeln!(out, "pub struct Exports {{")?;
+    writeln!(out, "    pub instance: Instance,")?;
+    writeln!(out, "}}")?;
+    writeln!(out)?;
+    writeln!(out, "#[allow(dead_code, non_snake_case)]")?;
+    writeln!(out, "impl Exports {{")?;
+    writeln!(out, "    pub fn new(instance: Instance) -> Self {{")?;
+    writeln!(out, "        Self {{ instance }}")?;
+    writeln!(out, "    }}")?;
+    let mut names = vec![];
+    for func in &program.funcs {
+        let export = match &func.export {
+            Some(export) if &*func.module != PRELUDE_MODULE => export,
+            _ => continue,
+        };
+        // e.g. exports "a-b" and "a_b"
+        let name = unique_name(&names, rust_name(export));
+        names.push(name.clone());
+        writeln!(out)?;
+        writeln!(
+            out,
+            "    /// fn {}.{}",
+            func.module,
+            wac_signature(&func.name, &func.type_)
+        )?;
+        write!(out, "    pub fn {}(&mut self", name)?;
+        for (param, type_) in &func.type_.parameters {
+            write!(out, ", {}: {}", rust_name(param), types.borrowed(type_))?;
+        }
+        let return_type = match &func.type_.return_type {
+            ReturnType::Type(type_) => types.owned(type_),
+            ReturnType::Void | ReturnType::NoReturn => "()".into(),
+        };
+        writeln!(out, ") -> Result<{}, EngineError> {{", return_type)?;
+
+        // strs and records are copied into the guest first
+        let mut args = vec![];
+        for (param, type_) in &func.type_.parameters {
+            let arg = rust_name(param);
+            if let Some(alloc) = types.alloc(type_, &arg, "self.instance") {
+                writeln!(out, "        let {} = {};", arg, alloc)?;
+            }
+            args.push(arg);
+        }
+        let args = match &args[..] {
+            [arg] => format!("({},)", arg),
+            _ => format!("({})", args.join(", ")),
+        };
+        match &func.type_.return_type {
+            ReturnType::Type(type_ @ Type::Str) | ReturnType::Type(type_ @ Type::Record(_)) => {
+                writeln!(
+                    out,
+                    "        let ptr: i32 = self.instance.call({:?}, {})?;",
+                    export, args
+                )?;
+                writeln!(out, "        let memory = self.instance.memory()?;")?;
+                writeln!(
+                    out,
+                    "        let result = {};",
+                    types.read(type_, "ptr", "?")
+                )?;
+                writeln!(out, "        self.instance.release(ptr as u32)?;")?;
+                writeln!(out, "        Ok(result)")?;
+            }
+            _ => writeln!(out, "        self.instance.call({:?}, {})", export, args)?,
+        }
+        writeln!(out, "    }}")?;
+    }
+    writeln!(out, "}}")?;
+    Ok(out)
+}
+
+/// The Rust structs generated for the program's records
+struct Types {
+    records: Vec<(Rc<Record>, String)>,
+}
+
+impl Types {
+    fn record_name(&self, rec: &Rc<Record>) -> &str {
+        match self.records.iter().find(|(r, _)| Rc::ptr_eq(r, rec)) {
+            Some((_, name)) => name,
+            None => panic!("Record {} is not in the program", rec.name),
+        }
+    }
+
+    /// The type of a value copied out of the guest
+    fn owned(&self, type_: &Type) -> String {
+        match type_ {
+            Type::Str => "String".into(),
+            Type::Record(rec) => self.record_name(rec).into(),
+            _ => rust_type(type_).into(),
+        }
+    }
+
+    /// The type of a value to be copied into the guest
+    fn borrowed(&self, type_: &Type) -> String {
+        match type_ {
+            Type::Str => "&str".into(),
+            Type::Record(rec) => format!("&{}", self.record_name(rec)),
+            _ => rust_type(type_).into(),
+        }
+    }
+
+    /// The type of a record's field, where a record may refer to itself
+    /// (and to no record at all, with a null pointer)
+    fn field(&self, type_: &Type) -> String {
+        match type_ {
+            Type::Record(rec) => format!("Option<Box<{}>>", self.record_name(rec)),
+            _ => self.owned(type_),
+        }
+    }
+
+    /// An expression copying the value of type_ that the wasm value arg
+    /// holds out of memory (with try, i.e. "?" or ".unwrap()", after
+    /// anything that can fail)
+    fn read(&self, type_: &Type, arg: &str, try_: &str) -> String {
+        match type_ {
+            Type::Str => format!("memory.read_str({} as u32){}", arg, try_),
+            Type::Record(rec) => format!(
+                "{}::read(memory, {} as u32){}",
+                self.record_name(rec),
+                arg,
+                try_
+            ),
+            _ => arg.into(),
+        }
+    }
+
+    /// An expression copying value into the guest, returning the wasm value
+    /// to pass for it, or None if value is one already
+    fn alloc(&self, type_: &Type, value: &str, instance: &str) -> Option<String> {
+        match type_ {
+            Type::Str => Some(format!("{}.alloc_str({})? as i32", instance, value)),
+            Type::Record(_) => Some(format!("{}.alloc(&mut {})? as i32", value, instance)),
+            _ => None,
+        }
+    }
+}
+
+/// A struct with the fields of a record, and methods for
+/// copying it out of and into the guest's memory
+fn gen_record(out: &mut String, types: &Types, rec: &Rc<Record>, name: &str) -> Result<(), Error> {
+    let layout = rec.layout();
+    writeln!(out)?;
+    writeln!(out, "/// record {}.{}", rec.module, rec.name)?;
+    writeln!(out, "#[derive(Debug, Clone, PartialEq)]")?;
+    writeln!(out, "pub struct {} {{", name)?;
+    for (field, type_, _) in &layout.fields {
+        writeln!(out, "    pub {}: {},", rust_name(field), types.field(type_))?;
+    }
+    writeln!(out, "}}")?;
+
+    writeln!(out)?;
+    writeln!(out, "#[allow(dead_code)]")?;
+    writeln!(out, "impl {} {{", name)?;
+    writeln!(out, "    /// Copies the record that ptr points to")?;
+    writeln!(
+        out,
+        "    pub fn read(memory: MemoryRef, ptr: u32) -> Result<Self, EngineError> {{"
+    )?;
+    writeln!(out, "        Ok(Self {{")?;
+    for (field, type_, offset) in &layout.fields {
+        let slot = format!("ptr.wrapping_add({})", offset);
+        let value = match type_ {
+            Type::Str => format!(
+                "memory.read_str(memory.read_slot::<i32>({})? as u32)?",
+                slot
+            ),
+            Type::Record(rec) => format!(
+                concat!(
+                    "match memory.read_slot::<i32>({})? {{\n",
+                    "                0 => None,\n",
+                    "                ptr => Some(Box::new({}::read(memory, ptr as u32)?)),\n",
+                    "            }}"
+                ),
+                slot,
+                types.record_name(rec)
+            ),
+            _ => format!("memory.read_slot({})?", slot),
+        };
+        writeln!(out, "            {}: {},", rust_name(field), value)?;
+    }
+    writeln!(out, "        }})")?;
+    writeln!(out, "    }}")?;
+
+    writeln!(out)?;
+    writeln!(
+        out,
+        "    /// Copies the record into the guest, returning its only reference"
+    )?;
+    writeln!(
+        out,
+        "    pub fn alloc(&self, instance: &mut Instance) -> Result<u32, EngineError> {{"
+    )?;
+    writeln!(out, "        let fields = [")?;
+    for (field, type_, _) in &layout.fields {
+        let field = format!("self.{}", rust_name(field));
+        let value = match type_ {
+            Type::Bool => format!("Value::I32({} as i32)", field),
+            Type::I32 => format!("Value::I32({})", field),
+            Type::I64 | Type::Id => format!("Value::I64({})", field),
+            Type::F32 => format!("Value::F32({})", field),
+            Type::F64 => format!("Value::F64({})", field),
+            Type::Str => format!("Value::I32(instance.alloc_str(&{})? as i32)", field),
+            Type::Record(_) => format!(
+                concat!(
+                    "Value::I32(match &{} {{\n",
+                    "                Some(value) => value.alloc(instance)? as i32,\n",
+                    "                None => 0,\n",
+                    "            }})"
+                ),
+                field
+            ),
+        };
+        writeln!(out, "            {},", value)?;
+    }
+    writeln!(out, "        ];")?;
+    let path = wasm_path(&rec.module, &rec.name);
+    writeln!(
+        out,
+        "        let record = instance.record({:?}).cloned().ok_or_else(|| {{",
+        path
+    )?;
+    writeln!(
+        out,
+        "            EngineError::Type({:?}.into())",
+        format!("The program has no record {}", path)
+    )?;
+    writeln!(out, "        }})?;")?;
+    writeln!(out, "        instance.alloc_record(&record, &fields)")?;
+    writeln!(out, "    }}")?;
+    writeln!(out, "}}")?;
+    Ok(())
+}
+
+/// e.g. "Add(a i32, b i32) i32", as it would be declared in wac
+fn wac_signature(name: &str, type_: &FuncType) -> String {
+    let params: Vec<_> = type_
+        .parameters
+        .iter()
+        .map(|(param, type_)| format!("{} {}", param, type_))
+        .collect();
+    match &type_.return_type {
+        ReturnType::Void => format!("{}({})", name, params.join(", ")),
+        return_type => format!("{}({}) {}", name, params.join(", "), return_type),
+    }
+}
+
+/// The type of the wasm value for type_
+fn rust_type(type_: &Type) -> &'static str {
+    match type_ {
+        Type::Bool => "bool",
+        Type::I32 => "i32",
+        Type::I64 => "i64",
+        Type::F32 => "f32",
+        Type::F64 => "f64",
+        // pointers into the guest's memory
+        Type::Str | Type::Record(_) => "i32",
+        Type::Id => "i64",
+    }
+}
+
+/// Whether values of type_ are strs or records, i.e. guest pointers
+fn is_object(type_: &Type) -> bool {
+    matches!(type_, Type::Str | Type::Record(_))
+}
+
+/// A Rust identifier for the given wac name (or export alias)
+fn rust_name(name: &str) -> String {
+    let mut ident: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if ident.is_empty() || ident.starts_with(|c: char| c.is_ascii_digit()) {
+        ident.insert(0, '_');
+    }
+    if RUST_RESERVED_NAMES.contains(&&*ident) || RUST_TYPE_NAMES.contains(&&*ident) {
+        ident.push('_');
+    }
+    ident
+}
+
+/// name, or if that is taken (e.g. by "a_b" for "a-b"), name with as
+/// many underscores added as it takes to make it unique
+fn unique_name(names: &[String], mut name: String) -> String {
+    while names.contains(&name) {
+        name.push('_');
+    }
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_config;
+    use crate::test_sources;
+    use crate::BackendKind;
+    use crate::Engine;
+    use crate::MemoryRef;
+    use crate::RunConfig;
+    use crate::TEST_BACKENDS;
+    use std::cell::RefCell;
+
+    /// Bindings for the program in bindgen/fixture.wac, checked in
+    /// so that they are compiled (and used below) along with the tests
+    mod fixture {
+        include!("bindgen/fixture.rs");
+    }
+
+    const FIXTURE: &str = include_str!("bindgen/fixture.wac");
+
+    #[test]
+    fn traits_and_wrappers() {
+        let sources = test_sources(
+            r#"
+extern "env" "log" log(msg str, type i32)
+extern "env" "ok" ok(flag bool) bool
+
+export "loop" fn Run(n i64) f64 {
+    log("running", 1)
+    return 1.5
+}
+
+export "loop-" fn Other() {
+}
+"#,
+        );
+        let config = test_config(BackendKind::Wasmer);
+        let bindings = translate_to_bindings(sources, &config).unwrap();
+        let lines: Vec<_> = bindings.lines().map(str::trim).collect();
+        for expected in &[
+            "/// extern \"env\" \"log\" log(msg str, type i32)",
+            "fn log(&self, memory: MemoryRef, msg: String, type_: i32);",
+            "fn ok(&self, memory: MemoryRef, flag: bool) -> bool;",
+            "engine.func(\"env\", \"ok\", move |memory: MemoryRef, a0: bool| imports.ok(memory, a0));",
+            "/// fn app.Run(n i64) f64",
+            "pub fn loop_(&mut self, n: i64) -> Result<f64, EngineError> {",
+            "self.instance.call(\"loop\", (n,))",
+            "pub fn loop__(&mut self) -> Result<(), EngineError> {",
+        ] {
+            assert!(lines.contains(expected), "{} not in\n{}", expected, bindings);
+        }
+        // the prelude's externs and exports are left out
+        assert!(!bindings.contains("stdout_write"));
+        assert!(!bindings.contains("__malloc"));
+
+        // imports cannot hand strs or records to the guest
+        let sources = test_sources(
+            r#"
+extern "env" "name" name() str
+
+export fn Name() str {
+    return name()
+}
+"#,
+        );
+        let error = translate_to_bindings(sources, &config).unwrap_err();
+        assert!(
+            error.message.contains("cannot return str"),
+            "{}",
+            error.message
+        );
+        assert_eq!(error.span[0].lineno(), 2);
+    }
+
+    #[test]
+    fn fixture_is_up_to_date() {
+        let config = test_config(BackendKind::Wasmer);
+        let bindings = translate_to_bindings(test_sources(FIXTURE), &config).unwrap();
+        assert!(
+            bindings == include_str!("bindgen/fixture.rs"),
+            "src/bindgen/fixture.rs is out of date, it should be:\n{}",
+            bindings
+        );
+    }
+
+    #[derive(Default)]
+    struct Host {
+        logged: RefCell<Vec<(String, fixture::Point)>>,
+    }
+
+    impl fixture::Imports for Host {
+        fn log(&self, _: MemoryRef, msg: String, at: fixture::Point) {
+            self.logged.borrow_mut().push((msg, at));
+        }
+    }
+
+    #[test]
+    fn fixture_bindings() {
+        use fixture::Exports;
+        use fixture::Point;
+        use fixture::Segment;
+
+        let origin = Point {
+            x: 0,
+            label: "origin".into(),
+            y: 0.0,
+        };
+        let point = Point {
+            x: 3,
+            label: "p".into(),
+            y: 1.5,
+        };
+        for &backend in TEST_BACKENDS {
+            let host = Rc::new(Host::default());
+            let mut engine = Engine::new(RunConfig {
+                heap_stats: true,
+                ..test_config(backend)
+            });
+            fixture::register_imports(&mut engine, host.clone());
+            let module = engine.compile(test_sources(FIXTURE)).unwrap();
+            let mut exports = Exports::new(engine.instantiate(&module).unwrap());
+
+            assert_eq!(exports.Echo("wac").unwrap(), "wac");
+            assert_eq!(exports.Same(&point).unwrap(), point);
+            let segment = Segment {
+                name: "s".into(),
+                start: Some(Box::new(origin.clone())),
+                end: None,
+            };
+            assert_eq!(exports.Join(&segment).unwrap(), segment);
+            exports.Log("here", &origin).unwrap();
+            assert_eq!(
+                *host.logged.borrow(),
+                vec![("here".to_owned(), origin.clone())]
+            );
+
+            // the bindings give up the references they are left with
+            let heap = exports.instance.stats().heap.clone().unwrap();
+            assert_eq!(heap.live_objects, 0, "{:?}", backend);
+        }
+    }
+}
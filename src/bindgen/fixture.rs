@@ -0,0 +1,157 @@
+// Generated by wac bindgen
+use std::rc::Rc;
+use wac::wasm::Value;
+use wac::Engine;
+use wac::EngineError;
+use wac::Instance;
+use wac::MemoryRef;
+
+/// record app.Point
+#[derive(Debug, Clone, PartialEq)]
+pub struct Point {
+    pub x: i32,
+    pub label: String,
+    pub y: f64,
+}
+
+#[allow(dead_code)]
+impl Point {
+    /// Copies the record that ptr points to
+    pub fn read(memory: MemoryRef, ptr: u32) -> Result<Self, EngineError> {
+        Ok(Self {
+            x: memory.read_slot(ptr.wrapping_add(24))?,
+            label: memory.read_str(memory.read_slot::<i32>(ptr.wrapping_add(16))? as u32)?,
+            y: memory.read_slot(ptr.wrapping_add(32))?,
+        })
+    }
+
+    /// Copies the record into the guest, returning its only reference
+    pub fn alloc(&self, instance: &mut Instance) -> Result<u32, EngineError> {
+        let fields = [
+            Value::I32(self.x),
+            Value::I32(instance.alloc_str(&self.label)? as i32),
+            Value::F64(self.y),
+        ];
+        let record = instance.record("app.Point").cloned().ok_or_else(|| {
+            EngineError::Type("The program has no record app.Point".into())
+        })?;
+        instance.alloc_record(&record, &fields)
+    }
+}
+
+/// record app.Segment
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub name: String,
+    pub start: Option<Box<Point>>,
+    pub end: Option<Box<Point>>,
+}
+
+#[allow(dead_code)]
+impl Segment {
+    /// Copies the record that ptr points to
+    pub fn read(memory: MemoryRef, ptr: u32) -> Result<Self, EngineError> {
+        Ok(Self {
+            name: memory.read_str(memory.read_slot::<i32>(ptr.wrapping_add(16))? as u32)?,
+            start: match memory.read_slot::<i32>(ptr.wrapping_add(24))? {
+                0 => None,
+                ptr => Some(Box::new(Point::read(memory, ptr as u32)?)),
+            },
+            end: match memory.read_slot::<i32>(ptr.wrapping_add(32))? {
+                0 => None,
+                ptr => Some(Box::new(Point::read(memory, ptr as u32)?)),
+            },
+        })
+    }
+
+    /// Copies the record into the guest, returning its only reference
+    pub fn alloc(&self, instance: &mut Instance) -> Result<u32, EngineError> {
+        let fields = [
+            Value::I32(instance.alloc_str(&self.name)? as i32),
+            Value::I32(match &self.start {
+                Some(value) => value.alloc(instance)? as i32,
+                None => 0,
+            }),
+            Value::I32(match &self.end {
+                Some(value) => value.alloc(instance)? as i32,
+                None => 0,
+            }),
+        ];
+        let record = instance.record("app.Segment").cloned().ok_or_else(|| {
+            EngineError::Type("The program has no record app.Segment".into())
+        })?;
+        instance.alloc_record(&record, &fields)
+    }
+}
+
+/// The functions the program imports
+#[allow(non_snake_case)]
+pub trait Imports {
+    /// extern "env" "log" log(msg str, at Point)
+    fn log(&self, memory: MemoryRef, msg: String, at: Point);
+}
+
+/// Provides the functions of imports to the programs the engine runs
+///
+/// (this panics if the program passes a str or record that is not
+/// in its memory, which only a corrupted program can do)
+#[allow(unused_variables)]
+pub fn register_imports<T: Imports + 'static>(engine: &mut Engine, imports: Rc<T>) {
+    {
+        let imports = imports.clone();
+        engine.func("env", "log", move |memory: MemoryRef, a0: i32, a1: i32| {
+            let a0 = memory.read_str(a0 as u32).unwrap();
+            let a1 = Point::read(memory, a1 as u32).unwrap();
+            imports.log(memory, a0, a1)
+        });
+    }
+}
+
+/// The program's exported functions
+pub struct Exports {
+    pub instance: Instance,
+}
+
+#[allow(dead_code, non_snake_case)]
+impl Exports {
+    pub fn new(instance: Instance) -> Self {
+        Self { instance }
+    }
+
+    /// fn app.Echo(s str) str
+    pub fn Echo(&mut self, s: &str) -> Result<String, EngineError> {
+        let s = self.instance.alloc_str(s)? as i32;
+        let ptr: i32 = self.instance.call("Echo", (s,))?;
+        let memory = self.instance.memory()?;
+        let result = memory.read_str(ptr as u32)?;
+        self.instance.release(ptr as u32)?;
+        Ok(result)
+    }
+
+    /// fn app.Same(p Point) Point
+    pub fn Same(&mut self, p: &Point) -> Result<Point, EngineError> {
+        let p = p.alloc(&mut self.instance)? as i32;
+        let ptr: i32 = self.instance.call("Same", (p,))?;
+        let memory = self.instance.memory()?;
+        let result = Point::read(memory, ptr as u32)?;
+        self.instance.release(ptr as u32)?;
+        Ok(result)
+    }
+
+    /// fn app.Join(s Segment) Segment
+    pub fn Join(&mut self, s: &Segment) -> Result<Segment, EngineError> {
+        let s = s.alloc(&mut self.instance)? as i32;
+        let ptr: i32 = self.instance.call("Join", (s,))?;
+        let memory = self.instance.memory()?;
+        let result = Segment::read(memory, ptr as u32)?;
+        self.instance.release(ptr as u32)?;
+        Ok(result)
+    }
+
+    /// fn app.Log(msg str, at Point)
+    pub fn Log(&mut self, msg: &str, at: &Point) -> Result<(), EngineError> {
+        let msg = self.instance.alloc_str(msg)? as i32;
+        let at = at.alloc(&mut self.instance)? as i32;
+        self.instance.call("Log", (msg, at))
+    }
+}
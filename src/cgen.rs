@@ -1,189 +1,688 @@
 use crate::ir::*;
+use crate::wasm;
+use crate::wasm::Instr;
+use crate::wasm::ValType;
 use crate::ByteCount;
 use crate::Error;
-use std::fmt::Write;
+use crate::FuncMap;
+use crate::RunConfig;
+use crate::Source;
+use crate::SourceMap;
+use crate::Span;
+use crate::Target;
+use std::collections::HashMap;
+use std::rc::Rc;
 
 impl Program {
-    /// Translate the given program into webassembly
-    pub fn wat(self) -> Result<String, Error> {
-        gen(self)
+    /// Translate the given program into webassembly text
+    pub fn wat(self, config: &RunConfig) -> Result<String, Error> {
+        Ok(self.module(config)?.0.wat())
+    }
+
+    /// Translate the given program into a webassembly module,
+    /// along with the spans of the generated instructions
+    pub fn module(self, config: &RunConfig) -> Result<(wasm::Module, SourceMap), Error> {
+        gen(self, config)
     }
 }
 
-/// translate a program into webassembly text
-fn gen(program: Program) -> Result<String, Error> {
-    let mut out = String::new();
+/// The module being generated, along with its source map
+///
+/// Instructions are added to the body of the current function
+/// one at a time, and are attributed to whatever span is current.
+struct Out {
+    module: wasm::Module,
+    body: Vec<Instr>,
+    span: Option<Span>,
+    source_map: SourceMap,
+
+    /// whether the current function needs HELPER_LOCAL
+    uses_helper: bool,
+
+    /// the number of extern arguments the current function keeps in
+    /// locals of their own until after the call (see gen_extern_call)
+    extern_args: usize,
 
+    /// whether to maintain the shadow stack (see gen_debug_data)
+    debug: bool,
+
+    /// whether to count fuel, and whether to poll the deadline of
+    /// the time limit (see gen_fuel_check)
+    metering: bool,
+    deadline: bool,
+
+    /// the functions that retain and release strs and records
+    /// (see RunConfig::refcount_funcs)
+    retain: Rc<str>,
+    release: Rc<str>,
+
+    call_site_ids: HashMap<(*const Source, usize), usize>,
+}
+
+/// A scratch local, declared in any function that uses it
+const HELPER_LOCAL: &str = "helper/i32";
+
+/// The local holding the i-th str or record passed to an extern
+/// in the current function
+fn extern_arg_local(i: usize) -> String {
+    format!("helper/extern{}", i)
+}
+
+impl Out {
+    fn new(config: &RunConfig) -> Self {
+        let (retain, release) = config.refcount_funcs();
+        Self {
+            module: wasm::Module::default(),
+            body: vec![],
+            span: None,
+            source_map: SourceMap::default(),
+            uses_helper: false,
+            extern_args: 0,
+            debug: config.debug,
+            metering: config.fuel_budget().is_some(),
+            deadline: config.time_limit.is_some(),
+            retain: format!("f/{}", retain).into(),
+            release: format!("f/{}", release).into(),
+            call_site_ids: HashMap::new(),
+        }
+    }
+
+    /// the id of the call site at the current span
+    fn call_site_id(&mut self) -> usize {
+        let span = match &self.span {
+            Some(span) => span,
+            None => return 0,
+        };
+        let call_sites = &mut self.source_map.call_sites;
+        *self
+            .call_site_ids
+            .entry((Rc::as_ptr(&span.source), span.main))
+            .or_insert_with(|| {
+                call_sites.push(span.clone());
+                call_sites.len()
+            })
+    }
+
+    /// adds an instruction to the current function
+    fn push(&mut self, instr: Instr) {
+        self.body.push(instr);
+        let func = self.source_map.funcs.last_mut().unwrap();
+        func.spans.push(self.span.clone());
+    }
+
+    /// sets the span of the instructions that follow, returning the old one
+    fn set_span(&mut self, span: Option<Span>) -> Option<Span> {
+        std::mem::replace(&mut self.span, span)
+    }
+
+    fn begin_func(&mut self, name: String) {
+        self.source_map.funcs.push(FuncMap {
+            name: name.into(),
+            spans: vec![],
+        });
+    }
+
+    /// adds the function, with the instructions pushed since begin_func as its body
+    fn end_func(&mut self, mut func: wasm::Func) {
+        if std::mem::replace(&mut self.uses_helper, false) {
+            func.locals.push((HELPER_LOCAL.into(), ValType::I32));
+        }
+        for i in 0..std::mem::replace(&mut self.extern_args, 0) {
+            func.locals.push((extern_arg_local(i).into(), ValType::I32));
+        }
+        func.body = std::mem::take(&mut self.body);
+        self.module.funcs.push(func);
+    }
+}
+
+/// translate a program into a webassembly module
+fn gen(program: Program, config: &RunConfig) -> Result<(wasm::Module, SourceMap), Error> {
+    let mut out = Out::new(config);
+
+    // imports must come before any other definitions
     for ext in &program.externs {
-        gen_extern(&mut out, ext)?;
+        gen_extern(&mut out, ext);
+    }
+    if out.metering {
+        gen_fuel_import(&mut out, "out_of_fuel", vec![]);
+    }
+    if out.deadline {
+        gen_fuel_import(&mut out, "check_deadline", vec![ValType::I32]);
+    }
+    for gvar in &program.globals {
+        if let Some((module, name)) = &gvar.import {
+            out.module.imports.push(wasm::Import {
+                module: module.clone(),
+                name: name.clone(),
+                kind: wasm::ImportKind::Global(gvar.wasm_name().into(), trtype(&gvar.type_), true),
+            });
+        }
+    }
+
+    // the code is generated before the data, as in debug builds
+    // it decides what goes into the debug tables in static memory
+    for func in &program.funcs {
+        gen_func(&mut out, func)?;
+    }
+
+    gen_start(&mut out, &program)?;
+
+    match (config.target, &program.main) {
+        (Target::Wac, _) => out.module.start = Some("start".into()),
+
+        // WASI host functions (e.g. fd_write in an initializer) can only be
+        // used once the instance is created, which is after the start function
+        // runs, so commands initialize globals in _start instead, and
+        // libraries (reactors in WASI terms) in _initialize
+        (Target::Wasi, Some(main)) if !config.library => {
+            out.begin_func("_start".into());
+            out.push(Instr::Call("start".into()));
+            out.push(Instr::Call(main.wasm_name().into()));
+            if main.type_.return_type == ReturnType::Type(Type::I32) {
+                // Main's result is the exit status
+                out.push(Instr::Call("f/__proc_exit".into()));
+            }
+            out.end_func(wasm::Func {
+                name: "_start".into(),
+                params: vec![],
+                results: vec![],
+                locals: vec![],
+                body: vec![],
+            });
+            gen_export(&mut out, "_start", "_start");
+        }
+        (Target::Wasi, _) => gen_export(&mut out, "_initialize", "start"),
+    }
+    for func in &program.funcs {
+        if let Some(export) = &func.export {
+            gen_export(&mut out, export, &func.wasm_name());
+        }
+    }
+    if let Some(budget) = config.fuel_budget() {
+        gen_fuel(&mut out, budget as i64);
+    }
+    if config.leak_check {
+        gen_release_globals(&mut out, &program);
     }
 
-    let static_mem_end = program.memory.borrow().get_mem_end();
-    let start_page_cnt = std::cmp::max(1, (static_mem_end + PAGE_SIZE - 1) / PAGE_SIZE);
+    let mut static_mem_end = program.memory.borrow().get_mem_end();
+    let debug_data = if config.debug {
+        let (start, data) = gen_debug_data(static_mem_end, &out.source_map);
+        static_mem_end = start + data.len() + SHADOW_STACK_SIZE;
+        Some((start, data))
+    } else {
+        None
+    };
+    let start_page_cnt = std::cmp::max(1, (static_mem_end + PAGE_SIZE - 1) / PAGE_SIZE) as u32;
+    check_max_pages(start_page_cnt as usize, config)?;
+    let limits = wasm::Limits {
+        min: start_page_cnt,
+        max: config.max_pages,
+    };
 
-    writeln!(out, "(memory $memory {})", start_page_cnt)?;
+    match &program.memory_import {
+        // the host's memory must be at least as large as the one we would
+        // define, and is laid out the same, with the static data written
+        // into it on instantiation (see ir.rs's Memory)
+        Some((module, name)) => out.module.imports.push(wasm::Import {
+            module: module.clone(),
+            name: name.clone(),
+            kind: wasm::ImportKind::Memory("memory".into(), limits),
+        }),
+        None => out.module.memory = Some(("memory".into(), limits)),
+    }
+    // WASI hosts access memory through the "memory" export
+    if config.export_memory || config.target == Target::Wasi {
+        out.module.exports.push(wasm::Export {
+            name: "memory".into(),
+            kind: wasm::ExportKind::Memory("memory".into()),
+        });
+    }
 
     // record where the current heap limit is
-    gen_data(&mut out, HEAP_LIMIT_PTR, &static_mem_end.to_le_bytes())?;
+    gen_data(
+        &mut out,
+        HEAP_LIMIT_PTR,
+        (static_mem_end as u32).to_le_bytes().to_vec(),
+    );
+    if config.track_allocs() {
+        // have the allocator report to the host (see leaks.rs)
+        gen_data(&mut out, TRACK_ALLOCS_PTR, 1u32.to_le_bytes().to_vec());
+    }
+    if config.debug_alloc {
+        gen_data(&mut out, DEBUG_ALLOC_PTR, 1u32.to_le_bytes().to_vec());
+    }
+    if config.heap_stats {
+        // have __retain and __release count their calls
+        gen_data(&mut out, HEAP_STATS_PTR, 1u32.to_le_bytes().to_vec());
+    }
 
     // write out the rest of the data
     let (start_pos, data) = program.memory.borrow().gen();
-    gen_data(&mut out, start_pos, &data)?;
+    gen_data(&mut out, start_pos, data);
+
+    if let Some((start, data)) = debug_data {
+        // the shadow stack starts out empty, right after the tables
+        let stack = (start + data.len()) as u32;
+        gen_data(&mut out, SHADOW_SP_PTR, stack.to_le_bytes().to_vec());
+        gen_data(
+            &mut out,
+            DEBUG_TABLES_PTR,
+            (start as u32).to_le_bytes().to_vec(),
+        );
+        gen_data(&mut out, start, data);
+    }
 
-    writeln!(
-        out,
-        "(global $rt/static_mem_end i32 (i32.const {}))",
-        static_mem_end
-    )?;
+    out.module.globals.push(wasm::Global {
+        name: "rt/static_mem_end".into(),
+        type_: ValType::I32,
+        mutable: false,
+        init: Instr::I32Const(static_mem_end as i32),
+    });
     for gvar in &program.globals {
-        gen_global(&mut out, gvar)?;
+        if gvar.import.is_none() {
+            gen_global(&mut out, gvar);
+        }
     }
 
-    for func in &program.funcs {
-        gen_func(&mut out, func)?;
+    let metadata = gen_metadata(&program, config);
+    out.module.custom.push((METADATA_SECTION.into(), metadata));
+
+    Ok((out.module, out.source_map))
+}
+
+/// The most pages memory may grow to (as in wasm32, 4GiB)
+pub(crate) const MAX_PAGES: usize = 65536;
+
+/// Checks that config.max_pages is within wasm32's limit,
+/// and that the static data fits in it
+pub(crate) fn check_max_pages(start_page_cnt: usize, config: &RunConfig) -> Result<(), Error> {
+    match config.max_pages {
+        Some(max_pages) if max_pages as usize > MAX_PAGES => Err(Error {
+            span: vec![],
+            message: format!(
+                "At most {} pages of memory can be allowed, not {}",
+                MAX_PAGES, max_pages
+            ),
+        }),
+        Some(max_pages) if start_page_cnt > max_pages as usize => Err(Error {
+            span: vec![],
+            message: format!(
+                "The program's static data needs {} pages of memory, but at most {} are allowed",
+                start_page_cnt, max_pages
+            ),
+        }),
+        _ => Ok(()),
     }
+}
 
-    gen_start(&mut out, &program)?;
+/// The export giving the fuel a metered program has left, as an i64
+pub const FUEL_LEFT_EXPORT: &str = "__fuel_left";
+
+/// How often (in units of fuel) a program with a deadline checks it
+pub const DEADLINE_CHECK_INTERVAL: i64 = 1 << 16;
 
-    writeln!(out, "(start $start)")?;
-    writeln!(out, r#"(export "Main" (func $f/Main))"#)?;
+pub const TRAP_OUT_OF_FUEL: &str = "out of fuel";
+pub const TRAP_DEADLINE_EXCEEDED: &str = "deadline exceeded";
+
+/// Declares the fuel counter, starting with the given budget,
+/// and the export that reads it
+fn gen_fuel(out: &mut Out, budget: i64) {
+    out.module.globals.push(wasm::Global {
+        name: "rt/fuel".into(),
+        type_: ValType::I64,
+        mutable: true,
+        init: Instr::I64Const(budget),
+    });
+    out.begin_func("fuel_left".into());
+    out.push(Instr::GlobalGet("rt/fuel".into()));
+    out.end_func(wasm::Func {
+        name: "rt/fuel_left".into(),
+        params: vec![],
+        results: vec![ValType::I64],
+        locals: vec![],
+        body: vec![],
+    });
+    gen_export(out, FUEL_LEFT_EXPORT, "rt/fuel_left");
+}
 
-    Ok(out)
+/// Declares the "wac" import (taking nothing) that
+/// gen_fuel_check calls as rt/<name>
+fn gen_fuel_import(out: &mut Out, name: &str, results: Vec<ValType>) {
+    out.module.imports.push(wasm::Import {
+        module: "wac".into(),
+        name: name.into(),
+        kind: wasm::ImportKind::Func(
+            format!("rt/{}", name).into(),
+            wasm::FuncType {
+                params: vec![],
+                results,
+            },
+        ),
+    });
 }
 
-fn gen_data(out: &mut String, start_pos: usize, data: &[u8]) -> Result<(), Error> {
-    write!(out, "(data (i32.const {}) \"", start_pos)?;
-    for byte in data {
-        write!(out, "\\{:02x}", byte)?;
+/// Consumes a unit of fuel, trapping if there is none left
+/// (or if the deadline has passed, which is checked every
+/// DEADLINE_CHECK_INTERVAL units)
+///
+/// This is done on entry to every function, which is enough to bound
+/// the running time as long as recursion is the only way to repeat code;
+/// loops will need it on their back edges too. The traps are raised by
+/// the host in out_of_fuel, so that it comes with its reason (the
+/// unreachable after it is never reached), and by the program when
+/// check_deadline says the deadline has passed (which the runner then
+/// reports as the reason, see Runner's Clock).
+fn gen_fuel_check(out: &mut Out) {
+    out.push(Instr::GlobalGet("rt/fuel".into()));
+    out.push(Instr::op("i64.eqz"));
+    out.push(Instr::If(None));
+    out.push(Instr::Call("rt/out_of_fuel".into()));
+    out.push(Instr::op("unreachable"));
+    out.push(Instr::End);
+    out.push(Instr::GlobalGet("rt/fuel".into()));
+    out.push(Instr::I64Const(1));
+    out.push(Instr::op("i64.sub"));
+    out.push(Instr::GlobalSet("rt/fuel".into()));
+    if out.deadline {
+        out.push(Instr::GlobalGet("rt/fuel".into()));
+        out.push(Instr::I64Const(DEADLINE_CHECK_INTERVAL - 1));
+        out.push(Instr::op("i64.and"));
+        out.push(Instr::op("i64.eqz"));
+        out.push(Instr::If(None));
+        out.push(Instr::Call("rt/check_deadline".into()));
+        out.push(Instr::If(None));
+        out.push(Instr::op("unreachable"));
+        out.push(Instr::End);
+        out.push(Instr::End);
     }
-    writeln!(out, "\")")?;
-    Ok(())
 }
 
-fn gen_global(out: &mut String, gvar: &Global) -> Result<(), Error> {
+/// The export releasing the globals, in programs checking for leaks
+pub const RELEASE_GLOBALS_EXPORT: &str = "__release_globals";
+
+/// Releases the globals holding strs and records, and zeroes them,
+/// so that only leaked objects are left (see Runner::check_leaks)
+fn gen_release_globals(out: &mut Out, program: &Program) {
+    out.begin_func("release_globals".into());
+    for gvar in &program.globals {
+        if gvar.import.is_none() && gvar.type_.retain_type() == RetainType::Typed {
+            out.set_span(Some(gvar.span.clone()));
+            release_var(out, &Variable::Global(gvar.clone()));
+            out.push(trzeroval(&gvar.type_));
+            out.push(Instr::GlobalSet(gvar.wasm_name().into()));
+        }
+    }
+    out.set_span(None);
+    out.end_func(wasm::Func {
+        name: "rt/release_globals".into(),
+        params: vec![],
+        results: vec![],
+        locals: vec![],
+        body: vec![],
+    });
+    gen_export(out, RELEASE_GLOBALS_EXPORT, "rt/release_globals");
+}
+
+/// The name of the custom section describing how the module was built
+pub const METADATA_SECTION: &str = "wac.metadata";
+
+/// The content of the metadata section, which is text with one
+/// 'key value' pair per line, e.g.
+///
+/// ```text
+///     version 0.0.1
+///     target wasi
+///     debug false
+///     export Add (a i32, b i32)i32
+/// ```
+///
+/// with an 'export' line giving the wac type of each exported function.
+fn gen_metadata(program: &Program, config: &RunConfig) -> Vec<u8> {
+    let mut out = String::new();
+    out.push_str(&format!("version {}\n", env!("CARGO_PKG_VERSION")));
+    out.push_str(&format!("target {}\n", config.target.name()));
+    out.push_str(&format!("debug {}\n", config.debug));
+    for func in &program.funcs {
+        if let Some(export) = &func.export {
+            out.push_str(&format!("export {} {}\n", export, func.type_));
+        }
+    }
+    out.into_bytes()
+}
+
+/// Lays out the tables that give names to the ids on the shadow stack,
+/// to be placed at the first 8-byte aligned address from start
+///
+/// In debug builds, every function pushes a (function id, call site id)
+/// frame onto a shadow stack on entry and pops it on exit, so that
+/// backtraces can be printed without any help from the engine.
+/// The shadow stack comes right after the tables, which are:
+///
+/// ```text
+///     [i32 shadow stack base][i32 function names][i32 call sites][i32 reserved]
+/// ```
+///
+/// where function names and call sites are arrays of pointers to strs,
+/// indexed by id (call site 0 is for calls from the host).
+fn gen_debug_data(start: usize, source_map: &SourceMap) -> (usize, Vec<u8>) {
+    let start = (start + 7) / 8 * 8;
+    let names_start = start + 16;
+    let sites_start = names_start + 4 * source_map.funcs.len();
+    let strings_start = sites_start + 4 * (source_map.call_sites.len() + 1);
+
+    let mut tables = Vec::new();
+    let mut strings = Vec::new();
+    let mut add_str = |tables: &mut Vec<u8>, string: &str| {
+        let ptr = (strings_start + strings.len()) as u32;
+        tables.extend(&ptr.to_le_bytes());
+        gen_str_object(&mut strings, string);
+    };
+    for func in &source_map.funcs {
+        add_str(&mut tables, &func.name);
+    }
+    tables.extend(&0u32.to_le_bytes());
+    for span in &source_map.call_sites {
+        add_str(
+            &mut tables,
+            &format!("{}:{}", span.source.name, span.lineno()),
+        );
+    }
+    strings.resize((strings.len() + 7) / 8 * 8, 0);
+
+    let mut data = Vec::new();
+    data.extend(&((strings_start + strings.len()) as u32).to_le_bytes());
+    data.extend(&(names_start as u32).to_le_bytes());
+    data.extend(&(sites_start as u32).to_le_bytes());
+    data.extend(&0u32.to_le_bytes());
+    data.extend(tables);
+    data.extend(strings);
+    (start, data)
+}
+
+fn gen_data(out: &mut Out, start_pos: usize, bytes: Vec<u8>) {
+    out.module.data.push(wasm::Data {
+        offset: start_pos as u32,
+        bytes,
+    });
+}
+
+fn gen_export(out: &mut Out, name: &str, func: &str) {
+    out.module.exports.push(wasm::Export {
+        name: name.into(),
+        kind: wasm::ExportKind::Func(func.into()),
+    });
+}
+
+fn gen_global(out: &mut Out, gvar: &Global) {
     // declare global variables
     // they are not actually initialized until 'gen_start'
-    writeln!(
-        out,
-        "(global $g/{} (mut {}) {})",
-        gvar.name,
-        trtype(&gvar.type_),
-        trzeroval(&gvar.type_)
-    )?;
-    Ok(())
+    out.module.globals.push(wasm::Global {
+        name: gvar.wasm_name().into(),
+        type_: trtype(&gvar.type_),
+        mutable: true,
+        init: trzeroval(&gvar.type_),
+    });
 }
 
 /// given a type gives the 'zero value expression' for the associated type
 /// this is primarily for initializing variables
-fn trzeroval(typ: &Type) -> &str {
-    match typ {
-        Type::F32 => "(f32.const 0)",
-        Type::F64 => "(f64.const 0)",
-        Type::I64 => "(i64.const 0)",
-        Type::I32 | Type::Bool | Type::Str | Type::Record(_) => "(i32.const 0)",
-        Type::Id => "(i64.const 0)",
+fn trzeroval(typ: &Type) -> Instr {
+    match trtype(typ) {
+        ValType::F32 => Instr::F32Const(0.0),
+        ValType::F64 => Instr::F64Const(0.0),
+        ValType::I64 => Instr::I64Const(0),
+        ValType::I32 => Instr::I32Const(0),
     }
 }
 
-fn gen_start(out: &mut String, program: &Program) -> Result<(), Error> {
+fn gen_start(out: &mut Out, program: &Program) -> Result<(), Error> {
     // Initialize global variables
-    out.push_str("(func $start\n");
-    for local in &program.gvar_init_locals {
-        out.push_str(&format!(
-            "(local $l/{}/{} {})\n",
-            local.id,
-            local.name,
-            trtype(&local.type_)
-        ));
-    }
+    out.begin_func("start".into());
     for gvar in &program.globals {
-        gen_expr(out, &gvar.init)?;
-        out.push_str(&format!("global.set $g/{}\n", gvar.name));
+        if let Some(init) = &gvar.init {
+            gen_expr(out, init)?;
+            out.set_span(Some(gvar.span.clone()));
+            out.push(Instr::GlobalSet(gvar.wasm_name().into()));
+        }
     }
-    // TOOD: release all local variables from gvar_init_locals here
-    out.push_str(")\n");
+    // release all local variables, as at the end of a function
+    for local in &program.gvar_init_locals {
+        out.set_span(Some(local.span.clone()));
+        release_var(out, &Variable::Local(local.clone()));
+    }
+    out.set_span(None);
+    out.end_func(wasm::Func {
+        name: "start".into(),
+        params: vec![],
+        results: vec![],
+        locals: program.gvar_init_locals.iter().map(trlocal).collect(),
+        body: vec![],
+    });
     Ok(())
 }
 
-fn gen_extern(out: &mut String, ext: &Extern) -> Result<(), Error> {
-    out.push_str(&format!(
-        "(import \"{}\" \"{}\" (func $f/{}",
-        ext.path.0, ext.path.1, ext.name
-    ));
-    for param in &ext.type_.parameters {
-        out.push_str(&format!(" (param {})", trtype(&param.1)));
+/// sets a variable, leaving the value on the stack
+fn gen_tee(out: &mut Out, var: &Variable) {
+    match var {
+        Variable::Local(_) => out.push(Instr::LocalTee(var.wasm_name().into())),
+        Variable::Global(_) => {
+            // there is no global.tee
+            out.push(set_var(var));
+            out.push(get_var(var));
+        }
+    }
+}
+
+fn get_var(var: &Variable) -> Instr {
+    match var {
+        Variable::Local(_) => Instr::LocalGet(var.wasm_name().into()),
+        Variable::Global(_) => Instr::GlobalGet(var.wasm_name().into()),
     }
-    out.push_str(&trrtype(&ext.type_.return_type));
-    out.push_str("))\n");
-    Ok(())
+}
+
+fn set_var(var: &Variable) -> Instr {
+    match var {
+        Variable::Local(_) => Instr::LocalSet(var.wasm_name().into()),
+        Variable::Global(_) => Instr::GlobalSet(var.wasm_name().into()),
+    }
+}
+
+fn gen_extern(out: &mut Out, ext: &Extern) {
+    out.module.imports.push(wasm::Import {
+        module: ext.path.0.clone(),
+        name: ext.path.1.clone(),
+        kind: wasm::ImportKind::Func(
+            ext.wasm_name().into(),
+            wasm::FuncType {
+                params: ext.type_.parameters.iter().map(|p| trtype(&p.1)).collect(),
+                results: trrtype(&ext.type_.return_type).into_iter().collect(),
+            },
+        ),
+    });
 }
 
 /// translate type
-fn trtype(type_: &Type) -> &'static str {
+pub(crate) fn trtype(type_: &Type) -> ValType {
     match type_ {
-        Type::Bool => "i32",
-        Type::I32 => "i32",
-        Type::I64 => "i64",
-        Type::F32 => "f32",
-        Type::F64 => "f64",
-        Type::Str => "i32",
-        Type::Record(_) => "i32",
-        Type::Id => "i64",
+        Type::Bool => ValType::I32,
+        Type::I32 => ValType::I32,
+        Type::I64 => ValType::I64,
+        Type::F32 => ValType::F32,
+        Type::F64 => ValType::F64,
+        Type::Str => ValType::I32,
+        Type::Record(_) => ValType::I32,
+        Type::Id => ValType::I64,
     }
 }
 
 /// translate return type
-fn trrtype(type_: &ReturnType) -> String {
+fn trrtype(type_: &ReturnType) -> Option<ValType> {
     match type_ {
-        ReturnType::Type(t) => format!(" (result {})", trtype(t)),
-        _ => "".into(),
+        ReturnType::Type(t) => Some(trtype(t)),
+        _ => None,
     }
 }
 
-fn gen_func(out: &mut String, func: &Func) -> Result<(), Error> {
-    out.push_str(&format!("(func $f/{}", func.name));
-    for param in func.parameters.borrow().iter() {
-        out.push_str(&format!(
-            " (param $l/{}/{} {})",
-            param.id,
-            param.name,
-            trtype(&param.type_)
-        ));
-    }
-    out.push_str(&trrtype(&func.type_.return_type));
-    out.push_str("\n");
+/// translate a local variable (or parameter) declaration
+fn trlocal(local: &Rc<Local>) -> (Rc<str>, ValType) {
+    (
+        Variable::Local(local.clone()).wasm_name().into(),
+        trtype(&local.type_),
+    )
+}
 
-    // declare the local variables, skipping parameters
-    for local in func
-        .locals
-        .borrow()
-        .iter()
-        .skip(func.parameters.borrow().len())
-    {
-        out.push_str(&format!(
-            "(local $l/{}/{} {})\n",
-            local.id,
-            local.name,
-            trtype(&local.type_)
-        ));
+fn gen_func(out: &mut Out, func: &Func) -> Result<(), Error> {
+    out.begin_func(wasm_path(&func.module, &func.name));
+    out.set_span(Some(func.span.clone()));
+
+    if out.debug {
+        gen_push_frame(out, out.source_map.funcs.len() - 1);
+    }
+    if out.metering {
+        gen_fuel_check(out);
     }
 
-    out.push_str("(block $ret");
-    out.push_str(&trrtype(&func.type_.return_type));
-    out.push_str("\n");
+    out.push(Instr::Block(
+        Some("ret".into()),
+        trrtype(&func.type_.return_type),
+    ));
 
     gen_stmt(out, func.body.borrow().as_ref().unwrap())?;
 
-    out.push_str(")\n");
-    // release all local variables here (including parameters)
+    out.push(Instr::End);
+    // release all local variables here (including parameters,
+    // except for borrowed ones)
     for local in func.locals.borrow().iter() {
-        release_var(out, &Variable::Local(local.clone()))?;
+        if !local.borrowed.get() {
+            release_var(out, &Variable::Local(local.clone()));
+        }
+    }
+    if out.debug {
+        gen_pop_frame(out);
     }
-    out.push_str(")\n");
+    out.set_span(None);
+
+    // declare the local variables, skipping parameters
+    let parameters = func.parameters.borrow();
+    out.end_func(wasm::Func {
+        name: func.wasm_name().into(),
+        params: parameters.iter().map(trlocal).collect(),
+        results: trrtype(&func.type_.return_type).into_iter().collect(),
+        locals: func
+            .locals
+            .borrow()
+            .iter()
+            .skip(parameters.len())
+            .map(trlocal)
+            .collect(),
+        body: vec![],
+    });
     Ok(())
 }
 
-fn gen_stmt(out: &mut String, stmt: &Stmt) -> Result<(), Error> {
+fn gen_stmt(out: &mut Out, stmt: &Stmt) -> Result<(), Error> {
+    let old_span = out.set_span(Some(stmt.span.clone()));
     match &stmt.data {
         StmtData::Block(stmts) => {
             for stmt in stmts {
@@ -193,77 +692,93 @@ fn gen_stmt(out: &mut String, stmt: &Stmt) -> Result<(), Error> {
         StmtData::If(pairs, other) => {
             for (cond, body) in pairs {
                 gen_expr(out, cond)?;
-                writeln!(out, "if")?;
+                out.push(Instr::If(None));
                 gen_stmt(out, body)?;
-                writeln!(out, "else")?;
+                out.push(Instr::Else);
             }
             gen_stmt(out, other)?;
             for _ in pairs {
-                writeln!(out, "end")?;
+                out.push(Instr::End);
             }
         }
         StmtData::Return(expr) => {
             gen_expr(out, expr)?;
-            out.push_str("br $ret\n");
+            out.push(Instr::Br("ret".into()));
         }
         StmtData::Expr(expr) => {
             gen_expr(out, expr)?;
-            assert_eq!(expr.type_, ReturnType::Void);
+            assert!(expr.type_ == ReturnType::Void || expr.type_ == ReturnType::NoReturn);
         }
     }
+    out.set_span(old_span);
     Ok(())
 }
 
-fn gen_expr(out: &mut String, expr: &Expr) -> Result<(), Error> {
+fn gen_expr(out: &mut Out, expr: &Expr) -> Result<(), Error> {
+    let old_span = out.set_span(Some(expr.span.clone()));
     match &expr.data {
         ExprData::Void => {}
-        ExprData::Bool(b) => writeln!(out, "i32.const {}", if *b { 1 } else { 0 })?,
-        ExprData::I32(x) => writeln!(out, "i32.const {}", x)?,
-        ExprData::I64(x) => writeln!(out, "i64.const {}", x)?,
-        ExprData::F32(x) => writeln!(out, "f32.const {}", x)?,
-        ExprData::F64(x) => writeln!(out, "f64.const {}", x)?,
+        ExprData::Bool(b) => out.push(Instr::I32Const(if *b { 1 } else { 0 })),
+        ExprData::I32(x) => out.push(Instr::I32Const(*x)),
+        ExprData::I64(x) => out.push(Instr::I64Const(*x)),
+        ExprData::F32(x) => out.push(Instr::F32Const(*x)),
+        ExprData::F64(x) => out.push(Instr::F64Const(*x)),
         ExprData::Str(ptr) => {
-            writeln!(out, "i32.const {}", ptr.get())?;
-            writeln!(out, "call $f/__retain")?;
-            writeln!(out, "i32.const {}", ptr.get())?;
+            out.push(Instr::I32Const(ptr.get() as i32));
+            gen_retain(out);
+            out.push(Instr::I32Const(ptr.get() as i32));
         }
         ExprData::GetVar(x) => match x.type_() {
             Type::Bool | Type::I32 | Type::I64 | Type::F32 | Type::F64 => {
-                writeln!(out, "{}.get {}", x.wasm_kind(), x.wasm_name())?;
+                out.push(get_var(x));
             }
             Type::Str | Type::Record(_) => {
-                writeln!(out, "{}.get {}", x.wasm_kind(), x.wasm_name())?;
-                writeln!(out, "call $f/__retain")?;
-                writeln!(out, "{}.get {}", x.wasm_kind(), x.wasm_name())?;
+                out.push(get_var(x));
+                gen_retain(out);
+                out.push(get_var(x));
             }
             Type::Id => panic!("TODO: gen_expr id GetVar (retain)"),
         },
-        ExprData::SetVar(x, expr) => match x.type_() {
+        ExprData::MoveVar(x) => {
+            out.push(get_var(x));
+            out.push(trzeroval(x.type_()));
+            out.push(set_var(x));
+        }
+        ExprData::BorrowVar(x) => out.push(get_var(x)),
+        ExprData::BorrowStr(ptr) => out.push(Instr::I32Const(ptr.get() as i32)),
+        ExprData::SetVar(x, setexpr) => match x.type_() {
             Type::Bool | Type::I32 | Type::I64 | Type::F32 | Type::F64 => {
-                gen_expr(out, expr)?;
-                writeln!(out, "{}.set {}", x.wasm_kind(), x.wasm_name())?;
+                gen_expr(out, setexpr)?;
+                if let ReturnType::Void = expr.type_ {
+                    out.push(set_var(x));
+                } else {
+                    // the new value is also the value of the assignment expression
+                    gen_tee(out, x);
+                }
             }
             Type::Str | Type::Record(_) => {
-                // save the old value on the stack (for release later)
-                writeln!(out, "{}.get {}", x.wasm_kind(), x.wasm_name())?;
-
-                gen_expr(out, expr)?;
-                writeln!(out, "{}.tee {}", x.wasm_kind(), x.wasm_name())?;
-
-                // retain the new value
-                writeln!(out, "call $f/__retain")?;
-
-                // release the old value
-                writeln!(out, "call $f/__release")?;
+                // the variable takes over the new value's reference,
+                // and the old value (read only now, in case evaluating
+                // the new one changed it) is released
+                gen_expr(out, setexpr)?;
+                out.push(get_var(x));
+                gen_release(out);
+                out.push(set_var(x));
+                if expr.type_ != ReturnType::Void {
+                    // the assignment expression holds its own reference
+                    out.push(get_var(x));
+                    gen_retain(out);
+                    out.push(get_var(x));
+                }
             }
             Type::Id => panic!("TODO: gen_expr id SetVar (retain + release)"),
         },
         ExprData::AugVar(x, op, expr) => match x.type_() {
             Type::Bool | Type::I32 | Type::I64 | Type::F32 | Type::F64 => {
-                out.push_str(&format!("{}.get {}\n", x.wasm_kind(), x.wasm_name()));
+                out.push(get_var(x));
                 gen_expr(out, expr)?;
-                out.push_str(&format!("{}\n", op));
-                out.push_str(&format!("{}.set {}\n", x.wasm_kind(), x.wasm_name()));
+                out.push(Instr::op(&op.to_string()));
+                out.push(set_var(x));
             }
             Type::Str | Type::Record(_) => {
                 panic!("TODO: gen_expr record AugLocal (retain + release)")
@@ -274,14 +789,10 @@ fn gen_expr(out: &mut String, expr: &Expr) -> Result<(), Error> {
             for arg in args {
                 gen_expr(out, arg)?;
             }
-            writeln!(out, "call $f/{}", func.name)?;
-        }
-        ExprData::CallExtern(ext, args) => {
-            for arg in args {
-                gen_expr(out, arg)?;
-            }
-            writeln!(out, "call $f/{}", ext.name)?;
+            gen_call(out, &func.wasm_name());
+            gen_noreturn_check(out, &func.type_);
         }
+        ExprData::CallExtern(ext, args) => gen_extern_call(out, ext, args)?,
         ExprData::Op(op, args) => {
             for arg in args {
                 gen_expr(out, arg)?;
@@ -291,70 +802,188 @@ fn gen_expr(out: &mut String, expr: &Expr) -> Result<(), Error> {
                 // we should take care to drop them
                 // this should mostly never happen, except for the
                 // Is/IsNot operands
-                release_tos(out, arg.type_.value().unwrap(), DropPolicy::Keep)?;
+                release_tos(out, arg.type_.value().unwrap(), DropPolicy::Keep);
             }
-            writeln!(out, "{}", op)?;
+            out.push(Instr::op(&op.to_string()));
+        }
+        ExprData::And(a, b) => {
+            gen_expr(out, a)?;
+            out.push(Instr::If(Some(ValType::I32)));
+            gen_expr(out, b)?;
+            out.push(Instr::Else);
+            out.push(Instr::I32Const(0));
+            out.push(Instr::End);
+        }
+        ExprData::Or(a, b) => {
+            gen_expr(out, a)?;
+            out.push(Instr::If(Some(ValType::I32)));
+            out.push(Instr::I32Const(1));
+            out.push(Instr::Else);
+            gen_expr(out, b)?;
+            out.push(Instr::End);
         }
         ExprData::DropPrimitive(x) => {
             gen_expr(out, x)?;
-            writeln!(out, "drop")?;
+            out.push(Instr::op("drop"));
         }
         ExprData::Asm(args, _, code) => {
             for arg in args {
                 gen_expr(out, arg)?;
             }
-            writeln!(out, "{}", code)?;
+            let instrs = wasm::parse_instrs(code).map_err(|message| Error {
+                span: vec![expr.span.clone()],
+                message,
+            })?;
+            for instr in instrs {
+                out.push(instr);
+            }
         }
         ExprData::Raw(var) => {
-            writeln!(out, "{}.get {}", var.wasm_kind(), var.wasm_name())?;
+            out.push(get_var(var));
             match var.type_() {
-                Type::F32 => writeln!(out, "i32.reinterpret_f32")?,
-                Type::F64 => writeln!(out, "i64.reinterpret_f64")?,
+                Type::F32 => out.push(Instr::op("i32.reinterpret_f32")),
+                Type::F64 => out.push(Instr::op("i64.reinterpret_f64")),
                 _ => {}
             }
         }
         ExprData::Read(byte_count, addr, offset) => {
             gen_expr(out, addr)?;
-            match byte_count {
-                ByteCount::N1 => write!(out, "i32.load_u")?,
-                ByteCount::N2 => write!(out, "i32.load16_u")?,
-                ByteCount::N4 => write!(out, "i32.load")?,
-                ByteCount::N8 => write!(out, "i64.load")?,
-            }
-            if *offset != 0 {
-                write!(out, " offset={}", offset)?;
-            }
-            writeln!(out, "")?;
+            let name = match byte_count {
+                ByteCount::N1 => "i32.load8_u",
+                ByteCount::N2 => "i32.load16_u",
+                ByteCount::N4 => "i32.load",
+                ByteCount::N8 => "i64.load",
+            };
+            out.push(Instr::mem(name, *offset));
         }
         ExprData::Write(byte_count, addr, data, offset) => {
             gen_expr(out, addr)?;
             gen_expr(out, data)?;
-            match byte_count {
-                ByteCount::N1 => write!(out, "i32.store8")?,
-                ByteCount::N2 => write!(out, "i32.store16")?,
-                ByteCount::N4 => write!(out, "i32.store")?,
-                ByteCount::N8 => write!(out, "i64.store")?,
-            }
-            if *offset != 0 {
-                write!(out, " offset={}", offset)?;
-            }
-            writeln!(out, "")?;
+            let name = match byte_count {
+                ByteCount::N1 => "i32.store8",
+                ByteCount::N2 => "i32.store16",
+                ByteCount::N4 => "i32.store",
+                ByteCount::N8 => "i64.store",
+            };
+            out.push(Instr::mem(name, *offset));
+        }
+    }
+    out.set_span(old_span);
+    Ok(())
+}
+
+/// calls an extern, which only borrows the strs and records passed to it:
+/// the caller keeps each in a local of its own, and releases it after
+fn gen_extern_call(out: &mut Out, ext: &Extern, args: &[Expr]) -> Result<(), Error> {
+    let mut locals = vec![];
+    for arg in args {
+        gen_expr(out, arg)?;
+        if arg.type_.value().unwrap().retain_type() == RetainType::Typed {
+            let local = extern_arg_local(out.extern_args);
+            out.extern_args += 1;
+            out.push(Instr::LocalTee(local.clone().into()));
+            locals.push(local);
         }
     }
+    gen_call(out, &ext.wasm_name());
+    gen_noreturn_check(out, &ext.type_);
+    for local in locals {
+        out.push(Instr::LocalGet(local.into()));
+        gen_release(out);
+    }
     Ok(())
 }
 
-fn release_var(out: &mut String, var: &Variable) -> Result<(), Error> {
+fn gen_retain(out: &mut Out) {
+    let retain = out.retain.clone();
+    gen_call(out, &retain);
+}
+
+fn gen_release(out: &mut Out) {
+    let release = out.release.clone();
+    gen_call(out, &release);
+}
+
+/// calls a function, recording where from in debug builds
+/// (for imports, so that traps in the host can be located)
+fn gen_call(out: &mut Out, wasm_name: &str) {
+    if out.debug {
+        let id = out.call_site_id();
+        out.push(Instr::I32Const(CALL_SITE_PTR as i32));
+        out.push(Instr::I32Const(id as i32));
+        out.push(Instr::op("i32.store"));
+    }
+    out.push(Instr::Call(wasm_name.into()));
+}
+
+/// pushes (func_id, call site id) onto the shadow stack,
+/// trapping if it overflows
+fn gen_push_frame(out: &mut Out, func_id: usize) {
+    let sp = || Instr::I32Const(SHADOW_SP_PTR as i32);
+
+    // the stack ends where the heap starts
+    out.push(sp());
+    out.push(Instr::op("i32.load"));
+    out.push(Instr::GlobalGet("rt/static_mem_end".into()));
+    out.push(Instr::op("i32.ge_u"));
+    out.push(Instr::If(None));
+    out.push(Instr::op("unreachable"));
+    out.push(Instr::End);
+
+    out.push(sp());
+    out.push(Instr::op("i32.load"));
+    out.push(Instr::I32Const(func_id as i32));
+    out.push(Instr::op("i32.store"));
+    out.push(sp());
+    out.push(Instr::op("i32.load"));
+    out.push(Instr::I32Const(CALL_SITE_PTR as i32));
+    out.push(Instr::op("i32.load"));
+    out.push(Instr::mem("i32.store", 4));
+    out.push(sp());
+    out.push(sp());
+    out.push(Instr::op("i32.load"));
+    out.push(Instr::I32Const(8));
+    out.push(Instr::op("i32.add"));
+    out.push(Instr::op("i32.store"));
+}
+
+/// pops the top frame off the shadow stack, leaving the
+/// function's result (if any) on the stack
+fn gen_pop_frame(out: &mut Out) {
+    let sp = || Instr::I32Const(SHADOW_SP_PTR as i32);
+    out.push(sp());
+    out.push(sp());
+    out.push(Instr::op("i32.load"));
+    out.push(Instr::I32Const(8));
+    out.push(Instr::op("i32.sub"));
+    out.push(Instr::op("i32.store"));
+
+    // back to the caller, whose next call will set the call site again
+    // (and any calls from the host after this one come from nowhere)
+    out.push(Instr::I32Const(CALL_SITE_PTR as i32));
+    out.push(Instr::I32Const(0));
+    out.push(Instr::op("i32.store"));
+}
+
+/// After calling a noreturn function, mark the rest of the code as
+/// unreachable, both so that the wasm validates and so that if the
+/// function ever does return (e.g. a misbehaving extern), we trap
+fn gen_noreturn_check(out: &mut Out, type_: &FuncType) {
+    if let ReturnType::NoReturn = type_.return_type {
+        out.push(Instr::op("unreachable"));
+    }
+}
+
+fn release_var(out: &mut Out, var: &Variable) {
     let type_ = var.type_();
     match type_.retain_type() {
         RetainType::Primitive => {}
         RetainType::Typed => {
-            writeln!(out, "{}.get {}", var.wasm_kind(), var.wasm_name())?;
-            writeln!(out, "call $f/__release")?;
+            out.push(get_var(var));
+            gen_release(out);
         }
         RetainType::Id => panic!("TODO: release_var id"),
     }
-    Ok(())
 }
 
 enum DropPolicy {
@@ -363,25 +992,448 @@ enum DropPolicy {
     Drop,
 }
 
-fn release_tos(out: &mut String, type_: &Type, drop_policy: DropPolicy) -> Result<(), Error> {
+fn release_tos(out: &mut Out, type_: &Type, drop_policy: DropPolicy) {
     match type_.retain_type() {
         RetainType::Primitive => match drop_policy {
             DropPolicy::Keep => {}
-            DropPolicy::Drop => {
-                writeln!(out, "drop")?;
-            }
+            DropPolicy::Drop => out.push(Instr::op("drop")),
         },
         RetainType::Typed => {
             match drop_policy {
                 DropPolicy::Keep => {
-                    writeln!(out, "local.tee $helper/i32")?;
-                    writeln!(out, "local.get $helper/i32")?;
+                    out.uses_helper = true;
+                    out.push(Instr::LocalTee(HELPER_LOCAL.into()));
+                    out.push(Instr::LocalGet(HELPER_LOCAL.into()));
                 }
                 DropPolicy::Drop => {}
             }
-            writeln!(out, "call $f/__release")?;
+            gen_release(out);
         }
         RetainType::Id => panic!("TODO: release_tos id"),
     }
-    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_config;
+    use crate::test_sources;
+    use crate::wasm;
+    use crate::Engine;
+    use crate::HostConfig;
+    use crate::MemoryRef;
+    use crate::RunConfig;
+    use crate::WasmArgs;
+    use crate::WasmResults;
+    use crate::TEST_BACKENDS;
+    use std::cell::RefCell;
+    use std::fmt::Debug;
+    use std::rc::Rc;
+
+    /// Makes each call (export, arguments and expected result) on a
+    /// new instance of the program, on every backend
+    fn check_calls<Args, R>(program: &str, calls: &[(&str, Args, R)])
+    where
+        Args: WasmArgs + Clone + Debug,
+        R: WasmResults + PartialEq + Debug,
+    {
+        let sources = test_sources(program);
+        for &backend in TEST_BACKENDS {
+            let engine = Engine::new(test_config(backend));
+            let module = engine.compile(sources.clone()).unwrap();
+            for (name, args, expected) in calls {
+                let mut instance = engine.instantiate(&module).unwrap();
+                let result: R = instance.call(name, args.clone()).unwrap();
+                assert_eq!(&result, expected, "{}{:?} on {:?}", name, args, backend);
+            }
+        }
+    }
+
+    #[test]
+    fn trunc_divide_is_signed() {
+        let program = r#"
+export fn Div(a i32, b i32) i32 {
+    return a // b
+}
+
+export fn Div64(a i64, b i64) i64 {
+    return a // b
+}
+"#;
+        check_calls(
+            program,
+            &[
+                ("Div", (-7, 2), -3),
+                ("Div", (7, -2), -3),
+                ("Div", (7, 2), 3),
+            ],
+        );
+        check_calls(program, &[("Div64", (-7i64, 2i64), -3i64)]);
+    }
+
+    #[test]
+    fn static_str_headers() {
+        // each static str's header has its own length and capacity
+        let program = r#"
+export fn Header(i i32, offset i32) i32 {
+    var a = "a"
+    var b = "hello"
+    if i == 0 {
+        return $read4($raw(a) + offset)
+    }
+    return $read4($raw(b) + offset)
+}
+
+export fn Last() i32 {
+    var s = "hello"
+    return $read1($raw(s) + 16 + 4)
+}
+"#;
+        check_calls(
+            program,
+            &[
+                // capacities (header and data) and lengths
+                ("Header", (0, 4), 17),
+                ("Header", (0, 12), 1),
+                ("Header", (1, 4), 21),
+                ("Header", (1, 12), 5),
+            ],
+        );
+        check_calls(program, &[("Last", (), b'o' as i32)]);
+    }
+
+    #[test]
+    fn runtime_operators() {
+        let program = r#"
+var calls = 0
+
+fn Bump() bool {
+    calls = calls + 1
+    return true
+}
+
+export fn Neg(a i32) i32 {
+    return -a
+}
+
+export fn Not(a bool) bool {
+    return not a
+}
+
+export fn Eq(a i32, b i32) bool {
+    return a == b and not (a != b)
+}
+
+export fn Bits(a i32, b i32) i32 {
+    return ((a & b) | (a ^ b)) << 4 >> 2
+}
+
+export fn Bits64(a i64, b i64) i64 {
+    return (a | b) << 33 >> 32
+}
+
+# the number of times the right operands were evaluated
+export fn ShortCircuit(a bool) i32 {
+    if a and Bump() {
+    }
+    if a or Bump() {
+    }
+    return calls
+}
+"#;
+        check_calls(program, &[("Neg", (5,), -5), ("Neg", (-5,), 5)]);
+        check_calls(program, &[("Not", (true,), false), ("Not", (false,), true)]);
+        check_calls(program, &[("Eq", (3, 3), true), ("Eq", (3, 4), false)]);
+        // (12 & 10 | 12 ^ 10) is 14, and >> keeps the sign
+        check_calls(program, &[("Bits", (12, 10), 56), ("Bits", (-12, 10), -8)]);
+        check_calls(program, &[("Bits64", (1i64, 2i64), 6i64)]);
+        check_calls(
+            program,
+            &[("ShortCircuit", (true,), 1), ("ShortCircuit", (false,), 1)],
+        );
+    }
+
+    #[test]
+    fn mixed_width_operands() {
+        // typed variables, so that no hint picks the literals' types
+        let program = r#"
+export fn Eq(a i32, b i64) bool {
+    return a == b and b == a and not (a != b)
+}
+
+export fn And(a i32, b i64) i64 {
+    return a & b
+}
+
+export fn Half(a f32, b f64) bool {
+    return a == b
+}
+"#;
+        check_calls(
+            program,
+            &[
+                ("Eq", (-3, -3i64), true),
+                ("Eq", (-1, 4294967295i64), false),
+            ],
+        );
+        // -1 extends to all ones, rather than to 4294967295
+        check_calls(program, &[("And", (-1, 1i64 << 32), 1i64 << 32)]);
+        check_calls(program, &[("Half", (0.5f32, 0.5f64), true)]);
+    }
+
+    #[test]
+    fn assignments_have_values() {
+        let program = r#"
+var total = 0
+var last = ""
+
+fn Twice(x i32) i32 {
+    return x * 2
+}
+
+export fn Locals(a i32) i32 {
+    var b = 0
+    var c = Twice(b = a + 1)
+    return b * 100 + c
+}
+
+# there is no global.tee, so this is generated differently
+export fn Globals(a i32) i32 {
+    var c = Twice(total = total + a)
+    return total * 100 + c
+}
+
+fn Len(s str) i32 {
+    return $read4($raw(s), offset: 12)
+}
+
+export fn Strs(t str) i32 {
+    var s = ""
+    var n = Len(last = s = t)
+    return n * 100 + Len(last) * 10 + Len(s)
+}
+
+export fn Clear() i32 {
+    return Len(last = "")
+}
+"#;
+        check_calls(program, &[("Locals", (3,), 408)]);
+        check_calls(program, &[("Globals", (3,), 306), ("Globals", (4,), 408)]);
+        for &backend in TEST_BACKENDS {
+            let engine = Engine::new(RunConfig {
+                heap_stats: true,
+                ..test_config(backend)
+            });
+            let module = engine.compile(test_sources(program)).unwrap();
+            let mut instance = engine.instantiate(&module).unwrap();
+            let text = instance.alloc_str("abc").unwrap() as i32;
+            assert_eq!(instance.call::<_, i32>("Strs", (text,)).unwrap(), 333);
+            // only last still refers to the str
+            let heap = instance.stats().heap.clone().unwrap();
+            assert_eq!(heap.live_objects, 1, "{:?}", backend);
+            assert_eq!(instance.call::<_, i32>("Clear", ()).unwrap(), 0);
+            let heap = instance.stats().heap.clone().unwrap();
+            assert_eq!(heap.live_objects, 0, "{:?}", backend);
+        }
+    }
+
+    #[test]
+    fn externs_borrow_their_arguments() {
+        let program = r#"
+extern "env" "count" count(s str, n i32) i32
+
+fn Name() str {
+    return "name"
+}
+
+export fn Count(s str) i32 {
+    return count(s, count(Name(), 1))
+}
+"#;
+        for &backend in TEST_BACKENDS {
+            let mut engine = Engine::new(RunConfig {
+                heap_stats: true,
+                ..test_config(backend)
+            });
+            engine.func("env", "count", |memory: MemoryRef, ptr: i32, n: i32| {
+                memory.read_str(ptr as u32).unwrap().len() as i32 + n
+            });
+            let module = engine.compile(test_sources(program)).unwrap();
+            let mut instance = engine.instantiate(&module).unwrap();
+            let text = instance.alloc_str("abc").unwrap() as i32;
+            assert_eq!(instance.call::<_, i32>("Count", (text,)).unwrap(), 8);
+            // the caller releases what it passed once the call returns
+            let heap = instance.stats().heap.clone().unwrap();
+            assert_eq!(heap.live_objects, 0, "{:?}", backend);
+        }
+    }
+
+    #[test]
+    fn assignments_take_over_the_new_value() {
+        let program = r#"
+fn Keep(s str) str {
+    return s
+}
+
+fn Len(s str) i32 {
+    return $read4($raw(s), offset: 12)
+}
+
+export fn Assign() i32 {
+    var s = arg(0)
+    var t = arg(0)
+    return Len(s = Keep(s = t))
+}
+"#;
+        for &backend in TEST_BACKENDS {
+            // (without the elision, which would hide extra retains)
+            let engine = Engine::new(RunConfig {
+                heap_stats: true,
+                debug_alloc: true,
+                debug: true,
+                elide_refcounts: false,
+                host: HostConfig {
+                    args: vec!["app".into()],
+                    ..HostConfig::default()
+                },
+                ..test_config(backend)
+            });
+            let module = engine.compile(test_sources(program)).unwrap();
+            let mut instance = engine.instantiate(&module).unwrap();
+            let result: Result<i32, _> = instance.call("Assign", ());
+            assert_eq!(result.map_err(|e| e.to_string()), Ok(3), "{:?}", backend);
+            let heap = instance.stats().heap.clone().unwrap();
+            assert_eq!(heap.live_objects, 0, "{:?}", backend);
+        }
+    }
+
+    #[test]
+    fn panics_report_the_caller() {
+        let sources = test_sources(
+            r#"
+fn Fail(msg str, __caller str) noreturn {
+    print(__caller)
+    print(": ")
+    panic(msg)
+}
+
+export fn Check(x i32) i32 {
+    assert(x >= 0, "x is negative")
+    if x > 10 {
+        panic("x is too big")
+    }
+    if x > 0 {
+        return x
+    }
+    Fail("x is zero")
+}
+"#,
+        );
+        for &backend in TEST_BACKENDS {
+            let stdout = Rc::new(RefCell::new(String::new()));
+            let mut engine = Engine::new(test_config(backend));
+            let out = stdout.clone();
+            engine.func(
+                "wac",
+                "stdout_write",
+                move |memory: MemoryRef, len: i32, ptr: i32| {
+                    let text = memory.read_string(ptr as u32, len as u32).unwrap();
+                    out.borrow_mut().push_str(&text);
+                    len
+                },
+            );
+            let module = engine.compile(sources.clone()).unwrap();
+            for (x, expected) in &[
+                (-1, "panic at app.wac:9: x is negative\n"),
+                (11, "panic at app.wac:11: x is too big\n"),
+                (0, "app.wac:16: panic at app.wac:5: x is zero\n"),
+            ] {
+                let mut instance = engine.instantiate(&module).unwrap();
+                stdout.borrow_mut().clear();
+                let result = instance.call::<_, i32>("Check", (*x,));
+                assert!(result.is_err(), "Check({}) on {:?}", x, backend);
+                assert_eq!(&*stdout.borrow(), expected, "on {:?}", backend);
+            }
+            let mut instance = engine.instantiate(&module).unwrap();
+            assert_eq!(instance.call::<_, i32>("Check", (3,)).unwrap(), 3);
+        }
+    }
+
+    #[test]
+    fn only_the_debug_allocator_checks_refs() {
+        let sources = test_sources(
+            r#"
+export fn Keep(s str) str {
+    var t = s
+    return t
+}
+"#,
+        );
+        // the functions Keep calls, to retain and release the strs
+        let calls = |debug_alloc: bool| {
+            let config = RunConfig {
+                debug_alloc,
+                debug: true,
+                elide_refcounts: false,
+                ..test_config(crate::BackendKind::Wasmer)
+            };
+            let (module, _) = crate::translate_to_module(sources.clone(), &config).unwrap();
+            let keep = module.funcs.iter().find(|f| &*f.name == "f/app.Keep");
+            let mut calls: Vec<_> = (keep.unwrap().body.iter())
+                .filter_map(|instr| match instr {
+                    wasm::Instr::Call(name) => Some(name.to_string()),
+                    _ => None,
+                })
+                .collect();
+            calls.sort();
+            calls.dedup();
+            calls
+        };
+        assert_eq!(calls(false), vec!["f/__release", "f/__retain"]);
+        assert_eq!(calls(true), vec!["f/__debug_release", "f/__debug_retain"]);
+    }
+
+    #[test]
+    fn imported_memory() {
+        let sources = test_sources(
+            r#"
+extern "env" "mem" memory
+
+export fn Answer() i32 {
+    return 42
+}
+"#,
+        );
+        let config = RunConfig {
+            max_pages: Some(20),
+            ..test_config(crate::BackendKind::Wasmer)
+        };
+        let (module, _) = crate::translate_to_module(sources.clone(), &config).unwrap();
+        assert!(module.memory.is_none());
+        let limits = module
+            .imports
+            .iter()
+            .find_map(|import| match &import.kind {
+                wasm::ImportKind::Memory(_, limits) => {
+                    assert_eq!((&*import.module, &*import.name), ("env", "mem"));
+                    Some(limits)
+                }
+                _ => None,
+            })
+            .unwrap();
+        assert!(limits.min >= 1);
+        assert_eq!(limits.max, Some(20));
+
+        // our runners have no memory to give it
+        for &backend in TEST_BACKENDS {
+            let engine = Engine::new(test_config(backend));
+            let module = engine.compile(sources.clone()).unwrap();
+            let err = engine.instantiate(&module).err().unwrap();
+            assert!(
+                err.to_string().contains("env.mem"),
+                "{} on {:?}",
+                err,
+                backend
+            );
+        }
+    }
 }
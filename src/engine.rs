@@ -0,0 +1,1100 @@
+//! An API for embedding wac programs in Rust
+//!
+//! An Engine compiles sources into Modules and instantiates them, with
+//! any host functions the embedder registered available as imports (next
+//! to the built in "wac" and WASI ones). Exports are called with Rust
+//! values, e.g.
+//!
+//! ```ignore
+//! let mut engine = Engine::new(RunConfig::default());
+//! engine.func("env", "square", |_: MemoryRef, x: i64| x * x);
+//! let module = engine.compile(sources)?;
+//! let mut instance = engine.instantiate(&module)?;
+//! let y: i32 = instance.call("Compute", (3, 4))?;
+//! ```
+//!
+//! Nothing is printed: failures are returned as EngineErrors.
+//!
+//! Programs run on the thread that calls them. With the interpreter
+//! backend, each call in the program nests calls in the interpreter, which
+//! traps once they take up more than RunConfig::interp_stack of the
+//! thread's stack (1 MiB by default, which suits the 2 MiB threads that
+//! Rust spawns); a larger limit needs a thread with a larger stack.
+use crate::trtype;
+use crate::wasm::FuncType;
+use crate::wasm::ValType;
+use crate::wasm::Value;
+use crate::Compiled;
+use crate::Error;
+use crate::GuestMemory;
+use crate::HostFunc;
+use crate::Leak;
+use crate::Record;
+use crate::RunConfig;
+use crate::RunStats;
+use crate::Runner;
+use crate::Source;
+use crate::Stop;
+use crate::HEADER_SIZE;
+use crate::RECORD_SLOT_SIZE;
+use std::fmt;
+use std::rc::Rc;
+
+pub struct Engine {
+    config: RunConfig,
+}
+
+/// A compiled program
+pub struct Module(Compiled);
+
+/// An instantiated program
+pub struct Instance(Runner);
+
+#[derive(Debug)]
+pub enum EngineError {
+    /// the sources failed to parse, check or compile
+    Compile(Error),
+
+    /// the program trapped (with the span and, in debug mode, backtrace),
+    /// or could not be instantiated or called
+    Runtime(Error),
+
+    /// the program exited, through exit or WASI's proc_exit
+    Exit(i32),
+
+    /// a call whose arguments or results do not match the export's type
+    Type(String),
+
+    /// an access outside of the guest's memory (or to a program without one)
+    OutOfBounds { ptr: u32, len: u32 },
+
+    /// the guest's allocator could not find room for an object of this size
+    /// (see RunConfig::max_pages)
+    OutOfMemory(usize),
+}
+
+impl fmt::Display for EngineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Compile(error) | Self::Runtime(error) => write!(f, "{}", error.format()),
+            Self::Exit(code) => write!(f, "Exited with status {}", code),
+            Self::Type(message) => write!(f, "{}", message),
+            Self::OutOfBounds { ptr, len } => {
+                write!(f, "Out of bounds memory access ({} bytes at {})", len, ptr)
+            }
+            Self::OutOfMemory(size) => write!(f, "Out of memory (allocating {} bytes)", size),
+        }
+    }
+}
+
+impl std::error::Error for EngineError {}
+
+impl From<Stop> for EngineError {
+    fn from(stop: Stop) -> Self {
+        match stop {
+            Stop::Exit(code) => Self::Exit(code),
+            Stop::Trap(trap, _) => Self::Runtime(Error {
+                span: vec![],
+                message: format!("Trap ({})", trap),
+            }),
+            Stop::Trapped(error) | Stop::Error(error) => Self::Runtime(error),
+        }
+    }
+}
+
+impl Engine {
+    /// The config's backend and host are used for every module
+    /// the engine compiles and instantiates
+    pub fn new(config: RunConfig) -> Self {
+        Self { config }
+    }
+
+    /// Provides a function to import as module.name, e.g. with
+    ///
+    /// ```text
+    /// extern "env" "square" square(x i32) i32
+    /// ```
+    ///
+    /// Its parameters are the guest's memory and then WasmValues,
+    /// and it returns either one WasmValue or ()
+    pub fn func<Args, F>(&mut self, module: &str, name: &str, func: F) -> &mut Self
+    where
+        F: IntoHostFunc<Args>,
+    {
+        let func = func.into_host_func(module, name);
+        self.config.host.funcs.push(Rc::new(func));
+        self
+    }
+
+    pub fn compile(&self, sources: Vec<Rc<Source>>) -> Result<Module, EngineError> {
+        match Compiled::new(sources, self.config.clone()) {
+            Ok(compiled) => Ok(Module(compiled)),
+            Err(error) => Err(EngineError::Compile(error)),
+        }
+    }
+
+    /// Instantiates the module with the engine's host functions
+    /// (this also runs the initializers of global variables, which
+    /// may exit, as EngineError::Exit)
+    pub fn instantiate(&self, module: &Module) -> Result<Instance, EngineError> {
+        Ok(Instance(module.0.try_instantiate(&self.config.host)?))
+    }
+}
+
+impl Module {
+    pub fn stats(&self) -> &RunStats {
+        self.0.stats()
+    }
+
+    /// The record type with the given name, qualified by its module
+    /// (e.g. "app.Point") unless it is in the prelude
+    pub fn record(&self, name: &str) -> Option<&Rc<Record>> {
+        self.0.record(name)
+    }
+}
+
+impl Instance {
+    /// Calls the function exported under the given name, with a tuple of
+    /// arguments, e.g. instance.call::<_, i32>("Add", (1, 2))
+    pub fn call<Args, Results>(&mut self, name: &str, args: Args) -> Result<Results, EngineError>
+    where
+        Args: WasmArgs,
+        Results: WasmResults,
+    {
+        let results = self.call_values(name, &args.into_values())?;
+        Results::from_values(&results).ok_or_else(|| {
+            EngineError::Type(format!(
+                "{} returned {:?}, not {:?}",
+                name,
+                results.iter().map(Value::type_).collect::<Vec<_>>(),
+                Results::types(),
+            ))
+        })
+    }
+
+    /// Calls the function exported under the given name, with
+    /// arguments and results whose types are only known at runtime
+    pub fn call_values(&mut self, name: &str, args: &[Value]) -> Result<Vec<Value>, EngineError> {
+        Ok(self.0.try_call(name, args)?)
+    }
+
+    /// Runs the program through its entry point, returning Main's
+    /// result (or the status it exited with), if any
+    pub fn run_main(&mut self) -> Result<Option<i32>, EngineError> {
+        match self.0.run_main() {
+            Ok(()) => Ok(self.0.stats().exit_code),
+            Err(error) => Err(EngineError::Runtime(error)),
+        }
+    }
+
+    /// Releases the globals, and returns the objects still alive
+    /// (if the engine checks for leaks; see RunConfig::leak_check)
+    ///
+    /// run_main does this itself, into the stats; either way,
+    /// the instance cannot be used afterwards.
+    pub fn check_leaks(&mut self) -> Result<Vec<Leak>, EngineError> {
+        self.0.check_leaks().map_err(EngineError::Runtime)
+    }
+
+    /// The record type with the given name, as Module::record
+    pub fn record(&self, name: &str) -> Option<&Rc<Record>> {
+        self.0.record(name)
+    }
+
+    /// Gives up a reference to a str or record, e.g. the one a call
+    /// returning it leaves the caller with
+    pub fn release(&mut self, ptr: u32) -> Result<(), EngineError> {
+        let release = self.0.release_func();
+        self.call(release, (ptr as i32,))
+    }
+
+    /// The guest's linear memory
+    pub fn memory(&self) -> Result<MemoryRef<'_>, EngineError> {
+        match self.0.memory() {
+            Some(memory) => Ok(MemoryRef(memory)),
+            None => Err(EngineError::Runtime(Error {
+                span: vec![],
+                message: "The program has no memory".into(),
+            })),
+        }
+    }
+
+    /// The stats so far, with the execution time summed over all calls
+    pub fn stats(&self) -> &RunStats {
+        self.0.stats()
+    }
+
+    /// Allocates an object of the given size with the guest's __malloc,
+    /// and writes its header (holding a single reference)
+    fn alloc_object(
+        &mut self,
+        size: usize,
+        ptrcnt: usize,
+        reserved: usize,
+    ) -> Result<u32, EngineError> {
+        let ptr = self.call::<_, i32>("__malloc", (size as i32,))? as u32;
+        if ptr == 0 {
+            return Err(EngineError::OutOfMemory(size));
+        }
+        let mut header = vec![];
+        for field in &[1, size, ptrcnt, reserved] {
+            header.extend(&(*field as u32).to_le_bytes());
+        }
+        self.memory()?.write_bytes(ptr, &header)?;
+        Ok(ptr)
+    }
+
+    /// Copies the string into a new str in the guest
+    ///
+    /// The caller holds the str's only reference, which it
+    /// gives up by passing the str to the program.
+    pub fn alloc_str(&mut self, string: &str) -> Result<u32, EngineError> {
+        let ptr = self.alloc_object(HEADER_SIZE + string.len(), 0, string.len())?;
+        self.memory()?
+            .write_string(ptr + HEADER_SIZE as u32, string)?;
+        Ok(ptr)
+    }
+
+    /// Creates a record in the guest with the given field values (in the
+    /// order the fields are declared), e.g. I32 pointers for str fields
+    ///
+    /// As with alloc_str, the caller holds the record's only reference,
+    /// and the record takes over the references held by the fields.
+    pub fn alloc_record(&mut self, record: &Record, fields: &[Value]) -> Result<u32, EngineError> {
+        let layout = record.layout();
+        let types = layout.fields.iter().map(|(_, type_, _)| trtype(type_));
+        if !types.eq(fields.iter().map(Value::type_)) {
+            return Err(EngineError::Type(format!(
+                "{:?} do not match the fields of {}",
+                fields, record.name
+            )));
+        }
+        let tag = self.0.record_tag(record);
+        let ptr = self.alloc_object(layout.size, layout.ptrcnt, tag as usize)?;
+        let memory = self.memory()?;
+        for ((_, _, offset), value) in layout.fields.iter().zip(fields) {
+            let slot = value.bits().to_le_bytes();
+            memory.write_bytes(ptr + *offset as u32, &slot)?;
+        }
+        Ok(ptr)
+    }
+}
+
+/// A guest's linear memory, as seen by the embedder
+#[derive(Clone, Copy)]
+pub struct MemoryRef<'a>(&'a dyn GuestMemory);
+
+impl<'a> MemoryRef<'a> {
+    /// the size of the memory in bytes
+    pub fn size(&self) -> usize {
+        self.0.size()
+    }
+
+    fn check(&self, ptr: u32, len: u32) -> Result<usize, EngineError> {
+        match (ptr as usize).checked_add(len as usize) {
+            Some(end) if end <= self.0.size() => Ok(ptr as usize),
+            _ => Err(EngineError::OutOfBounds { ptr, len }),
+        }
+    }
+
+    pub fn read_bytes(&self, ptr: u32, len: u32) -> Result<Vec<u8>, EngineError> {
+        let start = self.check(ptr, len)?;
+        let mut bytes = vec![0; len as usize];
+        self.0.read(start, &mut bytes);
+        Ok(bytes)
+    }
+
+    pub fn write_bytes(&self, ptr: u32, bytes: &[u8]) -> Result<(), EngineError> {
+        let start = self.check(ptr, bytes.len() as u32)?;
+        self.0.write(start, bytes);
+        Ok(())
+    }
+
+    /// Reads len bytes of UTF-8 starting at ptr (invalid sequences
+    /// are replaced, as in String::from_utf8_lossy)
+    pub fn read_string(&self, ptr: u32, len: u32) -> Result<String, EngineError> {
+        let bytes = self.read_bytes(ptr, len)?;
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    pub fn write_string(&self, ptr: u32, string: &str) -> Result<(), EngineError> {
+        self.write_bytes(ptr, string.as_bytes())
+    }
+
+    fn read_u32(&self, ptr: u32) -> Result<u32, EngineError> {
+        let bytes = self.read_bytes(ptr, 4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// Decodes the str that ptr points to (i.e. its header)
+    pub fn read_str(&self, ptr: u32) -> Result<String, EngineError> {
+        let len = self.read_u32(ptr.wrapping_add(12))?;
+        self.read_string(ptr.wrapping_add(HEADER_SIZE as u32), len)
+    }
+
+    /// Reads the fields of the record that ptr points to, in the order
+    /// they are declared (strs and other records as I32 pointers)
+    pub fn read_record(&self, record: &Record, ptr: u32) -> Result<Vec<Value>, EngineError> {
+        let layout = record.layout();
+        let mut values = vec![];
+        for (_, type_, offset) in &layout.fields {
+            let ptr = ptr.wrapping_add(*offset as u32);
+            values.push(Value::from_bits(trtype(type_), self.read_bits(ptr)?));
+        }
+        Ok(values)
+    }
+
+    /// Reads the value in the slot at ptr, which is where a record keeps
+    /// each field (see Record::layout), e.g. for generated bindings
+    pub fn read_slot<T: WasmValue>(&self, ptr: u32) -> Result<T, EngineError> {
+        let value = Value::from_bits(T::TYPE, self.read_bits(ptr)?);
+        Ok(T::from_value(value).unwrap())
+    }
+
+    fn read_bits(&self, ptr: u32) -> Result<u64, EngineError> {
+        let slot = self.read_bytes(ptr, RECORD_SLOT_SIZE as u32)?;
+        let mut bits = [0; 8];
+        bits.copy_from_slice(&slot);
+        Ok(u64::from_le_bytes(bits))
+    }
+}
+
+/// A Rust type that corresponds to a wasm value type
+/// (i.e. i32, i64, f32, f64 or bool)
+pub trait WasmValue: Sized {
+    const TYPE: ValType;
+    fn from_value(value: Value) -> Option<Self>;
+    fn into_value(self) -> Value;
+}
+
+macro_rules! wasm_types {
+    ($($t:ty => $variant:ident),*) => {$(
+        impl WasmValue for $t {
+            const TYPE: ValType = ValType::$variant;
+
+            fn from_value(value: Value) -> Option<Self> {
+                match value {
+                    Value::$variant(x) => Some(x),
+                    _ => None,
+                }
+            }
+
+            fn into_value(self) -> Value {
+                Value::$variant(self)
+            }
+        }
+    )*};
+}
+
+wasm_types!(i32 => I32, i64 => I64, f32 => F32, f64 => F64);
+
+/// (wac's bool, which is an i32 in wasm)
+impl WasmValue for bool {
+    const TYPE: ValType = ValType::I32;
+
+    fn from_value(value: Value) -> Option<Self> {
+        match value {
+            Value::I32(x) => Some(x != 0),
+            _ => None,
+        }
+    }
+
+    fn into_value(self) -> Value {
+        Value::I32(self as i32)
+    }
+}
+
+/// The arguments of a call into the guest, as a tuple
+pub trait WasmArgs {
+    fn into_values(self) -> Vec<Value>;
+}
+
+/// The results of a function, either () or a single value
+pub trait WasmResults: Sized {
+    fn types() -> Vec<ValType>;
+    fn from_values(values: &[Value]) -> Option<Self>;
+    fn into_values(self) -> Vec<Value>;
+}
+
+impl WasmResults for () {
+    fn types() -> Vec<ValType> {
+        vec![]
+    }
+
+    fn from_values(values: &[Value]) -> Option<Self> {
+        if values.is_empty() {
+            Some(())
+        } else {
+            None
+        }
+    }
+
+    fn into_values(self) -> Vec<Value> {
+        vec![]
+    }
+}
+
+impl<T: WasmValue> WasmResults for T {
+    fn types() -> Vec<ValType> {
+        vec![T::TYPE]
+    }
+
+    fn from_values(values: &[Value]) -> Option<Self> {
+        match values {
+            [value] => T::from_value(*value),
+            _ => None,
+        }
+    }
+
+    fn into_values(self) -> Vec<Value> {
+        vec![self.into_value()]
+    }
+}
+
+/// A closure that can be registered with Engine::func, where Args
+/// is the tuple of its parameter types after the MemoryRef
+pub trait IntoHostFunc<Args> {
+    fn into_host_func(self, module: &str, name: &str) -> HostFunc;
+}
+
+macro_rules! host_func_arities {
+    ($(($($arg:ident),*)),*) => {$(
+        impl<$($arg: WasmValue,)*> WasmArgs for ($($arg,)*) {
+            #[allow(non_snake_case)]
+            fn into_values(self) -> Vec<Value> {
+                let ($($arg,)*) = self;
+                vec![$($arg.into_value()),*]
+            }
+        }
+
+        impl<F, R, $($arg),*> IntoHostFunc<($($arg,)*)> for F
+        where
+            F: Fn(MemoryRef $(, $arg)*) -> R + 'static,
+            R: WasmResults,
+            $($arg: WasmValue,)*
+        {
+            fn into_host_func(self, module: &str, name: &str) -> HostFunc {
+                let type_ = FuncType {
+                    params: vec![$($arg::TYPE),*],
+                    results: R::types(),
+                };
+                HostFunc::new(module, name, type_, move |memory, args| {
+                    // (HostFunc::call checks the argument types)
+                    #[allow(unused_mut, unused_variables)]
+                    let mut args = args.iter().cloned();
+                    self(
+                        MemoryRef(memory)
+                        $(, $arg::from_value(args.next().unwrap()).unwrap())*
+                    )
+                    .into_values()
+                })
+            }
+        }
+    )*};
+}
+
+host_func_arities!(
+    (),
+    (A),
+    (A, B),
+    (A, B, C),
+    (A, B, C, D),
+    (A, B, C, D, E),
+    (A, B, C, D, E, G)
+);
+
+/// The prelude, and the given program as the module app (for tests)
+#[cfg(test)]
+pub(crate) fn test_sources(data: &str) -> Vec<Rc<Source>> {
+    let mut sources = vec![];
+    crate::add_sources(&mut sources, "prelude").unwrap();
+    sources.push(Rc::new(Source {
+        name: "app.wac".into(),
+        module: "app".into(),
+        data: data.into(),
+    }));
+    sources
+}
+
+/// A config building libraries for the given backend (for tests)
+#[cfg(test)]
+pub(crate) fn test_config(backend: crate::BackendKind) -> RunConfig {
+    RunConfig {
+        library: true,
+        backend,
+        ..RunConfig::default()
+    }
+}
+
+/// The backends that tests of code generation run on
+#[cfg(test)]
+pub(crate) const TEST_BACKENDS: &[crate::BackendKind] =
+    &[crate::BackendKind::Interpreter, crate::BackendKind::Wasmer];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BackendKind;
+    use crate::HostConfig;
+    use crate::Target;
+    use crate::RECORD_TAG;
+    use crate::REFCOUNT_STATS_PTR;
+    use crate::TRAP_DEADLINE_EXCEEDED;
+    use crate::TRAP_OUT_OF_FUEL;
+    use std::convert::TryInto;
+    use std::time::Duration;
+
+    #[test]
+    fn host_funcs_and_typed_calls() {
+        let sources = test_sources(
+            r#"
+extern "env" "square" square(x i64) i64
+extern "env" "greet" greet(ptr i32, len i32)
+
+export fn Compute(a i64, b i64) i64 {
+    greet(0, 2)
+    return square(a) + b
+}
+"#,
+        );
+        let mut engine = Engine::new(test_config(BackendKind::Interpreter));
+        engine
+            .func("env", "square", |_: MemoryRef, x: i64| x * x)
+            .func("env", "greet", |memory: MemoryRef, ptr: i32, len: i32| {
+                let text = memory.read_string(ptr as u32, len as u32).unwrap();
+                assert_eq!(text, "hi");
+            });
+        let module = engine.compile(sources).unwrap();
+        let mut instance = engine.instantiate(&module).unwrap();
+        instance.memory().unwrap().write_string(0, "hi").unwrap();
+
+        assert_eq!(
+            instance.call::<_, i64>("Compute", (3i64, 4i64)).unwrap(),
+            13
+        );
+        assert!(matches!(
+            instance.call::<_, i32>("Compute", (3i64, 4i64)),
+            Err(EngineError::Type(_))
+        ));
+        assert!(matches!(
+            instance.call::<_, ()>("Missing", ()),
+            Err(EngineError::Runtime(_))
+        ));
+        let size = instance.memory().unwrap().size() as u32;
+        assert!(matches!(
+            instance.memory().unwrap().read_bytes(size - 1, 2),
+            Err(EngineError::OutOfBounds { .. })
+        ));
+    }
+
+    #[test]
+    fn strs_and_records() {
+        let sources = test_sources(
+            r#"
+record Point { x i32 label str y f64 }
+
+export fn Greeting() str {
+    return "hello"
+}
+
+export fn Echo(s str) str {
+    return s
+}
+
+export fn Same(p Point) Point {
+    return p
+}
+"#,
+        );
+        let engine = Engine::new(test_config(BackendKind::Interpreter));
+        let module = engine.compile(sources).unwrap();
+        let point = module.record("app.Point").unwrap().clone();
+        let offsets: Vec<_> = point.layout().fields.iter().map(|f| f.2).collect();
+        assert_eq!(offsets, vec![24, 16, 32]);
+        let mut instance = engine.instantiate(&module).unwrap();
+
+        let ptr = instance.call::<_, i32>("Greeting", ()).unwrap();
+        assert_eq!(
+            instance.memory().unwrap().read_str(ptr as u32).unwrap(),
+            "hello"
+        );
+        let ptr = instance.alloc_str("héllo").unwrap();
+        let ptr = instance.call::<_, i32>("Echo", (ptr as i32,)).unwrap();
+        assert_eq!(
+            instance.memory().unwrap().read_str(ptr as u32).unwrap(),
+            "héllo"
+        );
+
+        let label = Value::I32(instance.alloc_str("origin").unwrap() as i32);
+        let fields = [Value::I32(3), label, Value::F64(1.5)];
+        let ptr = instance.alloc_record(&point, &fields).unwrap();
+        let ptr = instance.call::<_, i32>("Same", (ptr as i32,)).unwrap();
+        let memory = instance.memory().unwrap();
+        assert_eq!(memory.read_record(&point, ptr as u32).unwrap(), fields);
+        assert_eq!(memory.read_str(fields[1].bits() as u32).unwrap(), "origin");
+        assert!(matches!(
+            instance.alloc_record(&point, &fields[..2]),
+            Err(EngineError::Type(_))
+        ));
+    }
+
+    #[test]
+    fn fuel_runs_out() {
+        let sources = test_sources(
+            r#"
+export fn Count(n i32) i32 {
+    if n == 0 {
+        return 0
+    }
+    return Count(n - 1) + 1
+}
+
+export fn Div(a i32, b i32) i32 {
+    return a // b
+}
+"#,
+        );
+        for &backend in TEST_BACKENDS {
+            // (little enough that the interpreter runs out of fuel
+            // before it runs out of stack, see RunConfig::interp_stack)
+            let engine = Engine::new(RunConfig {
+                fuel: Some(30),
+                ..test_config(backend)
+            });
+            let module = engine.compile(sources.clone()).unwrap();
+            let mut instance = engine.instantiate(&module).unwrap();
+
+            assert_eq!(instance.call::<_, i32>("Count", (9,)).unwrap(), 9);
+            assert_eq!(instance.stats().fuel_consumed, Some(10));
+            match instance.call::<_, i32>("Count", (1000,)) {
+                Err(EngineError::Runtime(error)) => {
+                    assert!(
+                        error.message.contains(TRAP_OUT_OF_FUEL),
+                        "{:?}: {}",
+                        backend,
+                        error.message
+                    )
+                }
+                Err(error) => panic!("{:?}: {}", backend, error),
+                Ok(_) => panic!("{:?}: Count should have run out of fuel", backend),
+            }
+            assert_eq!(instance.stats().fuel_consumed, Some(30));
+
+            // other traps are not taken for running out, even with none left
+            let engine = Engine::new(RunConfig {
+                fuel: Some(1),
+                ..test_config(backend)
+            });
+            let module = engine.compile(sources.clone()).unwrap();
+            let mut instance = engine.instantiate(&module).unwrap();
+            match instance.call::<_, i32>("Div", (1, 0)) {
+                Err(EngineError::Runtime(error)) => {
+                    assert!(
+                        !error.message.contains(TRAP_OUT_OF_FUEL),
+                        "{:?}: {}",
+                        backend,
+                        error.message
+                    )
+                }
+                Err(error) => panic!("{:?}: {}", backend, error),
+                Ok(_) => panic!("{:?}: Div should have trapped", backend),
+            }
+            assert_eq!(instance.stats().fuel_consumed, Some(1));
+
+            // and more fuel than the counter holds is as good as unlimited
+            let engine = Engine::new(RunConfig {
+                fuel: Some(u64::MAX),
+                ..test_config(backend)
+            });
+            let module = engine.compile(sources.clone()).unwrap();
+            let mut instance = engine.instantiate(&module).unwrap();
+            assert_eq!(instance.call::<_, i32>("Count", (9,)).unwrap(), 9);
+            assert_eq!(instance.stats().fuel_consumed, Some(10));
+        }
+    }
+
+    #[test]
+    fn time_limit_runs_out() {
+        // enough calls to reach a check of the deadline, without
+        // recursing deep enough to run out of stack
+        let sources = test_sources(
+            r#"
+export fn Fib(n i32) i32 {
+    if n < 2 {
+        return n
+    }
+    return Fib(n - 1) + Fib(n - 2)
+}
+"#,
+        );
+        for &backend in TEST_BACKENDS {
+            let engine = Engine::new(RunConfig {
+                time_limit: Some(Duration::from_secs(0)),
+                ..test_config(backend)
+            });
+            let module = engine.compile(sources.clone()).unwrap();
+            let mut instance = engine.instantiate(&module).unwrap();
+            match instance.call::<_, i32>("Fib", (24,)) {
+                Err(EngineError::Runtime(error)) => {
+                    assert!(
+                        error.message.contains(TRAP_DEADLINE_EXCEEDED),
+                        "{:?}: {}",
+                        backend,
+                        error.message
+                    )
+                }
+                Err(error) => panic!("{:?}: {}", backend, error),
+                Ok(_) => panic!("{:?}: Fib should have run out of time", backend),
+            }
+        }
+
+        // the clock starts with each call, not with the instance (and the
+        // limit is well above how long Fib takes, even when interpreted)
+        let mut instances = vec![];
+        for &backend in TEST_BACKENDS {
+            let engine = Engine::new(RunConfig {
+                time_limit: Some(Duration::from_secs(2)),
+                ..test_config(backend)
+            });
+            let module = engine.compile(sources.clone()).unwrap();
+            instances.push((backend, engine.instantiate(&module).unwrap()));
+        }
+        std::thread::sleep(Duration::from_millis(2100));
+        for (backend, mut instance) in instances {
+            match instance.call::<_, i32>("Fib", (23,)) {
+                Ok(result) => assert_eq!(result, 28657),
+                Err(error) => panic!("{:?}: {}", backend, error),
+            }
+        }
+    }
+
+    #[test]
+    fn memory_limit_and_heap_stats() {
+        for &backend in TEST_BACKENDS {
+            let engine = Engine::new(RunConfig {
+                max_pages: Some(1),
+                heap_stats: true,
+                ..test_config(backend)
+            });
+            let module = engine.compile(test_sources("")).unwrap();
+            let mut instance = engine.instantiate(&module).unwrap();
+
+            // 16k blocks, of which only a few fit in the one page
+            let text = "x".repeat(10000);
+            let mut allocated = 0;
+            loop {
+                match instance.alloc_str(&text) {
+                    Ok(_) => allocated += 1,
+                    Err(EngineError::OutOfMemory(_)) => break,
+                    Err(error) => panic!("{:?}: {}", backend, error),
+                }
+                assert!(allocated < 4);
+            }
+            let heap = instance.stats().heap.clone().unwrap();
+            assert_eq!(heap.live_objects, allocated);
+            assert_eq!(heap.live_bytes, allocated * (1 << 14));
+            assert_eq!(heap.peak_bytes, heap.live_bytes);
+            assert_eq!(heap.free_blocks, 0);
+        }
+    }
+
+    #[test]
+    fn oversized_allocations_fail() {
+        for &backend in TEST_BACKENDS {
+            let engine = Engine::new(RunConfig {
+                heap_stats: true,
+                ..test_config(backend)
+            });
+            let module = engine.compile(test_sources("")).unwrap();
+            let mut instance = engine.instantiate(&module).unwrap();
+            for &len in &[-1, -16, (1 << 30) + 1, i32::MAX] {
+                let ptr: i32 = instance.call("__malloc", (len,)).unwrap();
+                assert_eq!(ptr, 0, "__malloc({}) on {:?}", len, backend);
+            }
+            let heap = instance.stats().heap.clone().unwrap();
+            assert_eq!(heap.live_objects, 0, "on {:?}", backend);
+        }
+    }
+
+    #[test]
+    fn refcounts_are_counted_only_with_heap_stats() {
+        let sources = test_sources(
+            r#"
+export fn Copy(s str) str {
+    var t = s
+    return t
+}
+"#,
+        );
+        for &backend in TEST_BACKENDS {
+            let retains = |heap_stats: bool| {
+                let engine = Engine::new(RunConfig {
+                    heap_stats,
+                    ..test_config(backend)
+                });
+                let module = engine.compile(sources.clone()).unwrap();
+                let mut instance = engine.instantiate(&module).unwrap();
+                let s = instance.alloc_str("text").unwrap();
+                let t = instance.call::<_, i32>("Copy", (s as i32,)).unwrap();
+                instance.release(t as u32).unwrap();
+                let memory = instance.memory().unwrap();
+                let counter = memory.read_bytes(REFCOUNT_STATS_PTR as u32, 4).unwrap();
+                u32::from_le_bytes(counter[..].try_into().unwrap())
+            };
+            assert_eq!(retains(false), 0, "{:?}", backend);
+            assert!(retains(true) > 0, "{:?}", backend);
+        }
+    }
+
+    #[test]
+    fn heap_stats_survive_corrupted_freelists() {
+        use crate::HeapStats;
+        use crate::FREELIST_START;
+        use std::cell::RefCell;
+
+        let write_u32 = |memory: &mut Vec<u8>, ptr: usize, value: u32| {
+            memory[ptr..ptr + 4].copy_from_slice(&value.to_le_bytes())
+        };
+        let mut memory = vec![0; 1 << 16];
+        // a 32 byte block that points to itself, and a list of
+        // the largest blocks, with the head pointing to itself
+        write_u32(&mut memory, FREELIST_START + 5 * 4, 4096);
+        write_u32(&mut memory, 4096, 4096);
+        write_u32(
+            &mut memory,
+            FREELIST_START + 31 * 4,
+            FREELIST_START as u32 + 31 * 4,
+        );
+        let stats = HeapStats::read(&RefCell::new(memory));
+        assert_eq!(stats.free_blocks, (1 << 16) / 32);
+        assert_eq!(stats.free_bytes, 1 << 16);
+    }
+
+    #[test]
+    fn memory_limit_beyond_wasm32() {
+        for &backend in TEST_BACKENDS {
+            let engine = Engine::new(RunConfig {
+                max_pages: Some(65537),
+                ..test_config(backend)
+            });
+            let result = engine
+                .compile(test_sources(""))
+                .and_then(|module| engine.instantiate(&module));
+            assert!(result.is_err(), "{:?}", backend);
+        }
+    }
+
+    #[test]
+    fn leaks() {
+        let sources = test_sources(
+            r#"
+record Point { x i32 label str }
+
+export fn Drop(p Point) {
+}
+
+export fn Name() str {
+    return arg(0)
+}
+"#,
+        );
+        for &backend in TEST_BACKENDS {
+            // (wasm programs need debug to tell where objects were allocated)
+            let engine = Engine::new(RunConfig {
+                leak_check: true,
+                debug: true,
+                host: HostConfig {
+                    args: vec!["app".into()],
+                    ..HostConfig::default()
+                },
+                ..test_config(backend)
+            });
+            let module = engine.compile(sources.clone()).unwrap();
+            let point = module.record("app.Point").unwrap().clone();
+            let mut instance = engine.instantiate(&module).unwrap();
+
+            let mut points = vec![];
+            for label in &["dropped", "kept"] {
+                let label = Value::I32(instance.alloc_str(label).unwrap() as i32);
+                let fields = [Value::I32(0), label];
+                points.push(instance.alloc_record(&point, &fields).unwrap());
+            }
+            // records are told apart by the type tag in their headers
+            let reserved = instance.memory().unwrap().read_bytes(points[1] + 12, 4);
+            let tag = u32::from_le_bytes(reserved.unwrap()[..].try_into().unwrap());
+            assert_eq!(tag & RECORD_TAG, RECORD_TAG, "{:?}", backend);
+            let name = instance.call::<_, i32>("Name", ()).unwrap() as u32;
+            // Drop releases its parameter, and with it the label
+            instance.call::<_, ()>("Drop", (points[0] as i32,)).unwrap();
+
+            let leaks = instance.check_leaks().unwrap();
+            let describe = |ptr: u32| {
+                let leak = leaks.iter().find(|leak| leak.ptr == ptr)?;
+                let line = leak.site.as_ref().map(|site| site.lineno());
+                Some((leak.type_.to_string(), line))
+            };
+            assert_eq!(describe(points[0]), None, "{:?}", backend);
+            assert_eq!(describe(points[1]), Some(("app.Point".into(), None)));
+            assert_eq!(describe(name), Some(("str".into(), Some(8))));
+            // the kept point and its label, and the str from Name
+            assert_eq!(leaks.len(), 3, "{:?}", backend);
+        }
+    }
+
+    #[test]
+    fn initializers_exit_and_trap() {
+        let sources = test_sources(
+            r#"
+var code = Init()
+
+fn Init() i32 {
+    var n = arg_count()
+    if n > 1 {
+        exit(n)
+    }
+    return 1 // (n - 1)
+}
+"#,
+        );
+        for &backend in TEST_BACKENDS {
+            let instantiate = |args: &[&str]| {
+                let engine = Engine::new(RunConfig {
+                    host: HostConfig {
+                        args: args.iter().map(|arg| arg.to_string()).collect(),
+                        ..HostConfig::default()
+                    },
+                    ..test_config(backend)
+                });
+                let module = engine.compile(sources.clone()).unwrap();
+                engine.instantiate(&module).map(|_| ())
+            };
+            match instantiate(&["app", "a", "b"]) {
+                Err(EngineError::Exit(3)) => {}
+                other => panic!("{:?}: {:?}", backend, other),
+            }
+            match instantiate(&["app"]) {
+                Err(EngineError::Runtime(error)) => assert!(
+                    error.message.starts_with("Trap"),
+                    "{:?}: {}",
+                    backend,
+                    error.message
+                ),
+                other => panic!("{:?}: {:?}", backend, other),
+            }
+        }
+    }
+
+    #[test]
+    fn leak_check_needs_the_wac_target() {
+        // WASI hosts cannot track allocations
+        let engine = Engine::new(RunConfig {
+            leak_check: true,
+            debug: true,
+            target: Target::Wasi,
+            ..test_config(BackendKind::Wasmer)
+        });
+        match engine.compile(test_sources("")) {
+            Err(EngineError::Compile(error)) => {
+                assert!(
+                    error.message.contains("needs the wac target"),
+                    "{}",
+                    error.message
+                )
+            }
+            Err(error) => panic!("{}", error),
+            Ok(_) => panic!("leak_check should have been refused"),
+        }
+    }
+
+    #[test]
+    fn debug_alloc_needs_the_wac_target() {
+        // WASI hosts cannot keep the quarantine or check references
+        let engine = Engine::new(RunConfig {
+            debug_alloc: true,
+            debug: true,
+            target: Target::Wasi,
+            ..test_config(BackendKind::Wasmer)
+        });
+        match engine.compile(test_sources("")) {
+            Err(EngineError::Compile(error)) => {
+                assert!(
+                    error.message.contains("needs the wac target"),
+                    "{}",
+                    error.message
+                )
+            }
+            Err(error) => panic!("{}", error),
+            Ok(_) => panic!("debug_alloc should have been refused"),
+        }
+    }
+
+    #[test]
+    fn limits_need_the_wac_target() {
+        // the fuel check traps through the wac imports
+        let configs = vec![
+            RunConfig {
+                fuel: Some(100),
+                ..test_config(BackendKind::Wasmer)
+            },
+            RunConfig {
+                time_limit: Some(Duration::from_secs(1)),
+                ..test_config(BackendKind::Wasmer)
+            },
+        ];
+        for config in configs {
+            let engine = Engine::new(RunConfig {
+                target: Target::Wasi,
+                ..config
+            });
+            match engine.compile(test_sources("")) {
+                Err(EngineError::Compile(error)) => {
+                    assert!(
+                        error.message.contains("needs the wac target"),
+                        "{}",
+                        error.message
+                    )
+                }
+                Err(error) => panic!("{}", error),
+                Ok(_) => panic!("the limit should have been refused"),
+            }
+        }
+    }
+
+    #[test]
+    fn double_free() {
+        let sources = test_sources(
+            r#"
+record Point { x i32 label str }
+
+export fn Drop(p Point) {
+}
+"#,
+        );
+        for &backend in TEST_BACKENDS {
+            let engine = Engine::new(RunConfig {
+                debug_alloc: true,
+                debug: true,
+                ..test_config(backend)
+            });
+            let module = engine.compile(sources.clone()).unwrap();
+            let point = module.record("app.Point").unwrap().clone();
+            let mut instance = engine.instantiate(&module).unwrap();
+
+            let label = Value::I32(instance.alloc_str("twice").unwrap() as i32);
+            let ptr = instance
+                .alloc_record(&point, &[Value::I32(0), label])
+                .unwrap();
+            instance.call::<_, ()>("Drop", (ptr as i32,)).unwrap();
+            // the point is in quarantine, so the second Drop finds it freed
+            let error = instance.call::<_, ()>("Drop", (ptr as i32,)).unwrap_err();
+            let message = error.to_string();
+            assert!(
+                message.contains("double free: release of app.Point"),
+                "{:?}: {}",
+                backend,
+                message
+            );
+            assert!(message.contains("freed at app.wac:"), "{}", message);
+        }
+    }
+}
@@ -1,5 +1,6 @@
 use crate::Span;
 
+#[derive(Debug)]
 pub struct Error {
     pub span: Vec<Span>,
     pub message: String,
@@ -25,15 +26,6 @@ impl From<std::fmt::Error> for Error {
     }
 }
 
-impl From<wabt::Error> for Error {
-    fn from(e: wabt::Error) -> Self {
-        Self {
-            span: vec![],
-            message: format!("{:?}", e),
-        }
-    }
-}
-
 impl From<wr::error::Error> for Error {
     fn from(e: wr::error::Error) -> Self {
         Self {
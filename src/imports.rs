@@ -1,21 +1,700 @@
-use std::cell::Cell;
+use crate::make_wasi_namespace;
+use crate::open_path;
+use crate::wasm::FuncType;
+use crate::wasm::ValType;
+use crate::wasm::Value;
+use crate::Error;
+use crate::OpenFlags;
+use crate::WasiState;
+use crate::TRAP_OUT_OF_FUEL;
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::BufRead;
+use std::io::Read;
 use std::io::Write;
+use std::path::PathBuf;
+use std::rc::Rc;
 
-pub fn make_import_object() -> wr::ImportObject {
-    wr::imports! {
-        "wac" => {
-            "stdout_write" => wr::func!(|ctx: &mut wr::Ctx, len: i32, ptr: i32| -> i32 {
-                let memory = ctx.memory(0);
-                let buf = read_bytes_from_memory(&memory, len, ptr);
-                std::io::stdout().write(&buf).unwrap() as i32
-            }),
+/// What a running program can see of the outside world,
+/// through either the "wac" imports or WASI
+#[derive(Clone, Default)]
+pub struct HostConfig {
+    /// the command line arguments, starting with the program name
+    pub args: Vec<String>,
+
+    /// the environment variables the program can read with getenv
+    /// (the wac command forwards none of its own unless given --env)
+    pub env: Vec<(String, String)>,
+
+    /// the directory files may be read from and written to (as ".");
+    /// if None, the program cannot access the filesystem
+    pub dir: Option<PathBuf>,
+
+    /// functions provided by the embedder (see Engine::func), which
+    /// take precedence over the built in ones of the same name
+    pub funcs: Vec<Rc<HostFunc>>,
+}
+
+/// A function that a program can import with extern, implemented in Rust
+pub struct HostFunc {
+    pub module: Rc<str>,
+    pub name: Rc<str>,
+    pub type_: FuncType,
+    func: Box<HostFn>,
+}
+
+type HostFn = dyn Fn(&dyn GuestMemory, &[Value]) -> Vec<Value>;
+
+impl HostFunc {
+    /// The function is only ever called with arguments matching type_,
+    /// and must return results matching it too
+    pub fn new<F>(module: &str, name: &str, type_: FuncType, func: F) -> Self
+    where
+        F: Fn(&dyn GuestMemory, &[Value]) -> Vec<Value> + 'static,
+    {
+        Self {
+            module: module.into(),
+            name: name.into(),
+            type_,
+            func: Box::new(func),
+        }
+    }
+
+    /// Calls the function, returning None if the arguments have the wrong types
+    pub fn call(&self, memory: &dyn GuestMemory, args: &[Value]) -> Option<Vec<Value>> {
+        let params = args.iter().map(|arg| arg.type_());
+        if !params.eq(self.type_.params.iter().cloned()) {
+            return None;
+        }
+        Some((self.func)(memory, args))
+    }
+}
+
+/// Returned (as a user error) from host functions, to unwind out of the guest
+#[derive(Debug)]
+pub enum HostStop {
+    /// exit or WASI's proc_exit was called with the given status
+    Exit(i32),
+
+    /// the guest made a call that the host cannot carry out,
+    /// e.g. passing it memory that is out of bounds
+    Trap(String),
+}
+
+/// The trap for a host function given memory the guest does not have
+pub const TRAP_HOST_OUT_OF_BOUNDS: &str = "out of bounds memory access in a host call";
+
+/// A guest's linear memory, as the host functions see it
+///
+/// This is implemented both for wasmer's memories and for the interpreter's,
+/// so that the same host functions serve either.
+pub trait GuestMemory {
+    /// the size of the memory in bytes
+    fn size(&self) -> usize;
+
+    /// copies out.len() bytes starting at ptr, which must be in bounds
+    fn read(&self, ptr: usize, out: &mut [u8]);
+
+    /// copies bytes to ptr, which must be in bounds
+    fn write(&self, ptr: usize, bytes: &[u8]);
+}
+
+impl GuestMemory for wr::Memory {
+    fn size(&self) -> usize {
+        let view: wr::memory::MemoryView<u8> = self.view();
+        view.len()
+    }
+
+    fn read(&self, ptr: usize, out: &mut [u8]) {
+        let view: wr::memory::MemoryView<u8> = self.view();
+        let cells = &view[ptr..ptr + out.len()];
+        for (byte, cell) in out.iter_mut().zip(cells) {
+            *byte = cell.get();
+        }
+    }
+
+    fn write(&self, ptr: usize, bytes: &[u8]) {
+        let view: wr::memory::MemoryView<u8> = self.view();
+        for (cell, byte) in view[ptr..ptr + bytes.len()].iter().zip(bytes) {
+            cell.set(*byte);
+        }
+    }
+}
+
+impl GuestMemory for RefCell<Vec<u8>> {
+    fn size(&self) -> usize {
+        self.borrow().len()
+    }
+
+    fn read(&self, ptr: usize, out: &mut [u8]) {
+        out.copy_from_slice(&self.borrow()[ptr..ptr + out.len()]);
+    }
+
+    fn write(&self, ptr: usize, bytes: &[u8]) {
+        self.borrow_mut()[ptr..ptr + bytes.len()].copy_from_slice(bytes);
+    }
+}
+
+/// Converts arguments of host functions from wasm values
+pub trait FromValue: Sized {
+    fn from_value(value: &Value) -> Option<Self>;
+}
+
+impl FromValue for i32 {
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::I32(x) => Some(*x),
+            _ => None,
         }
     }
 }
 
-fn read_bytes_from_memory(memory: &wr::Memory, len: i32, ptr: i32) -> Vec<u8> {
-    let ptr = ptr as usize;
-    let len = len as usize;
-    let view: wr::memory::MemoryView<u8> = memory.view();
-    view[ptr..ptr + len].iter().map(Cell::get).collect()
+impl FromValue for i64 {
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::I64(x) => Some(*x),
+            _ => None,
+        }
+    }
+}
+
+/// Converts the results of host functions into wasm values
+pub trait IntoValues {
+    fn into_values(self) -> Result<Vec<Value>, HostStop>;
+}
+
+impl IntoValues for i32 {
+    fn into_values(self) -> Result<Vec<Value>, HostStop> {
+        Ok(vec![Value::I32(self)])
+    }
+}
+
+impl IntoValues for () {
+    fn into_values(self) -> Result<Vec<Value>, HostStop> {
+        Ok(vec![])
+    }
+}
+
+impl IntoValues for Result<(), HostStop> {
+    fn into_values(self) -> Result<Vec<Value>, HostStop> {
+        self.map(|()| vec![])
+    }
+}
+
+impl IntoValues for Result<i32, HostStop> {
+    fn into_values(self) -> Result<Vec<Value>, HostStop> {
+        self.map(|x| vec![Value::I32(x)])
+    }
+}
+
+/// The host functions, for backends that call them by name
+/// rather than through an import object (i.e. the interpreter)
+pub struct Host {
+    funcs: Vec<Rc<HostFunc>>,
+    wac: WacHost,
+    wasi: WasiState,
+}
+
+impl Host {
+    pub fn new(config: HostConfig) -> Self {
+        Self {
+            funcs: config.funcs.clone(),
+            wac: WacHost::new(config.clone()),
+            wasi: WasiState::new(config),
+        }
+    }
+
+    fn find(&self, module: &str, name: &str) -> Option<&Rc<HostFunc>> {
+        self.funcs
+            .iter()
+            .find(|func| &*func.module == module && &*func.name == name)
+    }
+
+    /// Whether there is a function to import as module.name
+    pub fn provides(&self, module: &str, name: &str) -> bool {
+        if self.find(module, name).is_some() {
+            return true;
+        }
+        match module {
+            "wac" => WacHost::FUNCS.contains(&name),
+            "wasi_snapshot_preview1" => WasiState::FUNCS.contains(&name),
+            _ => false,
+        }
+    }
+
+    /// Calls the function imported as module.name, returning None if there
+    /// is no such function or the arguments have the wrong types
+    pub fn call(
+        &mut self,
+        module: &str,
+        name: &str,
+        memory: &dyn GuestMemory,
+        args: &[Value],
+    ) -> Option<Result<Vec<Value>, HostStop>> {
+        if let Some(func) = self.find(module, name) {
+            return func.call(memory, args).map(Ok);
+        }
+        match module {
+            "wac" => self.wac.call(name, memory, args),
+            "wasi_snapshot_preview1" => self.wasi.call(name, memory, args),
+            _ => None,
+        }
+    }
+}
+
+pub fn make_import_object(host: &HostConfig) -> wr::ImportObject {
+    let mut namespaces: Vec<(Rc<str>, wr::Namespace)> = vec![
+        ("wac".into(), make_wac_namespace(host.clone())),
+        // provided regardless of the target, so that third party
+        // WASI modules can be run too
+        (
+            "wasi_snapshot_preview1".into(),
+            make_wasi_namespace(host.clone()),
+        ),
+    ];
+    for func in &host.funcs {
+        let index = match namespaces.iter().position(|(m, _)| *m == func.module) {
+            Some(index) => index,
+            None => {
+                namespaces.push((func.module.clone(), wr::Namespace::new()));
+                namespaces.len() - 1
+            }
+        };
+        namespaces[index]
+            .1
+            .insert(&func.name, make_dynamic_func(func.clone()));
+    }
+    let mut import_object = wr::ImportObject::new();
+    for (module, namespace) in namespaces {
+        import_object.register(&*module, namespace);
+    }
+    import_object
+}
+
+fn make_dynamic_func(func: Rc<HostFunc>) -> impl wr::IsExport {
+    let wr_type = |type_: &ValType| match type_ {
+        ValType::I32 => wr::wasm::Type::I32,
+        ValType::I64 => wr::wasm::Type::I64,
+        ValType::F32 => wr::wasm::Type::F32,
+        ValType::F64 => wr::wasm::Type::F64,
+    };
+    let sig = wr::wasm::FuncSig::new(
+        func.type_.params.iter().map(wr_type).collect::<Vec<_>>(),
+        func.type_.results.iter().map(wr_type).collect::<Vec<_>>(),
+    );
+    wr::DynamicFunc::new(
+        std::sync::Arc::new(sig),
+        move |ctx: &mut wr::Ctx, args: &[wr::Value]| -> Vec<wr::Value> {
+            let args: Vec<_> = args.iter().filter_map(|a| from_wr_value(a).ok()).collect();
+            // (wasmer checks the arguments against the signature)
+            let results = func.call(ctx.memory(0), &args).unwrap_or_default();
+            results.into_iter().map(to_wr_value).collect()
+        },
+    )
+}
+
+pub(crate) fn from_wr_value(value: &wr::Value) -> Result<Value, Error> {
+    match value {
+        wr::Value::I32(x) => Ok(Value::I32(*x)),
+        wr::Value::I64(x) => Ok(Value::I64(*x)),
+        wr::Value::F32(x) => Ok(Value::F32(*x)),
+        wr::Value::F64(x) => Ok(Value::F64(*x)),
+        #[allow(unreachable_patterns)]
+        _ => Err(Error {
+            span: vec![],
+            message: format!("Unsupported value {:?}", value),
+        }),
+    }
+}
+
+pub(crate) fn to_wr_value(value: Value) -> wr::Value {
+    match value {
+        Value::I32(x) => wr::Value::I32(x),
+        Value::I64(x) => wr::Value::I64(x),
+        Value::F32(x) => wr::Value::F32(x),
+        Value::F64(x) => wr::Value::F64(x),
+    }
+}
+
+/// Host side state of the "wac" imports
+///
+/// Functions that produce a variable amount of data (e.g. reading a file)
+/// keep it here as 'pending' and return its length, so that the guest can
+/// allocate a str of the right size and then fetch it with take_pending.
+struct WacHost {
+    config: HostConfig,
+    pending: Vec<u8>,
+}
+
+impl WacHost {
+    fn new(config: HostConfig) -> Self {
+        Self {
+            config,
+            pending: vec![],
+        }
+    }
+
+    /// stores data as pending and returns its length
+    fn set_pending(&mut self, data: Vec<u8>) -> i32 {
+        self.pending = data;
+        self.pending.len() as i32
+    }
+
+    fn stdout_write(
+        &mut self,
+        memory: &dyn GuestMemory,
+        len: i32,
+        ptr: i32,
+    ) -> Result<i32, HostStop> {
+        let buf = read_bytes_from_memory(memory, len, ptr)?;
+        Ok(std::io::stdout().write(&buf).unwrap() as i32)
+    }
+
+    /// reads a line (including the newline), with a length of 0 at EOF
+    fn stdin_read_line(&mut self, _memory: &dyn GuestMemory) -> i32 {
+        let mut line = vec![];
+        let stdin = std::io::stdin();
+        stdin.lock().read_until(b'\n', &mut line).unwrap_or(0);
+        self.set_pending(line)
+    }
+
+    fn stdin_read_all(&mut self, _memory: &dyn GuestMemory) -> i32 {
+        let mut data = vec![];
+        std::io::stdin().read_to_end(&mut data).unwrap_or(0);
+        self.set_pending(data)
+    }
+
+    /// -1 if the file could not be read
+    fn file_read(
+        &mut self,
+        memory: &dyn GuestMemory,
+        path_len: i32,
+        path_ptr: i32,
+    ) -> Result<i32, HostStop> {
+        let path = read_bytes_from_memory(memory, path_len, path_ptr)?;
+        let flags = OpenFlags {
+            read: true,
+            ..OpenFlags::default()
+        };
+        let mut data = vec![];
+        let read = self
+            .open(&path, &flags)
+            .map(|mut file| file.read_to_end(&mut data));
+        match read {
+            Some(Ok(_)) => Ok(self.set_pending(data)),
+            _ => Ok(-1),
+        }
+    }
+
+    /// -1 if the file could not be written, 0 otherwise
+    fn file_write(
+        &mut self,
+        memory: &dyn GuestMemory,
+        path_len: i32,
+        path_ptr: i32,
+        len: i32,
+        ptr: i32,
+    ) -> Result<i32, HostStop> {
+        let path = read_bytes_from_memory(memory, path_len, path_ptr)?;
+        let data = read_bytes_from_memory(memory, len, ptr)?;
+        let flags = OpenFlags {
+            write: true,
+            create: true,
+            truncate: true,
+            ..OpenFlags::default()
+        };
+        match self
+            .open(&path, &flags)
+            .map(|mut file| file.write_all(&data))
+        {
+            Some(Ok(())) => Ok(0),
+            _ => Ok(-1),
+        }
+    }
+
+    /// files may only be accessed under the configured directory,
+    /// as with WASI (see open_path)
+    fn open(&self, path: &[u8], flags: &OpenFlags) -> Option<File> {
+        let root = self.config.dir.as_ref()?;
+        open_path(root, std::str::from_utf8(path).ok()?, flags).ok()
+    }
+
+    fn arg_count(&mut self, _memory: &dyn GuestMemory) -> i32 {
+        self.config.args.len() as i32
+    }
+
+    /// -1 if there is no such argument
+    fn arg_get(&mut self, _memory: &dyn GuestMemory, i: i32) -> i32 {
+        match self.config.args.get(i as usize) {
+            Some(arg) if i >= 0 => {
+                let arg = arg.as_bytes().to_vec();
+                self.set_pending(arg)
+            }
+            _ => -1,
+        }
+    }
+
+    /// -1 if the variable is not set
+    fn getenv(
+        &mut self,
+        memory: &dyn GuestMemory,
+        name_len: i32,
+        name_ptr: i32,
+    ) -> Result<i32, HostStop> {
+        let name = read_bytes_from_memory(memory, name_len, name_ptr)?;
+        let value = self
+            .config
+            .env
+            .iter()
+            .find(|(key, _)| key.as_bytes() == &name[..])
+            .map(|(_, value)| value.as_bytes().to_vec());
+        match value {
+            Some(value) => Ok(self.set_pending(value)),
+            None => Ok(-1),
+        }
+    }
+
+    /// copies the pending data to ptr, which must have room for all of it
+    fn take_pending(&mut self, memory: &dyn GuestMemory, ptr: i32) -> Result<(), HostStop> {
+        let data = std::mem::take(&mut self.pending);
+        write_bytes_to_memory(memory, ptr, &data)
+    }
+
+    fn exit(&mut self, _memory: &dyn GuestMemory, code: i32) -> Result<(), HostStop> {
+        Err(HostStop::Exit(code))
+    }
+
+    // the fuel check calls this to trap (see cgen's gen_fuel_check),
+    // so that the trap says why without the runner having to guess
+    fn out_of_fuel(&mut self, _memory: &dyn GuestMemory) -> Result<(), HostStop> {
+        Err(HostStop::Trap(TRAP_OUT_OF_FUEL.into()))
+    }
+
+    // the deadline starts with each call, so the runner provides its own
+    // check_deadline (see Runner's Clock), and without it there is none
+    fn check_deadline(&mut self, _memory: &dyn GuestMemory) -> i32 {
+        0
+    }
+
+    // __malloc and __free only report blocks when checking for leaks,
+    // and the runner then provides its own versions (see leaks.rs)
+    fn track_alloc(&mut self, _memory: &dyn GuestMemory, _ptr: i32, _len: i32) {}
+
+    fn track_free(&mut self, _memory: &dyn GuestMemory, _ptr: i32) {}
+
+    // likewise for the debug allocator, which without the runner frees
+    // blocks right away and checks nothing
+    fn quarantine(&mut self, _memory: &dyn GuestMemory, ptr: i32) -> i32 {
+        ptr
+    }
+
+    fn check_ref(&mut self, _memory: &dyn GuestMemory, _ptr: i32, _release: i32) -> i32 {
+        1
+    }
+}
+
+// each function forwards to the WacHost method of the same name,
+// both in the wasmer namespace and when called by name
+macro_rules! wac_funcs {
+    ($($name:ident($($arg:ident: $t:ty),*) -> $ret:ty),* $(,)?) => {
+        fn make_wac_namespace(config: HostConfig) -> wr::Namespace {
+            let host = Rc::new(RefCell::new(WacHost::new(config)));
+            let mut ns = wr::Namespace::new();
+            $({
+                let host = host.clone();
+                ns.insert(
+                    stringify!($name),
+                    wr::func!(move |ctx: &mut wr::Ctx $(, $arg: $t)*| -> $ret {
+                        host.borrow_mut().$name(ctx.memory(0) $(, $arg)*)
+                    }),
+                );
+            })*
+            ns
+        }
+
+        impl WacHost {
+            const FUNCS: &'static [&'static str] = &[$(stringify!($name)),*];
+
+            fn call(
+                &mut self,
+                name: &str,
+                memory: &dyn GuestMemory,
+                args: &[Value],
+            ) -> Option<Result<Vec<Value>, HostStop>> {
+                match name {
+                    $(stringify!($name) => {
+                        #[allow(unused_mut, unused_variables)]
+                        let mut args = args.iter();
+                        $(let $arg = <$t>::from_value(args.next()?)?;)*
+                        Some(self.$name(memory $(, $arg)*).into_values())
+                    })*
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+wac_funcs! {
+    stdout_write(len: i32, ptr: i32) -> Result<i32, HostStop>,
+    stdin_read_line() -> i32,
+    stdin_read_all() -> i32,
+    file_read(path_len: i32, path_ptr: i32) -> Result<i32, HostStop>,
+    file_write(path_len: i32, path_ptr: i32, len: i32, ptr: i32) -> Result<i32, HostStop>,
+    arg_count() -> i32,
+    arg_get(i: i32) -> i32,
+    getenv(name_len: i32, name_ptr: i32) -> Result<i32, HostStop>,
+    take_pending(ptr: i32) -> Result<(), HostStop>,
+    exit(code: i32) -> Result<(), HostStop>,
+    out_of_fuel() -> Result<(), HostStop>,
+    check_deadline() -> i32,
+    track_alloc(ptr: i32, len: i32) -> (),
+    track_free(ptr: i32) -> (),
+    quarantine(ptr: i32) -> i32,
+    check_ref(ptr: i32, release: i32) -> i32,
+}
+
+/// Copies the len bytes at ptr out of the guest's memory, trapping
+/// if they are not all in bounds (as the guest chooses both)
+pub fn read_bytes_from_memory(
+    memory: &dyn GuestMemory,
+    len: i32,
+    ptr: i32,
+) -> Result<Vec<u8>, HostStop> {
+    let len = len as u32 as usize;
+    let start = check_bounds(memory, ptr, len)?;
+    let mut bytes = vec![0; len];
+    memory.read(start, &mut bytes);
+    Ok(bytes)
+}
+
+pub fn write_bytes_to_memory(
+    memory: &dyn GuestMemory,
+    ptr: i32,
+    bytes: &[u8],
+) -> Result<(), HostStop> {
+    let start = check_bounds(memory, ptr, bytes.len())?;
+    memory.write(start, bytes);
+    Ok(())
+}
+
+/// Where the len bytes at ptr start, if they are all in bounds
+fn check_bounds(memory: &dyn GuestMemory, ptr: i32, len: usize) -> Result<usize, HostStop> {
+    let start = ptr as u32 as usize;
+    match start.checked_add(len) {
+        Some(end) if end <= memory.size() => Ok(start),
+        _ => Err(HostStop::Trap(TRAP_HOST_OUT_OF_BOUNDS.into())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_config;
+    use crate::test_sources;
+    use crate::Engine;
+    use crate::EngineError;
+    use crate::RunConfig;
+    use crate::TEST_BACKENDS;
+
+    #[test]
+    fn out_of_bounds_pointers_trap() {
+        let sources = test_sources(
+            r#"
+extern "wac" "stdout_write" write(len i32, ptr i32) i32
+extern "wac" "arg_get" arg_len(i i32) i32
+extern "wac" "take_pending" take(ptr i32)
+
+export fn Write(len i32, ptr i32) i32 {
+    return write(len, ptr)
+}
+
+export fn Take(ptr i32) {
+    arg_len(0)
+    take(ptr)
+}
+"#,
+        );
+        for &backend in TEST_BACKENDS {
+            let engine = Engine::new(RunConfig {
+                host: HostConfig {
+                    args: vec!["app".into()],
+                    ..HostConfig::default()
+                },
+                ..test_config(backend)
+            });
+            let module = engine.compile(sources.clone()).unwrap();
+            let mut instance = engine.instantiate(&module).unwrap();
+            let size = instance.memory().unwrap().size() as i32;
+            let results = vec![
+                instance.call::<_, i32>("Write", (16, size - 8)).map(|_| ()),
+                instance.call::<_, i32>("Write", (-1, 0)).map(|_| ()),
+                instance.call::<_, ()>("Take", (size - 2,)),
+                instance.call::<_, ()>("Take", (-1,)),
+            ];
+            for result in results {
+                match result {
+                    Err(EngineError::Runtime(error)) => {
+                        assert!(
+                            error.message.contains(TRAP_HOST_OUT_OF_BOUNDS),
+                            "{}",
+                            error.message
+                        )
+                    }
+                    other => panic!("{:?}: {:?}", backend, other),
+                }
+            }
+            assert_eq!(instance.call::<_, i32>("Write", (0, size)).unwrap(), 0);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn files_are_not_opened_through_symlinks() {
+        let root = std::env::temp_dir().join(format!("wac-imports-test-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.with_extension("secret"), "secret").unwrap();
+        std::os::unix::fs::symlink(root.with_extension("secret"), root.join("link")).unwrap();
+        let target = root.with_extension("outside");
+        std::os::unix::fs::symlink(&target, root.join("dangling")).unwrap();
+
+        let sources = test_sources(
+            r#"
+extern "wac" "file_read" file_read(path_len i32, path_ptr i32) i32
+extern "wac" "file_write" file_write(path_len i32, path_ptr i32, len i32, ptr i32) i32
+
+export fn Read(path str) i32 {
+    var p = $raw(path)
+    return file_read($read4(p, offset: 12), p + 16)
+}
+
+export fn Write(path str) i32 {
+    var p = $raw(path)
+    return file_write($read4(p, offset: 12), p + 16, $read4(p, offset: 12), p + 16)
+}
+"#,
+        );
+        for &backend in TEST_BACKENDS {
+            let engine = Engine::new(RunConfig {
+                host: HostConfig {
+                    dir: Some(root.clone()),
+                    ..HostConfig::default()
+                },
+                ..test_config(backend)
+            });
+            let module = engine.compile(sources.clone()).unwrap();
+            let mut instance = engine.instantiate(&module).unwrap();
+            let mut call = |name: &str, path: &str| {
+                let path = instance.alloc_str(path).unwrap() as i32;
+                instance.call::<_, i32>(name, (path,)).unwrap()
+            };
+            assert_eq!(call("Read", "link"), -1);
+            assert_eq!(call("Write", "dangling"), -1);
+            assert!(!target.exists());
+            assert_eq!(call("Write", "new.txt"), 0);
+            assert_eq!(call("Read", "new.txt"), "new.txt".len() as i32);
+        }
+        std::fs::remove_dir_all(&root).unwrap();
+        std::fs::remove_file(root.with_extension("secret")).unwrap();
+    }
 }
@@ -0,0 +1,201 @@
+//! Ordering of global variable initializers
+//!
+//! Globals are initialized in $start one after another, so a global whose
+//! initializer reads another global (directly, or through any chain of
+//! function calls) must come after it.
+use crate::ir::*;
+use crate::Error;
+use crate::Span;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::rc::Rc;
+
+/// Sorts the globals so that every global is initialized after all the
+/// globals its initializer depends on.
+/// Globals that do not depend on each other keep their original order.
+pub fn order_globals(globals: Vec<Rc<Global>>) -> Result<Vec<Rc<Global>>, Error> {
+    let mut orderer = Orderer {
+        states: HashMap::new(),
+        stack: Vec::new(),
+        out: Vec::new(),
+    };
+    for global in &globals {
+        orderer.visit(global)?;
+    }
+    Ok(orderer.out)
+}
+
+enum State {
+    InProgress,
+    Done,
+}
+
+struct Orderer {
+    states: HashMap<*const Global, State>,
+
+    /// globals currently being visited, each with the trail of spans
+    /// leading to the read of the next global on the stack
+    stack: Vec<(Rc<Global>, Vec<Span>)>,
+
+    out: Vec<Rc<Global>>,
+}
+
+impl Orderer {
+    fn visit(&mut self, global: &Rc<Global>) -> Result<(), Error> {
+        match self.states.get(&Rc::as_ptr(global)) {
+            Some(State::Done) => return Ok(()),
+            Some(State::InProgress) => return Err(self.cycle_error(global)),
+            None => {}
+        }
+        self.states.insert(Rc::as_ptr(global), State::InProgress);
+        let mut reads = Reads::default();
+        if let Some(init) = &global.init {
+            reads.expr(init, &vec![]);
+        }
+        for (dep, trail) in reads.globals {
+            self.stack.push((global.clone(), trail));
+            self.visit(&dep)?;
+            self.stack.pop();
+        }
+        self.states.insert(Rc::as_ptr(global), State::Done);
+        self.out.push(global.clone());
+        Ok(())
+    }
+
+    fn cycle_error(&self, global: &Rc<Global>) -> Error {
+        let start = self
+            .stack
+            .iter()
+            .position(|(g, _)| Rc::ptr_eq(g, global))
+            .unwrap();
+        let cycle = self.stack[start..]
+            .iter()
+            .map(|(g, trail)| {
+                let mut span = vec![g.span.clone()];
+                span.extend(trail.iter().cloned());
+                (g.name.clone(), span)
+            })
+            .collect();
+        global_cycle_error(cycle)
+    }
+}
+
+/// Error for globals whose initializers depend on each other.
+/// Each global in the cycle is given with its name, followed by the spans
+/// of its declaration and of the code leading to the read of the next one.
+pub fn global_cycle_error(cycle: Vec<(Rc<str>, Vec<Span>)>) -> Error {
+    let first = cycle[0].0.clone();
+    let message = if cycle.len() == 1 {
+        format!(
+            "Global {} is read in its own initializer, before it is initialized",
+            first
+        )
+    } else {
+        let names: Vec<_> = cycle.iter().map(|(name, _)| name.to_string()).collect();
+        format!(
+            "Global initializers form a cycle ({} -> {}), so some global \
+            would be read before it is initialized",
+            names.join(" -> "),
+            first
+        )
+    };
+    Error {
+        span: cycle.into_iter().flat_map(|(_, span)| span).collect(),
+        message,
+    }
+}
+
+/// The globals that may be read while evaluating some code,
+/// including through function calls
+#[derive(Default)]
+struct Reads {
+    /// each global read along with the spans of the calls leading
+    /// to it, and the span of the read itself
+    globals: Vec<(Rc<Global>, Vec<Span>)>,
+
+    seen_globals: HashSet<*const Global>,
+    seen_funcs: HashSet<*const Func>,
+}
+
+impl Reads {
+    fn read(&mut self, var: &Variable, span: &Span, trail: &Vec<Span>) {
+        if let Variable::Global(global) = var {
+            if self.seen_globals.insert(Rc::as_ptr(global)) {
+                let mut trail = trail.clone();
+                trail.push(span.clone());
+                self.globals.push((global.clone(), trail));
+            }
+        }
+    }
+
+    fn func(&mut self, func: &Rc<Func>, trail: &Vec<Span>) {
+        if self.seen_funcs.insert(Rc::as_ptr(func)) {
+            if let Some(body) = func.body.borrow().as_ref() {
+                self.stmt(body, trail);
+            }
+        }
+    }
+
+    fn stmt(&mut self, stmt: &Stmt, trail: &Vec<Span>) {
+        match &stmt.data {
+            StmtData::Block(stmts) => {
+                for stmt in stmts {
+                    self.stmt(stmt, trail);
+                }
+            }
+            StmtData::If(pairs, other) => {
+                for (cond, body) in pairs {
+                    self.expr(cond, trail);
+                    self.stmt(body, trail);
+                }
+                self.stmt(other, trail);
+            }
+            StmtData::Return(expr) | StmtData::Expr(expr) => self.expr(expr, trail),
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr, trail: &Vec<Span>) {
+        match &expr.data {
+            ExprData::Void
+            | ExprData::Bool(_)
+            | ExprData::I32(_)
+            | ExprData::I64(_)
+            | ExprData::F32(_)
+            | ExprData::F64(_)
+            | ExprData::Str(_)
+            | ExprData::BorrowStr(_) => {}
+            ExprData::GetVar(var)
+            | ExprData::MoveVar(var)
+            | ExprData::BorrowVar(var)
+            | ExprData::Raw(var) => self.read(var, &expr.span, trail),
+            ExprData::SetVar(_, setexpr) => self.expr(setexpr, trail),
+            ExprData::AugVar(var, _, augexpr) => {
+                self.read(var, &expr.span, trail);
+                self.expr(augexpr, trail);
+            }
+            ExprData::CallFunc(func, args) => {
+                for arg in args {
+                    self.expr(arg, trail);
+                }
+                let mut trail = trail.clone();
+                trail.push(expr.span.clone());
+                self.func(func, &trail);
+            }
+            ExprData::CallExtern(_, args) | ExprData::Op(_, args) | ExprData::Asm(args, ..) => {
+                for arg in args {
+                    self.expr(arg, trail);
+                }
+            }
+            ExprData::And(left, right) | ExprData::Or(left, right) => {
+                self.expr(left, trail);
+                self.expr(right, trail);
+            }
+            ExprData::Read(_, addr, _) => self.expr(addr, trail),
+            ExprData::Write(_, addr, value, _) => {
+                self.expr(addr, trail);
+                self.expr(value, trail);
+            }
+            ExprData::DropPrimitive(expr) => self.expr(expr, trail),
+        }
+    }
+}
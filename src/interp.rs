@@ -0,0 +1,1255 @@
+//! A tree-walking interpreter for ir::Program
+//!
+//! The program is run directly, without generating any wasm, against a
+//! linear memory laid out the way cgen lays it out, and with the same host
+//! functions as the wasmer backend (see imports.rs). Starting up is much
+//! cheaper than compiling and instantiating a module, which is what dominates
+//! the time for small scripts. And as it shares nothing with cgen beyond the
+//! IR, it also serves as a reference to test the generated code against.
+//!
+//! $asm is supported for straight line code, i.e. numeric, memory and
+//! stack instructions, but not control flow, locals, globals or calls.
+use crate::alloc_site;
+use crate::check_max_pages;
+use crate::check_ref;
+use crate::ir::*;
+use crate::new_alloc_table;
+use crate::quarantine;
+use crate::record_alloc;
+use crate::trtype;
+use crate::wasm;
+use crate::wasm::Instr;
+use crate::wasm::Value;
+use crate::AllocTable;
+use crate::BackendInstance;
+use crate::ByteCount;
+use crate::Error;
+use crate::GuestMemory;
+use crate::Host;
+use crate::HostStop;
+use crate::RunConfig;
+use crate::Span;
+use crate::Stop;
+use crate::Target;
+use crate::DEADLINE_CHECK_INTERVAL;
+use crate::MAX_PAGES;
+use crate::TRAP_DEADLINE_EXCEEDED;
+use crate::TRAP_OUT_OF_FUEL;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::Duration;
+use std::time::Instant;
+
+/// How much native stack the interpreter may use by default, beyond what
+/// is in use when it is called, before it traps (see RunConfig::interp_stack)
+///
+/// Calls in the interpreted program are nested calls in the interpreter
+/// too, of about 20 KB each in debug builds of wac and 2 KB in release
+/// builds. This leaves room for the rest of a 2 MiB thread, which is
+/// what Rust gives spawned threads (e.g. those of cargo test).
+pub const DEFAULT_INTERP_STACK: usize = 1 << 20;
+
+const TRAP_UNREACHABLE: &str = "unreachable";
+const TRAP_OUT_OF_BOUNDS: &str = "out of bounds memory access";
+const TRAP_DIVIDE_BY_ZERO: &str = "integer divide by zero";
+const TRAP_INTEGER_OVERFLOW: &str = "integer overflow";
+const TRAP_INVALID_CONVERSION: &str = "invalid conversion to integer";
+const TRAP_STACK_EXHAUSTED: &str = "call stack exhausted";
+
+pub struct Interpreter {
+    program: Rc<Program>,
+    target: Target,
+    library: bool,
+
+    /// functions by export name
+    exports: HashMap<Rc<str>, Rc<Func>>,
+
+    /// the runtime functions that cgen calls directly
+    retain: Option<Rc<Func>>,
+    release: Option<Rc<Func>>,
+
+    globals: HashMap<*const Global, Value>,
+    memory: RefCell<Vec<u8>>,
+    host: Host,
+    frames: Vec<Frame>,
+
+    /// where the native stack was when the host called in, and how far
+    /// past it the interpreter may go
+    stack_base: usize,
+    max_stack: usize,
+
+    /// the instructions of each $asm, parsed the first time it runs
+    asm: HashMap<Rc<str>, Rc<[Instr]>>,
+
+    /// whether traps come with a backtrace
+    debug: bool,
+
+    /// the fuel left, if the program is metered (as in cgen's gen_fuel_check)
+    fuel: Option<i64>,
+
+    /// how long each call from the host may run, if limited,
+    /// and when the current one must stop by
+    time_limit: Option<Duration>,
+    deadline: Option<Instant>,
+
+    /// the most pages memory may grow to
+    max_pages: usize,
+
+    /// the live allocations, when checking for leaks (see leaks.rs)
+    allocations: Option<AllocTable>,
+}
+
+struct Frame {
+    /// the function's wac name (e.g. "app.Main")
+    name: Rc<str>,
+
+    /// indexed by Local.id (so parameters come first)
+    locals: Vec<Value>,
+
+    /// where the function was called from (None for calls from the host)
+    call_site: Option<Span>,
+}
+
+enum Flow {
+    Next,
+    Return(Option<Value>),
+}
+
+impl Interpreter {
+    /// Sets up memory, and for the wac target, also initializes global
+    /// variables (as the start function would)
+    pub fn new(program: Rc<Program>, config: &RunConfig) -> Result<Self, Stop> {
+        let unknown_import = |path: &(Rc<str>, Rc<str>), span: Option<&Span>| {
+            Stop::Error(Error {
+                span: span.into_iter().cloned().collect(),
+                message: format!("Unknown import {}.{}", path.0, path.1),
+            })
+        };
+        let host = Host::new(config.host.clone());
+        for ext in &program.externs {
+            if !host.provides(&ext.path.0, &ext.path.1) {
+                return Err(unknown_import(&ext.path, Some(&ext.span)));
+            }
+        }
+        for gvar in &program.globals {
+            if let Some(path) = &gvar.import {
+                return Err(unknown_import(path, Some(&gvar.span)));
+            }
+        }
+        // nor can the memory be, as the interpreter keeps its own
+        if let Some(path) = &program.memory_import {
+            return Err(unknown_import(path, None));
+        }
+
+        let mut exports = HashMap::new();
+        let mut runtime = HashMap::new();
+        for func in &program.funcs {
+            if let Some(export) = &func.export {
+                exports.insert(export.clone(), func.clone());
+            }
+            runtime.insert(func.wasm_name(), func.clone());
+        }
+        let globals = program
+            .globals
+            .iter()
+            .map(|gvar| (Rc::as_ptr(gvar), Value::zero(trtype(&gvar.type_))))
+            .collect();
+
+        // the same layout as cgen's, except that there are no debug tables
+        // (backtraces come from the interpreter's own call stack)
+        let (memory, static_mem_end) = {
+            let static_memory = program.memory.borrow();
+            let static_mem_end = static_memory.get_mem_end();
+            let pages = std::cmp::max(1, (static_mem_end + PAGE_SIZE - 1) / PAGE_SIZE);
+            check_max_pages(pages, config).map_err(Stop::Error)?;
+            let mut memory = vec![0; pages * PAGE_SIZE];
+            let (start, data) = static_memory.gen();
+            memory[start..start + data.len()].copy_from_slice(&data);
+            (memory, static_mem_end)
+        };
+
+        let (retain, release) = config.refcount_funcs();
+        let allocations = if config.track_allocs() {
+            Some(new_alloc_table(&program.records))
+        } else {
+            None
+        };
+        let mut interpreter = Self {
+            program,
+            target: config.target,
+            library: config.library,
+            exports,
+            retain: runtime.get(&*format!("f/{}", retain)).cloned(),
+            release: runtime.get(&*format!("f/{}", release)).cloned(),
+            globals,
+            memory: RefCell::new(memory),
+            host,
+            frames: vec![],
+            stack_base: 0,
+            max_stack: config.interp_stack,
+            asm: HashMap::new(),
+            debug: config.debug,
+            fuel: config.fuel_budget().map(|fuel| fuel as i64),
+            time_limit: config.time_limit,
+            deadline: None,
+            max_pages: config.max_pages.map_or(MAX_PAGES, |max| max as usize),
+            allocations,
+        };
+        interpreter.memory.get_mut()[HEAP_LIMIT_PTR..HEAP_LIMIT_PTR + 4]
+            .copy_from_slice(&(static_mem_end as u32).to_le_bytes());
+        if config.track_allocs() {
+            interpreter.memory.get_mut()[TRACK_ALLOCS_PTR..TRACK_ALLOCS_PTR + 4]
+                .copy_from_slice(&1u32.to_le_bytes());
+        }
+        if config.debug_alloc {
+            interpreter.memory.get_mut()[DEBUG_ALLOC_PTR..DEBUG_ALLOC_PTR + 4]
+                .copy_from_slice(&1u32.to_le_bytes());
+        }
+        if config.heap_stats {
+            interpreter.memory.get_mut()[HEAP_STATS_PTR..HEAP_STATS_PTR + 4]
+                .copy_from_slice(&1u32.to_le_bytes());
+        }
+
+        if interpreter.target == Target::Wac {
+            interpreter.init_globals()?;
+        }
+        Ok(interpreter)
+    }
+
+    fn init_globals(&mut self) -> Result<(), Stop> {
+        // (the start frame is the outermost, as for calls from the host)
+        self.stack_base = stack_pointer();
+        self.start_clock();
+        let locals = self.program.gvar_init_locals.clone();
+        self.frames.push(Frame {
+            name: "start".into(),
+            locals: locals
+                .iter()
+                .map(|l| Value::zero(trtype(&l.type_)))
+                .collect(),
+            call_site: None,
+        });
+        let result = self.eval_global_inits(&locals);
+        self.frames.pop();
+        result
+    }
+
+    /// evaluates the initializers of the globals in the start frame,
+    /// and then releases its local variables
+    fn eval_global_inits(&mut self, locals: &[Rc<Local>]) -> Result<(), Stop> {
+        for gvar in self.program.globals.clone() {
+            if let Some(init) = &gvar.init {
+                let value = self.eval_value(init)?;
+                self.globals.insert(Rc::as_ptr(&gvar), value);
+            }
+        }
+        for local in locals {
+            if let RetainType::Typed = local.type_.retain_type() {
+                let value = self.frames.last().unwrap().locals[local.id];
+                self.call_runtime(false, value, &local.span)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The table of live allocations, if checking for leaks
+    pub(crate) fn allocations(&self) -> Option<AllocTable> {
+        self.allocations.clone()
+    }
+
+    /// handles the allocator's "wac" imports when tracking allocations
+    /// (see leaks.rs), returning None for any other import
+    fn track(&self, name: &str, args: &[Value]) -> Option<Option<Value>> {
+        let table = self.allocations.as_ref()?;
+        let sites = self.frames.iter().rev();
+        let site = || alloc_site(sites.filter_map(|frame| frame.call_site.as_ref()));
+        match (name, args) {
+            ("track_alloc", [Value::I32(ptr), Value::I32(len)]) => {
+                record_alloc(table, *ptr as u32, *len as u32, site());
+                Some(None)
+            }
+            ("track_free", [Value::I32(ptr)]) => {
+                table.borrow_mut().live.remove(&(*ptr as u32));
+                Some(None)
+            }
+            ("quarantine", [Value::I32(ptr)]) => {
+                let evicted = quarantine(table, &self.memory, *ptr as u32, site());
+                Some(Some(Value::I32(evicted as i32)))
+            }
+            ("check_ref", [Value::I32(ptr), Value::I32(release)]) => {
+                let ok = check_ref(table, &self.memory, *ptr as u32, *release != 0);
+                Some(Some(Value::I32(ok as i32)))
+            }
+            _ => None,
+        }
+    }
+
+    // (kept out of eval, whose stack frame every nested call adds to)
+    fn call_extern(
+        &mut self,
+        ext: &Extern,
+        exprs: &[Expr],
+        span: &Span,
+    ) -> Result<Option<Value>, Stop> {
+        let args = self.eval_values(exprs)?;
+        if &*ext.path.0 == "wac" {
+            if let Some(result) = self.track(&ext.path.1, &args) {
+                return Ok(result);
+            }
+        }
+        let result = self
+            .host
+            .call(&ext.path.0, &ext.path.1, &self.memory, &args);
+        let results = match result {
+            Some(Ok(results)) => results,
+            Some(Err(HostStop::Exit(code))) => return Err(Stop::Exit(code)),
+            Some(Err(HostStop::Trap(trap))) => return Err(self.trap(span, &trap)),
+            None => {
+                return Err(Stop::Error(Error {
+                    span: vec![span.clone()],
+                    message: format!(
+                        "Invalid call to {}.{} with {:?}",
+                        ext.path.0, ext.path.1, args
+                    ),
+                }))
+            }
+        };
+        self.check_noreturn(&ext.type_, span)?;
+        // the host only borrows the strs and records passed to it
+        for (expr, arg) in exprs.iter().zip(args) {
+            if expr.type_.value().unwrap().retain_type() == RetainType::Typed {
+                self.call_runtime(false, arg, span)?;
+            }
+        }
+        Ok(results.first().cloned())
+    }
+
+    /// sets the deadline of a call from the host (or of the initializers)
+    fn start_clock(&mut self) {
+        // (with none if it is too far off for an Instant)
+        self.deadline = self
+            .time_limit
+            .and_then(|limit| Instant::now().checked_add(limit));
+    }
+
+    fn call_func(
+        &mut self,
+        func: &Rc<Func>,
+        args: Vec<Value>,
+        call_site: Option<&Span>,
+    ) -> Result<Option<Value>, Stop> {
+        let stack_pointer = stack_pointer();
+        if self.frames.is_empty() {
+            // the host is calling in
+            self.stack_base = stack_pointer;
+            self.start_clock();
+        } else if self.stack_base.saturating_sub(stack_pointer) > self.max_stack {
+            return Err(self.trap(call_site.unwrap_or(&func.span), TRAP_STACK_EXHAUSTED));
+        }
+        let locals = func.locals.borrow().clone();
+        let mut values: Vec<_> = locals
+            .iter()
+            .map(|local| Value::zero(trtype(&local.type_)))
+            .collect();
+        for (param, arg) in func.parameters.borrow().iter().zip(args) {
+            values[param.id] = arg;
+        }
+        self.frames.push(Frame {
+            name: wasm_path(&func.module, &func.name).into(),
+            locals: values,
+            call_site: call_site.cloned(),
+        });
+
+        let result = self.call_body(func, &locals);
+        self.frames.pop();
+        result
+    }
+
+    /// runs the body of the function on top of the call stack, and then
+    /// releases its local variables (including parameters, except for
+    /// borrowed ones)
+    fn call_body(&mut self, func: &Func, locals: &[Rc<Local>]) -> Result<Option<Value>, Stop> {
+        if let Some(fuel) = &mut self.fuel {
+            if *fuel == 0 {
+                return Err(self.trap(&func.span, TRAP_OUT_OF_FUEL));
+            }
+            *fuel -= 1;
+            if *fuel & (DEADLINE_CHECK_INTERVAL - 1) == 0
+                && matches!(self.deadline, Some(deadline) if Instant::now() >= deadline)
+            {
+                return Err(self.trap(&func.span, TRAP_DEADLINE_EXCEEDED));
+            }
+        }
+        let result = match self.exec(func.body.borrow().as_ref().unwrap())? {
+            Flow::Return(value) => value,
+            Flow::Next => None,
+        };
+        for local in locals {
+            if local.type_.retain_type() == RetainType::Typed && !local.borrowed.get() {
+                let value = self.frames.last().unwrap().locals[local.id];
+                self.call_runtime(false, value, &func.span)?;
+            }
+        }
+        Ok(result)
+    }
+
+    /// calls __retain (or __release) on a str or record
+    /// (or their debug versions, see RunConfig::refcount_funcs)
+    fn call_runtime(&mut self, retain: bool, value: Value, span: &Span) -> Result<(), Stop> {
+        let (func, name) = if retain {
+            (self.retain.clone(), "__retain")
+        } else {
+            (self.release.clone(), "__release")
+        };
+        match func {
+            Some(func) => self.call_func(&func, vec![value], Some(span)).map(|_| ()),
+            None => Err(Stop::Error(Error {
+                span: vec![span.clone()],
+                message: format!("{} is not defined (is the prelude missing?)", name),
+            })),
+        }
+    }
+
+    fn exec(&mut self, stmt: &Stmt) -> Result<Flow, Stop> {
+        match &stmt.data {
+            StmtData::Block(stmts) => {
+                for stmt in stmts {
+                    if let Flow::Return(value) = self.exec(stmt)? {
+                        return Ok(Flow::Return(value));
+                    }
+                }
+                Ok(Flow::Next)
+            }
+            StmtData::If(pairs, other) => {
+                for (cond, body) in pairs {
+                    if self.eval_value(cond)? != Value::I32(0) {
+                        return self.exec(body);
+                    }
+                }
+                self.exec(other)
+            }
+            StmtData::Return(expr) => Ok(Flow::Return(self.eval(expr)?)),
+            StmtData::Expr(expr) => {
+                self.eval(expr)?;
+                Ok(Flow::Next)
+            }
+        }
+    }
+
+    fn eval_value(&mut self, expr: &Expr) -> Result<Value, Stop> {
+        Ok(self.eval(expr)?.expect("expression without a value"))
+    }
+
+    fn eval_values(&mut self, exprs: &[Expr]) -> Result<Vec<Value>, Stop> {
+        exprs.iter().map(|expr| self.eval_value(expr)).collect()
+    }
+
+    fn eval(&mut self, expr: &Expr) -> Result<Option<Value>, Stop> {
+        let span = &expr.span;
+        let value = match &expr.data {
+            ExprData::Void => return Ok(None),
+            ExprData::Bool(b) => Value::I32(if *b { 1 } else { 0 }),
+            ExprData::I32(x) => Value::I32(*x),
+            ExprData::I64(x) => Value::I64(*x),
+            ExprData::F32(x) => Value::F32(*x),
+            ExprData::F64(x) => Value::F64(*x),
+            ExprData::Str(ptr) => {
+                let value = Value::I32(ptr.get() as i32);
+                self.call_runtime(true, value, span)?;
+                value
+            }
+            ExprData::GetVar(x) => {
+                let value = self.get_var(x);
+                match x.type_().retain_type() {
+                    RetainType::Primitive => {}
+                    RetainType::Typed => self.call_runtime(true, value, span)?,
+                    RetainType::Id => panic!("TODO: interp id GetVar (retain)"),
+                }
+                value
+            }
+            ExprData::MoveVar(x) => {
+                let value = self.get_var(x);
+                self.set_var(x, Value::zero(trtype(x.type_())));
+                value
+            }
+            ExprData::BorrowVar(x) => self.get_var(x),
+            ExprData::BorrowStr(ptr) => Value::I32(ptr.get() as i32),
+            ExprData::SetVar(x, setexpr) => match x.type_().retain_type() {
+                RetainType::Primitive => {
+                    let value = self.eval_value(setexpr)?;
+                    self.set_var(x, value);
+                    if let ReturnType::Void = expr.type_ {
+                        return Ok(None);
+                    }
+                    // the new value is also the value of the assignment expression
+                    value
+                }
+                RetainType::Typed => {
+                    // (as in cgen, the old value is read after the new one)
+                    let value = self.eval_value(setexpr)?;
+                    let old = self.get_var(x);
+                    self.call_runtime(false, old, span)?;
+                    self.set_var(x, value);
+                    if let ReturnType::Void = expr.type_ {
+                        return Ok(None);
+                    }
+                    // the assignment expression holds its own reference
+                    self.call_runtime(true, value, span)?;
+                    value
+                }
+                RetainType::Id => panic!("TODO: interp id SetVar (retain + release)"),
+            },
+            ExprData::AugVar(x, op, augexpr) => match x.type_().retain_type() {
+                RetainType::Primitive => {
+                    let old = self.get_var(x);
+                    let value = self.eval_value(augexpr)?;
+                    let value = self.numeric(span, &op.to_string(), &[old, value])?;
+                    self.set_var(x, value);
+                    return Ok(None);
+                }
+                _ => panic!("TODO: interp non-primitive AugVar (retain + release)"),
+            },
+            ExprData::CallFunc(func, args) => {
+                let args = self.eval_values(args)?;
+                let result = self.call_func(func, args, Some(span))?;
+                self.check_noreturn(&func.type_, span)?;
+                return Ok(result);
+            }
+            ExprData::CallExtern(ext, args) => {
+                return self.call_extern(ext, args, span);
+            }
+            ExprData::Op(op, args) => {
+                let mut values = Vec::new();
+                for arg in args {
+                    let value = self.eval_value(arg)?;
+                    // non-primitive operands (i.e. of 'is' and 'is not')
+                    // are released once they are evaluated
+                    match arg.type_.value().unwrap().retain_type() {
+                        RetainType::Primitive => {}
+                        RetainType::Typed => self.call_runtime(false, value, span)?,
+                        RetainType::Id => panic!("TODO: interp release id operand"),
+                    }
+                    values.push(value);
+                }
+                self.numeric(span, &op.to_string(), &values)?
+            }
+            ExprData::And(a, b) => {
+                if self.eval_value(a)? != Value::I32(0) {
+                    self.eval_value(b)?
+                } else {
+                    Value::I32(0)
+                }
+            }
+            ExprData::Or(a, b) => {
+                if self.eval_value(a)? != Value::I32(0) {
+                    Value::I32(1)
+                } else {
+                    self.eval_value(b)?
+                }
+            }
+            ExprData::DropPrimitive(x) => {
+                self.eval(x)?;
+                return Ok(None);
+            }
+            ExprData::Asm(args, type_, code) => {
+                let args = self.eval_values(args)?;
+                let mut stack = self.run_asm(span, code, args)?;
+                let expected = if let ReturnType::Type(_) = type_ {
+                    1
+                } else {
+                    0
+                };
+                if stack.len() != expected {
+                    return Err(Stop::Error(Error {
+                        span: vec![span.clone()],
+                        message: format!(
+                            "$asm left {} values on the stack, instead of {}",
+                            stack.len(),
+                            expected
+                        ),
+                    }));
+                }
+                return Ok(stack.pop());
+            }
+            ExprData::Raw(var) => match self.get_var(var) {
+                Value::F32(x) => Value::I32(x.to_bits() as i32),
+                Value::F64(x) => Value::I64(x.to_bits() as i64),
+                value => value,
+            },
+            ExprData::Read(byte_count, addr, offset) => {
+                let addr = self.eval_value(addr)?;
+                let name = match byte_count {
+                    ByteCount::N1 => "i32.load8_u",
+                    ByteCount::N2 => "i32.load16_u",
+                    ByteCount::N4 => "i32.load",
+                    ByteCount::N8 => "i64.load",
+                };
+                self.load(name, addr, *offset)
+                    .map_err(|trap| self.trap(span, trap))?
+            }
+            ExprData::Write(byte_count, addr, data, offset) => {
+                let addr = self.eval_value(addr)?;
+                let data = self.eval_value(data)?;
+                let name = match byte_count {
+                    ByteCount::N1 => "i32.store8",
+                    ByteCount::N2 => "i32.store16",
+                    ByteCount::N4 => "i32.store",
+                    ByteCount::N8 => "i64.store",
+                };
+                self.store(name, addr, data, *offset)
+                    .map_err(|trap| self.trap(span, trap))?;
+                return Ok(None);
+            }
+        };
+        Ok(Some(value))
+    }
+
+    /// if a noreturn function returns after all, traps as the generated code would
+    fn check_noreturn(&self, type_: &FuncType, span: &Span) -> Result<(), Stop> {
+        match type_.return_type {
+            ReturnType::NoReturn => Err(self.trap(span, TRAP_UNREACHABLE)),
+            _ => Ok(()),
+        }
+    }
+
+    fn get_var(&self, var: &Variable) -> Value {
+        match var {
+            Variable::Local(local) => self.frames.last().unwrap().locals[local.id],
+            Variable::Global(gvar) => self.globals[&Rc::as_ptr(gvar)],
+        }
+    }
+
+    fn set_var(&mut self, var: &Variable, value: Value) {
+        match var {
+            Variable::Local(local) => self.frames.last_mut().unwrap().locals[local.id] = value,
+            Variable::Global(gvar) => {
+                self.globals.insert(Rc::as_ptr(gvar), value);
+            }
+        }
+    }
+
+    /// runs the instructions of an $asm, given the values of its arguments
+    /// as the initial stack, and returns what is left on the stack
+    fn run_asm(
+        &mut self,
+        span: &Span,
+        code: &Rc<str>,
+        args: Vec<Value>,
+    ) -> Result<Vec<Value>, Stop> {
+        let error = |message: String| {
+            Stop::Error(Error {
+                span: vec![span.clone()],
+                message,
+            })
+        };
+        let instrs = match self.asm.get(code) {
+            Some(instrs) => instrs.clone(),
+            None => {
+                let instrs: Rc<[Instr]> = wasm::parse_instrs(code).map_err(error)?.into();
+                self.asm.insert(code.clone(), instrs.clone());
+                instrs
+            }
+        };
+        let mut stack = args;
+        for instr in instrs.iter() {
+            let mut pop = |count: usize| {
+                if stack.len() < count {
+                    Err(error(format!(
+                        "Not enough values on the stack for {}",
+                        instr
+                    )))
+                } else {
+                    Ok(stack.split_off(stack.len() - count))
+                }
+            };
+            let value = match instr {
+                Instr::I32Const(x) => Value::I32(*x),
+                Instr::I64Const(x) => Value::I64(*x),
+                Instr::F32Const(x) => Value::F32(*x),
+                Instr::F64Const(x) => Value::F64(*x),
+                Instr::Mem(op, offset) if op.name.contains(".store") => {
+                    let args = pop(2)?;
+                    self.store(op.name, args[0], args[1], *offset)
+                        .map_err(|trap| self.trap(span, trap))?;
+                    continue;
+                }
+                Instr::Mem(op, offset) => {
+                    let addr = pop(1)?[0];
+                    self.load(op.name, addr, *offset)
+                        .map_err(|trap| self.trap(span, trap))?
+                }
+                Instr::Op(op) => match op.name {
+                    "nop" => continue,
+                    "unreachable" => {
+                        // check_ref may have found a use after free or the like
+                        let violation = match &self.allocations {
+                            Some(table) => table.borrow_mut().violation.take(),
+                            None => None,
+                        };
+                        let trap = violation.as_deref().unwrap_or(TRAP_UNREACHABLE);
+                        return Err(self.trap(span, trap));
+                    }
+                    "drop" => {
+                        pop(1)?;
+                        continue;
+                    }
+                    "select" => match pop(3)?[..] {
+                        [a, b, Value::I32(cond)] => {
+                            if cond != 0 {
+                                a
+                            } else {
+                                b
+                            }
+                        }
+                        _ => return Err(error("Invalid operands for select".into())),
+                    },
+                    "memory.size" => Value::I32((self.memory.borrow().len() / PAGE_SIZE) as i32),
+                    "memory.grow" => match pop(1)?[0] {
+                        Value::I32(delta) => Value::I32(self.grow(delta as u32 as usize)),
+                        _ => return Err(error("Invalid operand for memory.grow".into())),
+                    },
+                    "return" => {
+                        return Err(error(format!(
+                            "{} in $asm is not supported by the interpreter",
+                            instr
+                        )))
+                    }
+                    name => {
+                        let args = pop(arity(name))?;
+                        self.numeric(span, name, &args)?
+                    }
+                },
+                _ => {
+                    return Err(error(format!(
+                        "{} in $asm is not supported by the interpreter",
+                        instr
+                    )))
+                }
+            };
+            stack.push(value);
+        }
+        Ok(stack)
+    }
+
+    /// grows memory by the given number of pages, returning the old
+    /// number of pages, or -1 if it cannot grow that much
+    fn grow(&mut self, delta: usize) -> i32 {
+        let memory = self.memory.get_mut();
+        let pages = memory.len() / PAGE_SIZE;
+        if pages + delta > self.max_pages {
+            return -1;
+        }
+        memory.resize((pages + delta) * PAGE_SIZE, 0);
+        pages as i32
+    }
+
+    fn numeric(&self, span: &Span, name: &str, args: &[Value]) -> Result<Value, Stop> {
+        match numeric(name, args) {
+            Some(Ok(value)) => Ok(value),
+            Some(Err(trap)) => Err(self.trap(span, trap)),
+            None => Err(Stop::Error(Error {
+                span: vec![span.clone()],
+                message: format!("Invalid operands for {}: {:?}", name, args),
+            })),
+        }
+    }
+
+    /// the start of the bytes accessed, if they are all in bounds
+    fn address(&self, addr: Value, offset: u32, len: usize) -> Result<usize, &'static str> {
+        let addr = match addr {
+            Value::I32(addr) => addr as u32 as u64 + offset as u64,
+            _ => panic!("address of type {:?}", addr.type_()),
+        };
+        if addr + len as u64 > self.memory.borrow().len() as u64 {
+            Err(TRAP_OUT_OF_BOUNDS)
+        } else {
+            Ok(addr as usize)
+        }
+    }
+
+    fn load(&self, name: &str, addr: Value, offset: u32) -> Result<Value, &'static str> {
+        let len = access_size(name);
+        let start = self.address(addr, offset, len)?;
+        let mut bytes = [0; 8];
+        bytes[..len].copy_from_slice(&self.memory.borrow()[start..start + len]);
+        let bits = u64::from_le_bytes(bytes);
+        Ok(match name {
+            "i32.load" => Value::I32(bits as i32),
+            "i64.load" => Value::I64(bits as i64),
+            "f32.load" => Value::F32(f32::from_bits(bits as u32)),
+            "f64.load" => Value::F64(f64::from_bits(bits)),
+            "i32.load8_s" => Value::I32(bits as i8 as i32),
+            "i32.load8_u" => Value::I32(bits as u8 as i32),
+            "i32.load16_s" => Value::I32(bits as i16 as i32),
+            "i32.load16_u" => Value::I32(bits as u16 as i32),
+            "i64.load8_s" => Value::I64(bits as i8 as i64),
+            "i64.load8_u" => Value::I64(bits as u8 as i64),
+            "i64.load16_s" => Value::I64(bits as i16 as i64),
+            "i64.load16_u" => Value::I64(bits as u16 as i64),
+            "i64.load32_s" => Value::I64(bits as i32 as i64),
+            "i64.load32_u" => Value::I64(bits as u32 as i64),
+            _ => panic!("Unknown load {}", name),
+        })
+    }
+
+    fn store(
+        &mut self,
+        name: &str,
+        addr: Value,
+        value: Value,
+        offset: u32,
+    ) -> Result<(), &'static str> {
+        let len = access_size(name);
+        let start = self.address(addr, offset, len)?;
+        let bits = value.bits();
+        self.memory.get_mut()[start..start + len].copy_from_slice(&bits.to_le_bytes()[..len]);
+        Ok(())
+    }
+
+    /// An error for a trap at the given span, with a backtrace (and the
+    /// spans of the calls that led there) in debug mode
+    fn trap(&self, span: &Span, trap: &str) -> Stop {
+        let func = match self.frames.last() {
+            Some(frame) => &frame.name,
+            None => "(host)",
+        };
+        let mut message = format!("Trap ({}) in {}", trap, func);
+        let mut spans = vec![span.clone()];
+        if self.debug {
+            message.push_str("\nbacktrace (most recent call first):");
+            for frame in self.frames.iter().rev() {
+                message.push_str(&format!("\n  {}", frame.name));
+                if let Some(span) = &frame.call_site {
+                    message.push_str(&format!(
+                        ", called at {}:{}",
+                        span.source.name,
+                        span.lineno()
+                    ));
+                    spans.push(span.clone());
+                }
+            }
+        }
+        Stop::Trapped(Error {
+            span: spans,
+            message,
+        })
+    }
+}
+
+impl BackendInstance for Interpreter {
+    /// WASI programs also have the _start or _initialize function that
+    /// cgen would generate
+    fn call(&mut self, name: &str, args: &[Value]) -> Result<Vec<Value>, Stop> {
+        let main = self.program.main.clone();
+        match (self.target, name, main) {
+            (Target::Wasi, "_start", Some(main)) if !self.library && args.is_empty() => {
+                self.init_globals()?;
+                return match self.call_func(&main, vec![], None)? {
+                    // Main's result is the exit status
+                    Some(Value::I32(code)) => Err(Stop::Exit(code)),
+                    _ => Ok(vec![]),
+                };
+            }
+            (Target::Wasi, "_initialize", main)
+                if (main.is_none() || self.library) && args.is_empty() =>
+            {
+                self.init_globals()?;
+                return Ok(vec![]);
+            }
+            _ => {}
+        }
+
+        let func = match self.exports.get(name) {
+            Some(func) => func.clone(),
+            None => {
+                return Err(Stop::Error(Error {
+                    span: vec![],
+                    message: format!("No function is exported as {:?}", name),
+                }))
+            }
+        };
+        let types: Vec<_> = func
+            .type_
+            .parameters
+            .iter()
+            .map(|(_, type_)| trtype(type_))
+            .collect();
+        if types.len() != args.len() || types.iter().zip(args).any(|(t, arg)| *t != arg.type_()) {
+            return Err(Stop::Error(Error {
+                span: vec![func.span.clone()],
+                message: format!("Invalid arguments for {}: {:?}", name, args),
+            }));
+        }
+        Ok(self
+            .call_func(&func, args.to_vec(), None)?
+            .into_iter()
+            .collect())
+    }
+
+    fn memory(&self) -> Option<&dyn GuestMemory> {
+        Some(&self.memory)
+    }
+
+    fn fuel_left(&mut self) -> Option<u64> {
+        self.fuel.map(|fuel| fuel as u64)
+    }
+
+    fn release_globals(&mut self) -> Result<(), Stop> {
+        for gvar in self.program.globals.clone() {
+            if gvar.import.is_none() && gvar.type_.retain_type() == RetainType::Typed {
+                let zero = Value::zero(trtype(&gvar.type_));
+                let value = self.globals.insert(Rc::as_ptr(&gvar), zero).unwrap();
+                self.call_runtime(false, value, &gvar.span)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// the number of bytes a load or store accesses
+/// Roughly where the top of the native stack is, which
+/// (on the platforms wac runs on) grows down
+#[inline(never)]
+fn stack_pointer() -> usize {
+    let marker = 0u8;
+    &marker as *const u8 as usize
+}
+
+fn access_size(name: &str) -> usize {
+    match &name[name.find('.').map(|i| i + 1).unwrap_or(0)..] {
+        "load8_s" | "load8_u" | "store8" => 1,
+        "load16_s" | "load16_u" | "store16" => 2,
+        "load32_s" | "load32_u" | "store32" => 4,
+        _ if name.starts_with("i32") || name.starts_with("f32") => 4,
+        _ => 8,
+    }
+}
+
+/// the number of operands of a numeric instruction
+///
+/// Besides the ones listed, the unary instructions are the conversions,
+/// which all have a number in their names (e.g. i32.wrap_i64, i32.extend8_s).
+fn arity(name: &str) -> usize {
+    let op = &name[name.find('.').map(|i| i + 1).unwrap_or(0)..];
+    match op {
+        "clz" | "ctz" | "popcnt" | "eqz" | "abs" | "neg" | "sqrt" | "ceil" | "floor" | "trunc"
+        | "nearest" => 1,
+        _ if op.chars().any(|c| c.is_ascii_digit()) => 1,
+        _ => 2,
+    }
+}
+
+/// applies a binary integer instruction, given its name without the type
+macro_rules! int_binop {
+    ($V:ident, $s:ty, $u:ty, $op:expr, $a:expr, $b:expr) => {{
+        let (a, b): ($s, $s) = ($a, $b);
+        let bits = (std::mem::size_of::<$s>() * 8) as u32;
+        let flag = |x: bool| Value::I32(x as i32);
+        match $op {
+            "add" => Value::$V(a.wrapping_add(b)),
+            "sub" => Value::$V(a.wrapping_sub(b)),
+            "mul" => Value::$V(a.wrapping_mul(b)),
+            "div_s" | "div_u" | "rem_s" | "rem_u" if b == 0 => {
+                return Some(Err(TRAP_DIVIDE_BY_ZERO))
+            }
+            "div_s" if a == <$s>::MIN && b == -1 => return Some(Err(TRAP_INTEGER_OVERFLOW)),
+            "div_s" => Value::$V(a / b),
+            "div_u" => Value::$V(((a as $u) / (b as $u)) as $s),
+            "rem_s" => Value::$V(a.wrapping_rem(b)),
+            "rem_u" => Value::$V(((a as $u) % (b as $u)) as $s),
+            "and" => Value::$V(a & b),
+            "or" => Value::$V(a | b),
+            "xor" => Value::$V(a ^ b),
+            "shl" => Value::$V(a.wrapping_shl(b as u32)),
+            "shr_s" => Value::$V(a.wrapping_shr(b as u32)),
+            "shr_u" => Value::$V((a as $u).wrapping_shr(b as u32) as $s),
+            "rotl" => Value::$V(a.rotate_left(b as u32 & (bits - 1))),
+            "rotr" => Value::$V(a.rotate_right(b as u32 & (bits - 1))),
+            "eq" => flag(a == b),
+            "ne" => flag(a != b),
+            "lt_s" => flag(a < b),
+            "lt_u" => flag((a as $u) < (b as $u)),
+            "gt_s" => flag(a > b),
+            "gt_u" => flag((a as $u) > (b as $u)),
+            "le_s" => flag(a <= b),
+            "le_u" => flag((a as $u) <= (b as $u)),
+            "ge_s" => flag(a >= b),
+            "ge_u" => flag((a as $u) >= (b as $u)),
+            _ => return None,
+        }
+    }};
+}
+
+/// applies a binary float instruction, given its name without the type
+macro_rules! float_binop {
+    ($V:ident, $t:ty, $op:expr, $a:expr, $b:expr) => {{
+        let (a, b): ($t, $t) = ($a, $b);
+        let flag = |x: bool| Value::I32(x as i32);
+        match $op {
+            "add" => Value::$V(a + b),
+            "sub" => Value::$V(a - b),
+            "mul" => Value::$V(a * b),
+            "div" => Value::$V(a / b),
+            "min" => Value::$V(fmin(a as f64, b as f64) as $t),
+            "max" => Value::$V(fmax(a as f64, b as f64) as $t),
+            "copysign" => Value::$V(a.copysign(b)),
+            "eq" => flag(a == b),
+            "ne" => flag(a != b),
+            "lt" => flag(a < b),
+            "gt" => flag(a > b),
+            "le" => flag(a <= b),
+            "ge" => flag(a >= b),
+            _ => return None,
+        }
+    }};
+}
+
+/// applies a unary float instruction, given its name without the type
+macro_rules! float_unop {
+    ($V:ident, $t:ty, $op:expr, $x:expr) => {{
+        let x: $t = $x;
+        match $op {
+            "abs" => Value::$V(x.abs()),
+            "neg" => Value::$V(-x),
+            "sqrt" => Value::$V(x.sqrt()),
+            "ceil" => Value::$V(x.ceil()),
+            "floor" => Value::$V(x.floor()),
+            "trunc" => Value::$V(x.trunc()),
+            "nearest" => Value::$V(nearest(x as f64) as $t),
+            _ => return None,
+        }
+    }};
+}
+
+/// Applies a numeric instruction to its operands, with the semantics
+/// (and traps) of webassembly
+///
+/// Returns None if there is no such instruction for operands of these types.
+fn numeric(name: &str, args: &[Value]) -> Option<Result<Value, &'static str>> {
+    use Value::*;
+    let dot = name.find('.')?;
+    let (type_, op) = (&name[..dot], &name[dot + 1..]);
+    Some(Ok(match (type_, args) {
+        ("i32", [I32(a), I32(b)]) => int_binop!(I32, i32, u32, op, *a, *b),
+        ("i64", [I64(a), I64(b)]) => int_binop!(I64, i64, u64, op, *a, *b),
+        ("f32", [F32(a), F32(b)]) => float_binop!(F32, f32, op, *a, *b),
+        ("f64", [F64(a), F64(b)]) => float_binop!(F64, f64, op, *a, *b),
+        ("f32", [F32(x)]) if !op.contains('_') => float_unop!(F32, f32, op, *x),
+        ("f64", [F64(x)]) if !op.contains('_') => float_unop!(F64, f64, op, *x),
+        (_, [x]) => match (name, *x) {
+            ("i32.clz", I32(x)) => I32(x.leading_zeros() as i32),
+            ("i32.ctz", I32(x)) => I32(x.trailing_zeros() as i32),
+            ("i32.popcnt", I32(x)) => I32(x.count_ones() as i32),
+            ("i32.eqz", I32(x)) => I32((x == 0) as i32),
+            ("i64.clz", I64(x)) => I64(x.leading_zeros() as i64),
+            ("i64.ctz", I64(x)) => I64(x.trailing_zeros() as i64),
+            ("i64.popcnt", I64(x)) => I64(x.count_ones() as i64),
+            ("i64.eqz", I64(x)) => I32((x == 0) as i32),
+            ("i32.extend8_s", I32(x)) => I32(x as i8 as i32),
+            ("i32.extend16_s", I32(x)) => I32(x as i16 as i32),
+            ("i64.extend8_s", I64(x)) => I64(x as i8 as i64),
+            ("i64.extend16_s", I64(x)) => I64(x as i16 as i64),
+            ("i64.extend32_s", I64(x)) => I64(x as i32 as i64),
+            ("i32.wrap_i64", I64(x)) => I32(x as i32),
+            ("i64.extend_i32_s", I32(x)) => I64(x as i64),
+            ("i64.extend_i32_u", I32(x)) => I64(x as u32 as i64),
+            ("f32.convert_i32_s", I32(x)) => F32(x as f32),
+            ("f32.convert_i32_u", I32(x)) => F32(x as u32 as f32),
+            ("f32.convert_i64_s", I64(x)) => F32(x as f32),
+            ("f32.convert_i64_u", I64(x)) => F32(x as u64 as f32),
+            ("f64.convert_i32_s", I32(x)) => F64(x as f64),
+            ("f64.convert_i32_u", I32(x)) => F64(x as u32 as f64),
+            ("f64.convert_i64_s", I64(x)) => F64(x as f64),
+            ("f64.convert_i64_u", I64(x)) => F64(x as u64 as f64),
+            ("f32.demote_f64", F64(x)) => F32(x as f32),
+            ("f64.promote_f32", F32(x)) => F64(x as f64),
+            ("i32.reinterpret_f32", F32(x)) => I32(x.to_bits() as i32),
+            ("i64.reinterpret_f64", F64(x)) => I64(x.to_bits() as i64),
+            ("f32.reinterpret_i32", I32(x)) => F32(f32::from_bits(x as u32)),
+            ("f64.reinterpret_i64", I64(x)) => F64(f64::from_bits(x as u64)),
+            (_, F32(x)) if op.starts_with("trunc_") => return Some(truncate(type_, op, x as f64)),
+            (_, F64(x)) if op.starts_with("trunc_") => return Some(truncate(type_, op, x)),
+            _ => return None,
+        },
+        _ => return None,
+    }))
+}
+
+/// converts a float to an integer, trapping if the result would be out of
+/// range (or saturating instead, for the trunc_sat instructions)
+fn truncate(type_: &str, op: &str, x: f64) -> Result<Value, &'static str> {
+    let signed = op.ends_with("_s");
+    if op.starts_with("trunc_sat_") {
+        // 'as' saturates, and maps NaN to 0
+        return Ok(match (type_, signed) {
+            ("i32", true) => Value::I32(x as i32),
+            ("i32", false) => Value::I32(x as u32 as i32),
+            (_, true) => Value::I64(x as i64),
+            (_, false) => Value::I64(x as u64 as i64),
+        });
+    }
+    if x.is_nan() {
+        return Err(TRAP_INVALID_CONVERSION);
+    }
+    let (min, max) = match (type_, signed) {
+        ("i32", true) => (-2147483648.0, 2147483648.0),
+        ("i32", false) => (0.0, 4294967296.0),
+        (_, true) => (-9223372036854775808.0, 9223372036854775808.0),
+        (_, false) => (0.0, 18446744073709551616.0),
+    };
+    let x = x.trunc();
+    if x < min || x >= max {
+        return Err(TRAP_INTEGER_OVERFLOW);
+    }
+    Ok(match (type_, signed) {
+        ("i32", true) => Value::I32(x as i32),
+        ("i32", false) => Value::I32(x as u32 as i32),
+        (_, true) => Value::I64(x as i64),
+        (_, false) => Value::I64(x as u64 as i64),
+    })
+}
+
+/// min, where NaN wins, and -0 is less than 0
+fn fmin(a: f64, b: f64) -> f64 {
+    if a.is_nan() || b.is_nan() {
+        f64::NAN
+    } else if a == b {
+        if a.is_sign_negative() {
+            a
+        } else {
+            b
+        }
+    } else {
+        a.min(b)
+    }
+}
+
+/// max, where NaN wins, and 0 is greater than -0
+fn fmax(a: f64, b: f64) -> f64 {
+    if a.is_nan() || b.is_nan() {
+        f64::NAN
+    } else if a == b {
+        if a.is_sign_negative() {
+            b
+        } else {
+            a
+        }
+    } else {
+        a.max(b)
+    }
+}
+
+/// rounds to the nearest integer, with ties going to the even one
+fn nearest(x: f64) -> f64 {
+    let rounded = x.round();
+    if (rounded - x).abs() == 0.5 {
+        2.0 * (x / 2.0).round()
+    } else {
+        rounded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_config;
+    use crate::test_sources;
+    use crate::BackendKind;
+    use crate::Engine;
+    use crate::EngineError;
+
+    #[test]
+    fn numeric_semantics() {
+        use Value::*;
+        let apply = |name, args: &[Value]| numeric(name, args).unwrap();
+        assert_eq!(
+            apply("i32.add", &[I32(i32::MAX), I32(1)]),
+            Ok(I32(i32::MIN))
+        );
+        assert_eq!(
+            apply("i32.div_s", &[I32(7), I32(0)]),
+            Err(TRAP_DIVIDE_BY_ZERO)
+        );
+        assert_eq!(
+            apply("i32.div_s", &[I32(i32::MIN), I32(-1)]),
+            Err(TRAP_INTEGER_OVERFLOW)
+        );
+        assert_eq!(apply("i32.rem_s", &[I32(i32::MIN), I32(-1)]), Ok(I32(0)));
+        assert_eq!(apply("i32.div_u", &[I32(-1), I32(2)]), Ok(I32(i32::MAX)));
+        assert_eq!(apply("i64.shl", &[I64(1), I64(65)]), Ok(I64(2)));
+        assert_eq!(apply("i32.shr_u", &[I32(-1), I32(28)]), Ok(I32(15)));
+        assert_eq!(apply("i32.lt_u", &[I32(1), I32(-1)]), Ok(I32(1)));
+        assert_eq!(apply("i64.eqz", &[I64(0)]), Ok(I32(1)));
+        assert_eq!(apply("i32.clz", &[I32(1)]), Ok(I32(31)));
+        assert_eq!(apply("f64.nearest", &[F64(2.5)]), Ok(F64(2.0)));
+        assert_eq!(apply("f64.nearest", &[F64(-3.5)]), Ok(F64(-4.0)));
+        assert_eq!(apply("f32.min", &[F32(0.0), F32(-0.0)]), Ok(F32(-0.0)));
+        assert!(apply("f32.min", &[F32(f32::NAN), F32(1.0)]) != Ok(F32(1.0)));
+        assert_eq!(apply("i32.trunc_f64_s", &[F64(-2.9)]), Ok(I32(-2)));
+        assert_eq!(apply("i32.trunc_f64_u", &[F64(-0.5)]), Ok(I32(0)));
+        assert_eq!(
+            apply("i32.trunc_f32_s", &[F32(3e9)]),
+            Err(TRAP_INTEGER_OVERFLOW)
+        );
+        assert_eq!(
+            apply("i64.trunc_f64_s", &[F64(f64::NAN)]),
+            Err(TRAP_INVALID_CONVERSION)
+        );
+        assert_eq!(apply("i32.trunc_sat_f32_s", &[F32(3e9)]), Ok(I32(i32::MAX)));
+        assert_eq!(
+            apply("f64.convert_i32_u", &[I32(-1)]),
+            Ok(F64(4294967295.0))
+        );
+        assert_eq!(apply("i64.extend_i32_s", &[I32(-1)]), Ok(I64(-1)));
+        assert_eq!(numeric("i32.add", &[I32(1), I64(2)]), None);
+    }
+
+    #[test]
+    fn arities_and_access_sizes() {
+        assert_eq!(arity("i32.add"), 2);
+        assert_eq!(arity("i32.lt_u"), 2);
+        assert_eq!(arity("f32.sqrt"), 1);
+        assert_eq!(arity("i64.extend_i32_u"), 1);
+        assert_eq!(arity("i32.extend8_s"), 1);
+        assert_eq!(access_size("i32.load8_u"), 1);
+        assert_eq!(access_size("i64.store16"), 2);
+        assert_eq!(access_size("i64.load32_s"), 4);
+        assert_eq!(access_size("f32.store"), 4);
+        assert_eq!(access_size("i64.load"), 8);
+    }
+
+    #[test]
+    fn deep_recursion_traps() {
+        // on a thread with the stack Rust gives spawned threads by default
+        let thread = std::thread::Builder::new().stack_size(2 << 20).spawn(|| {
+            let sources = test_sources(
+                r#"
+export fn Depth(n i32) i32 {
+    if n is 0 {
+        return 0
+    }
+    return 1 + Depth(n - 1)
+}
+"#,
+            );
+            let engine = Engine::new(test_config(BackendKind::Interpreter));
+            let module = engine.compile(sources).unwrap();
+            let mut instance = engine.instantiate(&module).unwrap();
+            assert_eq!(instance.call::<_, i32>("Depth", (20,)).unwrap(), 20);
+            match instance.call::<_, i32>("Depth", (1 << 20,)) {
+                Err(EngineError::Runtime(error)) => {
+                    assert!(
+                        error.message.contains(TRAP_STACK_EXHAUSTED),
+                        "{}",
+                        error.message
+                    )
+                }
+                other => panic!("{:?}", other),
+            }
+            // (and the interpreter is left fit to call again)
+            assert_eq!(instance.call::<_, i32>("Depth", (20,)).unwrap(), 20);
+        });
+        thread.unwrap().join().unwrap();
+    }
+}
@@ -1,6 +1,7 @@
 use crate::Binop;
 use crate::ByteCount;
 use crate::Span;
+use std::cell::Cell;
 use std::cell::RefCell;
 use std::cmp;
 use std::collections::HashMap;
@@ -13,6 +14,10 @@ pub const HEADER_SIZE: usize = 16;
 
 pub struct Program {
     pub span: Span,
+
+    /// the Main function, not required when building a library
+    pub main: Option<Rc<Func>>,
+
     pub externs: Vec<Rc<Extern>>,
     pub records: Vec<Rc<Record>>,
     pub globals: Vec<Rc<Global>>,
@@ -23,21 +28,96 @@ pub struct Program {
     pub gvar_init_locals: Vec<Rc<Local>>,
 
     pub memory: Rc<RefCell<Memory>>,
+
+    /// if set, the linear memory is provided by the host
+    /// (with extern "module" "name" memory) rather than defined
+    pub memory_import: Option<(Rc<str>, Rc<str>)>,
+}
+
+/// Name of the module whose items are visible from every other module
+/// without an import.
+/// Functions and globals in the prelude also keep their unqualified wasm
+/// names, since cgen refers to runtime functions like __retain directly.
+pub const PRELUDE_MODULE: &str = "prelude";
+
+/// The name used to identify an item from the given module in the
+/// generated wasm (after the f/ or g/ prefix)
+pub fn wasm_path(module: &str, name: &str) -> String {
+    if module == PRELUDE_MODULE {
+        name.to_owned()
+    } else {
+        format!("{}.{}", module, name)
+    }
 }
 
 pub const PAGE_SIZE: usize = 65536;
 pub const HEAP_LIMIT_PTR: usize = 16;
+pub const SHADOW_SP_PTR: usize = 20;
+pub const CALL_SITE_PTR: usize = 24;
+pub const DEBUG_TABLES_PTR: usize = 28;
 pub const FREELIST_START: usize = 32;
 pub const FREELIST_END: usize = FREELIST_START + 4 * 32;
+pub const ALLOC_STATS_PTR: usize = FREELIST_END;
+pub const TRACK_ALLOCS_PTR: usize = ALLOC_STATS_PTR + 16;
+pub const DEBUG_ALLOC_PTR: usize = TRACK_ALLOCS_PTR + 4;
+pub const HEAP_STATS_PTR: usize = DEBUG_ALLOC_PTR + 4;
+pub const REFCOUNT_STATS_PTR: usize = HEAP_STATS_PTR + 4;
+pub const RUNTIME_DATA_END: usize = REFCOUNT_STATS_PTR + 8;
+
+/// The parts of the layout that the prelude uses, which the solver
+/// declares as (private) constants of the prelude module
+pub const PRELUDE_CONSTANTS: &[(&str, usize)] = &[
+    ("__PAGE_SIZE", PAGE_SIZE),
+    ("__HEAP_LIMIT_PTR", HEAP_LIMIT_PTR),
+    ("__SHADOW_SP_PTR", SHADOW_SP_PTR),
+    ("__DEBUG_TABLES_PTR", DEBUG_TABLES_PTR),
+    ("__FREELIST_START", FREELIST_START),
+    ("__ALLOC_STATS_PTR", ALLOC_STATS_PTR),
+    ("__TRACK_ALLOCS_PTR", TRACK_ALLOCS_PTR),
+    ("__DEBUG_ALLOC_PTR", DEBUG_ALLOC_PTR),
+    ("__HEAP_STATS_PTR", HEAP_STATS_PTR),
+    ("__REFCOUNT_STATS_PTR", REFCOUNT_STATS_PTR),
+];
+
+/// size of the shadow stack in debug builds (room for 8192 frames)
+pub const SHADOW_STACK_SIZE: usize = 65536;
 
 /// information about the state of memory when the program starts
 ///
 /// Initially, memory will be laid out as such:
 ///
-///     [16-bytes unused][16-bytes ptr to heap limit][(4 * 32)-bytes freelist ptrs]
+/// ```text
+///     [16-bytes scratch][16-bytes runtime ptrs][(4 * 32)-bytes freelist ptrs]
 ///                       ^
-///                       heap limit ptr
-///                       only first 4 bytes are used
+///                       heap limit ptr, followed by the shadow stack ptr,
+///                       call site id and debug tables ptr
+///
+///     [16-bytes allocator stats][4-bytes track allocs flag][4-bytes debug alloc flag]
+///     [8-bytes refcount stats]
+/// ```
+///
+/// The scratch space is for passing arguments to and getting results
+/// from host calls (e.g. WASI iovecs)
+///
+/// The shadow stack ptr, call site id and debug tables ptr are only
+/// used in debug builds, for backtraces (see cgen's gen_debug_data),
+/// and are 0 otherwise
+///
+/// The allocator stats are counters that __malloc and __free keep
+/// (see HeapStats), and the track allocs flag is set when checking
+/// for leaks, and along with the debug alloc flag, for the debug
+/// allocator (see leaks.rs)
+///
+/// The refcount stats count the calls to __retain and __release, but
+/// only when the heap stats flag is set, as the host then reads them
+/// (see HeapStats)
+///
+/// The layout is the same when the memory is imported (see
+/// Program::memory_import): the runtime data and the static data are
+/// written to the start of the host's memory on instantiation, and the
+/// heap takes up the rest of it, growing it as needed. All of it is
+/// reserved for the program, and hosts share buffers with it by
+/// allocating them with the exported __malloc (see Instance::alloc_str).
 ///
 pub struct Memory {
     /// number of bytes of memory at the start that is left unused
@@ -54,7 +134,7 @@ pub struct Memory {
 impl Memory {
     pub fn new() -> Self {
         Self {
-            reserved: FREELIST_END,
+            reserved: RUNTIME_DATA_END,
             strings_map: HashMap::new(),
             strings: Vec::new(),
             next_strings_offset: 0,
@@ -81,14 +161,7 @@ impl Memory {
         let mut data = Vec::new();
 
         for string in &self.strings {
-            // header data (16-bytes)
-            data.extend(&(1u32).to_le_bytes()); // refcnt
-            data.extend(&((HEADER_SIZE + data.len()) as u32).to_le_bytes()); // capacity
-            data.extend(&(0u32).to_le_bytes()); // ptrcnt
-            data.extend(&(data.len() as u32).to_le_bytes()); // strlen
-
-            // actual string data
-            data.extend(string.as_bytes());
+            gen_str_object(&mut data, string);
         }
 
         (self.reserved, data)
@@ -104,6 +177,18 @@ impl Memory {
     }
 }
 
+/// appends the bytes of a static str (with a refcnt of 1) to data
+pub fn gen_str_object(data: &mut Vec<u8>, string: &str) {
+    // header data (16-bytes)
+    data.extend(&(1u32).to_le_bytes()); // refcnt
+    data.extend(&((HEADER_SIZE + string.len()) as u32).to_le_bytes()); // capacity
+    data.extend(&(0u32).to_le_bytes()); // ptrcnt
+    data.extend(&(string.len() as u32).to_le_bytes()); // strlen
+
+    // actual string data
+    data.extend(string.as_bytes());
+}
+
 /// pointer to an interned str in static memory
 pub struct StrPtr {
     pub memory: Rc<RefCell<Memory>>,
@@ -173,6 +258,30 @@ impl Item {
             Self::Constant(r) => &r.span,
         }
     }
+
+    /// the module the item was declared in, or None for local variables
+    pub fn module(&self) -> Option<&Rc<str>> {
+        match self {
+            Self::Record(r) => Some(&r.module),
+            Self::Func(r) => Some(&r.module),
+            Self::Extern(r) => Some(&r.module),
+            Self::Local(_) => None,
+            Self::Global(r) => Some(&r.module),
+            Self::Constant(r) => Some(&r.module),
+        }
+    }
+
+    /// whether the item may be used from outside the module it was declared in
+    pub fn public(&self) -> bool {
+        match self {
+            Self::Record(r) => r.public,
+            Self::Func(r) => r.public,
+            Self::Extern(r) => r.public,
+            Self::Local(_) => false,
+            Self::Global(r) => r.public,
+            Self::Constant(r) => r.public,
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -189,17 +298,10 @@ impl Variable {
         }
     }
 
-    pub fn wasm_kind(&self) -> &'static str {
-        match self {
-            Self::Local(_) => "local",
-            Self::Global(_) => "global",
-        }
-    }
-
     pub fn wasm_name(&self) -> String {
         match self {
-            Self::Local(var) => format!("$l/{}/{}", var.id, var.name),
-            Self::Global(var) => format!("$g/{}", var.name),
+            Self::Local(var) => format!("l/{}/{}", var.id, var.name),
+            Self::Global(var) => var.wasm_name(),
         }
     }
 }
@@ -234,14 +336,78 @@ impl Callable {
             Self::Extern(r) => &r.type_,
         }
     }
+    pub fn wasm_name(&self) -> String {
+        match self {
+            Self::Func(r) => r.wasm_name(),
+            Self::Extern(r) => r.wasm_name(),
+        }
+    }
 }
 
 pub struct Record {
     pub span: Span,
+    pub module: Rc<str>,
+    pub public: bool,
     pub name: Rc<str>,
     pub fields: RefCell<Vec<(Rc<str>, Type)>>,
 }
 
+/// Where a record's fields are in memory
+///
+/// Records are reference counted objects (see 01memory.wac), i.e.
+///
+/// ```text
+///     [i32 refcnt][i32 capacity][i32 ptrcnt][i32 reserved][fields...]
+/// ```
+///
+/// where each field takes an 8 byte slot, and the fields that hold
+/// references (strs, records and ids) come first, so that there are
+/// ptrcnt of them at the start of the data; the reserved word holds
+/// the record's type tag (see RECORD_TAG)
+pub struct RecordLayout {
+    /// each field's name, type and offset from the start of the object,
+    /// in the order they were declared
+    pub fields: Vec<(Rc<str>, Type, usize)>,
+    pub ptrcnt: usize,
+
+    /// the size of the object, including the header
+    pub size: usize,
+}
+
+pub const RECORD_SLOT_SIZE: usize = 8;
+
+/// Records are tagged with RECORD_TAG plus their index in
+/// Program::records, which tells them apart from strs, whose reserved
+/// word holds their length (at most __MAX_ALLOC, so without this bit)
+pub const RECORD_TAG: u32 = 1 << 31;
+
+impl Record {
+    pub fn layout(&self) -> RecordLayout {
+        let fields = self.fields.borrow();
+        let is_ptr = |type_: &Type| type_.retain_type() != RetainType::Primitive;
+        let ptrcnt = fields.iter().filter(|(_, type_)| is_ptr(type_)).count();
+        let (mut next_ptr, mut next_other) = (0, ptrcnt);
+        let fields = fields
+            .iter()
+            .map(|(name, type_)| {
+                let slot = if is_ptr(type_) {
+                    &mut next_ptr
+                } else {
+                    &mut next_other
+                };
+                let offset = HEADER_SIZE + *slot * RECORD_SLOT_SIZE;
+                *slot += 1;
+                (name.clone(), type_.clone(), offset)
+            })
+            .collect::<Vec<_>>();
+        RecordLayout {
+            size: HEADER_SIZE + fields.len() * RECORD_SLOT_SIZE,
+            fields,
+            ptrcnt,
+        }
+    }
+}
+
 impl fmt::Debug for Record {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "Record({})", self.name)
@@ -250,7 +416,7 @@ impl fmt::Debug for Record {
 
 impl cmp::PartialEq for Record {
     fn eq(&self, other: &Self) -> bool {
-        self.name.eq(&other.name)
+        self.module.eq(&other.module) && self.name.eq(&other.name)
     }
 }
 
@@ -369,20 +535,47 @@ impl fmt::Display for FuncType {
 
 pub struct Extern {
     pub span: Span,
+    pub module: Rc<str>,
+    pub public: bool,
     pub path: (Rc<str>, Rc<str>),
     pub name: Rc<str>,
     pub type_: FuncType,
 }
 
+impl Extern {
+    pub fn wasm_name(&self) -> String {
+        format!("f/{}", wasm_path(&self.module, &self.name))
+    }
+}
+
 pub struct Global {
     pub span: Span,
+    pub module: Rc<str>,
+    pub public: bool,
     pub name: Rc<str>,
     pub type_: Type,
-    pub init: Expr,
+
+    /// the initial value, or None if the global is provided by the host
+    pub init: Option<Expr>,
+
+    /// where the host provides the global from, if it does
+    pub import: Option<(Rc<str>, Rc<str>)>,
+}
+
+impl Global {
+    pub fn wasm_name(&self) -> String {
+        format!("g/{}", wasm_path(&self.module, &self.name))
+    }
 }
 
 pub struct Func {
     pub span: Span,
+    pub module: Rc<str>,
+    pub public: bool,
+
+    /// the name the function is exported under, if it is exported
+    pub export: Option<Rc<str>>,
+
     pub name: Rc<str>,
     pub type_: FuncType,
     pub parameters: RefCell<Vec<Rc<Local>>>,
@@ -390,21 +583,39 @@ pub struct Func {
     pub body: RefCell<Option<Stmt>>,
 }
 
+impl Func {
+    pub fn wasm_name(&self) -> String {
+        format!("f/{}", wasm_path(&self.module, &self.name))
+    }
+}
+
 pub struct Constant {
     pub span: Span,
+    pub module: Rc<str>,
+    pub public: bool,
     pub name: Rc<str>,
     pub value: ConstVal,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum ConstVal {
+    Bool(bool),
     I32(i32),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+    Str(Rc<str>),
 }
 
 impl ConstVal {
     pub fn type_(&self) -> Type {
         match self {
+            Self::Bool(_) => Type::Bool,
             Self::I32(_) => Type::I32,
+            Self::I64(_) => Type::I64,
+            Self::F32(_) => Type::F32,
+            Self::F64(_) => Type::F64,
+            Self::Str(_) => Type::Str,
         }
     }
 }
@@ -414,6 +625,11 @@ pub struct Local {
     pub name: Rc<str>,
     pub id: usize,
     pub type_: Type,
+
+    /// for parameters, whether the callers keep the reference they pass,
+    /// rather than handing it over, so that it is not released on return
+    /// (see ownership.rs)
+    pub borrowed: Cell<bool>,
 }
 
 pub struct Stmt {
@@ -444,6 +660,17 @@ pub enum ExprData {
     F64(f64),
     Str(StrPtr),
     GetVar(Variable),
+
+    /// reads a local for the last time, handing over its reference
+    /// rather than retaining it (the local is zeroed, so that it is not
+    /// released on return either)
+    MoveVar(Variable),
+
+    /// reads a local, or a str literal, without retaining it, for
+    /// passing to a borrowed parameter
+    BorrowVar(Variable),
+    BorrowStr(StrPtr),
+
     SetVar(Variable, Box<Expr>),
     AugVar(Variable, TypedWasmOp, Box<Expr>),
     CallFunc(Rc<Func>, Vec<Expr>),
@@ -451,7 +678,11 @@ pub enum ExprData {
 
     Op(TypedWasmOp, Vec<Expr>),
 
-    Asm(Vec<Expr>, Type, Rc<str>),
+    /// short circuiting 'and' and 'or' on bools
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+
+    Asm(Vec<Expr>, ReturnType, Rc<str>),
 
     Raw(Variable),
 
@@ -519,6 +750,8 @@ pub enum UntypedWasmOp {
     ge_u,
     convert_i32_s,
     convert_i64_s,
+    extend_i32_s,
+    promote_f32,
 }
 
 impl UntypedWasmOp {
@@ -530,8 +763,15 @@ impl UntypedWasmOp {
             Binop::Multiply => Self::mul,
             Binop::Remainder => Self::rem_s,
             Binop::TruncDivide => Self::div_s,
+            Binop::BitwiseAnd => Self::and,
+            Binop::BitwiseOr => Self::or,
+            Binop::BitwiseXor => Self::xor,
+            Binop::ShiftLeft => Self::shl,
+            Binop::ShiftRight => Self::shr_s,
 
             // returns bool
+            Binop::Equal => Self::eq,
+            Binop::NotEqual => Self::ne,
             Binop::LessThan => Self::lt_s,
             Binop::LessThanOrEqual => Self::le_s,
             Binop::GreaterThan => Self::gt_s,
@@ -549,6 +789,8 @@ impl UntypedWasmOp {
             Binop::Divide => Self::div,
 
             // returns bool
+            Binop::Equal => Self::eq,
+            Binop::NotEqual => Self::ne,
             Binop::LessThan => Self::lt,
             Binop::LessThanOrEqual => Self::le,
             Binop::GreaterThan => Self::gt,
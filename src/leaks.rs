@@ -0,0 +1,355 @@
+//! Finding objects that are never freed (RunConfig::leak_check)
+//!
+//! When checking for leaks, __malloc and __free report every block to
+//! the host (through the "wac" imports track_alloc and track_free),
+//! which keeps a side table of the live ones, along with where each was
+//! allocated. After Main returns, the runner releases the globals, and
+//! any object still in the table has leaked, i.e. a retain somewhere was
+//! never matched by a release.
+//!
+//! The allocation site is the innermost call outside of the prelude, e.g.
+//! the call to read_line for the str it returns. The interpreter knows it
+//! from its own call stack, while wasm programs need the shadow stack of
+//! debug builds (without which the site is unknown).
+//!
+//! The debug allocator (RunConfig::debug_alloc) uses the same table to
+//! catch objects that are used after being freed. __free poisons blocks,
+//! and rather than reusing them right away, hands them to the host, which
+//! keeps the last QUARANTINE_LEN of them out of reach. Programs retain
+//! and release objects with __debug_retain and __debug_release instead of
+//! the usual ones (see RunConfig::refcount_funcs), which have the host
+//! check their target first: releasing a freed object is a double free,
+//! retaining one a use after free, and either traps (with the message the
+//! host left in the table).
+use crate::read_shadow_stack;
+use crate::wasm::FuncType;
+use crate::wasm::ValType;
+use crate::wasm::Value;
+use crate::wasm_path;
+use crate::GuestMemory;
+use crate::HostFunc;
+use crate::Record;
+use crate::SourceMap;
+use crate::Span;
+use crate::HEADER_SIZE;
+use crate::PRELUDE_MODULE;
+use crate::RECORD_TAG;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+/// How many freed blocks the debug allocator keeps from being reused
+pub const QUARANTINE_LEN: usize = 256;
+
+/// A block handed out by __malloc
+pub struct Allocation {
+    /// the size asked for (the block itself may be larger)
+    pub len: u32,
+    pub site: Option<Span>,
+
+    /// the object's type, as told by its header when it was freed
+    /// (before the debug allocator poisoned it)
+    pub type_: Option<Rc<str>>,
+
+    /// where the block was freed, if it is in quarantine
+    pub freed_at: Option<Span>,
+}
+
+#[derive(Default)]
+pub struct Allocations {
+    /// the blocks not yet freed, by address
+    pub live: BTreeMap<u32, Allocation>,
+
+    /// the freed blocks in quarantine, and the order they were freed in
+    pub freed: BTreeMap<u32, Allocation>,
+    pub quarantine: VecDeque<u32>,
+
+    /// what check_ref last found wrong, for the trap that follows
+    pub violation: Option<String>,
+
+    /// the program's record types, which their type tags index
+    pub records: Vec<Rc<Record>>,
+}
+
+pub type AllocTable = Rc<RefCell<Allocations>>;
+
+/// An empty table, for a program with the given record types
+pub(crate) fn new_alloc_table(records: &[Rc<Record>]) -> AllocTable {
+    Rc::new(RefCell::new(Allocations {
+        records: records.to_vec(),
+        ..Allocations::default()
+    }))
+}
+
+/// An object that was still alive after Main returned
+/// (and the globals were released)
+#[derive(Debug, Clone)]
+pub struct Leak {
+    pub ptr: u32,
+    pub type_: Rc<str>,
+
+    /// the object's size (including the header) and refcnt, per its header
+    pub size: u32,
+    pub refcnt: u32,
+    pub site: Option<Span>,
+}
+
+impl Leak {
+    /// e.g. "str (21 bytes, refcnt 1) at 0x1f0, allocated at app.wac:3"
+    pub fn format(&self) -> String {
+        format!(
+            "{} ({} bytes, refcnt {}) at {:#x}, allocated at {}",
+            self.type_,
+            self.size,
+            self.refcnt,
+            self.ptr,
+            format_site(&self.site)
+        )
+    }
+}
+
+fn format_site(site: &Option<Span>) -> String {
+    match site {
+        Some(span) => format!("{}:{}", span.source.name, span.lineno()),
+        None => "an unknown site".into(),
+    }
+}
+
+/// The first call site outside of the prelude, among the sites of the
+/// calls on the stack, innermost first
+pub(crate) fn alloc_site<'a>(mut call_sites: impl Iterator<Item = &'a Span>) -> Option<Span> {
+    call_sites
+        .find(|span| &*span.source.module != PRELUDE_MODULE)
+        .cloned()
+}
+
+/// Host functions recording allocations in the table, which take the
+/// place of the "wac" imports of the same name in wasm programs
+///
+/// Sites are found with the shadow stack, if the program keeps one.
+pub(crate) fn tracking_funcs(
+    table: &AllocTable,
+    source_map: &Rc<SourceMap>,
+    shadow_stack: bool,
+) -> Vec<Rc<HostFunc>> {
+    let source_map = source_map.clone();
+    let site = Rc::new(move |memory: &dyn GuestMemory| {
+        if shadow_stack {
+            let (_, frames) = read_shadow_stack(memory)?;
+            let sites = frames.iter().filter_map(|(_, site)| *site);
+            // (ids the guest scribbled over are skipped, as in trap_error)
+            alloc_site(sites.filter_map(|site| source_map.call_sites.get(site)))
+        } else {
+            None
+        }
+    });
+    use ValType::I32;
+    let mut funcs = vec![];
+    let (table_, site_) = (table.clone(), site.clone());
+    funcs.push(wac_func(
+        "track_alloc",
+        &[I32, I32],
+        &[],
+        move |memory, args| {
+            if let [Value::I32(ptr), Value::I32(len)] = args {
+                record_alloc(&table_, *ptr as u32, *len as u32, site_(memory));
+            }
+            vec![]
+        },
+    ));
+    let table_ = table.clone();
+    funcs.push(wac_func("track_free", &[I32], &[], move |_, args| {
+        if let [Value::I32(ptr)] = args {
+            table_.borrow_mut().live.remove(&(*ptr as u32));
+        }
+        vec![]
+    }));
+    let table_ = table.clone();
+    funcs.push(wac_func(
+        "quarantine",
+        &[I32],
+        &[I32],
+        move |memory, args| {
+            let evicted = match args {
+                [Value::I32(ptr)] => quarantine(&table_, memory, *ptr as u32, site(memory)),
+                _ => 0,
+            };
+            vec![Value::I32(evicted as i32)]
+        },
+    ));
+    let table_ = table.clone();
+    funcs.push(wac_func(
+        "check_ref",
+        &[I32, I32],
+        &[I32],
+        move |memory, args| {
+            let ok = match args {
+                [Value::I32(ptr), Value::I32(release)] => {
+                    check_ref(&table_, memory, *ptr as u32, *release != 0)
+                }
+                _ => true,
+            };
+            vec![Value::I32(ok as i32)]
+        },
+    ));
+    funcs
+}
+
+fn wac_func<F>(name: &str, params: &[ValType], results: &[ValType], func: F) -> Rc<HostFunc>
+where
+    F: Fn(&dyn GuestMemory, &[Value]) -> Vec<Value> + 'static,
+{
+    let type_ = FuncType {
+        params: params.to_vec(),
+        results: results.to_vec(),
+    };
+    Rc::new(HostFunc::new("wac", name, type_, func))
+}
+
+pub(crate) fn record_alloc(table: &AllocTable, ptr: u32, len: u32, site: Option<Span>) {
+    let allocation = Allocation {
+        len,
+        site,
+        type_: None,
+        freed_at: None,
+    };
+    table.borrow_mut().live.insert(ptr, allocation);
+}
+
+/// Moves the freed block at ptr into quarantine (before __free poisons
+/// it), and returns the block it pushes out, which may then be reused
+/// (or 0 if there is none)
+pub(crate) fn quarantine(
+    table: &AllocTable,
+    memory: &dyn GuestMemory,
+    ptr: u32,
+    site: Option<Span>,
+) -> u32 {
+    let mut table = table.borrow_mut();
+    let mut allocation = match table.live.remove(&ptr) {
+        Some(allocation) => allocation,
+        // not one of ours, so there is nothing to report about it later
+        None => return ptr,
+    };
+    allocation.type_ = Some(object_type(&allocation, &table.records, memory, ptr));
+    allocation.freed_at = site;
+    table.freed.insert(ptr, allocation);
+    table.quarantine.push_back(ptr);
+    if table.quarantine.len() <= QUARANTINE_LEN {
+        return 0;
+    }
+    let evicted = table.quarantine.pop_front().unwrap();
+    table.freed.remove(&evicted);
+    evicted
+}
+
+/// Checks the target of a retain or release: it must not have been freed,
+/// and if live, must have a positive refcnt. Pointers the table does not
+/// know of (e.g. to static strs, which are never freed) pass.
+///
+/// On failure, the reason is left in the table's violation.
+pub(crate) fn check_ref(
+    table: &AllocTable,
+    memory: &dyn GuestMemory,
+    ptr: u32,
+    release: bool,
+) -> bool {
+    let mut table = table.borrow_mut();
+    let op = if release { "release" } else { "retain" };
+    let violation = if let Some(allocation) = table.live.get(&ptr) {
+        let refcnt = read_header(memory, ptr).map_or(0, |header| header[0] as i32);
+        if refcnt > 0 {
+            return true;
+        }
+        format!(
+            "{} of {} allocated at {}, whose refcnt is {}",
+            op,
+            object_type(allocation, &table.records, memory, ptr),
+            format_site(&allocation.site),
+            refcnt
+        )
+    } else if let Some(allocation) = table.freed.get(&ptr) {
+        format!(
+            "{}: {} of {} allocated at {}, and freed at {}",
+            if release {
+                "double free"
+            } else {
+                "use after free"
+            },
+            op,
+            allocation.type_.as_deref().unwrap_or("object"),
+            format_site(&allocation.site),
+            format_site(&allocation.freed_at)
+        )
+    } else {
+        return true;
+    };
+    table.violation = Some(violation);
+    false
+}
+
+/// The objects left in the table, as described by their headers
+pub(crate) fn find_leaks(table: &AllocTable, memory: &dyn GuestMemory) -> Vec<Leak> {
+    let mut leaks = vec![];
+    let table = table.borrow();
+    for (ptr, allocation) in table.live.iter() {
+        let [refcnt, size, _, _] = match read_header(memory, *ptr) {
+            Some(header) => header,
+            None => continue,
+        };
+        leaks.push(Leak {
+            ptr: *ptr,
+            type_: object_type(allocation, &table.records, memory, *ptr),
+            size,
+            refcnt,
+            site: allocation.site.clone(),
+        });
+    }
+    leaks
+}
+
+/// The object's type, if the table knows it, or else as told by its
+/// header: records carry a type tag, and strs their length
+fn object_type(
+    allocation: &Allocation,
+    records: &[Rc<Record>],
+    memory: &dyn GuestMemory,
+    ptr: u32,
+) -> Rc<str> {
+    if let Some(type_) = &allocation.type_ {
+        return type_.clone();
+    }
+    let [_, size, ptrcnt, reserved] = match read_header(memory, ptr) {
+        Some(header) => header,
+        None => return "object".into(),
+    };
+    if reserved & RECORD_TAG != 0 {
+        if let Some(record) = records.get((reserved & !RECORD_TAG) as usize) {
+            return wasm_path(&record.module, &record.name).into();
+        }
+    }
+    if ptrcnt == 0 && size.checked_sub(HEADER_SIZE as u32) == Some(reserved) {
+        "str".into()
+    } else {
+        format!("object with {} pointers", ptrcnt).into()
+    }
+}
+
+/// The refcnt, capacity, ptrcnt and reserved fields of the object at ptr
+fn read_header(memory: &dyn GuestMemory, ptr: u32) -> Option<[u32; 4]> {
+    let mut header = [0; HEADER_SIZE];
+    if ptr as usize + HEADER_SIZE > memory.size() {
+        return None;
+    }
+    memory.read(ptr as usize, &mut header);
+    let field = |i: usize| {
+        u32::from_le_bytes([
+            header[4 * i],
+            header[4 * i + 1],
+            header[4 * i + 2],
+            header[4 * i + 3],
+        ])
+    };
+    Some([field(0), field(1), field(2), field(3)])
+}
@@ -1,32 +1,53 @@
 mod ast;
+mod bindgen;
 mod cgen;
+mod engine;
 mod er;
 mod imports;
+mod initorder;
+mod interp;
 mod ir;
+mod leaks;
 mod lexer;
+mod ownership;
 mod parsef;
 mod parser;
 mod runners;
 mod scope;
 mod solver;
 mod span;
+mod srcmap;
 mod start;
 mod tr;
+mod wasi;
+pub mod wasm;
 
 extern crate binaryen;
 extern crate wasmer_runtime as wr;
 
+// (so that the generated bindings bindgen's tests compile can refer to it)
+#[cfg(test)]
+extern crate self as wac;
+
 pub use ast::*;
+pub use bindgen::*;
 pub use cgen::*;
+pub use engine::*;
 pub use er::*;
 pub use imports::*;
+pub use initorder::*;
+pub use interp::*;
 pub use ir::*;
+pub use leaks::*;
 pub use lexer::*;
+pub use ownership::*;
 pub use parsef::*;
 pub use parser::*;
 pub use runners::*;
 pub use scope::*;
 pub use solver::*;
 pub use span::*;
+pub use srcmap::*;
 pub use start::*;
 pub use tr::*;
+pub use wasi::*;
@@ -0,0 +1,367 @@
+//! Eliding retains and releases of strs and records
+//! (RunConfig::elide_refcounts)
+//!
+//! Every expression of a str or record type evaluates to a reference that
+//! its user takes over: reading a variable retains its value, which the
+//! function it is passed to (or the variable it is stored in) releases
+//! later, for locals and parameters when the function returns, and for
+//! the arguments of an extern when the extern returns. This pass
+//! finds where the retain and the matching release can be left out:
+//!
+//! - a local that is not read again is moved rather than retained, i.e.
+//!   it is zeroed, handing its reference over (as when it is returned)
+//! - a parameter that the function never assigns to is borrowed if every
+//!   call passes it a local (that no other argument of the call assigns
+//!   to) or a str literal, which the caller keeps alive through the call
+//!   anyway; the argument is then not retained, nor is the parameter
+//!   released
+//!
+//! Since wac has no loops, the control flow of a function body is a tree,
+//! which is walked backwards to find the last read of each local.
+//!
+//! Exported functions are called from the host, so they own their
+//! parameters, as do functions called from the initializers of globals.
+use crate::ir::*;
+use std::collections::HashSet;
+
+/// Marks the borrowed parameters, and rewrites the reads of locals
+/// (and str literals) into moves and borrows throughout the program
+pub fn elide_refcounts(program: &Program) {
+    let borrowed = borrowed_params(program);
+    for func in &program.funcs {
+        for param in func.parameters.borrow().iter() {
+            param
+                .borrowed
+                .set(borrowed.contains(&(param.as_ref() as *const _)));
+        }
+    }
+    for func in &program.funcs {
+        if let Some(body) = func.body.borrow_mut().as_mut() {
+            stmt(body, &mut HashSet::new());
+        }
+    }
+}
+
+/// The parameters (of str or record type) that can be borrowed
+fn borrowed_params(program: &Program) -> HashSet<*const Local> {
+    let mut params = HashSet::new();
+    for func in &program.funcs {
+        if func.export.is_none() {
+            for param in func.parameters.borrow().iter() {
+                if param.type_.retain_type() == RetainType::Typed {
+                    params.insert(param.as_ref() as *const _);
+                }
+            }
+        }
+    }
+    for func in &program.funcs {
+        if let Some(body) = func.body.borrow().as_ref() {
+            visit_stmt(body, &mut |expr| match &expr.data {
+                ExprData::SetVar(Variable::Local(local), _) => {
+                    params.remove(&(local.as_ref() as *const _));
+                }
+                ExprData::CallFunc(callee, args) => {
+                    let callee_params = callee.parameters.borrow();
+                    for (i, (param, arg)) in callee_params.iter().zip(args).enumerate() {
+                        let borrowable = match &arg.data {
+                            // (the local must not be released by assigning
+                            // to it in another argument, before the call)
+                            ExprData::GetVar(Variable::Local(local)) => args
+                                .iter()
+                                .enumerate()
+                                .all(|(j, other)| i == j || !assigns(other, local)),
+                            ExprData::Str(_) => true,
+                            _ => false,
+                        };
+                        if !borrowable {
+                            params.remove(&(param.as_ref() as *const _));
+                        }
+                    }
+                }
+                _ => {}
+            });
+        }
+    }
+    // the initializers of globals are left as they are
+    for gvar in &program.globals {
+        if let Some(init) = &gvar.init {
+            visit_expr(init, &mut |expr| {
+                if let ExprData::CallFunc(callee, _) = &expr.data {
+                    for param in callee.parameters.borrow().iter() {
+                        params.remove(&(param.as_ref() as *const _));
+                    }
+                }
+            });
+        }
+    }
+    params
+}
+
+/// Whether the expression assigns to the local anywhere in it
+fn assigns(expr: &Expr, local: &Local) -> bool {
+    let mut found = false;
+    visit_expr(expr, &mut |expr| match &expr.data {
+        ExprData::SetVar(Variable::Local(l), _) | ExprData::AugVar(Variable::Local(l), ..) => {
+            found |= l.id == local.id;
+        }
+        _ => {}
+    });
+    found
+}
+
+/// Rewrites the statement, given the locals that may be read after it
+/// (live), which are updated to those that may be read from its start
+fn stmt(stmt: &mut Stmt, live: &mut HashSet<usize>) {
+    match &mut stmt.data {
+        StmtData::Block(stmts) => {
+            for stmt in stmts.iter_mut().rev() {
+                self::stmt(stmt, live);
+            }
+        }
+        StmtData::If(pairs, other) => {
+            let after = live.clone();
+            self::stmt(other, live);
+            for (cond, body) in pairs.iter_mut().rev() {
+                // live is what may be read if the condition is false
+                let mut body_live = after.clone();
+                self::stmt(body, &mut body_live);
+                live.extend(body_live);
+                expr(cond, live);
+            }
+        }
+        StmtData::Return(ret) => {
+            live.clear();
+            expr(ret, live);
+        }
+        StmtData::Expr(e) => expr(e, live),
+    }
+}
+
+/// Like stmt, but for an expression, whose operands are
+/// evaluated in order (and so are walked in reverse)
+fn expr(expr: &mut Expr, live: &mut HashSet<usize>) {
+    if let ExprData::GetVar(Variable::Local(local)) = &expr.data {
+        let owned = local.type_.retain_type() == RetainType::Typed && !local.borrowed.get();
+        if live.insert(local.id) && owned {
+            let var = Variable::Local(local.clone());
+            expr.data = ExprData::MoveVar(var);
+        }
+        return;
+    }
+    match &mut expr.data {
+        ExprData::Void
+        | ExprData::Bool(_)
+        | ExprData::I32(_)
+        | ExprData::I64(_)
+        | ExprData::F32(_)
+        | ExprData::F64(_)
+        | ExprData::Str(_)
+        | ExprData::BorrowStr(_)
+        | ExprData::GetVar(_) => {}
+        ExprData::MoveVar(var) | ExprData::BorrowVar(var) | ExprData::Raw(var) => read(var, live),
+        ExprData::SetVar(var, setexpr) => {
+            // the old value is only read for releasing it
+            if let Variable::Local(local) = var {
+                live.remove(&local.id);
+            }
+            self::expr(setexpr, live);
+        }
+        ExprData::AugVar(var, _, augexpr) => {
+            read(var, live);
+            self::expr(augexpr, live);
+        }
+        ExprData::CallFunc(func, args) => {
+            // what is borrowed must outlive the call, and so every argument
+            for (param, arg) in func.parameters.borrow().iter().zip(args.iter_mut()) {
+                if param.borrowed.get() {
+                    borrow(arg, live);
+                }
+            }
+            for arg in args.iter_mut().rev() {
+                self::expr(arg, live);
+            }
+        }
+        ExprData::CallExtern(_, args) | ExprData::Op(_, args) | ExprData::Asm(args, ..) => {
+            for arg in args.iter_mut().rev() {
+                self::expr(arg, live);
+            }
+        }
+        ExprData::And(left, right) | ExprData::Or(left, right) => {
+            // the right operand may not be evaluated at all
+            let mut right_live = live.clone();
+            self::expr(right, &mut right_live);
+            live.extend(right_live);
+            self::expr(left, live);
+        }
+        ExprData::Read(_, addr, _) => self::expr(addr, live),
+        ExprData::Write(_, addr, value, _) => {
+            self::expr(value, live);
+            self::expr(addr, live);
+        }
+        ExprData::DropPrimitive(e) => self::expr(e, live),
+    }
+}
+
+fn read(var: &Variable, live: &mut HashSet<usize>) {
+    if let Variable::Local(local) = var {
+        live.insert(local.id);
+    }
+}
+
+/// Rewrites an argument for a borrowed parameter
+/// (which borrowed_params made sure is a local or a str literal)
+fn borrow(arg: &mut Expr, live: &mut HashSet<usize>) {
+    let data = std::mem::replace(&mut arg.data, ExprData::Void);
+    arg.data = match data {
+        ExprData::GetVar(var) => {
+            read(&var, live);
+            ExprData::BorrowVar(var)
+        }
+        ExprData::Str(ptr) => ExprData::BorrowStr(ptr),
+        data => data,
+    };
+}
+
+fn visit_stmt(stmt: &Stmt, f: &mut dyn FnMut(&Expr)) {
+    match &stmt.data {
+        StmtData::Block(stmts) => {
+            for stmt in stmts {
+                visit_stmt(stmt, f);
+            }
+        }
+        StmtData::If(pairs, other) => {
+            for (cond, body) in pairs {
+                visit_expr(cond, f);
+                visit_stmt(body, f);
+            }
+            visit_stmt(other, f);
+        }
+        StmtData::Return(expr) | StmtData::Expr(expr) => visit_expr(expr, f),
+    }
+}
+
+/// Calls f on the expression and every expression in it
+fn visit_expr(expr: &Expr, f: &mut dyn FnMut(&Expr)) {
+    f(expr);
+    match &expr.data {
+        ExprData::SetVar(_, e)
+        | ExprData::AugVar(_, _, e)
+        | ExprData::Read(_, e, _)
+        | ExprData::DropPrimitive(e) => visit_expr(e, f),
+        ExprData::CallFunc(_, args)
+        | ExprData::CallExtern(_, args)
+        | ExprData::Op(_, args)
+        | ExprData::Asm(args, ..) => {
+            for arg in args {
+                visit_expr(arg, f);
+            }
+        }
+        ExprData::And(a, b) | ExprData::Or(a, b) | ExprData::Write(_, a, b, _) => {
+            visit_expr(a, f);
+            visit_expr(b, f);
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_config;
+    use crate::test_sources;
+    use crate::Engine;
+    use crate::HostConfig;
+    use crate::RunConfig;
+    use crate::TEST_BACKENDS;
+
+    #[test]
+    fn moves_and_borrows() {
+        let sources = test_sources(
+            r#"
+fn Len(s str) i32 {
+    return $read4($raw(s), offset: 12)
+}
+
+fn Twice(s str) i32 {
+    return Len(s) + Len(s)
+}
+
+fn Keep(s str) str {
+    return s
+}
+
+export fn Run() i32 {
+    var s = Keep(arg(0))
+    var t = s
+    return Twice(s) + Len(t)
+}
+"#,
+        );
+        let run = |backend, elide_refcounts| {
+            let engine = Engine::new(RunConfig {
+                heap_stats: true,
+                leak_check: true,
+                debug: true,
+                elide_refcounts,
+                host: HostConfig {
+                    args: vec!["app".into()],
+                    ..HostConfig::default()
+                },
+                ..test_config(backend)
+            });
+            let module = engine.compile(sources.clone()).unwrap();
+            let mut instance = engine.instantiate(&module).unwrap();
+            let result: i32 = instance.call("Run", ()).unwrap();
+            let retains = instance.stats().heap.as_ref().unwrap().retains;
+            let leaks = instance.check_leaks().unwrap();
+            (result, retains, leaks.len())
+        };
+        // only t = s retains, as Keep's result is moved into s,
+        // and Twice and Len borrow their parameters
+        for &backend in TEST_BACKENDS {
+            assert_eq!(run(backend, true), (9, 1, 0), "{:?}", backend);
+            assert_eq!(run(backend, false), (9, 8, 0), "{:?}", backend);
+        }
+    }
+
+    #[test]
+    fn no_borrowing_what_another_argument_assigns() {
+        let sources = test_sources(
+            r#"
+fn Len(s str) i32 {
+    return $read4($raw(s), offset: 12)
+}
+
+fn Both(s str, n i32) i32 {
+    return Len(s) + n
+}
+
+export fn Run() i32 {
+    var s = arg(0)
+    var t = "xy"
+    return Both(s, Len(s = t))
+}
+"#,
+        );
+        for &backend in TEST_BACKENDS {
+            // (the debug allocator traps on a use after free)
+            let engine = Engine::new(RunConfig {
+                elide_refcounts: true,
+                debug_alloc: true,
+                leak_check: true,
+                debug: true,
+                host: HostConfig {
+                    args: vec!["app".into()],
+                    ..HostConfig::default()
+                },
+                ..test_config(backend)
+            });
+            let module = engine.compile(sources.clone()).unwrap();
+            let mut instance = engine.instantiate(&module).unwrap();
+            // Both gets the "app" that s held before the assignment
+            let result: Result<i32, _> = instance.call("Run", ());
+            assert_eq!(result.map_err(|e| e.to_string()), Ok(5), "{:?}", backend);
+            assert_eq!(instance.check_leaks().unwrap().len(), 0, "{:?}", backend);
+        }
+    }
+}
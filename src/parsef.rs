@@ -51,19 +51,38 @@ pub fn parse(source: &Rc<Source>) -> Result<File, Error> {
 
 fn parse_file(parser: &mut Parser) -> Result<File, ParseError> {
     let span = parser.span();
+    let module = span.source.module.clone();
+    let mut target = None;
+    let mut imports = Vec::new();
     let mut constants = Vec::new();
     let mut externs = Vec::new();
+    let mut extern_globals = Vec::new();
+    let mut extern_memories = Vec::new();
     let mut funcs = Vec::new();
     let mut globals = Vec::new();
     let mut records = Vec::new();
     consume_delim(parser);
     while !parser.at(Token::EOF) {
+        // declarations are private to their module unless marked 'pub'
+        let public = parser.consume(Token::Name("pub"));
         match parser.peek() {
-            Token::Name("const") => constants.push(parse_const(parser)?),
-            Token::Name("extern") => externs.push(parse_extern(parser)?),
-            Token::Name("fn") => funcs.push(parse_func(parser)?),
-            Token::Name("record") => records.push(parse_record(parser)?),
-            Token::Name("var") => globals.push(parse_global(parser)?),
+            Token::Name("import") if !public => imports.push(parse_import(parser)?),
+            Token::Name("target") if !public && target.is_none() => {
+                let span = parser.span();
+                parser.gettok();
+                let name = parser.expect_name()?;
+                target = Some((span.upto(&parser.span()), name));
+            }
+            Token::Name("const") => constants.push(parse_const(parser, public)?),
+            Token::Name("extern") => match parse_extern(parser, public)? {
+                ParsedExtern::Func(ext) => externs.push(ext),
+                ParsedExtern::Global(ext) => extern_globals.push(ext),
+                ParsedExtern::Memory(ext) => extern_memories.push(ext),
+            },
+            Token::Name("fn") => funcs.push(parse_func(parser, public)?),
+            Token::Name("export") => funcs.push(parse_exported_func(parser, public)?),
+            Token::Name("record") => records.push(parse_record(parser, public)?),
+            Token::Name("var") => globals.push(parse_global(parser, public)?),
             _ => {
                 return Err(ParseError::InvalidToken {
                     span: parser.span(),
@@ -77,8 +96,13 @@ fn parse_file(parser: &mut Parser) -> Result<File, ParseError> {
     let span = span.upto(&parser.span());
     Ok(File {
         span,
+        module,
+        target,
+        imports,
         constants,
         externs,
+        extern_globals,
+        extern_memories,
         funcs,
         globals,
         records,
@@ -113,7 +137,35 @@ fn at_delim(parser: &mut Parser) -> bool {
         || parser.at(Token::Newline)
 }
 
-fn parse_const(parser: &mut Parser) -> Result<RawConstant, ParseError> {
+fn parse_import(parser: &mut Parser) -> Result<RawImport, ParseError> {
+    let span = parser.span();
+    parser.expect(Token::Name("import"))?;
+    let module = parse_qualified_name(parser)?;
+    let alias = if parser.consume(Token::Name("as")) {
+        parser.expect_name()?
+    } else {
+        // by default, a module is imported under the last component of its name
+        module.rsplit('.').next().unwrap().into()
+    };
+    let span = span.upto(&parser.span());
+    Ok(RawImport {
+        span,
+        module,
+        alias,
+    })
+}
+
+/// Parses a possibly dotted name (e.g. 'foo' or 'foo.bar')
+fn parse_qualified_name(parser: &mut Parser) -> Result<Rc<str>, ParseError> {
+    let mut name = parser.expect_name()?;
+    while parser.at(Token::Dot) {
+        parser.gettok();
+        name = format!("{}.{}", name, parser.expect_name()?).into();
+    }
+    Ok(name)
+}
+
+fn parse_const(parser: &mut Parser, public: bool) -> Result<RawConstant, ParseError> {
     let span = parser.span();
     parser.expect(Token::Name("const"))?;
     let name = parser.expect_name()?;
@@ -127,13 +179,14 @@ fn parse_const(parser: &mut Parser) -> Result<RawConstant, ParseError> {
     let span = span.upto(&parser.span());
     Ok(RawConstant {
         span,
+        public,
         name,
         type_,
         expr,
     })
 }
 
-fn parse_global(parser: &mut Parser) -> Result<RawGlobal, ParseError> {
+fn parse_global(parser: &mut Parser, public: bool) -> Result<RawGlobal, ParseError> {
     let span = parser.span();
     parser.expect(Token::Name("var"))?;
     let name = parser.expect_name()?;
@@ -147,29 +200,56 @@ fn parse_global(parser: &mut Parser) -> Result<RawGlobal, ParseError> {
     let span = span.upto(&parser.span());
     Ok(RawGlobal {
         span,
+        public,
         name,
         type_,
         init,
     })
 }
 
-fn parse_extern(parser: &mut Parser) -> Result<RawExtern, ParseError> {
+enum ParsedExtern {
+    Func(RawExtern),
+    Global(RawExternGlobal),
+    Memory(RawExternMemory),
+}
+
+fn parse_extern(parser: &mut Parser, public: bool) -> Result<ParsedExtern, ParseError> {
     let span = parser.span();
     parser.expect(Token::Name("extern"))?;
     let path0 = parser.expect_string()?;
     let path1 = parser.expect_string()?;
+    let path = (path0, path1);
+    let is_func = parser.lookahaed(1) == Some(Token::LParen);
+    if parser.at_name("memory") && !is_func {
+        parser.gettok();
+        let span = span.upto(&parser.span());
+        return Ok(ParsedExtern::Memory(RawExternMemory { span, path }));
+    }
+    if parser.consume(Token::Name("var")) {
+        let name = parser.expect_name()?;
+        let type_ = parse_type(parser)?;
+        let span = span.upto(&parser.span());
+        return Ok(ParsedExtern::Global(RawExternGlobal {
+            span,
+            public,
+            path,
+            name,
+            type_,
+        }));
+    }
     let name = parser.expect_name()?;
     let type_ = parse_func_type(parser, None)?;
     let span = span.upto(&parser.span());
-    Ok(RawExtern {
+    Ok(ParsedExtern::Func(RawExtern {
         span,
-        path: (path0, path1),
+        public,
+        path,
         name,
         type_,
-    })
+    }))
 }
 
-fn parse_record(parser: &mut Parser) -> Result<RawRecord, ParseError> {
+fn parse_record(parser: &mut Parser, public: bool) -> Result<RawRecord, ParseError> {
     let span = parser.span();
     parser.expect(Token::Name("record"))?;
     let name = parser.expect_name()?;
@@ -182,10 +262,15 @@ fn parse_record(parser: &mut Parser) -> Result<RawRecord, ParseError> {
         fields.push((field_name, field_type));
     }
     let span = span.upto(&parser.span());
-    Ok(RawRecord { span, name, fields })
+    Ok(RawRecord {
+        span,
+        public,
+        name,
+        fields,
+    })
 }
 
-fn parse_func(parser: &mut Parser) -> Result<RawFunc, ParseError> {
+fn parse_func(parser: &mut Parser, public: bool) -> Result<RawFunc, ParseError> {
     let span = parser.span();
     parser.expect(Token::Name("fn"))?;
     let name = parser.expect_name()?;
@@ -194,12 +279,27 @@ fn parse_func(parser: &mut Parser) -> Result<RawFunc, ParseError> {
     let span = span.upto(&parser.span());
     Ok(RawFunc {
         span,
+        public,
+        export: None,
         name,
         type_,
         body,
     })
 }
 
+/// Parses 'export fn ...' or 'export "alias" fn ...'
+fn parse_exported_func(parser: &mut Parser, public: bool) -> Result<RawFunc, ParseError> {
+    parser.expect(Token::Name("export"))?;
+    let alias = if parser.at(Pattern::String) {
+        Some(parser.expect_string()?)
+    } else {
+        None
+    };
+    let mut func = parse_func(parser, public)?;
+    func.export = Some(alias.unwrap_or_else(|| func.name.clone()));
+    Ok(func)
+}
+
 fn parse_block(parser: &mut Parser) -> Result<RawStmt, ParseError> {
     let span = parser.span();
     let mut stmts = Vec::new();
@@ -302,8 +402,24 @@ fn parse_atom(parser: &mut Parser) -> Result<RawExpr, ParseError> {
                 data: RawExprData::Str(string),
             })
         }
+        Token::Name("true") | Token::Name("false") => {
+            let value = parser.gettok() == Token::Name("true");
+            Ok(RawExpr {
+                span,
+                data: RawExprData::Bool(value),
+            })
+        }
+        Token::Plus | Token::Minus | Token::Name("not") => {
+            let op = Unop::from_token(parser.gettok()).expect("impossible unop");
+            let arg = parse_expr(parser, PREC_UNARY)?;
+            let span = span.upto(&parser.span());
+            Ok(RawExpr {
+                span,
+                data: RawExprData::Unop(op, arg.into()),
+            })
+        }
         Token::Name(_) => {
-            let name = parser.expect_name()?;
+            let name = parse_qualified_name(parser)?;
             Ok(RawExpr {
                 span,
                 data: RawExprData::GetVar(name),
@@ -493,7 +609,13 @@ fn parse_infix(parser: &mut Parser, mut lhs: RawExpr, prec: u32) -> Result<RawEx
                     }
                 }
             }
-            Token::Lt | Token::Le | Token::Gt | Token::Ge | Token::Name("is") => {
+            Token::Lt
+            | Token::Le
+            | Token::Gt
+            | Token::Ge
+            | Token::Eq2
+            | Token::Ne
+            | Token::Name("is") => {
                 if prec > PREC_CMP {
                     break;
                 }
@@ -542,6 +664,41 @@ fn parse_infix(parser: &mut Parser, mut lhs: RawExpr, prec: u32) -> Result<RawEx
                     }
                 }
             }
+            Token::Name("or") | Token::Name("and") => {
+                let op_prec = if parser.at(Token::Name("or")) {
+                    PREC_LOGICAL_OR
+                } else {
+                    PREC_LOGICAL_AND
+                };
+                if prec > op_prec {
+                    break;
+                }
+                let op = Binop::from_token(parser.gettok()).expect("impossible binop");
+                let rhs = parse_expr(parser, op_prec + 1)?;
+                let span = span.upto(&parser.span());
+                lhs = RawExpr {
+                    span,
+                    data: RawExprData::Binop(op, lhs.into(), rhs.into()),
+                };
+            }
+            Token::VerticalBar | Token::Caret | Token::Ampersand | Token::Lt2 | Token::Gt2 => {
+                let op_prec = match parser.peek() {
+                    Token::VerticalBar => PREC_BITWISE_OR,
+                    Token::Caret => PREC_BITWISE_XOR,
+                    Token::Ampersand => PREC_BITWISE_AND,
+                    _ => PREC_SHIFT,
+                };
+                if prec > op_prec {
+                    break;
+                }
+                let op = Binop::from_token(parser.gettok()).expect("impossible binop");
+                let rhs = parse_expr(parser, op_prec + 1)?;
+                let span = span.upto(&parser.span());
+                lhs = RawExpr {
+                    span,
+                    data: RawExprData::Binop(op, lhs.into(), rhs.into()),
+                };
+            }
             Token::Plus | Token::Minus => {
                 if prec > PREC_SUM {
                     break;
@@ -578,7 +735,7 @@ fn parse_type(parser: &mut Parser) -> Result<TypeExpr, ParseError> {
     let span = parser.span();
     let texpr = TypeExpr {
         span,
-        name: parser.expect_name()?,
+        name: parse_qualified_name(parser)?,
     };
     Ok(texpr)
 }
@@ -13,7 +13,7 @@ use std::rc::Rc;
 pub const RESERVED_NAMES: &'static [&'static str] = &[
     "fn", "trait", "impl", "record", "enum", "extern", "import", "var", "const", "true", "false",
     "nil", "and", "or", "is", "not", "in", "if", "else", "for", "while", "break", "continue",
-    "return", "switch", "new",
+    "return", "switch", "new", "pub", "export",
 ];
 
 pub struct Parser<'a> {
@@ -1,76 +1,973 @@
+use crate::find_leaks;
+use crate::from_wr_value;
 use crate::make_import_object;
-use crate::translate;
+use crate::new_alloc_table;
+use crate::read_bytes_from_memory;
+use crate::to_wr_value;
+use crate::tracking_funcs;
+use crate::translate_to_program;
+use crate::wasm::write_custom_section;
+use crate::wasm::FuncType;
+use crate::wasm::ValType;
+use crate::wasm::Value;
+use crate::wasm_path;
+use crate::write_bytes_to_memory;
+use crate::AllocTable;
 use crate::Error;
+use crate::GuestMemory;
+use crate::HostConfig;
+use crate::HostFunc;
+use crate::HostStop;
+use crate::Interpreter;
+use crate::Leak;
+use crate::Location;
+use crate::Program;
+use crate::Record;
 use crate::Source;
+use crate::SourceMap;
+use crate::ALLOC_STATS_PTR;
+use crate::CALL_SITE_PTR;
+use crate::DEBUG_TABLES_PTR;
+use crate::DEFAULT_INTERP_STACK;
+use crate::FREELIST_END;
+use crate::FREELIST_START;
+use crate::FUEL_LEFT_EXPORT;
+use crate::RECORD_TAG;
+use crate::REFCOUNT_STATS_PTR;
+use crate::RELEASE_GLOBALS_EXPORT;
+use crate::SHADOW_SP_PTR;
+use crate::SOURCE_MAP_SECTION;
+use crate::TRAP_DEADLINE_EXCEEDED;
+use std::cell::Cell;
 use std::rc::Rc;
+use std::time::Duration;
 use std::time::Instant;
 
 pub fn run(sources: Vec<Rc<Source>>, config: RunConfig) -> Result<RunStats, Error> {
-    let import_object = make_import_object();
+    let library = config.library;
+    let mut runner = Runner::new(sources, config)?;
+    if !library {
+        runner.run_main()?;
+    }
+    Ok(runner.stats)
+}
 
-    let start = Instant::now();
-    let wat_code = translate(sources)?;
-    let translate_sec = start.elapsed().as_secs_f64();
-    let wat_code_size = wat_code.len();
+/// A wasm engine that compiled programs can run on
+///
+/// Engines provide the host functions by calling into imports.rs (e.g.
+/// through Host::call), which works against any GuestMemory.
+pub trait Backend {
+    /// Compiles (and validates) a binary module
+    fn compile(&self, code: &[u8]) -> Result<Box<dyn CompiledModule>, Error>;
+}
 
-    let start = Instant::now();
-    let wasm_code = wabt::wat2wasm(&wat_code)?;
-    let wat2wasm_sec = start.elapsed().as_secs_f64();
-    let wasm_code_size = wasm_code.len();
+pub trait CompiledModule {
+    /// Instantiates the module with the given access to the outside world,
+    /// which also runs its start function
+    fn instantiate(&self, host: &HostConfig) -> Result<Box<dyn BackendInstance>, Stop>;
+}
 
-    let start = Instant::now();
-    let optimized_code = if let Some(optlevel) = config.optimize {
-        let mut config = binaryen::CodegenConfig::default();
-        config.optimization_level = optlevel;
-        let mut binaryen_module = binaryen::Module::read(&wasm_code).unwrap();
-        binaryen_module.optimize(&config);
-        binaryen_module.write()
-    } else {
-        wasm_code
+/// A running program, either an instantiated module or the interpreter
+pub trait BackendInstance {
+    /// Calls the function exported under the given name
+    fn call(&mut self, name: &str, args: &[Value]) -> Result<Vec<Value>, Stop>;
+
+    /// The linear memory, if there is one
+    fn memory(&self) -> Option<&dyn GuestMemory>;
+
+    /// The fuel the program has left, if it is metered
+    fn fuel_left(&mut self) -> Option<u64> {
+        match self.call(FUEL_LEFT_EXPORT, &[]).ok()?[..] {
+            [Value::I64(fuel)] => Some(fuel as u64),
+            _ => None,
+        }
+    }
+
+    /// Releases (and zeroes) the globals holding strs and records,
+    /// as the last step in checking for leaks
+    fn release_globals(&mut self) -> Result<(), Stop> {
+        self.call(RELEASE_GLOBALS_EXPORT, &[]).map(|_| ())
+    }
+}
+
+/// Why a call into a program ended early
+pub enum Stop {
+    /// the program exited, through exit or WASI's proc_exit
+    Exit(i32),
+
+    /// the program trapped at the given offset in the code, if known
+    /// (the runner locates it with the source map)
+    Trap(String, Option<usize>),
+
+    /// the program trapped, and the error already says where
+    /// (as the interpreter's do, and Runner::try_call's)
+    Trapped(Error),
+
+    /// any other failure, including traps that are already located
+    Error(Error),
+}
+
+/// Runs modules with wasmer
+pub struct WasmerBackend;
+
+impl Backend for WasmerBackend {
+    fn compile(&self, code: &[u8]) -> Result<Box<dyn CompiledModule>, Error> {
+        match wr::compile(code) {
+            Ok(module) => Ok(Box::new(WasmerModule(module))),
+            Err(error) => Err(Error {
+                span: vec![],
+                message: format!("{:?}", error),
+            }),
+        }
+    }
+}
+
+struct WasmerModule(wr::Module);
+
+impl CompiledModule for WasmerModule {
+    fn instantiate(&self, host: &HostConfig) -> Result<Box<dyn BackendInstance>, Stop> {
+        match self.0.instantiate(&make_import_object(host)) {
+            Ok(instance) => Ok(Box::new(WasmerInstance(instance))),
+            // the start function (which initializes globals) stopped
+            Err(wr::error::Error::RuntimeError(error)) => Err(runtime_stop(error)),
+            Err(error) => Err(Stop::Error(error.into())),
+        }
+    }
+}
+
+struct WasmerInstance(wr::Instance);
+
+/// Why wasmer stopped running the program, on a call or in the start function
+fn runtime_stop(error: wr::error::RuntimeError) -> Stop {
+    use wr::error::InvokeError;
+    use wr::error::RuntimeError;
+    match error {
+        RuntimeError::User(payload) => match payload.downcast::<HostStop>() {
+            Ok(stop) => match *stop {
+                HostStop::Exit(code) => Stop::Exit(code),
+                // (wasmer does not say where the host was called from)
+                HostStop::Trap(trap) => Stop::Trap(trap, None),
+            },
+            Err(payload) => Stop::Error(RuntimeError::User(payload).into()),
+        },
+        RuntimeError::InvokeError(InvokeError::TrapCode { code, srcloc }) => {
+            Stop::Trap(format!("{:?}", code), Some(srcloc as usize))
+        }
+        RuntimeError::InvokeError(InvokeError::UnknownTrapCode { trap_code, srcloc }) => {
+            Stop::Trap(trap_code, Some(srcloc as usize))
+        }
+        error => Stop::Error(error.into()),
+    }
+}
+
+impl BackendInstance for WasmerInstance {
+    fn call(&mut self, name: &str, args: &[Value]) -> Result<Vec<Value>, Stop> {
+        let func: wr::DynFunc = self
+            .0
+            .exports
+            .get(name)
+            .map_err(|error| Stop::Error(error.into()))?;
+        let args: Vec<_> = args.iter().cloned().map(to_wr_value).collect();
+        match func.call(&args) {
+            Ok(results) => results
+                .iter()
+                .map(from_wr_value)
+                .collect::<Result<_, _>>()
+                .map_err(Stop::Error),
+            Err(error) => Err(runtime_stop(error)),
+        }
+    }
+
+    fn memory(&self) -> Option<&dyn GuestMemory> {
+        Some(self.0.context().memory(0))
+    }
+}
+
+/// A program compiled for the configured backend, which can be
+/// instantiated any number of times
+pub struct Compiled {
+    code: CompiledCode,
+    config: RunConfig,
+    stats: RunStats,
+    source_map: Rc<SourceMap>,
+
+    /// the program's record types, for marshalling them from the host
+    records: Vec<Rc<Record>>,
+}
+
+enum CompiledCode {
+    /// the wasm code, kept (unless it was optimized, as binaryen does not
+    /// preserve instructions) for locating traps with the source map
+    Wasm(Box<dyn CompiledModule>, Option<Rc<[u8]>>),
+
+    /// the checked program, for the interpreter
+    Program(Rc<Program>),
+}
+
+impl Compiled {
+    /// Compiles the program with the configured backend
+    pub fn new(sources: Vec<Rc<Source>>, config: RunConfig) -> Result<Self, Error> {
+        match config.backend {
+            BackendKind::Wasmer => Self::with_backend(sources, config, &WasmerBackend),
+            BackendKind::Interpreter => {
+                let start = Instant::now();
+                let program = translate_to_program(sources, &config)?;
+                let stats = RunStats {
+                    translate_sec: start.elapsed().as_secs_f64(),
+                    ..RunStats::default()
+                };
+                Ok(Self {
+                    records: program.records.clone(),
+                    code: CompiledCode::Program(Rc::new(program)),
+                    config,
+                    stats,
+                    source_map: Rc::new(SourceMap::default()),
+                })
+            }
+        }
+    }
+
+    /// Compiles the program to wasm, and that with the given backend
+    pub fn with_backend(
+        sources: Vec<Rc<Source>>,
+        config: RunConfig,
+        backend: &dyn Backend,
+    ) -> Result<Self, Error> {
+        let Wasm {
+            code,
+            source_map,
+            stats,
+            records,
+        } = compile(sources, config.clone())?;
+        let module = backend.compile(&code)?;
+        let code = if config.optimize.is_some() {
+            None
+        } else {
+            Some(code.into())
+        };
+        Ok(Self {
+            code: CompiledCode::Wasm(module, code),
+            config,
+            stats,
+            source_map: Rc::new(source_map),
+            records,
+        })
+    }
+
+    /// The stats of compiling the program
+    pub fn stats(&self) -> &RunStats {
+        &self.stats
+    }
+
+    /// The record type with the given name, qualified by its module
+    /// (e.g. "app.Point") unless it is in the prelude
+    pub fn record(&self, name: &str) -> Option<&Rc<Record>> {
+        find_record(&self.records, name)
+    }
+
+    /// Instantiates the program with the given access to the outside world
+    /// (this also runs the initializers of global variables)
+    pub fn instantiate(&self, host: &HostConfig) -> Result<Runner, Error> {
+        self.try_instantiate(host).map_err(init_error)
+    }
+
+    /// Like instantiate, but leaves exiting to the caller, and reports
+    /// traps as Stop::Trapped
+    pub fn try_instantiate(&self, host: &HostConfig) -> Result<Runner, Stop> {
+        let start = Instant::now();
+        let config = &self.config;
+        let shadow_stack = config.debug && matches!(self.code, CompiledCode::Wasm(..));
+        let mut clock = None;
+        let (instance, code, allocations): (Box<dyn BackendInstance>, _, _) = match &self.code {
+            CompiledCode::Wasm(module, code) => {
+                let mut host = host.clone();
+                let allocations = if config.track_allocs() {
+                    let table = new_alloc_table(&self.records);
+                    let funcs = tracking_funcs(&table, &self.source_map, shadow_stack);
+                    host.funcs.splice(0..0, funcs);
+                    Some(table)
+                } else {
+                    None
+                };
+                if let Some(limit) = config.time_limit {
+                    let clock_ = Clock::new(limit);
+                    host.funcs.insert(0, clock_.check_deadline_func());
+                    // (the initializers run as the module is instantiated)
+                    clock_.start();
+                    clock = Some(clock_);
+                }
+                let instance = module.instantiate(&host).map_err(|stop| match stop {
+                    // (with no backtrace, as the shadow stack went with the instance)
+                    Stop::Trap(trap, offset) => Stop::Trapped(locate_trap(
+                        &self.source_map,
+                        code.as_deref(),
+                        &Clock::reason(&clock, trap),
+                        offset,
+                        vec![],
+                        None,
+                    )),
+                    stop => stop,
+                })?;
+                (instance, code.clone(), allocations)
+            }
+            CompiledCode::Program(program) => {
+                // (the interpreter keeps its own clock)
+                let mut config = config.clone();
+                config.host = host.clone();
+                let interpreter = Interpreter::new(program.clone(), &config)?;
+                let allocations = interpreter.allocations();
+                (Box::new(interpreter), None, allocations)
+            }
+        };
+        let stats = RunStats {
+            instantiate_sec: start.elapsed().as_secs_f64(),
+            ..self.stats.clone()
+        };
+        Ok(Runner {
+            instance,
+            target: config.target,
+            stats,
+            code,
+            source_map: self.source_map.clone(),
+            shadow_stack,
+            release_func: config.refcount_funcs().1,
+            fuel: config.fuel_budget(),
+            clock,
+            heap_stats: config.heap_stats,
+            leak_check: config.leak_check,
+            allocations,
+            records: self.records.clone(),
+        })
+    }
+}
+
+/// The deadline of the current call into a wasm program with a time limit,
+/// which the program checks every so often through check_deadline (see
+/// cgen's gen_fuel_check); the runner starts it over for each call
+#[derive(Clone)]
+struct Clock {
+    limit: Duration,
+    deadline: Rc<Cell<Option<Instant>>>,
+
+    /// whether check_deadline found the deadline passed,
+    /// so that the trap that follows can say so
+    exceeded: Rc<Cell<bool>>,
+}
+
+impl Clock {
+    fn new(limit: Duration) -> Self {
+        Self {
+            limit,
+            deadline: Rc::new(Cell::new(None)),
+            exceeded: Rc::new(Cell::new(false)),
+        }
+    }
+
+    fn start(&self) {
+        // (with no deadline if it is too far off for an Instant)
+        self.deadline.set(Instant::now().checked_add(self.limit));
+        self.exceeded.set(false);
+    }
+
+    /// The program's check_deadline, which returns 1 if the deadline has
+    /// passed, and the program then traps
+    fn check_deadline_func(&self) -> Rc<HostFunc> {
+        let type_ = FuncType {
+            params: vec![],
+            results: vec![ValType::I32],
+        };
+        let clock = self.clone();
+        Rc::new(HostFunc::new(
+            "wac",
+            "check_deadline",
+            type_,
+            move |_, _| {
+                let now = Instant::now();
+                let exceeded = matches!(clock.deadline.get(), Some(deadline) if now >= deadline);
+                clock.exceeded.set(exceeded);
+                vec![Value::I32(exceeded as i32)]
+            },
+        ))
+    }
+
+    /// The reason for a trap, which is the deadline if the program
+    /// trapped for having passed it
+    fn reason(clock: &Option<Clock>, trap: String) -> String {
+        match clock {
+            Some(clock) if clock.exceeded.get() => TRAP_DEADLINE_EXCEEDED.into(),
+            _ => trap,
+        }
+    }
+}
+
+/// An instantiated wac program, whose exported functions
+/// can be called by name
+pub struct Runner {
+    instance: Box<dyn BackendInstance>,
+    target: Target,
+    stats: RunStats,
+
+    /// the code, if it can be used to locate traps (see CompiledCode)
+    code: Option<Rc<[u8]>>,
+    source_map: Rc<SourceMap>,
+
+    /// whether the program keeps a shadow stack for backtraces
+    /// (the interpreter keeps its own)
+    shadow_stack: bool,
+
+    /// the export releasing strs and records (see RunConfig::refcount_funcs)
+    release_func: &'static str,
+
+    /// the fuel the program started with, if it is metered
+    fuel: Option<u64>,
+
+    /// the deadline of the current call, for wasm programs with a time limit
+    clock: Option<Clock>,
+
+    /// whether to read the allocator's counters after each call
+    heap_stats: bool,
+
+    /// whether to check for leaks after Main
+    leak_check: bool,
+
+    /// the allocations, when the allocator reports them
+    allocations: Option<AllocTable>,
+
+    /// the program's record types, for marshalling them from the host
+    records: Vec<Rc<Record>>,
+}
+
+impl Runner {
+    /// Compiles and instantiates the program with the configured backend
+    /// (this also runs the initializers of global variables)
+    pub fn new(sources: Vec<Rc<Source>>, config: RunConfig) -> Result<Self, Error> {
+        let host = config.host.clone();
+        Compiled::new(sources, config)?.instantiate(&host)
+    }
+
+    /// Compiles the program to wasm, and instantiates it on the given backend
+    pub fn with_backend(
+        sources: Vec<Rc<Source>>,
+        config: RunConfig,
+        backend: &dyn Backend,
+    ) -> Result<Self, Error> {
+        let host = config.host.clone();
+        Compiled::with_backend(sources, config, backend)?.instantiate(&host)
+    }
+
+    /// Runs the program through its entry point, recording its exit status
+    /// (Main's result, if it returns one) in the stats
+    pub fn run_main(&mut self) -> Result<(), Error> {
+        match self.try_run_main() {
+            Ok(()) => Ok(()),
+            Err(Stop::Trapped(error)) | Err(Stop::Error(error)) => Err(error),
+            // (try_run_main records exits, and try_call locates traps)
+            Err(Stop::Exit(_)) | Err(Stop::Trap(..)) => unreachable!(),
+        }
+    }
+
+    /// Like run_main, but reports traps as Stop::Trapped, apart from
+    /// other errors
+    pub fn try_run_main(&mut self) -> Result<(), Stop> {
+        match self.try_call(self.target.entry_point(), &[]) {
+            Ok(results) => {
+                if let [Value::I32(code)] = results[..] {
+                    self.stats.exit_code = Some(code);
+                }
+            }
+            Err(Stop::Exit(code)) => self.stats.exit_code = Some(code),
+            Err(stop) => return Err(stop),
+        }
+        if self.leak_check {
+            self.stats.leaks = Some(self.check_leaks().map_err(Stop::Error)?);
+        }
+        Ok(())
+    }
+
+    /// Releases the globals, and returns the objects still alive, if
+    /// checking for leaks (which leaves the program unfit to call again)
+    pub fn check_leaks(&mut self) -> Result<Vec<Leak>, Error> {
+        let table = match &self.allocations {
+            Some(table) => table.clone(),
+            None => return Ok(vec![]),
+        };
+        if let Some(clock) = &self.clock {
+            clock.start();
+        }
+        match self.instance.release_globals() {
+            Ok(()) => {}
+            Err(Stop::Exit(code)) => {
+                return Err(Error {
+                    span: vec![],
+                    message: format!("Exited with status {} while releasing globals", code),
+                })
+            }
+            Err(Stop::Trap(trap, offset)) => return Err(self.trap_error(&trap, offset)),
+            Err(Stop::Trapped(error)) | Err(Stop::Error(error)) => return Err(error),
+        }
+        Ok(match self.memory() {
+            Some(memory) => find_leaks(&table, memory),
+            None => vec![],
+        })
+    }
+
+    /// The type tag of the record, for its header (see RECORD_TAG),
+    /// or 0 if it is not one of the program's
+    pub(crate) fn record_tag(&self, record: &Record) -> u32 {
+        let index = self.records.iter().position(|rec| **rec == *record);
+        index.map_or(0, |index| RECORD_TAG | index as u32)
+    }
+
+    /// Calls the function exported under the given name
+    pub fn call(&mut self, name: &str, args: &[Value]) -> Result<Vec<Value>, Error> {
+        match self.try_call(name, args) {
+            Ok(results) => Ok(results),
+            // the program exited early through exit or WASI's proc_exit
+            Err(Stop::Exit(code)) => {
+                self.stats.exit_code = Some(code);
+                Ok(vec![])
+            }
+            // (try_call locates traps)
+            Err(Stop::Trap(..)) => unreachable!(),
+            Err(Stop::Trapped(error)) | Err(Stop::Error(error)) => Err(error),
+        }
+    }
+
+    /// Like call, but leaves exiting to the caller, and reports
+    /// traps as Stop::Trapped, located in the wac source
+    pub fn try_call(&mut self, name: &str, args: &[Value]) -> Result<Vec<Value>, Stop> {
+        if let Some(clock) = &self.clock {
+            clock.start();
+        }
+        let start = Instant::now();
+        let result = self.instance.call(name, args);
+        self.stats.exec_sec += start.elapsed().as_secs_f64();
+
+        if let Some(fuel) = self.fuel {
+            let fuel_left = self.instance.fuel_left();
+            self.stats.fuel_consumed = fuel_left.map(|left| fuel - left);
+        }
+        if self.heap_stats {
+            let heap = self.memory().map(HeapStats::read);
+            self.stats.heap = heap;
+        }
+        match result {
+            Err(Stop::Trap(mut trap, offset)) => {
+                if let Some(table) = &self.allocations {
+                    // check_ref found a use after free or the like
+                    if let Some(violation) = table.borrow_mut().violation.take() {
+                        trap = violation;
+                    }
+                }
+                trap = Clock::reason(&self.clock, trap);
+                Err(Stop::Trapped(self.trap_error(&trap, offset)))
+            }
+            result => result,
+        }
+    }
+
+    /// The exported function that gives up a reference to a str or record
+    pub(crate) fn release_func(&self) -> &'static str {
+        self.release_func
+    }
+
+    /// The record type with the given name (see Compiled::record)
+    pub fn record(&self, name: &str) -> Option<&Rc<Record>> {
+        find_record(&self.records, name)
+    }
+
+    /// The program's linear memory, if it has one
+    pub fn memory(&self) -> Option<&dyn GuestMemory> {
+        self.instance.memory()
+    }
+
+    /// The stats so far, with the execution time summed over all calls
+    pub fn stats(&self) -> &RunStats {
+        &self.stats
+    }
+
+    /// Points the error at the wac code that trapped, if it can be found
+    ///
+    /// In debug builds, the error also points at the call of each frame on
+    /// the shadow stack and comes with a backtrace naming them. Traps in
+    /// host functions, which wasmer gives no offset for, are then located
+    /// by the call to the import and the function that made it.
+    fn trap_error(&self, trap: &str, offset: Option<usize>) -> Error {
+        let (frames, import_site) = self.take_shadow_stack().unwrap_or_default();
+        locate_trap(
+            &self.source_map,
+            self.code.as_deref(),
+            trap,
+            offset,
+            frames,
+            import_site,
+        )
+    }
+
+    /// The frames left on the shadow stack by a trap, innermost first,
+    /// and the call site of the import being called if any, after which
+    /// the stack is emptied for the next call
+    fn take_shadow_stack(&self) -> Option<(Vec<Frame>, Option<usize>)> {
+        if !self.shadow_stack {
+            return None;
+        }
+        let memory = self.instance.memory()?;
+        let (base, frames) = read_shadow_stack(memory)?;
+        let site = read_bytes_from_memory(memory, 4, CALL_SITE_PTR as i32).ok()?;
+        let site = u32::from_le_bytes([site[0], site[1], site[2], site[3]]);
+        write_bytes_to_memory(memory, SHADOW_SP_PTR as i32, &base.to_le_bytes()).ok()?;
+        write_bytes_to_memory(memory, CALL_SITE_PTR as i32, &0u32.to_le_bytes()).ok()?;
+        Some((frames, (site as usize).checked_sub(1)))
+    }
+}
+
+/// A frame on the shadow stack, as (func id, call site index)
+pub(crate) type Frame = (usize, Option<usize>);
+
+/// The base of the shadow stack, and the (func id, call site index) of
+/// each frame on it, innermost first (see cgen's gen_push_frame)
+///
+/// The call site is where the frame's function was called from,
+/// and is None for calls from the host. The stack is None if the
+/// pointers to it are out of bounds.
+pub(crate) fn read_shadow_stack(memory: &dyn GuestMemory) -> Option<(u32, Vec<Frame>)> {
+    let read = |ptr: u32| {
+        let bytes = read_bytes_from_memory(memory, 4, ptr as i32).ok()?;
+        Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
     };
-    let optimized_code_size = optimized_code.len();
-    let optimize_sec = start.elapsed().as_secs_f64();
+    let base = read(read(DEBUG_TABLES_PTR as u32)?)?;
+    let mut frame = read(SHADOW_SP_PTR as u32)?;
+    let mut frames = vec![];
+    while frame > base {
+        frame -= 8;
+        let site = (read(frame + 4)? as usize).checked_sub(1);
+        frames.push((read(frame)? as usize, site));
+    }
+    Some((base, frames))
+}
 
-    let start = Instant::now();
-    let instance = wr::instantiate(&optimized_code, &import_object)?;
-    let instantiate_sec = start.elapsed().as_secs_f64();
+/// The error for a trap, located at the offset in the code if it can be
+/// (see Runner::trap_error), with the frames left on the shadow stack
+fn locate_trap(
+    source_map: &SourceMap,
+    code: Option<&[u8]>,
+    trap: &str,
+    offset: Option<usize>,
+    frames: Vec<Frame>,
+    import_site: Option<usize>,
+) -> Error {
+    // the span of a call site id read from the shadow stack, which is
+    // None for calls from the host (or ids the guest scribbled over)
+    let call_site = |site: Option<usize>| source_map.call_sites.get(site?);
+    let mut location = match (code, offset) {
+        (Some(code), Some(offset)) => source_map.locate(code, offset),
+        _ => None,
+    };
+    if let (None, None, Some((func, _))) = (&location, offset, frames.first()) {
+        location = source_map.funcs.get(*func).map(|func| Location {
+            func: func.name.clone(),
+            span: call_site(import_site).cloned(),
+        });
+    }
+    let mut error = match (location, offset) {
+        (Some(location), _) => Error {
+            span: location.span.into_iter().collect(),
+            message: format!("Trap ({}) in {}", trap, location.func),
+        },
+        (None, Some(offset)) => Error {
+            span: vec![],
+            message: format!("Trap ({}) at wasm offset {}", trap, offset),
+        },
+        (None, None) => Error {
+            span: vec![],
+            message: format!("Trap ({})", trap),
+        },
+    };
+    if frames.is_empty() {
+        return error;
+    }
 
-    let start = Instant::now();
-    let main: wr::Func<(), ()> = instance.exports.get("Main")?;
-    main.call()?;
-    let exec_sec = start.elapsed().as_secs_f64();
+    error
+        .message
+        .push_str("\nbacktrace (most recent call first):");
+    for (func, site) in frames {
+        match source_map.funcs.get(func) {
+            Some(func) => error.message.push_str(&format!("\n  {}", func.name)),
+            None => error.message.push_str(&format!("\n  (function {})", func)),
+        }
+        if let Some(span) = call_site(site) {
+            error.message.push_str(&format!(
+                ", called at {}:{}",
+                span.source.name,
+                span.lineno()
+            ));
+            error.span.push(span.clone());
+        }
+    }
+    error
+}
 
-    Ok(RunStats {
-        translate_sec,
-        wat2wasm_sec,
-        instantiate_sec,
-        optimize_sec,
-        exec_sec,
-        wat_code_size,
-        wasm_code_size,
-        optimized_code_size,
-    })
+fn find_record<'a>(records: &'a [Rc<Record>], name: &str) -> Option<&'a Rc<Record>> {
+    records
+        .iter()
+        .find(|rec| wasm_path(&rec.module, &rec.name) == name)
 }
 
+/// An error for a program that exits (or fails) while being initialized
+fn init_error(stop: Stop) -> Error {
+    match stop {
+        Stop::Exit(code) => Error {
+            span: vec![],
+            message: format!("Exited with status {} during initialization", code),
+        },
+        // (try_instantiate reports traps as Stop::Trapped)
+        Stop::Trap(..) => unreachable!(),
+        Stop::Trapped(error) | Stop::Error(error) => error,
+    }
+}
+
+/// The environment the generated module is meant to run in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    /// our own runner, with the "wac" imports from imports.rs
+    Wac,
+
+    /// any runtime implementing wasi_snapshot_preview1
+    Wasi,
+}
+
+impl Target {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "wac" => Some(Self::Wac),
+            "wasi" => Some(Self::Wasi),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Wac => "wac",
+            Self::Wasi => "wasi",
+        }
+    }
+
+    /// the export that runs the program
+    pub fn entry_point(&self) -> &'static str {
+        match self {
+            Self::Wac => "Main",
+            Self::Wasi => "_start",
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct RunConfig {
     pub optimize: Option<u32>,
+
+    /// which target to build for, which also selects the
+    /// prelude files that are used (e.g. for I/O)
+    pub target: Target,
+
+    /// build a library: Main is optional, and is not called when running
+    pub library: bool,
+
+    /// export the linear memory as "memory", so that the host can share it
+    pub export_memory: bool,
+
+    /// keep a shadow call stack, so that panics and traps
+    /// come with a backtrace (at some cost in speed)
+    pub debug: bool,
+
+    /// what the program is allowed to access when run
+    pub host: HostConfig,
+
+    /// what runs the program (when it is run rather than just compiled)
+    pub backend: BackendKind,
+
+    /// if set, the program traps once it has made this many calls
+    /// (see cgen's gen_fuel_check, and fuel_budget for the limit)
+    pub fuel: Option<u64>,
+
+    /// if set, each call into the program traps once it has run this
+    /// long, as does instantiating it (for the initializers); like fuel,
+    /// this needs the wac target
+    pub time_limit: Option<Duration>,
+
+    /// the most pages of memory the program may grow to, beyond which
+    /// __malloc fails (at most wasm32's own limit of 65536, which is
+    /// the default)
+    pub max_pages: Option<u32>,
+
+    /// how much native stack the interpreter may use, beyond what is in
+    /// use when it is called, before it traps with "call stack exhausted"
+    /// (see DEFAULT_INTERP_STACK); it runs on the caller's thread, which
+    /// must have at least this much stack to spare
+    pub interp_stack: usize,
+
+    /// read the allocator's counters after each call, into RunStats::heap
+    pub heap_stats: bool,
+
+    /// keep a table of the live allocations, and after Main, release the
+    /// globals and report what is left in RunStats::leaks (see leaks.rs);
+    /// wasm programs also need debug to tell where objects were allocated
+    pub leak_check: bool,
+
+    /// poison freed blocks and keep them in quarantine, and check the
+    /// targets of retains and releases, trapping on a use after free or a
+    /// double free (see leaks.rs); like leak_check, this needs debug for
+    /// the sites in wasm
+    pub debug_alloc: bool,
+
+    /// leave out the retains and releases that the ownership of
+    /// strs and records makes redundant (see ownership.rs), which is
+    /// off unless asked for
+    pub elide_refcounts: bool,
+}
+
+/// What runs programs, for run and Runner
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendKind {
+    /// compile to wasm, and run that with wasmer
+    Wasmer,
+
+    /// interpret the checked program directly, skipping code generation
+    /// (see interp.rs)
+    Interpreter,
 }
 
 impl Default for RunConfig {
     fn default() -> Self {
-        Self { optimize: None }
+        Self {
+            optimize: None,
+            target: Target::Wac,
+            library: false,
+            export_memory: false,
+            debug: false,
+            host: HostConfig::default(),
+            backend: BackendKind::Wasmer,
+            fuel: None,
+            time_limit: None,
+            max_pages: None,
+            interp_stack: DEFAULT_INTERP_STACK,
+            heap_stats: false,
+            leak_check: false,
+            debug_alloc: false,
+            elide_refcounts: false,
+        }
+    }
+}
+
+impl RunConfig {
+    /// the fuel that metered programs start with, which is unlimited for
+    /// programs metered only for the time limit, and otherwise the fuel
+    /// set (at most i64::MAX, as the counter is an i64)
+    pub(crate) fn fuel_budget(&self) -> Option<u64> {
+        if self.fuel.is_none() && self.time_limit.is_none() {
+            return None;
+        }
+        let max = i64::MAX as u64;
+        Some(self.fuel.map_or(max, |fuel| fuel.min(max)))
+    }
+
+    /// whether __malloc and __free report to the host
+    pub(crate) fn track_allocs(&self) -> bool {
+        self.leak_check || self.debug_alloc
+    }
+
+    /// the prelude functions that retain and release strs and records,
+    /// which with the debug allocator first check their target
+    pub(crate) fn refcount_funcs(&self) -> (&'static str, &'static str) {
+        if self.debug_alloc {
+            ("__debug_retain", "__debug_release")
+        } else {
+            ("__retain", "__release")
+        }
     }
 }
 
+#[derive(Clone, Default)]
 pub struct RunStats {
     pub translate_sec: f64,
-    pub wat2wasm_sec: f64,
+    pub encode_sec: f64,
     pub optimize_sec: f64,
     pub instantiate_sec: f64,
     pub exec_sec: f64,
-    pub wat_code_size: usize,
     pub wasm_code_size: usize,
     pub optimized_code_size: usize,
+
+    /// the status the program exited with, if it called exit
+    /// (or WASI's proc_exit) or returned one from Main
+    pub exit_code: Option<i32>,
+
+    /// the fuel used so far, if the program is metered
+    pub fuel_consumed: Option<u64>,
+
+    /// the state of the heap after the last call, if heap_stats is set
+    pub heap: Option<HeapStats>,
+
+    /// the objects still alive after Main, if leak_check is set
+    pub leaks: Option<Vec<Leak>>,
+}
+
+/// The allocator's view of the heap (see prelude/malloc.wac)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HeapStats {
+    /// blocks allocated and not yet freed, and the bytes they take up
+    pub live_objects: u32,
+    pub live_bytes: u32,
+
+    /// the most bytes live at once
+    pub peak_bytes: u32,
+
+    /// blocks allocated in all
+    pub total_allocs: u32,
+
+    /// blocks on the freelists, waiting to be reused, and their size
+    pub free_blocks: u32,
+    pub free_bytes: u32,
+
+    /// the calls to __retain and __release (on objects), which
+    /// RunConfig::elide_refcounts cuts down on
+    pub retains: u32,
+    pub releases: u32,
+}
+
+impl HeapStats {
+    /// Reads the allocator's counters, and walks its freelists
+    pub fn read(memory: &dyn GuestMemory) -> Self {
+        let read_u32 = |ptr: usize| {
+            let mut bytes = [0; 4];
+            if ptr + 4 <= memory.size() {
+                memory.read(ptr, &mut bytes);
+            }
+            u32::from_le_bytes(bytes)
+        };
+        let mut stats = Self {
+            live_objects: read_u32(ALLOC_STATS_PTR),
+            live_bytes: read_u32(ALLOC_STATS_PTR + 4),
+            peak_bytes: read_u32(ALLOC_STATS_PTR + 8),
+            total_allocs: read_u32(ALLOC_STATS_PTR + 12),
+            retains: read_u32(REFCOUNT_STATS_PTR),
+            releases: read_u32(REFCOUNT_STATS_PTR + 4),
+            ..Self::default()
+        };
+        // free blocks do not overlap, so they take up at most all of memory,
+        // which bounds the walk even if the program has corrupted the lists
+        // (e.g. into cycles); blocks are at least 16 bytes, of class 4
+        let mut budget = memory.size();
+        for class in 4..(FREELIST_END - FREELIST_START) / 4 {
+            let size = 1usize << class;
+            let mut ptr = read_u32(FREELIST_START + class * 4) as usize;
+            while ptr != 0 && budget >= size {
+                stats.free_blocks = stats.free_blocks.saturating_add(1);
+                stats.free_bytes = stats.free_bytes.saturating_add(size as u32);
+                ptr = read_u32(ptr) as usize;
+                budget -= size;
+            }
+        }
+        stats
+    }
 }
 
 impl RunStats {
@@ -78,16 +975,10 @@ impl RunStats {
         use std::fmt::Write;
         let mut out = String::new();
         writeln!(out, "translate time    : {}s", self.translate_sec).unwrap();
-        writeln!(out, "wat2wasm time     : {}s", self.wat2wasm_sec).unwrap();
+        writeln!(out, "encode time       : {}s", self.encode_sec).unwrap();
         writeln!(out, "optimize time     : {}s", self.optimize_sec).unwrap();
         writeln!(out, "instantiate time  : {}s", self.instantiate_sec).unwrap();
         writeln!(out, "execution time    : {}s", self.exec_sec).unwrap();
-        writeln!(
-            out,
-            "wat code size     : {:.3}kb",
-            (self.wat_code_size as f64) / 2.0f64.powi(10)
-        )
-        .unwrap();
         writeln!(
             out,
             "wasm code size    : {:.3}kb",
@@ -100,6 +991,41 @@ impl RunStats {
             (self.optimized_code_size as f64) / 2.0f64.powi(10)
         )
         .unwrap();
+        if let Some(fuel) = self.fuel_consumed {
+            writeln!(out, "fuel consumed     : {}", fuel).unwrap();
+        }
+        if let Some(heap) = &self.heap {
+            writeln!(
+                out,
+                "heap objects      : {} live, {} allocated",
+                heap.live_objects, heap.total_allocs
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "heap bytes        : {} live, {} peak",
+                heap.live_bytes, heap.peak_bytes
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "freelists         : {} blocks, {} bytes",
+                heap.free_blocks, heap.free_bytes
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "refcounting       : {} retains, {} releases",
+                heap.retains, heap.releases
+            )
+            .unwrap();
+        }
+        if let Some(leaks) = &self.leaks {
+            writeln!(out, "leaked objects    : {}", leaks.len()).unwrap();
+            for leak in leaks {
+                writeln!(out, "  {}", leak.format()).unwrap();
+            }
+        }
         out
     }
 }
@@ -108,14 +1034,35 @@ pub fn translate_to_wasm(
     sources: Vec<Rc<Source>>,
     config: RunConfig,
 ) -> Result<(Vec<u8>, RunStats), Error> {
+    let wasm = compile(sources, config)?;
+    Ok((wasm.code, wasm.stats))
+}
+
+/// A program translated to wasm
+struct Wasm {
+    code: Vec<u8>,
+
+    /// the source map of the unoptimized code
+    source_map: SourceMap,
+    stats: RunStats,
+    records: Vec<Rc<Record>>,
+}
+
+fn compile(sources: Vec<Rc<Source>>, config: RunConfig) -> Result<Wasm, Error> {
     let start = Instant::now();
-    let wat_code = translate(sources)?;
+    let program = translate_to_program(sources, &config)?;
+    let records = program.records.clone();
+    let (module, source_map) = program.module(&config)?;
     let translate_sec = start.elapsed().as_secs_f64();
-    let wat_code_size = wat_code.len();
 
     let start = Instant::now();
-    let wasm_code = wabt::wat2wasm(&wat_code)?;
-    let wat2wasm_sec = start.elapsed().as_secs_f64();
+    let (mut wasm_code, offsets) = module.encode_with_offsets()?;
+    if config.optimize.is_none() {
+        // (optimizing would move the instructions around)
+        let section = source_map.encode(&offsets);
+        write_custom_section(&mut wasm_code, SOURCE_MAP_SECTION, &section);
+    }
+    let encode_sec = start.elapsed().as_secs_f64();
     let wasm_code_size = wasm_code.len();
 
     let start = Instant::now();
@@ -133,14 +1080,22 @@ pub fn translate_to_wasm(
 
     let stats = RunStats {
         translate_sec,
-        wat2wasm_sec,
+        encode_sec,
         instantiate_sec: 0.0,
         optimize_sec,
         exec_sec: 0.0,
-        wat_code_size,
         wasm_code_size,
         optimized_code_size,
+        exit_code: None,
+        fuel_consumed: None,
+        heap: None,
+        leaks: None,
     };
 
-    Ok((optimized_code, stats))
+    Ok(Wasm {
+        code: optimized_code,
+        source_map,
+        stats,
+        records,
+    })
 }
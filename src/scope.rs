@@ -2,6 +2,7 @@ use crate::ast::*;
 use crate::ir::*;
 use crate::Error;
 use crate::Span;
+use std::cell::Cell;
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::rc::Rc;
@@ -9,17 +10,25 @@ use std::rc::Rc;
 pub trait Scope {
     fn decl(&mut self, name: Rc<str>, item: Item) -> Result<(), Error>;
     fn get(&self, name: &str) -> Option<&Item>;
-    fn declconst(&mut self, span: Span, name: Rc<str>, value: ConstVal) -> Result<(), Error> {
-        let decl = Rc::new(Constant {
-            span,
-            name: name.clone(),
-            value,
-        });
-        self.decl(name, Item::Constant(decl))?;
-        Ok(())
+
+    /// the module names are being resolved from
+    fn current_module(&self) -> &Rc<str>;
+
+    /// Like get, but fails if the item is private to another module
+    fn get_visible(&self, span: &Span, name: &str) -> Result<Option<&Item>, Error> {
+        match self.get(name) {
+            Some(item) => match item.module() {
+                Some(module) if !item.public() && module != self.current_module() => Err(Error {
+                    span: vec![span.clone(), item.span().clone()],
+                    message: format!("{} is private to module {}", name, module),
+                }),
+                _ => Ok(Some(item)),
+            },
+            None => Ok(None),
+        }
     }
     fn get_return_type(&self, span: &Span, name: &str) -> Result<ReturnType, Error> {
-        match self.get(name) {
+        match self.get_visible(span, name)? {
             Some(item) => match item {
                 Item::Record(rec) => Ok(ReturnType::Type(Type::Record(rec.clone()))),
                 _ => Err(Error {
@@ -45,7 +54,7 @@ pub trait Scope {
         }
     }
     fn get_callable(&self, span: &Span, name: &str) -> Result<Callable, Error> {
-        match self.get(name) {
+        match self.get_visible(span, name)? {
             Some(Item::Func(func)) => Ok(Callable::Func(func.clone())),
             Some(Item::Extern(func)) => Ok(Callable::Extern(func.clone())),
             Some(item) => Err(Error {
@@ -59,7 +68,7 @@ pub trait Scope {
         }
     }
     fn get_constant(&self, span: &Span, name: &str) -> Result<&Rc<Constant>, Error> {
-        match self.get(name) {
+        match self.get_visible(span, name)? {
             Some(Item::Constant(constant)) => Ok(constant),
             Some(item) => Err(Error {
                 span: vec![span.clone(), item.span().clone()],
@@ -76,7 +85,7 @@ pub trait Scope {
         span: &Span,
         name: &str,
     ) -> Result<VariableOrConstant, Error> {
-        match self.get(name) {
+        match self.get_visible(span, name)? {
             Some(Item::Local(var)) => {
                 Ok(VariableOrConstant::Variable(Variable::Local(var.clone())))
             }
@@ -95,7 +104,7 @@ pub trait Scope {
         }
     }
     fn get_variable(&self, span: &Span, name: &str) -> Result<Variable, Error> {
-        match self.get(name) {
+        match self.get_visible(span, name)? {
             Some(Item::Local(var)) => Ok(Variable::Local(var.clone())),
             Some(Item::Global(var)) => Ok(Variable::Global(var.clone())),
             Some(item) => Err(Error {
@@ -134,29 +143,143 @@ pub trait Scope {
 }
 
 pub struct GlobalScope {
-    map: HashMap<Rc<str>, Item>,
+    /// the items declared in each module
+    modules: HashMap<Rc<str>, HashMap<Rc<str>, Item>>,
+
+    /// for each module, the modules it imports, keyed by alias
+    imports: HashMap<Rc<str>, HashMap<Rc<str>, Rc<str>>>,
+
+    /// the module whose items are currently being declared or resolved
+    module: Rc<str>,
+
     memory: Rc<RefCell<Memory>>,
 }
 
 impl GlobalScope {
     pub fn new() -> Self {
+        let mut modules = HashMap::new();
+        modules.insert(PRELUDE_MODULE.into(), HashMap::new());
         Self {
-            map: HashMap::new(),
+            modules,
+            imports: HashMap::new(),
+            module: PRELUDE_MODULE.into(),
             memory: Rc::new(RefCell::new(Memory::new())),
         }
     }
+    pub fn add_module(&mut self, module: Rc<str>) {
+        self.modules.entry(module).or_insert_with(HashMap::new);
+    }
+    pub fn module(&self) -> &Rc<str> {
+        &self.module
+    }
+    pub fn set_module(&mut self, module: Rc<str>) {
+        assert!(self.modules.contains_key(&module));
+        self.module = module;
+    }
+    pub fn add_import(&mut self, import: &RawImport) -> Result<(), Error> {
+        if !self.modules.contains_key(&import.module) {
+            return Err(Error {
+                span: vec![import.span.clone()],
+                message: format!("module {} not found", import.module),
+            });
+        }
+        let imports = self
+            .imports
+            .entry(self.module.clone())
+            .or_insert_with(HashMap::new);
+        // imports are shared by all files in a module, so two files importing
+        // the same module under the same name is fine
+        if let Some(old_module) = imports.get(&import.alias).filter(|m| **m != import.module) {
+            return Err(Error {
+                span: vec![import.span.clone()],
+                message: format!(
+                    "{} is already used as the name for module {}",
+                    import.alias, old_module
+                ),
+            });
+        }
+        imports.insert(import.alias.clone(), import.module.clone());
+        Ok(())
+    }
+
+    /// Returns the (module, name) pairs the given name could refer to
+    /// from the current module, in the order they should be tried.
+    ///
+    /// A qualified name 'b.name' refers to 'name' in the module imported
+    /// as 'b'. An unqualified name refers to an item in the current module,
+    /// or failing that, to an item in the prelude.
+    pub fn candidates<'a>(&self, name: &'a str) -> Vec<(Rc<str>, &'a str)> {
+        if let Some(i) = name.find('.') {
+            let alias = &name[..i];
+            match self.imports.get(&self.module).and_then(|m| m.get(alias)) {
+                Some(module) => vec![(module.clone(), &name[i + 1..])],
+                None => vec![],
+            }
+        } else if self.module.as_ref() == PRELUDE_MODULE {
+            vec![(self.module.clone(), name)]
+        } else {
+            vec![(self.module.clone(), name), (PRELUDE_MODULE.into(), name)]
+        }
+    }
+
+    /// Get an item declared in the given module
+    pub fn get_in(&self, module: &str, name: &str) -> Option<&Item> {
+        self.modules.get(module).and_then(|m| m.get(name))
+    }
+
+    pub fn declconst(
+        &mut self,
+        span: Span,
+        public: bool,
+        name: Rc<str>,
+        value: ConstVal,
+    ) -> Result<(), Error> {
+        let decl = Rc::new(Constant {
+            span,
+            module: self.module.clone(),
+            public,
+            name: name.clone(),
+            value,
+        });
+        self.decl(name, Item::Constant(decl))?;
+        Ok(())
+    }
     pub fn declvar(
         &mut self,
         span: Span,
+        public: bool,
         name: Rc<str>,
         type_: Type,
         init: Expr,
     ) -> Result<Rc<Global>, Error> {
         let global = Rc::new(Global {
             span,
+            module: self.module.clone(),
+            public,
             name: name.clone(),
             type_,
-            init,
+            init: Some(init),
+            import: None,
+        });
+        self.decl(name, Item::Global(global.clone()))?;
+        Ok(global)
+    }
+    pub fn declimport(
+        &mut self,
+        span: Span,
+        public: bool,
+        name: Rc<str>,
+        type_: Type,
+        path: (Rc<str>, Rc<str>),
+    ) -> Result<Rc<Global>, Error> {
+        let global = Rc::new(Global {
+            span,
+            module: self.module.clone(),
+            public,
+            name: name.clone(),
+            type_,
+            init: None,
+            import: Some(path),
         });
         self.decl(name, Item::Global(global.clone()))?;
         Ok(global)
@@ -168,18 +291,28 @@ impl GlobalScope {
 
 impl Scope for GlobalScope {
     fn decl(&mut self, name: Rc<str>, item: Item) -> Result<(), Error> {
-        if let Some(old_item) = self.map.get(&name) {
+        let map = self.modules.get_mut(&self.module).unwrap();
+        if let Some(old_item) = map.get(&name) {
             return Err(Error {
                 span: vec![old_item.span().clone(), item.span().clone()],
                 message: format!("Redefinition of {}", name),
             });
         }
-        self.map.insert(name, item);
+        map.insert(name, item);
         Ok(())
     }
 
     fn get(&self, name: &str) -> Option<&Item> {
-        self.map.get(name)
+        for (module, name) in self.candidates(name) {
+            if let Some(item) = self.get_in(&module, name) {
+                return Some(item);
+            }
+        }
+        None
+    }
+
+    fn current_module(&self) -> &Rc<str> {
+        &self.module
     }
 }
 
@@ -221,6 +354,7 @@ impl<'a> LocalScope<'a> {
             name: name.clone(),
             type_,
             id,
+            borrowed: Cell::new(false),
         });
         self.decl(name, Item::Local(local.clone()))?;
         Ok(local)
@@ -258,4 +392,8 @@ impl<'a> Scope for LocalScope<'a> {
         }
         self.g.get(name)
     }
+
+    fn current_module(&self) -> &Rc<str> {
+        &self.g.module
+    }
 }
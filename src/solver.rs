@@ -1,11 +1,20 @@
 use crate::ast::*;
+use crate::initorder::*;
 use crate::ir::*;
 use crate::scope::*;
 use crate::Error;
+use crate::RunConfig;
+use crate::Span;
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::rc::Rc;
 
-pub fn solve(files: &Vec<File>) -> Result<Program, Error> {
+/// If the last parameter of a function has this name and type str,
+/// callers do not pass it explicitly. Instead, it is filled in with
+/// the "file:line" location of the call site (e.g. for panic and assert)
+pub const CALLER_PARAM: &str = "__caller";
+
+pub fn solve(files: &Vec<File>, config: &RunConfig) -> Result<Program, Error> {
     let mut gscope = GlobalScope::new();
 
     let mut records_with_ast = Vec::new();
@@ -14,13 +23,37 @@ pub fn solve(files: &Vec<File>) -> Result<Program, Error> {
     let mut externs = Vec::new();
     let mut funcs = Vec::new();
     let mut globals = Vec::new();
+    let mut exports = HashMap::new();
+    let mut memory_import: Option<(Span, (Rc<str>, Rc<str>))> = None;
     let memory = gscope.memory().clone();
 
+    // register all modules, so that imports can be checked
+    for file in files {
+        gscope.add_module(file.module.clone());
+    }
+
+    // the memory layout is defined once, in ir.rs
+    gscope.add_module(PRELUDE_MODULE.into());
+    gscope.set_module(PRELUDE_MODULE.into());
+    for (name, value) in PRELUDE_CONSTANTS {
+        let value = ConstVal::I32(*value as i32);
+        gscope.declconst(files[0].span.clone(), false, (*name).into(), value)?;
+    }
+    for file in files {
+        gscope.set_module(file.module.clone());
+        for import in &file.imports {
+            gscope.add_import(import)?;
+        }
+    }
+
     // initialize type names in gscope
     for file in files {
+        gscope.set_module(file.module.clone());
         for node in &file.records {
             let rec = Rc::new(Record {
                 span: node.span.clone(),
+                module: file.module.clone(),
+                public: node.public,
                 name: node.name.clone(),
                 fields: RefCell::new(vec![]),
             });
@@ -31,20 +64,32 @@ pub fn solve(files: &Vec<File>) -> Result<Program, Error> {
     }
 
     // initialize global constants
+    // constants may refer to other constants declared later (or in
+    // other files), so they are resolved on demand
+    let mut pending = Pending::new();
     for file in files {
         for node in &file.constants {
-            let hint = if let Some(type_) = &node.type_ {
-                Some(gscope.resolve_type(type_)?)
-            } else {
-                None
-            };
-            let value = solve_constexpr(&mut gscope, &node.expr, hint)?;
-            gscope.declconst(node.span.clone(), node.name.clone(), value)?;
+            let key = (file.module.clone(), node.name.clone());
+            if let Some(old_node) = pending.nodes.insert(key, node) {
+                return Err(Error {
+                    span: vec![old_node.span.clone(), node.span.clone()],
+                    message: format!("Redefinition of {}", node.name),
+                });
+            }
+        }
+    }
+    for file in files {
+        for node in &file.constants {
+            let key = (file.module.clone(), node.name.clone());
+            if pending.nodes.contains_key(&key) {
+                solve_constant(&mut gscope, &mut pending, file.module.clone(), node)?;
+            }
         }
     }
 
     // initialize the fields for records
     for (rec, node) in &records_with_ast {
+        gscope.set_module(rec.module.clone());
         let mut fields = Vec::new();
         for (field_name, field_type_expr) in &node.fields {
             let field_type = gscope.resolve_type(field_type_expr)?;
@@ -55,10 +100,13 @@ pub fn solve(files: &Vec<File>) -> Result<Program, Error> {
 
     // initialize extern/function prototypes
     for file in files {
+        gscope.set_module(file.module.clone());
         for node in &file.externs {
             let type_ = gscope.resolve_func_type(&node.type_)?;
             let ext = Rc::new(Extern {
                 span: node.span.clone(),
+                module: file.module.clone(),
+                public: node.public,
                 path: node.path.clone(),
                 name: node.name.clone(),
                 type_,
@@ -66,10 +114,65 @@ pub fn solve(files: &Vec<File>) -> Result<Program, Error> {
             externs.push(ext.clone());
             gscope.decl(node.name.clone(), Item::Extern(ext))?;
         }
+        for node in &file.extern_globals {
+            let type_ = gscope.resolve_type(&node.type_)?;
+            match type_ {
+                Type::Bool | Type::I32 | Type::I64 | Type::F32 | Type::F64 => {}
+                _ => {
+                    return Err(Error {
+                        span: vec![node.span.clone()],
+                        message: format!("extern var cannot have type {}", type_),
+                    })
+                }
+            }
+            let global = gscope.declimport(
+                node.span.clone(),
+                node.public,
+                node.name.clone(),
+                type_,
+                node.path.clone(),
+            )?;
+            globals.push(global);
+        }
+        // (there is only the one memory)
+        for node in &file.extern_memories {
+            if let Some((old_span, _)) = &memory_import {
+                return Err(Error {
+                    span: vec![old_span.clone(), node.span.clone()],
+                    message: "The memory may only be imported once".into(),
+                });
+            }
+            memory_import = Some((node.span.clone(), node.path.clone()));
+        }
         for node in &file.funcs {
             let type_ = gscope.resolve_func_type(&node.type_)?;
+            let export = if is_main(&file.module, &node.name) {
+                // the Main function is always exported, and under its own name
+                match &node.export {
+                    Some(alias) if alias.as_ref() != "Main" => {
+                        return Err(Error {
+                            span: vec![node.span.clone()],
+                            message: format!("Main cannot be exported as {:?}", alias),
+                        })
+                    }
+                    _ => Some(node.name.clone()),
+                }
+            } else {
+                node.export.clone()
+            };
+            if let Some(export) = &export {
+                if let Some(old_span) = exports.insert(export.clone(), node.span.clone()) {
+                    return Err(Error {
+                        span: vec![old_span, node.span.clone()],
+                        message: format!("{:?} is exported more than once", export),
+                    });
+                }
+            }
             let func = Rc::new(Func {
                 span: node.span.clone(),
+                module: file.module.clone(),
+                public: node.public,
+                export,
                 name: node.name.clone(),
                 type_,
                 parameters: RefCell::new(vec![]),
@@ -83,47 +186,75 @@ pub fn solve(files: &Vec<File>) -> Result<Program, Error> {
     }
 
     // resolve global variables
+    // like constants, the initializer of a global may refer to globals
+    // declared later, so those are resolved on demand
+    let mut pending = Pending::new();
+    for file in files {
+        for node in &file.globals {
+            let key = (file.module.clone(), node.name.clone());
+            if let Some(old_node) = pending.nodes.insert(key, node) {
+                return Err(Error {
+                    span: vec![old_node.span.clone(), node.span.clone()],
+                    message: format!("Redefinition of {}", node.name),
+                });
+            }
+        }
+    }
     let mut gvar_init_lscope = LocalScope::new(&mut gscope, None);
     for file in files {
         for node in &file.globals {
-            let span = node.span.clone();
-            let name = node.name.clone();
-            let (type_, expr) = if let Some(type_) = &node.type_ {
-                let type_ = gvar_init_lscope.gscope().resolve_type(type_)?;
-                let expr =
-                    solve_typed_expr(&mut gvar_init_lscope, &node.init, &type_.clone().into())?;
-                (type_, expr)
-            } else {
-                let expr = solve_value_expr(&mut gvar_init_lscope, &node.init, None)?;
-                let type_ = expr.type_.value().cloned().unwrap();
-                (type_, expr)
-            };
-            let global = gvar_init_lscope.gscope().declvar(span, name, type_, expr)?;
-            globals.push(global);
+            let key = (file.module.clone(), node.name.clone());
+            if pending.nodes.contains_key(&key) {
+                let module = file.module.clone();
+                solve_global(
+                    &mut gvar_init_lscope,
+                    &mut pending,
+                    &mut globals,
+                    module,
+                    node,
+                )?;
+            }
         }
     }
     let gvar_init_locals = gvar_init_lscope.locals().clone();
 
     // resolve functions
-    let mut main_found = false;
+    let mut main: Option<Rc<Func>> = None;
     for (func, node) in &funcs_with_ast {
-        if "Main" == func.name.as_ref() {
-            main_found = true;
-            if func.type_
-                != (FuncType {
-                    parameters: vec![],
-                    return_type: ReturnType::Void,
-                })
-            {
+        if is_main(&func.module, &func.name) {
+            if let Some(old_main) = &main {
+                return Err(Error {
+                    span: vec![old_main.span.clone(), func.span.clone()],
+                    message: format!(
+                        "Main is defined in both {} and {}",
+                        old_main.module, func.module
+                    ),
+                });
+            }
+            main = Some(func.clone());
+            // a Main returning i32 gives the program's exit status
+            let valid = func.type_.parameters.is_empty()
+                && match &func.type_.return_type {
+                    ReturnType::Void | ReturnType::Type(Type::I32) => true,
+                    _ => false,
+                };
+            if !valid {
                 return Err(Error {
                     span: vec![func.span.clone()],
-                    message: format!("Expected Main to have type ()void, but got {}", func.type_),
+                    message: format!(
+                        "Expected Main to have type ()void or ()i32, but got {}",
+                        func.type_
+                    ),
                 });
             }
         }
+        gscope.set_module(func.module.clone());
         solve_func(&mut gscope, func, node)?;
     }
-    if !main_found {
+    // globals may only be initialized once everything they read is
+    let globals = order_globals(globals)?;
+
+    if main.is_none() && !config.library {
         return Err(Error {
             span: vec![],
             message: format!("Main function not found"),
@@ -132,15 +263,23 @@ pub fn solve(files: &Vec<File>) -> Result<Program, Error> {
 
     Ok(Program {
         span: files[0].span.clone(),
+        main,
         externs,
         globals,
         funcs,
         records,
         gvar_init_locals,
         memory,
+        memory_import: memory_import.map(|(_, path)| path),
     })
 }
 
+/// Checks whether a function is the program's entry point
+/// (functions in the prelude never are)
+fn is_main(module: &str, name: &str) -> bool {
+    name == "Main" && module != PRELUDE_MODULE
+}
+
 fn solve_func(gscope: &mut GlobalScope, func: &Rc<Func>, node: &RawFunc) -> Result<(), Error> {
     let mut lscope = LocalScope::new(gscope, Some(func));
     for (param_name, param_type) in &func.type_.parameters {
@@ -150,7 +289,18 @@ fn solve_func(gscope: &mut GlobalScope, func: &Rc<Func>, node: &RawFunc) -> Resu
     let body = solve_stmt(&mut lscope, &node.body)?;
     match &func.type_.return_type {
         ReturnType::Void => {}
-        ReturnType::NoReturn => panic!("TODO: figure out how noreturn will work"),
+        ReturnType::NoReturn => match &body.return_state {
+            // a noreturn function may not contain any return statements,
+            // so 'AlwaysReturns' here means that every path ends in
+            // a call to another noreturn function
+            ReturnState::AlwaysReturns => {}
+            _ => {
+                return Err(Error {
+                    span: vec![func.span.clone()],
+                    message: format!("noreturn function might return"),
+                });
+            }
+        },
         ReturnType::Type(_) => {
             match &body.return_state {
                 ReturnState::AlwaysReturns => {}
@@ -175,50 +325,242 @@ fn solve_func(gscope: &mut GlobalScope, func: &Rc<Func>, node: &RawFunc) -> Resu
     Ok(())
 }
 
-fn solve_constexpr(
+/// Constants or globals that have not been solved yet
+struct Pending<'a, T> {
+    /// keyed by (module, name)
+    nodes: HashMap<(Rc<str>, Rc<str>), &'a T>,
+
+    /// the items currently being solved, used to detect cycles
+    stack: Vec<(Rc<str>, &'a T)>,
+}
+
+impl<'a, T> Pending<'a, T> {
+    fn new() -> Self {
+        Self {
+            nodes: HashMap::new(),
+            stack: vec![],
+        }
+    }
+}
+
+fn solve_global<'a>(
+    lscope: &mut LocalScope,
+    pending: &mut Pending<'a, RawGlobal>,
+    globals: &mut Vec<Rc<Global>>,
+    module: Rc<str>,
+    node: &'a RawGlobal,
+) -> Result<(), Error> {
+    let old_module = lscope.gscope().module().clone();
+    lscope.gscope().set_module(module.clone());
+
+    pending.nodes.remove(&(module.clone(), node.name.clone()));
+    pending.stack.push((module, node));
+
+    // make sure the globals this initializer reads are declared first
+    let mut reads = Vec::new();
+    raw_expr_reads(&node.init, &mut reads);
+    for (name, read_span) in reads {
+        for (module, short_name) in lscope.gscope().candidates(&name) {
+            if lscope.gscope().get_in(&module, short_name).is_some() {
+                break;
+            }
+            let key = (module.clone(), short_name.into());
+            if let Some(dep) = pending.nodes.get(&key).cloned() {
+                solve_global(lscope, pending, globals, module, dep)?;
+                break;
+            }
+            let in_progress = pending
+                .stack
+                .iter()
+                .position(|(m, g)| *m == module && g.name.as_ref() == short_name);
+            if let Some(i) = in_progress {
+                let mut cycle: Vec<_> = pending.stack[i..]
+                    .iter()
+                    .map(|(_, g)| (g.name.clone(), vec![g.span.clone()]))
+                    .collect();
+                cycle.last_mut().unwrap().1.push(read_span);
+                return Err(global_cycle_error(cycle));
+            }
+        }
+    }
+
+    let span = node.span.clone();
+    let name = node.name.clone();
+    let (type_, expr) = if let Some(type_) = &node.type_ {
+        let type_ = lscope.gscope().resolve_type(type_)?;
+        let expr = solve_typed_expr(lscope, &node.init, &type_.clone().into())?;
+        (type_, expr)
+    } else {
+        let expr = solve_value_expr(lscope, &node.init, None)?;
+        let type_ = expr.type_.value().cloned().unwrap();
+        (type_, expr)
+    };
+    let global = lscope
+        .gscope()
+        .declvar(span, node.public, name, type_, expr)?;
+    globals.push(global);
+
+    pending.stack.pop();
+    lscope.gscope().set_module(old_module);
+    Ok(())
+}
+
+/// Collects the names of the variables an expression reads directly
+fn raw_expr_reads(expr: &RawExpr, out: &mut Vec<(Rc<str>, Span)>) {
+    match &expr.data {
+        RawExprData::Void
+        | RawExprData::Bool(_)
+        | RawExprData::Int(_)
+        | RawExprData::Float(_)
+        | RawExprData::Str(_)
+        | RawExprData::Char(_) => {}
+        RawExprData::GetVar(name) | RawExprData::Raw(name) => {
+            out.push((name.clone(), expr.span.clone()));
+        }
+        RawExprData::SetVar(_, setexpr) => raw_expr_reads(setexpr, out),
+        RawExprData::AugVar(name, _, augexpr) => {
+            out.push((name.clone(), expr.span.clone()));
+            raw_expr_reads(augexpr, out);
+        }
+        RawExprData::CallFunc(_, args) | RawExprData::Asm(args, ..) => {
+            for arg in args {
+                raw_expr_reads(arg, out);
+            }
+        }
+        RawExprData::Unop(_, arg) => raw_expr_reads(arg, out),
+        RawExprData::Binop(_, left, right) | RawExprData::Read(_, left, right) => {
+            raw_expr_reads(left, out);
+            raw_expr_reads(right, out);
+        }
+        RawExprData::Write(_, addr, value, offset) => {
+            raw_expr_reads(addr, out);
+            raw_expr_reads(value, out);
+            raw_expr_reads(offset, out);
+        }
+    }
+}
+
+fn solve_constant<'a>(
+    gscope: &mut GlobalScope,
+    pending: &mut Pending<'a, RawConstant>,
+    module: Rc<str>,
+    node: &'a RawConstant,
+) -> Result<(), Error> {
+    // constants may be requested from other modules,
+    // but must be resolved in their own
+    let old_module = gscope.module().clone();
+    gscope.set_module(module.clone());
+
+    pending.nodes.remove(&(module.clone(), node.name.clone()));
+    pending.stack.push((module, node));
+    let hint = if let Some(type_) = &node.type_ {
+        Some(gscope.resolve_type(type_)?)
+    } else {
+        None
+    };
+    let value = solve_constexpr(gscope, pending, &node.expr, hint.clone())?;
+    let value = match hint {
+        Some(type_) => match widen_const(value, &type_) {
+            Ok(value) => value,
+            Err(value) => {
+                return Err(Error {
+                    span: vec![node.span.clone()],
+                    message: format!("Expected {} but got {}", type_, value.type_()),
+                })
+            }
+        },
+        None => value,
+    };
+    pending.stack.pop();
+    gscope.declconst(node.span.clone(), node.public, node.name.clone(), value)?;
+    gscope.set_module(old_module);
+    Ok(())
+}
+
+fn solve_constexpr<'a>(
     gscope: &mut GlobalScope,
+    pending: &mut Pending<'a, RawConstant>,
     node: &RawExpr,
     hint: Option<Type>,
 ) -> Result<ConstVal, Error> {
     match &node.data {
-        RawExprData::Int(x) => Ok(ConstVal::I32(*x as i32)),
-        RawExprData::GetVar(name) => Ok(gscope.get_constant(&node.span, name)?.value.clone()),
-        RawExprData::Binop(op, lhs, rhs) => match op {
-            Binop::Add
-            | Binop::Subtract
-            | Binop::Multiply
-            | Binop::Remainder
-            | Binop::TruncDivide => {
-                let lhs = solve_constexpr(gscope, lhs, hint.clone())?;
-                let rhs = solve_constexpr(gscope, rhs, hint)?;
-                match (op, &lhs, &rhs) {
-                    (Binop::Add, ConstVal::I32(a), ConstVal::I32(b)) => Ok(ConstVal::I32(a + b)),
-                    (Binop::Subtract, ConstVal::I32(a), ConstVal::I32(b)) => {
-                        Ok(ConstVal::I32(a - b))
-                    }
-                    (Binop::Multiply, ConstVal::I32(a), ConstVal::I32(b)) => {
-                        Ok(ConstVal::I32(a + b))
-                    }
-                    (Binop::Remainder, ConstVal::I32(a), ConstVal::I32(b)) => {
-                        Ok(ConstVal::I32(a % b))
-                    }
-                    (Binop::TruncDivide, ConstVal::I32(a), ConstVal::I32(b)) => {
-                        Ok(ConstVal::I32(a / b))
-                    }
-                    _ => Err(Error {
-                        span: vec![node.span.clone()],
-                        message: format!(
-                            "Unsupported constexpr binop {:?}, {:?}, {:?}",
-                            op, lhs, rhs,
-                        ),
-                    }),
+        RawExprData::Bool(x) => Ok(ConstVal::Bool(*x)),
+        RawExprData::Int(x) => match hint {
+            Some(Type::I64) => Ok(ConstVal::I64(*x)),
+            Some(Type::F32) => Ok(ConstVal::F32(*x as f32)),
+            Some(Type::F64) => Ok(ConstVal::F64(*x as f64)),
+            // without a type to go by, literals too big for an i32 are i64s
+            None if int_literal_i32(&node.span, *x).is_err() => Ok(ConstVal::I64(*x)),
+            _ => Ok(ConstVal::I32(int_literal_i32(&node.span, *x)?)),
+        },
+        RawExprData::Float(x) => match hint {
+            Some(Type::F32) => Ok(ConstVal::F32(*x as f32)),
+            _ => Ok(ConstVal::F64(*x)),
+        },
+        RawExprData::Char(ch) => Ok(ConstVal::I32(*ch as i32)),
+        RawExprData::Str(string) => Ok(ConstVal::Str(string.clone())),
+        RawExprData::GetVar(name) => {
+            for (module, short_name) in gscope.candidates(name) {
+                if gscope.get_in(&module, short_name).is_some() {
+                    break;
+                }
+                let key = (module.clone(), short_name.into());
+                if let Some(dep) = pending.nodes.get(&key).cloned() {
+                    solve_constant(gscope, pending, module, dep)?;
+                    break;
+                }
+                let in_progress = pending
+                    .stack
+                    .iter()
+                    .position(|(m, c)| *m == module && c.name.as_ref() == short_name);
+                if let Some(i) = in_progress {
+                    let mut span: Vec<_> = pending.stack[i..]
+                        .iter()
+                        .map(|(_, c)| c.span.clone())
+                        .collect();
+                    span.push(node.span.clone());
+                    return Err(Error {
+                        span,
+                        message: format!("Constant {} depends on itself", name),
+                    });
                 }
             }
-            _ => Err(Error {
-                span: vec![node.span.clone()],
-                message: format!("Unsupported constexpr binop {:?}", op),
-            }),
-        },
+            Ok(gscope.get_constant(&node.span, name)?.value.clone())
+        }
+        RawExprData::Unop(op, arg) => {
+            let arg = solve_constexpr(gscope, pending, arg, hint)?;
+            eval_const_unop(&node.span, *op, arg)
+        }
+        RawExprData::Binop(op, lhs, rhs) => {
+            // only the arithmetic and bitwise ops produce a value
+            // of the same type as their operands
+            let hint = match op {
+                Binop::Add
+                | Binop::Subtract
+                | Binop::Multiply
+                | Binop::Divide
+                | Binop::TruncDivide
+                | Binop::Remainder
+                | Binop::BitwiseAnd
+                | Binop::BitwiseOr
+                | Binop::BitwiseXor
+                | Binop::ShiftLeft
+                | Binop::ShiftRight => hint,
+                _ => None,
+            };
+            let lhs = solve_constexpr(gscope, pending, lhs, hint.clone())?;
+
+            // 'and' and 'or' short circuit, so that e.g. a division by zero
+            // in an unused operand is not an error
+            match (op, &lhs) {
+                (Binop::LogicalAnd, ConstVal::Bool(false)) => return Ok(lhs),
+                (Binop::LogicalOr, ConstVal::Bool(true)) => return Ok(lhs),
+                _ => {}
+            }
+
+            let rhs = solve_constexpr(gscope, pending, rhs, hint)?;
+            eval_const_binop(&node.span, *op, lhs, rhs)
+        }
         _ => Err(Error {
             span: vec![node.span.clone()],
             message: format!("Expected constexpr"),
@@ -231,8 +573,9 @@ fn solve_constexpr_u32(
     node: &RawExpr,
     hint: Option<Type>,
 ) -> Result<u32, Error> {
-    match solve_constexpr(gscope, node, hint)? {
+    match solve_constexpr(gscope, &mut Pending::new(), node, hint)? {
         ConstVal::I32(x) if x >= 0 => Ok(x as u32),
+        ConstVal::I64(x) if x >= 0 && x <= u32::MAX as i64 => Ok(x as u32),
         _ => Err(Error {
             span: vec![node.span.clone()],
             message: format!("Expected u32 constexpr"),
@@ -240,6 +583,246 @@ fn solve_constexpr_u32(
     }
 }
 
+fn int_literal_i32(span: &Span, x: i64) -> Result<i32, Error> {
+    if x < i32::MIN as i64 || x > i32::MAX as i64 {
+        Err(Error {
+            span: vec![span.clone()],
+            message: format!("Integer literal {} does not fit in an i32", x),
+        })
+    } else {
+        Ok(x as i32)
+    }
+}
+
+/// Converts a constant to the given type, if the conversion does
+/// not lose any range (e.g. i32 to i64 or i64 to f64).
+/// If the conversion is not possible, the original value is returned
+/// as the error value
+fn widen_const(value: ConstVal, type_: &Type) -> Result<ConstVal, ConstVal> {
+    Ok(match (value, type_) {
+        (value, type_) if &value.type_() == type_ => value,
+        (ConstVal::I32(x), Type::I64) => ConstVal::I64(x as i64),
+        (ConstVal::I32(x), Type::F32) => ConstVal::F32(x as f32),
+        (ConstVal::I32(x), Type::F64) => ConstVal::F64(x as f64),
+        (ConstVal::I64(x), Type::F32) => ConstVal::F32(x as f32),
+        (ConstVal::I64(x), Type::F64) => ConstVal::F64(x as f64),
+        (ConstVal::F32(x), Type::F64) => ConstVal::F64(x as f64),
+        (value, _) => return Err(value),
+    })
+}
+
+/// Given two numeric types, returns the type both should be
+/// converted to before applying a binary operation
+fn const_binop_type(a: &Type, b: &Type) -> Option<Type> {
+    match (a, b) {
+        (Type::F64, Type::I32)
+        | (Type::F64, Type::I64)
+        | (Type::F64, Type::F32)
+        | (Type::F64, Type::F64)
+        | (Type::I32, Type::F64)
+        | (Type::I64, Type::F64)
+        | (Type::F32, Type::F64) => Some(Type::F64),
+        (Type::F32, Type::I32)
+        | (Type::F32, Type::I64)
+        | (Type::F32, Type::F32)
+        | (Type::I32, Type::F32)
+        | (Type::I64, Type::F32) => Some(Type::F32),
+        (Type::I64, Type::I32) | (Type::I64, Type::I64) | (Type::I32, Type::I64) => Some(Type::I64),
+        (Type::I32, Type::I32) => Some(Type::I32),
+        _ => None,
+    }
+}
+
+fn eval_const_unop(span: &Span, op: Unop, arg: ConstVal) -> Result<ConstVal, Error> {
+    let value = match (op, &arg) {
+        (Unop::Positive, ConstVal::I32(_))
+        | (Unop::Positive, ConstVal::I64(_))
+        | (Unop::Positive, ConstVal::F32(_))
+        | (Unop::Positive, ConstVal::F64(_)) => Some(arg.clone()),
+        (Unop::Negative, ConstVal::I32(x)) => x.checked_neg().map(ConstVal::I32),
+        (Unop::Negative, ConstVal::I64(x)) => x.checked_neg().map(ConstVal::I64),
+        (Unop::Negative, ConstVal::F32(x)) => Some(ConstVal::F32(-x)),
+        (Unop::Negative, ConstVal::F64(x)) => Some(ConstVal::F64(-x)),
+        (Unop::Not, ConstVal::Bool(x)) => Some(ConstVal::Bool(!x)),
+        _ => {
+            return Err(Error {
+                span: vec![span.clone()],
+                message: format!("Unsupported constexpr unop {:?}, {:?}", op, arg),
+            })
+        }
+    };
+    value.ok_or_else(|| Error {
+        span: vec![span.clone()],
+        message: format!("Overflow in constexpr {:?} {:?}", op, arg),
+    })
+}
+
+fn eval_const_binop(
+    span: &Span,
+    op: Binop,
+    lhs: ConstVal,
+    rhs: ConstVal,
+) -> Result<ConstVal, Error> {
+    let unsupported = |lhs: &ConstVal, rhs: &ConstVal| Error {
+        span: vec![span.clone()],
+        message: format!("Unsupported constexpr binop {:?}, {:?}, {:?}", op, lhs, rhs,),
+    };
+    match (op, &lhs, &rhs) {
+        (Binop::LogicalAnd, ConstVal::Bool(a), ConstVal::Bool(b)) => {
+            return Ok(ConstVal::Bool(*a && *b))
+        }
+        (Binop::LogicalOr, ConstVal::Bool(a), ConstVal::Bool(b)) => {
+            return Ok(ConstVal::Bool(*a || *b))
+        }
+        (Binop::Equal, ConstVal::Bool(a), ConstVal::Bool(b))
+        | (Binop::Is, ConstVal::Bool(a), ConstVal::Bool(b)) => return Ok(ConstVal::Bool(a == b)),
+        (Binop::NotEqual, ConstVal::Bool(a), ConstVal::Bool(b))
+        | (Binop::IsNot, ConstVal::Bool(a), ConstVal::Bool(b)) => {
+            return Ok(ConstVal::Bool(a != b))
+        }
+        (Binop::Equal, ConstVal::Str(a), ConstVal::Str(b)) => return Ok(ConstVal::Bool(a == b)),
+        (Binop::NotEqual, ConstVal::Str(a), ConstVal::Str(b)) => return Ok(ConstVal::Bool(a != b)),
+        _ => {}
+    }
+
+    // all remaining operations are on numbers
+    let type_ = match const_binop_type(&lhs.type_(), &rhs.type_()) {
+        Some(type_) => type_,
+        None => return Err(unsupported(&lhs, &rhs)),
+    };
+
+    // Like at runtime, '/' on integers always produces a float
+    let type_ = match (op, type_) {
+        (Binop::Divide, Type::I32) => Type::F32,
+        (Binop::Divide, Type::I64) => Type::F64,
+        (_, type_) => type_,
+    };
+    let lhs = widen_const(lhs, &type_).unwrap();
+    let rhs = widen_const(rhs, &type_).unwrap();
+
+    let value = match (&lhs, &rhs) {
+        (ConstVal::I32(a), ConstVal::I32(b)) => {
+            eval_const_int_binop(span, op, *a as i64, *b as i64, 32)?.map(|x| match x {
+                IntOrBool::Int(x) => ConstVal::I32(x as i32),
+                IntOrBool::Bool(x) => ConstVal::Bool(x),
+            })
+        }
+        (ConstVal::I64(a), ConstVal::I64(b)) => {
+            eval_const_int_binop(span, op, *a, *b, 64)?.map(|x| match x {
+                IntOrBool::Int(x) => ConstVal::I64(x),
+                IntOrBool::Bool(x) => ConstVal::Bool(x),
+            })
+        }
+        (ConstVal::F32(a), ConstVal::F32(b)) => eval_const_float_binop(op, *a as f64, *b as f64)
+            .map(|x| match x {
+                FloatOrBool::Float(x) => ConstVal::F32(x as f32),
+                FloatOrBool::Bool(x) => ConstVal::Bool(x),
+            }),
+        (ConstVal::F64(a), ConstVal::F64(b)) => {
+            eval_const_float_binop(op, *a, *b).map(|x| match x {
+                FloatOrBool::Float(x) => ConstVal::F64(x),
+                FloatOrBool::Bool(x) => ConstVal::Bool(x),
+            })
+        }
+        _ => panic!("Impossible constexpr binop operands {:?}, {:?}", lhs, rhs),
+    };
+    value.ok_or_else(|| unsupported(&lhs, &rhs))
+}
+
+enum IntOrBool {
+    Int(i64),
+    Bool(bool),
+}
+
+/// Evaluates an integer binop with the given bit width (32 or 64).
+/// Returns Ok(None) if the operation is not supported for integers
+fn eval_const_int_binop(
+    span: &Span,
+    op: Binop,
+    a: i64,
+    b: i64,
+    bits: u32,
+) -> Result<Option<IntOrBool>, Error> {
+    let (min, max) = if bits == 32 {
+        (i32::MIN as i64, i32::MAX as i64)
+    } else {
+        (i64::MIN, i64::MAX)
+    };
+    let value = match op {
+        Binop::Add => a.checked_add(b),
+        Binop::Subtract => a.checked_sub(b),
+        Binop::Multiply => a.checked_mul(b),
+        Binop::TruncDivide | Binop::Remainder => {
+            if b == 0 {
+                return Err(Error {
+                    span: vec![span.clone()],
+                    message: format!("Division by zero in constexpr"),
+                });
+            }
+            if let Binop::TruncDivide = op {
+                a.checked_div(b)
+            } else {
+                a.checked_rem(b)
+            }
+        }
+        Binop::BitwiseAnd => Some(a & b),
+        Binop::BitwiseOr => Some(a | b),
+        Binop::BitwiseXor => Some(a ^ b),
+        Binop::ShiftLeft | Binop::ShiftRight => {
+            if b < 0 || b >= bits as i64 {
+                return Err(Error {
+                    span: vec![span.clone()],
+                    message: format!("Shift amount {} out of range for i{}", b, bits),
+                });
+            }
+            // like wasm, bits shifted out past the width of the
+            // type are discarded
+            Some(match (op, bits) {
+                (Binop::ShiftLeft, 32) => ((a as i32) << b) as i64,
+                (Binop::ShiftLeft, _) => a << b,
+                _ => a >> b,
+            })
+        }
+        Binop::Equal | Binop::Is => return Ok(Some(IntOrBool::Bool(a == b))),
+        Binop::NotEqual | Binop::IsNot => return Ok(Some(IntOrBool::Bool(a != b))),
+        Binop::LessThan => return Ok(Some(IntOrBool::Bool(a < b))),
+        Binop::LessThanOrEqual => return Ok(Some(IntOrBool::Bool(a <= b))),
+        Binop::GreaterThan => return Ok(Some(IntOrBool::Bool(a > b))),
+        Binop::GreaterThanOrEqual => return Ok(Some(IntOrBool::Bool(a >= b))),
+        Binop::Divide | Binop::LogicalAnd | Binop::LogicalOr => return Ok(None),
+    };
+    match value {
+        Some(x) if x >= min && x <= max => Ok(Some(IntOrBool::Int(x))),
+        _ => Err(Error {
+            span: vec![span.clone()],
+            message: format!("Overflow in constexpr: {} {:?} {} (i{})", a, op, b, bits),
+        }),
+    }
+}
+
+enum FloatOrBool {
+    Float(f64),
+    Bool(bool),
+}
+
+fn eval_const_float_binop(op: Binop, a: f64, b: f64) -> Option<FloatOrBool> {
+    Some(match op {
+        Binop::Add => FloatOrBool::Float(a + b),
+        Binop::Subtract => FloatOrBool::Float(a - b),
+        Binop::Multiply => FloatOrBool::Float(a * b),
+        Binop::Divide => FloatOrBool::Float(a / b),
+        Binop::TruncDivide => FloatOrBool::Float((a / b).trunc()),
+        Binop::Remainder => FloatOrBool::Float(a % b),
+        Binop::Equal | Binop::Is => FloatOrBool::Bool(a == b),
+        Binop::NotEqual | Binop::IsNot => FloatOrBool::Bool(a != b),
+        Binop::LessThan => FloatOrBool::Bool(a < b),
+        Binop::LessThanOrEqual => FloatOrBool::Bool(a <= b),
+        Binop::GreaterThan => FloatOrBool::Bool(a > b),
+        Binop::GreaterThanOrEqual => FloatOrBool::Bool(a >= b),
+        _ => return None,
+    })
+}
+
 fn solve_stmt(lscope: &mut LocalScope, node: &RawStmt) -> Result<Stmt, Error> {
     match &node.data {
         RawStmtData::Block(nodes) => {
@@ -310,6 +893,12 @@ fn solve_stmt(lscope: &mut LocalScope, node: &RawStmt) -> Result<Stmt, Error> {
             })
         }
         RawStmtData::Return(enode) => {
+            if let Some(ReturnType::NoReturn) = lscope.return_type() {
+                return Err(Error {
+                    span: vec![node.span.clone()],
+                    message: format!("return is not allowed in a noreturn function"),
+                });
+            }
             if let Some(rtype) = lscope.return_type().cloned() {
                 let expr = solve_typed_expr(lscope, enode, &rtype)?;
                 Ok(Stmt {
@@ -326,9 +915,18 @@ fn solve_stmt(lscope: &mut LocalScope, node: &RawStmt) -> Result<Stmt, Error> {
         }
         RawStmtData::Expr(enode) => {
             let expr = solve_typed_expr(lscope, enode, &ReturnType::Void)?;
+
+            // control never continues past a call to a noreturn function,
+            // so for the purposes of checking the rest of the function,
+            // this is treated the same as a return statement
+            let return_state = if let ReturnType::NoReturn = &expr.type_ {
+                ReturnState::AlwaysReturns
+            } else {
+                ReturnState::NeverReturns
+            };
             Ok(Stmt {
                 span: node.span.clone(),
-                return_state: ReturnState::NeverReturns,
+                return_state,
                 data: StmtData::Expr(expr),
             })
         }
@@ -370,6 +968,16 @@ fn auto_cast(
 ) -> Result<Expr, Error> {
     match (&expr.type_, expected_type) {
         (a, b) if a == b => Ok(expr),
+
+        // noreturn expressions are allowed wherever a void expression
+        // is expected (e.g. as a statement), and since they never produce
+        // a value, they may also be used wherever any value is expected
+        (ReturnType::NoReturn, ReturnType::Void) => Ok(expr),
+        (ReturnType::NoReturn, ReturnType::Type(_)) => Ok(Expr {
+            span: expr.span,
+            type_: expected_type.clone(),
+            data: expr.data,
+        }),
         (ReturnType::Type(Type::Bool), ReturnType::Void)
         | (ReturnType::Type(Type::I32), ReturnType::Void)
         | (ReturnType::Type(Type::I64), ReturnType::Void)
@@ -379,6 +987,30 @@ fn auto_cast(
             type_: ReturnType::Void,
             data: ExprData::DropPrimitive(expr.into()),
         }),
+        // as in widen_const, so that mixed operands behave the same
+        // at runtime as they do in constants
+        (ReturnType::Type(Type::I32), ReturnType::Type(Type::I64)) => Ok(Expr {
+            span: expr.span.clone(),
+            type_: Type::I64.into(),
+            data: ExprData::Op(
+                TypedWasmOp {
+                    type_: Type::I64.wasm(),
+                    op: UntypedWasmOp::extend_i32_s,
+                },
+                vec![expr],
+            ),
+        }),
+        (ReturnType::Type(Type::F32), ReturnType::Type(Type::F64)) => Ok(Expr {
+            span: expr.span.clone(),
+            type_: Type::F64.into(),
+            data: ExprData::Op(
+                TypedWasmOp {
+                    type_: Type::F64.wasm(),
+                    op: UntypedWasmOp::promote_f32,
+                },
+                vec![expr],
+            ),
+        }),
         (ReturnType::Type(Type::I32), ReturnType::Type(Type::F32)) => Ok(Expr {
             span: expr.span.clone(),
             type_: Type::F32.into(),
@@ -466,10 +1098,16 @@ fn solve_expr(
                 type_: Type::F64.into(),
                 data: ExprData::F64(*x as f64),
             }),
+            // as with constants, literals too big for an i32 are i64s
+            None if int_literal_i32(&node.span, *x).is_err() => Ok(Expr {
+                span: node.span.clone(),
+                type_: Type::I64.into(),
+                data: ExprData::I64(*x),
+            }),
             _ => Ok(Expr {
                 span: node.span.clone(),
                 type_: Type::I32.into(),
-                data: ExprData::I32(*x as i32),
+                data: ExprData::I32(int_literal_i32(&node.span, *x)?),
             }),
         },
         RawExprData::Float(x) => match hint.as_ref().and_then(|h| h.value()) {
@@ -484,30 +1122,18 @@ fn solve_expr(
                 data: ExprData::F64(*x as f64),
             }),
         },
-        RawExprData::Str(string) => {
-            lscope.memory().borrow_mut().intern(string);
-            Ok(Expr {
-                span: node.span.clone(),
-                type_: Type::Str.into(),
-                data: ExprData::Str(StrPtr {
-                    memory: lscope.memory().clone(),
-                    string: string.clone(),
-                }),
-            })
-        }
+        RawExprData::Str(string) => Ok(const_expr(
+            lscope,
+            &node.span,
+            &ConstVal::Str(string.clone()),
+        )),
         RawExprData::GetVar(name) => match lscope.get_variable_or_constant(&node.span, name)? {
             VariableOrConstant::Variable(var) => Ok(Expr {
                 span: node.span.clone(),
                 type_: var.type_().clone().into(),
                 data: ExprData::GetVar(var),
             }),
-            VariableOrConstant::Constant(cnst) => Ok(Expr {
-                span: node.span.clone(),
-                type_: cnst.value.type_().clone().into(),
-                data: match &cnst.value {
-                    ConstVal::I32(value) => ExprData::I32(*value),
-                },
-            }),
+            VariableOrConstant::Constant(cnst) => Ok(const_expr(lscope, &node.span, &cnst.value)),
         },
         RawExprData::SetVar(name, enode) => {
             let var = lscope.get_variable(&node.span, name)?;
@@ -549,21 +1175,39 @@ fn solve_expr(
         }
         RawExprData::CallFunc(fname, raw_args) => {
             let func = lscope.get_callable(&node.span, fname)?;
-            if func.type_().parameters.len() != raw_args.len() {
+            let parameters = &func.type_().parameters;
+            let takes_caller = match parameters.last() {
+                Some((name, Type::Str)) => name.as_ref() == CALLER_PARAM,
+                _ => false,
+            };
+            let explicit_parameters = if takes_caller {
+                &parameters[..parameters.len() - 1]
+            } else {
+                &parameters[..]
+            };
+            if explicit_parameters.len() != raw_args.len() {
                 return Err(Error {
                     span: vec![node.span.clone(), func.span().clone()],
                     message: format!(
                         "Expected {} args, but got {}",
-                        func.type_().parameters.len(),
+                        explicit_parameters.len(),
                         raw_args.len()
                     ),
                 });
             }
             let mut args = Vec::new();
-            for (raw_arg, (_, arg_type)) in raw_args.iter().zip(&func.type_().parameters) {
+            for (raw_arg, (_, arg_type)) in raw_args.iter().zip(explicit_parameters) {
                 let arg = solve_typed_expr(lscope, raw_arg, &arg_type.clone().into())?;
                 args.push(arg);
             }
+            if takes_caller {
+                let location = format!("{}:{}", node.span.source.name, node.span.lineno());
+                args.push(const_expr(
+                    lscope,
+                    &node.span,
+                    &ConstVal::Str(location.into()),
+                ));
+            }
             Ok(Expr {
                 span: node.span.clone(),
                 type_: func.type_().return_type.clone(),
@@ -580,6 +1224,54 @@ fn solve_expr(
                 | (Unop::Positive, Type::I64)
                 | (Unop::Positive, Type::F32)
                 | (Unop::Positive, Type::F64) => Ok(arg),
+                (Unop::Negative, Type::I32) | (Unop::Negative, Type::I64) => {
+                    let type_ = arg.type_.value().unwrap().clone();
+                    let zero = Expr {
+                        span: node.span.clone(),
+                        type_: type_.clone().into(),
+                        data: if let Type::I32 = type_ {
+                            ExprData::I32(0)
+                        } else {
+                            ExprData::I64(0)
+                        },
+                    };
+                    Ok(Expr {
+                        span: node.span.clone(),
+                        type_: type_.clone().into(),
+                        data: ExprData::Op(
+                            TypedWasmOp {
+                                op: UntypedWasmOp::sub,
+                                type_: type_.wasm(),
+                            },
+                            vec![zero, arg],
+                        ),
+                    })
+                }
+                (Unop::Negative, Type::F32) | (Unop::Negative, Type::F64) => {
+                    let type_ = arg.type_.value().unwrap().clone();
+                    Ok(Expr {
+                        span: node.span.clone(),
+                        type_: type_.clone().into(),
+                        data: ExprData::Op(
+                            TypedWasmOp {
+                                op: UntypedWasmOp::neg,
+                                type_: type_.wasm(),
+                            },
+                            vec![arg],
+                        ),
+                    })
+                }
+                (Unop::Not, Type::Bool) => Ok(Expr {
+                    span: node.span.clone(),
+                    type_: Type::Bool.into(),
+                    data: ExprData::Op(
+                        TypedWasmOp {
+                            op: UntypedWasmOp::eqz,
+                            type_: Type::Bool.wasm(),
+                        },
+                        vec![arg],
+                    ),
+                }),
                 (op, type_) => Err(Error {
                     span: vec![node.span.clone()],
                     message: format!("{:?} {} op combo not supported", op, type_),
@@ -606,12 +1298,93 @@ fn solve_expr(
                     ),
                 })
             }
+            Binop::LogicalAnd | Binop::LogicalOr => {
+                let arg1 = solve_typed_expr(lscope, arg1, &Type::Bool.into())?;
+                let arg2 = solve_typed_expr(lscope, arg2, &Type::Bool.into())?;
+                Ok(Expr {
+                    span: node.span.clone(),
+                    type_: Type::Bool.into(),
+                    data: if let Binop::LogicalAnd = op {
+                        ExprData::And(arg1.into(), arg2.into())
+                    } else {
+                        ExprData::Or(arg1.into(), arg2.into())
+                    },
+                })
+            }
+            Binop::Equal | Binop::NotEqual => {
+                let arg1 = solve_value_expr(lscope, arg1, None)?;
+                let arg2 = solve_value_expr(lscope, arg2, arg1.type_.value())?;
+                let type_ = match (arg1.type_.value().unwrap(), arg2.type_.value().unwrap()) {
+                    (Type::Bool, Type::Bool) => Type::Bool,
+                    (Type::F64, _) | (_, Type::F64) => Type::F64,
+                    (Type::F32, _) | (_, Type::F32) => Type::F32,
+                    (Type::I64, _) | (_, Type::I64) => Type::I64,
+                    (Type::I32, Type::I32) => Type::I32,
+                    (a, b) => {
+                        return Err(Error {
+                            span: vec![node.span.clone()],
+                            message: format!("{:?} not supported for {} and {}", op, a, b),
+                        })
+                    }
+                };
+                let arg1 = auto_cast(lscope, arg1, &type_.clone().into())?;
+                let arg2 = auto_cast(lscope, arg2, &type_.clone().into())?;
+                Ok(Expr {
+                    span: node.span.clone(),
+                    type_: Type::Bool.into(),
+                    data: ExprData::Op(
+                        TypedWasmOp {
+                            op: match type_ {
+                                Type::F32 | Type::F64 => {
+                                    UntypedWasmOp::from_binop_for_float(*op).unwrap()
+                                }
+                                _ => UntypedWasmOp::from_binop_for_int(*op).unwrap(),
+                            },
+                            type_: type_.wasm(),
+                        },
+                        vec![arg1, arg2],
+                    ),
+                })
+            }
+            Binop::BitwiseAnd
+            | Binop::BitwiseOr
+            | Binop::BitwiseXor
+            | Binop::ShiftLeft
+            | Binop::ShiftRight => {
+                let arg1 = solve_value_expr(lscope, arg1, None)?;
+                let arg2 = solve_value_expr(lscope, arg2, arg1.type_.value())?;
+                let type_ = match (arg1.type_.value().unwrap(), arg2.type_.value().unwrap()) {
+                    (Type::I64, Type::I32) | (Type::I32, Type::I64) | (Type::I64, Type::I64) => {
+                        Type::I64
+                    }
+                    (Type::I32, Type::I32) => Type::I32,
+                    (a, b) => {
+                        return Err(Error {
+                            span: vec![node.span.clone()],
+                            message: format!("{:?} not supported for {} and {}", op, a, b),
+                        })
+                    }
+                };
+                let arg1 = auto_cast(lscope, arg1, &type_.clone().into())?;
+                let arg2 = auto_cast(lscope, arg2, &type_.clone().into())?;
+                Ok(Expr {
+                    span: node.span.clone(),
+                    type_: type_.clone().into(),
+                    data: ExprData::Op(
+                        TypedWasmOp {
+                            op: UntypedWasmOp::from_binop_for_int(*op).unwrap(),
+                            type_: type_.wasm(),
+                        },
+                        vec![arg1, arg2],
+                    ),
+                })
+            }
             Binop::LessThan
             | Binop::LessThanOrEqual
             | Binop::GreaterThan
             | Binop::GreaterThanOrEqual => {
                 let arg1 = solve_value_expr(lscope, arg1, None)?;
-                let arg2 = solve_value_expr(lscope, arg2, None)?;
+                let arg2 = solve_value_expr(lscope, arg2, arg1.type_.value())?;
                 let type_ = match (arg1.type_.value().unwrap(), arg2.type_.value().unwrap()) {
                     (Type::F64, _) | (_, Type::F64) => Type::F64,
                     (Type::F32, _) | (_, Type::F32) => Type::F32,
@@ -764,7 +1537,7 @@ fn solve_expr(
                         type_: intermediate_type.clone().into(),
                         data: ExprData::Op(
                             TypedWasmOp {
-                                op: UntypedWasmOp::div_u,
+                                op: UntypedWasmOp::div_s,
                                 type_: intermediate_type.wasm(),
                             },
                             vec![arg1, arg2],
@@ -773,17 +1546,16 @@ fn solve_expr(
                     it => panic!("Impossible truncdiv intermediate type: {}", it),
                 }
             }
-            _ => panic!("Operator {:?} not yet supported", op),
         },
         RawExprData::Asm(raw_args, type_, code) => {
             let mut args = Vec::new();
             for raw_arg in raw_args {
                 args.push(solve_value_expr(lscope, raw_arg, None)?);
             }
-            let type_ = lscope.resolve_type(type_)?;
+            let type_ = lscope.resolve_return_type(type_)?;
             Ok(Expr {
                 span: node.span.clone(),
-                type_: type_.clone().into(),
+                type_: type_.clone(),
                 data: ExprData::Asm(args, type_, code.clone()),
             })
         }
@@ -838,3 +1610,266 @@ fn solve_expr(
         }
     }
 }
+
+/// Builds the expression for the given constant value
+fn const_expr(lscope: &mut LocalScope, span: &Span, value: &ConstVal) -> Expr {
+    let data = match value {
+        ConstVal::Bool(x) => ExprData::Bool(*x),
+        ConstVal::I32(x) => ExprData::I32(*x),
+        ConstVal::I64(x) => ExprData::I64(*x),
+        ConstVal::F32(x) => ExprData::F32(*x),
+        ConstVal::F64(x) => ExprData::F64(*x),
+        ConstVal::Str(string) => {
+            lscope.memory().borrow_mut().intern(string);
+            ExprData::Str(StrPtr {
+                memory: lscope.memory().clone(),
+                string: string.clone(),
+            })
+        }
+    };
+    Expr {
+        span: span.clone(),
+        type_: value.type_().into(),
+        data,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+    use crate::Source;
+
+    fn solve_constants(text: &str) -> Result<GlobalScope, String> {
+        let source = Rc::new(Source {
+            name: "[test]".into(),
+            module: "test".into(),
+            data: text.into(),
+        });
+        let file = parse(&source).map_err(|e| e.format())?;
+        let mut gscope = GlobalScope::new();
+        gscope.add_module(file.module.clone());
+        gscope.set_module(file.module.clone());
+        let mut pending = Pending::new();
+        for node in &file.constants {
+            pending
+                .nodes
+                .insert((file.module.clone(), node.name.clone()), node);
+        }
+        for node in &file.constants {
+            let key = (file.module.clone(), node.name.clone());
+            if pending.nodes.contains_key(&key) {
+                solve_constant(&mut gscope, &mut pending, file.module.clone(), node)
+                    .map_err(|e| e.message)?;
+            }
+        }
+        Ok(gscope)
+    }
+
+    /// Solves a program made of (module, text) pairs
+    fn solve_modules(modules: &[(&str, &str)]) -> Result<Program, String> {
+        let mut files = Vec::new();
+        for (module, text) in modules {
+            let source = Rc::new(Source {
+                name: format!("[{}]", module).into(),
+                module: (*module).into(),
+                data: (*text).into(),
+            });
+            files.push(parse(&source).map_err(|e| e.format())?);
+        }
+        solve(&files, &RunConfig::default()).map_err(|e| e.message)
+    }
+
+    fn value(gscope: &GlobalScope, name: &str) -> ConstVal {
+        match gscope.get(name) {
+            Some(Item::Constant(c)) => c.value.clone(),
+            _ => panic!("constant {} not found", name),
+        }
+    }
+
+    #[test]
+    fn constants_in_any_order() {
+        let gscope = solve_constants(
+            r#"
+            const C = B * 2
+            const B = A + 1
+            const A = 20
+            const S = "hello"
+            const T = S == "hello" and not (A < 0)
+            "#,
+        )
+        .unwrap();
+        assert_eq!(value(&gscope, "C"), ConstVal::I32(42));
+        assert_eq!(value(&gscope, "S"), ConstVal::Str("hello".into()));
+        assert_eq!(value(&gscope, "T"), ConstVal::Bool(true));
+    }
+
+    #[test]
+    fn constant_types() {
+        let gscope = solve_constants(
+            r#"
+            const BIG i64 = 5000000000
+            const INFERRED = 5000000000
+            const WIDE i64 = 7
+            const HALF = 1 / 2
+            const MIXED = 1.5 + 2
+            const BITS = 1 << 4 | 3 ^ 1
+            "#,
+        )
+        .unwrap();
+        assert_eq!(value(&gscope, "BIG"), ConstVal::I64(5000000000));
+        assert_eq!(value(&gscope, "INFERRED"), ConstVal::I64(5000000000));
+        assert_eq!(value(&gscope, "WIDE"), ConstVal::I64(7));
+        assert_eq!(value(&gscope, "HALF"), ConstVal::F32(0.5));
+        assert_eq!(value(&gscope, "MIXED"), ConstVal::F64(3.5));
+        assert_eq!(value(&gscope, "BITS"), ConstVal::I32(18));
+    }
+
+    #[test]
+    fn big_literals_are_i64() {
+        let program = solve_modules(&[("app", "var big = 5000000000\nfn Main() {}")]).unwrap();
+        assert_eq!(program.globals[0].type_, Type::I64);
+        let message = |text| solve_modules(&[("app", text)]).err().unwrap();
+        assert!(message("var small i32 = 5000000000\nfn Main() {}").contains("does not fit"));
+    }
+
+    #[test]
+    fn constant_errors() {
+        let message = |text| solve_constants(text).err().unwrap();
+        assert!(message("const X = 2147483647 + 1").starts_with("Overflow"));
+        assert!(message("const X i32 = 3000000000").contains("does not fit"));
+        assert!(message("const X = 1 // 0").starts_with("Division by zero"));
+        assert!(message("const X = Y\nconst Y = X").contains("depends on itself"));
+        assert!(message("const X i32 = 1.5").starts_with("Expected i32"));
+    }
+
+    #[test]
+    fn modules() {
+        let util = r#"
+            pub const K = 40
+            const HIDDEN = 1
+            pub fn add(a i32, b i32) i32 {
+                return a + b + K + HIDDEN
+            }
+            "#;
+        let program = solve_modules(&[
+            ("app.util", util),
+            (
+                "app",
+                r#"
+                import app.util
+                import app.util as u
+                const X = u.K + 2
+                fn Main() {
+                    util.add(X, u.K)
+                }
+                "#,
+            ),
+        ])
+        .unwrap();
+        let main = program.main.unwrap();
+        assert_eq!(main.module.as_ref(), "app");
+        assert_eq!(main.wasm_name(), "f/app.Main");
+
+        let message = |app| {
+            solve_modules(&[("app.util", util), ("app", app)])
+                .err()
+                .unwrap()
+        };
+        assert!(message("import nope\nfn Main() {}").contains("module nope not found"));
+        assert!(message("fn Main() {\nadd(1, 2)\n}").contains("not found"));
+        assert!(message("import app.util\nfn Main() {\nutil.sub(1, 2)\n}").contains("not found"));
+        assert!(message("fn NotMain() {}").contains("Main function not found"));
+        assert_eq!(
+            message("import app.util\nconst X = util.HIDDEN\nfn Main() {}"),
+            "util.HIDDEN is private to module app.util",
+        );
+    }
+
+    #[test]
+    fn global_init_order() {
+        let program = solve_modules(&[(
+            "app",
+            r#"
+            var a = b + f()
+            var b = 1
+            var c = 2
+            fn f() i32 {
+                return c
+            }
+            fn Main() {}
+            "#,
+        )])
+        .unwrap();
+        let names: Vec<_> = program.globals.iter().map(|g| g.name.as_ref()).collect();
+        assert_eq!(names, vec!["b", "c", "a"]);
+
+        let message = |text| solve_modules(&[("app", text)]).err().unwrap();
+        assert!(message("var a = a + 1\nfn Main() {}").contains("read in its own initializer"));
+        assert!(message("var a = b\nvar b = a\nfn Main() {}").contains("(a -> b -> a)"));
+        assert!(
+            message("var a = f()\nvar b = a\nfn f() i32 {\nreturn b\n}\nfn Main() {}")
+                .contains("form a cycle (a -> b -> a)")
+        );
+    }
+
+    #[test]
+    fn exports() {
+        let program = solve_modules(&[(
+            "app",
+            r#"
+            export fn f() {}
+            export "g2" fn g() {}
+            fn h() {}
+            fn Main() {}
+            "#,
+        )])
+        .unwrap();
+        let exports: Vec<_> = program
+            .funcs
+            .iter()
+            .map(|f| f.export.as_ref().map(|e| e.as_ref()))
+            .collect();
+        assert_eq!(exports, vec![Some("f"), Some("g2"), None, Some("Main")]);
+
+        let message = |text| solve_modules(&[("app", text)]).err().unwrap();
+        assert!(
+            message("export fn f() {}\nexport \"f\" fn g() {}\nfn Main() {}")
+                .contains("exported more than once")
+        );
+        assert!(message("export \"run\" fn Main() {}").contains("cannot be exported"));
+
+        // Main may return the exit status
+        assert!(solve_modules(&[("app", "fn Main() i32 {\nreturn 3\n}")]).is_ok());
+        assert!(message("fn Main() str {\nreturn \"\"\n}").contains("()void or ()i32"));
+        assert!(message("fn Main(x i32) {}").contains("()void or ()i32"));
+    }
+
+    #[test]
+    fn extern_imports() {
+        let program = solve_modules(&[(
+            "app",
+            r#"
+            extern "env" "counter" var counter i32
+            var doubled = counter * 2
+            fn Main() {}
+            "#,
+        )])
+        .unwrap();
+        let imports: Vec<_> = program.globals.iter().map(|g| g.import.is_some()).collect();
+        assert_eq!(imports, vec![true, false]);
+
+        let program = solve_modules(&[("app", "extern \"env\" \"mem\" memory\nfn Main() {}")]);
+        let path = ("env".into(), "mem".into());
+        assert_eq!(program.unwrap().memory_import, Some(path));
+
+        let message = |text| solve_modules(&[("app", text)]).err().unwrap();
+        assert!(
+            message("extern \"a\" \"m\" memory\nextern \"b\" \"m\" memory\nfn Main() {}")
+                .contains("may only be imported once")
+        );
+        // (nothing could call through a table)
+        assert!(solve_modules(&[("app", "extern \"env\" \"t\" table\nfn Main() {}")]).is_err());
+        assert!(message("extern \"a\" \"s\" var s str").contains("cannot have type str"));
+    }
+}
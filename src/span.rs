@@ -3,6 +3,10 @@ use std::rc::Rc;
 
 pub struct Source {
     pub name: Rc<str>,
+
+    /// name of the module the source belongs to
+    /// (e.g. "foo.bar" for the directory foo/bar)
+    pub module: Rc<str>,
     pub data: Rc<str>,
 }
 
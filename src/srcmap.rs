@@ -0,0 +1,432 @@
+//! Mapping locations in the generated wasm back to wac source
+//!
+//! cgen records the span of every instruction it emits, function by
+//! function. Runtimes report traps as byte offsets into the wasm binary,
+//! so to find the span for an offset, the code section is decoded just
+//! far enough to tell which instruction of which function it falls on.
+//!
+//! The source map can also be written into the binary itself as a custom
+//! section (see SourceMap::encode), for tools other than the runner.
+use crate::wasm::name;
+use crate::wasm::uleb;
+use crate::Source;
+use crate::Span;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// The name of the custom section the source map is written to
+pub const SOURCE_MAP_SECTION: &str = "wac.sourcemap";
+
+/// The spans of the instructions of each function defined in a module
+#[derive(Default)]
+pub struct SourceMap {
+    /// in the order the functions are defined (i.e. excluding imports),
+    /// which in debug builds is also the order of their ids
+    pub funcs: Vec<FuncMap>,
+
+    /// in debug builds, the span of each call site, where call site id
+    /// i is at index i - 1 (as id 0 is for calls from the host)
+    pub call_sites: Vec<Span>,
+}
+
+pub struct FuncMap {
+    /// the function's wac name (e.g. "app.Main")
+    pub name: Rc<str>,
+
+    /// the span of each instruction in the function body, in order
+    /// (None for instructions not tied to any particular source)
+    pub spans: Vec<Option<Span>>,
+}
+
+/// Where some offset in a wasm binary is in the wac source
+pub struct Location {
+    pub func: Rc<str>,
+    pub span: Option<Span>,
+}
+
+impl SourceMap {
+    /// Finds the instruction at the given offset in wasm,
+    /// which must be the binary the source map was generated for
+    pub fn locate(&self, wasm: &[u8], offset: usize) -> Option<Location> {
+        let (func_index, instr_index) = find_instruction(wasm, offset)?;
+        let func = self.funcs.get(func_index)?;
+        Some(Location {
+            func: func.name.clone(),
+            span: func.spans.get(instr_index).cloned().flatten(),
+        })
+    }
+
+    /// Encodes the source map as the content of a custom section, given
+    /// the offset of each instruction (from Module::encode_with_offsets)
+    ///
+    /// The section lists the names of the source files, followed by an
+    /// entry for every instruction whose location differs from that of
+    /// the one before it:
+    ///
+    /// ```text
+    ///     [offset][file index + 1][line][column]
+    /// ```
+    ///
+    /// where a file index + 1 of 0 means the instructions that follow have
+    /// no location (and then the line and column are left out). Lines and
+    /// columns start at 1, and all numbers are unsigned LEB128.
+    pub fn encode(&self, offsets: &[Vec<u32>]) -> Vec<u8> {
+        let mut files: Vec<&Rc<Source>> = Vec::new();
+        let mut file_ids = HashMap::new();
+        let mut line_starts = HashMap::new();
+        let mut entries = Vec::new();
+        let mut entry_count = 0;
+        let mut last = None;
+        for (func, offsets) in self.funcs.iter().zip(offsets) {
+            for (span, offset) in func.spans.iter().zip(offsets) {
+                let location = span
+                    .as_ref()
+                    .map(|span| (Rc::as_ptr(&span.source), span.main));
+                if location == last {
+                    continue;
+                }
+                last = location;
+                entry_count += 1;
+                uleb(&mut entries, *offset as u64);
+                let span = match span {
+                    Some(span) => span,
+                    None => {
+                        entries.push(0);
+                        continue;
+                    }
+                };
+                let source = Rc::as_ptr(&span.source);
+                let file_id = *file_ids.entry(source).or_insert_with(|| {
+                    files.push(&span.source);
+                    files.len()
+                });
+                let starts = line_starts.entry(source).or_insert_with(|| {
+                    let data = &span.source.data;
+                    let newlines = data.match_indices('\n').map(|(i, _)| i + 1);
+                    std::iter::once(0).chain(newlines).collect::<Vec<_>>()
+                });
+                let line = match starts.binary_search(&span.main) {
+                    Ok(i) => i + 1,
+                    Err(i) => i,
+                };
+                uleb(&mut entries, file_id as u64);
+                uleb(&mut entries, line as u64);
+                uleb(&mut entries, (span.main - starts[line - 1] + 1) as u64);
+            }
+        }
+
+        let mut out = Vec::new();
+        uleb(&mut out, files.len() as u64);
+        for file in files {
+            name(&mut out, &file.name);
+        }
+        uleb(&mut out, entry_count);
+        out.extend(entries);
+        out
+    }
+}
+
+const SECTION_CODE: u8 = 10;
+
+/// Finds the (defined function index, instruction index) of the
+/// instruction starting at the given offset
+fn find_instruction(wasm: &[u8], offset: usize) -> Option<(usize, usize)> {
+    let mut reader = Reader { wasm, pos: 8 };
+    while reader.pos < wasm.len() {
+        let id = reader.byte()?;
+        let size = reader.leb()? as usize;
+        let end = reader.pos + size;
+        if id == SECTION_CODE && offset < end {
+            return find_in_code(&mut reader, offset);
+        }
+        reader.pos = end;
+    }
+    None
+}
+
+fn find_in_code(reader: &mut Reader, offset: usize) -> Option<(usize, usize)> {
+    let count = reader.leb()? as usize;
+    for func_index in 0..count {
+        let size = reader.leb()? as usize;
+        let end = reader.pos + size;
+        if offset >= end {
+            reader.pos = end;
+            continue;
+        }
+        let local_groups = reader.leb()?;
+        for _ in 0..local_groups {
+            reader.leb()?;
+            reader.byte()?;
+        }
+        let mut instr_index = 0;
+        while reader.pos < end {
+            if reader.pos == offset {
+                return Some((func_index, instr_index));
+            }
+            reader.instruction()?;
+            instr_index += 1;
+        }
+        return None;
+    }
+    None
+}
+
+struct Reader<'a> {
+    wasm: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn byte(&mut self) -> Option<u8> {
+        let byte = *self.wasm.get(self.pos)?;
+        self.pos += 1;
+        Some(byte)
+    }
+
+    /// reads an unsigned LEB128 number
+    /// (signed ones can be skipped with this too, as they take
+    /// up the same bytes)
+    fn leb(&mut self) -> Option<u64> {
+        let mut result = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.byte()?;
+            if shift < 64 {
+                result |= ((byte & 0x7f) as u64) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Some(result);
+            }
+        }
+    }
+
+    fn skip(&mut self, n: usize) {
+        self.pos += n;
+    }
+
+    /// skips over a single instruction, along with its immediates
+    fn instruction(&mut self) -> Option<()> {
+        match self.byte()? {
+            // block, loop and if (with a block type), br, br_if,
+            // call, local/global get/set/tee, table get/set,
+            // i32.const, i64.const, memory.size and memory.grow,
+            // ref.null and ref.func
+            0x02..=0x04
+            | 0x0c
+            | 0x0d
+            | 0x10
+            | 0x20..=0x26
+            | 0x41
+            | 0x42
+            | 0x3f
+            | 0x40
+            | 0xd0
+            | 0xd2 => {
+                self.leb()?;
+            }
+            // br_table
+            0x0e => {
+                let count = self.leb()?;
+                for _ in 0..=count {
+                    self.leb()?;
+                }
+            }
+            // call_indirect
+            0x11 => {
+                self.leb()?;
+                self.leb()?;
+            }
+            // typed select
+            0x1c => {
+                let count = self.leb()?;
+                self.skip(count as usize);
+            }
+            // loads and stores (alignment and offset)
+            0x28..=0x3e => {
+                self.leb()?;
+                self.leb()?;
+            }
+            0x43 => self.skip(4),
+            0x44 => self.skip(8),
+            // saturating truncations and bulk memory operations
+            0xfc => match self.leb()? {
+                0..=7 => {}
+                8 | 12 | 14 => {
+                    self.leb()?;
+                    self.leb()?;
+                }
+                10 => self.skip(2),
+                9 | 11 | 13 | 15..=17 => {
+                    self.leb()?;
+                }
+                _ => return None,
+            },
+            // SIMD and other proposals we never generate
+            0xfd | 0xfe => return None,
+            _ => {}
+        }
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_config;
+    use crate::test_sources;
+    use crate::Backend;
+    use crate::BackendInstance;
+    use crate::BackendKind;
+    use crate::Compiled;
+    use crate::CompiledModule;
+    use crate::Engine;
+    use crate::EngineError;
+    use crate::Error;
+    use crate::HostConfig;
+    use crate::RunConfig;
+    use crate::Stop;
+    use crate::TEST_BACKENDS;
+
+    #[test]
+    fn instructions_in_code() {
+        #[rustfmt::skip]
+        let wasm = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00,
+            // type section: () -> i32
+            0x01, 0x05, 0x01, 0x60, 0x00, 0x01, 0x7f,
+            // function section
+            0x03, 0x02, 0x01, 0x00,
+            // code section: i32.const 10, i32.const 0, i32.div_s, end
+            0x0a, 0x09, 0x01, 0x07, 0x00, 0x41, 0x0a, 0x41, 0x00, 0x6d, 0x0b,
+        ];
+        assert_eq!(find_instruction(&wasm, 24), Some((0, 0)));
+        assert_eq!(find_instruction(&wasm, 28), Some((0, 2)));
+        assert_eq!(find_instruction(&wasm, 29), Some((0, 3)));
+        // in the middle of an instruction, or outside of the code
+        assert_eq!(find_instruction(&wasm, 25), None);
+        assert_eq!(find_instruction(&wasm, 12), None);
+    }
+
+    #[test]
+    fn traps_point_at_the_calls_that_led_there() {
+        let sources = test_sources(
+            r#"
+extern "wac" "stdout_write" write(len i32, ptr i32) i32
+
+export fn Outer(len i32) i32 {
+    return Inner(len)
+}
+
+fn Inner(len i32) i32 {
+    return write(len, -1)
+}
+
+export fn Quotient(a i32, b i32) i32 {
+    return Divide(a, b)
+}
+
+fn Divide(a i32, b i32) i32 {
+    return a // b
+}
+"#,
+        );
+        for &backend in TEST_BACKENDS {
+            let engine = Engine::new(RunConfig {
+                debug: true,
+                ..test_config(backend)
+            });
+            let module = engine.compile(sources.clone()).unwrap();
+            let mut instance = engine.instantiate(&module).unwrap();
+            let lines = |error: EngineError| match error {
+                EngineError::Runtime(error) => {
+                    let lines: Vec<_> = error.span.iter().map(|span| span.lineno()).collect();
+                    (lines, error.message)
+                }
+                error => panic!("{:?} on {:?}", error, backend),
+            };
+
+            // a trap in the host is at the call of the import
+            let (spans, message) = lines(instance.call::<_, i32>("Outer", (16,)).unwrap_err());
+            assert_eq!(spans, vec![9, 5], "{} on {:?}", message, backend);
+            assert!(message.contains("in app.Inner"), "{}", message);
+            assert!(message.contains("app.Outer"), "{}", message);
+
+            // (wasm traps are located by their offset, if the engine has it)
+            let (spans, message) = lines(instance.call::<_, i32>("Quotient", (1, 0)).unwrap_err());
+            assert_eq!(spans.last(), Some(&13), "{} on {:?}", message, backend);
+        }
+    }
+
+    #[test]
+    fn scribbled_frames_are_skipped() {
+        // the shadow stack is in the guest's memory, so the ids on it
+        // may be anything by the time the host reads them
+        let sources = test_sources(&format!(
+            r#"
+export fn Scribble(b i32) i32 {{
+    var top = $read4({}) - 8
+    $write4(top, 100000)
+    $write4(top, 100000, offset: 4)
+    return 1 // b
+}}
+"#,
+            crate::SHADOW_SP_PTR
+        ));
+        for &backend in TEST_BACKENDS {
+            let engine = Engine::new(RunConfig {
+                debug: true,
+                ..test_config(backend)
+            });
+            let module = engine.compile(sources.clone()).unwrap();
+            let mut instance = engine.instantiate(&module).unwrap();
+            assert!(matches!(
+                instance.call::<_, i32>("Scribble", (0,)),
+                Err(EngineError::Runtime(_))
+            ));
+        }
+    }
+
+    /// A backend whose modules trap as they are instantiated, at the
+    /// offset of the last instruction in the code
+    struct TrapOnStart;
+
+    struct TrapOnStartModule(Vec<u8>);
+
+    impl Backend for TrapOnStart {
+        fn compile(&self, code: &[u8]) -> Result<Box<dyn CompiledModule>, Error> {
+            Ok(Box::new(TrapOnStartModule(code.to_vec())))
+        }
+    }
+
+    impl CompiledModule for TrapOnStartModule {
+        fn instantiate(&self, _host: &HostConfig) -> Result<Box<dyn BackendInstance>, Stop> {
+            let code = &self.0;
+            let offset = (0..code.len())
+                .rev()
+                .find(|&offset| find_instruction(code, offset).is_some());
+            Err(Stop::Trap("unreachable".into(), offset))
+        }
+    }
+
+    #[test]
+    fn initializer_traps_are_located() {
+        let config = RunConfig {
+            optimize: None,
+            ..test_config(BackendKind::Wasmer)
+        };
+        let compiled =
+            Compiled::with_backend(test_sources("var x = 1"), config, &TrapOnStart).unwrap();
+        match compiled.try_instantiate(&HostConfig::default()) {
+            Err(Stop::Trapped(error)) => {
+                assert!(
+                    error.message.starts_with("Trap (unreachable) in "),
+                    "{}",
+                    error.message
+                )
+            }
+            Err(_) => panic!("the trap should have been located"),
+            Ok(_) => panic!("the module should have trapped"),
+        }
+    }
+}
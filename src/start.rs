@@ -1,25 +1,141 @@
-use crate::run;
 use crate::translate;
+use crate::translate_to_bindings;
 use crate::translate_to_wasm;
+use crate::BackendKind;
+use crate::Compiled;
 use crate::RunConfig;
 use crate::Source;
+use crate::Stop;
+use crate::Target;
 use std::path::Path;
 use std::rc::Rc;
+use std::time::Duration;
+
+/// The status wac exits with when the program traps
+/// (compile errors, and other failures to run it, exit with 1)
+pub const TRAP_EXIT_CODE: i32 = 134;
+
+/// The longest --time-limit, in seconds (about 30 years)
+const MAX_TIME_LIMIT_SECS: f64 = 1e9;
+
+/// The stack size of the thread everything runs on, which is mostly
+/// for the interpreter, which may use half of it (see RunConfig::interp_stack)
+const STACK_SIZE: usize = 1 << 30;
 
 pub fn main() {
+    // the memory is only committed as it is used
+    let main = std::thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(run_main)
+        .unwrap();
+    if main.join().is_err() {
+        std::process::exit(101);
+    }
+}
+
+fn run_main() {
     let mut mode = Mode::Run;
     let mut sources = Vec::<Rc<Source>>::new();
-    let mut run_config = RunConfig::default();
+    let mut run_config = RunConfig {
+        interp_stack: STACK_SIZE / 2,
+        ..RunConfig::default()
+    };
+    let mut program_name = String::from("wac");
+    // the program sees none of the host's environment unless asked
+    let mut env_names = Vec::<String>::new();
+    let mut forward_env = false;
     // let mut test_prefix = String::new();
-    for arg in std::env::args().skip(1) {
+    let mut args = std::env::args().skip(1).peekable();
+    if args.peek().map(String::as_str) == Some("bindgen") {
+        args.next();
+        mode = Mode::Bindgen;
+        // Main is optional, as the bindings are for embedding the program
+        run_config.library = true;
+    }
+    while let Some(arg) = args.next() {
         let arg: &str = &arg;
         match arg {
+            "--" => {
+                // the rest are for the program itself
+                run_config.host.args.extend(args.by_ref());
+            }
             "-c" => {
                 mode = Mode::CompileOnly;
             }
             "-w" => {
                 mode = Mode::Wasm;
             }
+            "--lib" => {
+                run_config.library = true;
+            }
+            "--export-memory" => {
+                run_config.export_memory = true;
+            }
+            "--debug" => {
+                run_config.debug = true;
+            }
+            "--interp" => {
+                run_config.backend = BackendKind::Interpreter;
+            }
+            "--heap-stats" => {
+                run_config.heap_stats = true;
+            }
+            "--leak-check" => {
+                // (debug builds can tell where leaked objects were allocated)
+                run_config.leak_check = true;
+                run_config.debug = true;
+            }
+            "--debug-alloc" => {
+                run_config.debug_alloc = true;
+                run_config.debug = true;
+            }
+            "--elide-refcounts" => {
+                run_config.elide_refcounts = true;
+            }
+            "--env" => {
+                forward_env = true;
+            }
+            _ if arg.starts_with("--env=") => {
+                env_names.push(arg["--env=".len()..].to_owned());
+            }
+            _ if arg.starts_with("--dir=") => {
+                run_config.host.dir = Some(arg["--dir=".len()..].into());
+            }
+            _ if arg.starts_with("--fuel=") => {
+                let text = &arg["--fuel=".len()..];
+                match text.parse() {
+                    Ok(fuel) => run_config.fuel = Some(fuel),
+                    Err(_) => panic!("Invalid fuel {:?} (must be a call count)", text),
+                }
+            }
+            _ if arg.starts_with("--time-limit=") => {
+                // (for the initializers, and then again for Main)
+                let text = &arg["--time-limit=".len()..];
+                // (bounded, as Duration panics on overflow)
+                match text.parse() {
+                    Ok(secs) if (0.0..=MAX_TIME_LIMIT_SECS).contains(&secs) => {
+                        run_config.time_limit = Some(Duration::from_secs_f64(secs))
+                    }
+                    _ => panic!(
+                        "Invalid time limit {:?} (must be in seconds, from 0 to {})",
+                        text, MAX_TIME_LIMIT_SECS
+                    ),
+                }
+            }
+            _ if arg.starts_with("--max-pages=") => {
+                let text = &arg["--max-pages=".len()..];
+                match text.parse() {
+                    Ok(pages) => run_config.max_pages = Some(pages),
+                    Err(_) => panic!("Invalid max pages {:?} (must be a page count)", text),
+                }
+            }
+            _ if arg.starts_with("--target=") => {
+                let name = &arg["--target=".len()..];
+                match Target::from_name(name) {
+                    Some(target) => run_config.target = target,
+                    None => panic!("Invalid target {:?} (must be wac or wasi)", name),
+                }
+            }
             _ if arg.starts_with("-O") => {
                 let text = &arg["-O".len()..];
                 let optlevel: Result<u32, _> = text.parse();
@@ -36,18 +152,47 @@ pub fn main() {
             // }
             _ => {
                 add_sources(&mut sources, arg).unwrap();
+                program_name = arg.to_owned();
             }
         }
     }
+    // the program sees itself as the last source given
+    // (which comes after e.g. the prelude)
+    run_config.host.args.insert(0, program_name);
+    run_config.host.env = if forward_env {
+        std::env::vars().collect()
+    } else {
+        env_names
+            .into_iter()
+            .filter_map(|name| std::env::var(&name).ok().map(|value| (name, value)))
+            .collect()
+    };
 
     match mode {
-        Mode::Run => match run(sources, run_config) {
-            Ok(stats) => eprintln!("{}", stats.format()),
-            Err(error) => {
-                eprintln!("{}", error.format());
-                std::process::exit(1);
+        Mode::Run => {
+            let library = run_config.library;
+            let host = run_config.host.clone();
+            let compiled = match Compiled::new(sources, run_config) {
+                Ok(compiled) => compiled,
+                Err(error) => {
+                    eprintln!("{}", error.format());
+                    std::process::exit(1);
+                }
+            };
+            // instantiating runs the initializers of globals,
+            // which may trap or exit
+            let mut runner = match compiled.try_instantiate(&host) {
+                Ok(runner) => runner,
+                Err(stop) => exit_early(stop),
+            };
+            if !library {
+                if let Err(stop) = runner.try_run_main() {
+                    exit_early(stop);
+                }
             }
-        },
+            eprintln!("{}", runner.stats().format());
+            std::process::exit(runner.stats().exit_code.unwrap_or(0));
+        }
         // Mode::Test => match run_tests(sources, &test_prefix) {
         //     Ok(()) => {}
         //     Err(error) => {
@@ -66,7 +211,14 @@ pub fn main() {
                 std::process::exit(1);
             }
         },
-        Mode::CompileOnly => match translate(sources) {
+        Mode::CompileOnly => match translate(sources, &run_config) {
+            Ok(string) => print!("{}", string),
+            Err(error) => {
+                eprintln!("{}", error.format());
+                std::process::exit(1);
+            }
+        },
+        Mode::Bindgen => match translate_to_bindings(sources, &run_config) {
             Ok(string) => print!("{}", string),
             Err(error) => {
                 eprintln!("{}", error.format());
@@ -76,23 +228,42 @@ pub fn main() {
     }
 }
 
+/// Exits with the status the program exited with, or after reporting
+/// why it stopped: with TRAP_EXIT_CODE for traps, and 1 otherwise
+fn exit_early(stop: Stop) -> ! {
+    let (error, code) = match stop {
+        Stop::Exit(code) => std::process::exit(code),
+        Stop::Trap(trap, _) => (format!("Trap ({})", trap), TRAP_EXIT_CODE),
+        Stop::Trapped(error) => (error.format(), TRAP_EXIT_CODE),
+        Stop::Error(error) => (error.format(), 1),
+    };
+    eprintln!("{}", error);
+    std::process::exit(code);
+}
+
 enum Mode {
     Run,
     Wasm,
     // Test,
     CompileOnly,
+
+    /// print Rust bindings for the program (see bindgen.rs)
+    Bindgen,
 }
 
-fn add_sources(out: &mut Vec<Rc<Source>>, path_str: &str) -> Result<(), std::io::Error> {
+pub(crate) fn add_sources(out: &mut Vec<Rc<Source>>, path_str: &str) -> Result<(), std::io::Error> {
     let path = Path::new(path_str);
     if path.is_dir() {
         add_sources_rec(out, "", path)?;
     } else if path.is_file() {
         // at the top level, if a user specifies a file, even if it has the wrong file
         // extension, accept it as a source
+        // such a file forms a module by itself, named after the file
         let data = std::fs::read_to_string(path).unwrap();
+        let module = path.file_stem().and_then(|s| s.to_str()).unwrap_or("[??]");
         out.push(Rc::new(Source {
             name: path_str.into(),
+            module: module.into(),
             data: data.into(),
         }));
     }
@@ -124,9 +295,14 @@ fn add_sources_rec(
         }
     } else if path.is_file() {
         if file_name.ends_with(".wac") {
+            // all files in the same directory belong to the same module,
+            // e.g. the files in '/app/util/' form the module 'app.util'
             let data = std::fs::read_to_string(path).unwrap();
+            let dir = &base[..base.len() - file_name.len() - 1];
+            let module = dir.trim_start_matches('/').replace('/', ".");
             out.push(Rc::new(Source {
                 name: base.into(),
+                module: module.into(),
                 data: data.into(),
             }));
         }
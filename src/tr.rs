@@ -1,14 +1,76 @@
+use crate::elide_refcounts;
 use crate::parse;
 use crate::solve;
+use crate::wasm;
 use crate::Error;
+use crate::Program;
+use crate::RunConfig;
 use crate::Source;
+use crate::SourceMap;
+use crate::Target;
 use std::rc::Rc;
 
-pub fn translate(sources: Vec<Rc<Source>>) -> Result<String, Error> {
+pub fn translate(sources: Vec<Rc<Source>>, config: &RunConfig) -> Result<String, Error> {
+    Ok(translate_to_module(sources, config)?.0.wat())
+}
+
+/// Translates to a webassembly module, along with the spans of the instructions in it
+pub fn translate_to_module(
+    sources: Vec<Rc<Source>>,
+    config: &RunConfig,
+) -> Result<(wasm::Module, SourceMap), Error> {
+    translate_to_program(sources, config)?.module(config)
+}
+
+/// Parses and checks the sources for the configured target
+pub fn translate_to_program(
+    sources: Vec<Rc<Source>>,
+    config: &RunConfig,
+) -> Result<Program, Error> {
+    if config.track_allocs() && config.target != Target::Wac {
+        // only our own runner provides track_alloc and the like
+        let message = if config.leak_check {
+            "Checking for leaks needs the wac target"
+        } else {
+            "The debug allocator needs the wac target"
+        };
+        return Err(Error {
+            span: vec![],
+            message: message.into(),
+        });
+    }
+    if config.fuel_budget().is_some() && config.target != Target::Wac {
+        // (as the fuel check calls into the wac imports)
+        let message = if config.fuel.is_some() {
+            "Fuel needs the wac target"
+        } else {
+            "The time limit needs the wac target"
+        };
+        return Err(Error {
+            span: vec![],
+            message: message.into(),
+        });
+    }
     let mut files = Vec::new();
     for source in &sources {
-        files.push(parse(source)?);
+        let file = parse(source)?;
+        if let Some((span, name)) = &file.target {
+            match Target::from_name(name) {
+                Some(target) if target != config.target => continue,
+                Some(_) => {}
+                None => {
+                    return Err(Error {
+                        span: vec![span.clone()],
+                        message: format!("Unknown target {}", name),
+                    })
+                }
+            }
+        }
+        files.push(file);
+    }
+    let program = solve(&files, config)?;
+    if config.elide_refcounts {
+        elide_refcounts(&program);
     }
-    let program = solve(&files)?;
-    program.wat()
+    Ok(program)
 }
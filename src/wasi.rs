@@ -0,0 +1,753 @@
+//! A host implementation of the commonly used parts of wasi_snapshot_preview1
+//!
+//! Guests get stdio (fds 0, 1 and 2), args, environment variables, clocks,
+//! random bytes, and optionally files under a single preopened directory
+//! (fd 3). Paths are resolved against that directory and may not
+//! escape it, whether with '..', absolute paths or symlinks (which are
+//! not followed at all).
+use crate::wasm::Value;
+use crate::FromValue;
+use crate::GuestMemory;
+use crate::HostConfig;
+use crate::HostStop;
+use std::cell::RefCell;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::fs::File;
+use std::hash::BuildHasher;
+use std::hash::Hasher;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::Instant;
+use std::time::SystemTime;
+
+pub type Errno = i32;
+
+pub const ERRNO_SUCCESS: Errno = 0;
+pub const ERRNO_ACCES: Errno = 2;
+pub const ERRNO_BADF: Errno = 8;
+pub const ERRNO_EXIST: Errno = 20;
+pub const ERRNO_FAULT: Errno = 21;
+pub const ERRNO_ILSEQ: Errno = 25;
+pub const ERRNO_INVAL: Errno = 28;
+pub const ERRNO_IO: Errno = 29;
+pub const ERRNO_ISDIR: Errno = 31;
+pub const ERRNO_NOENT: Errno = 44;
+pub const ERRNO_NOTDIR: Errno = 54;
+pub const ERRNO_SPIPE: Errno = 70;
+pub const ERRNO_NOTCAPABLE: Errno = 76;
+
+const PREOPEN_FD: u32 = 3;
+const PREOPEN_NAME: &str = ".";
+
+const CLOCK_REALTIME: i32 = 0;
+
+const FILETYPE_CHARACTER_DEVICE: u8 = 2;
+const FILETYPE_DIRECTORY: u8 = 3;
+const FILETYPE_REGULAR_FILE: u8 = 4;
+
+const OFLAGS_CREAT: i32 = 1;
+const OFLAGS_DIRECTORY: i32 = 2;
+const OFLAGS_EXCL: i32 = 4;
+const OFLAGS_TRUNC: i32 = 8;
+const FDFLAGS_APPEND: i32 = 1;
+const RIGHTS_FD_READ: i64 = 1 << 1;
+const RIGHTS_FD_WRITE: i64 = 1 << 6;
+
+const WHENCE_SET: i32 = 0;
+const WHENCE_CUR: i32 = 1;
+const WHENCE_END: i32 = 2;
+
+enum FdEntry {
+    Stdin,
+    Stdout,
+    Stderr,
+    Dir(PathBuf),
+    File(File),
+}
+
+pub struct WasiState {
+    config: HostConfig,
+    fds: HashMap<u32, FdEntry>,
+    next_fd: u32,
+    start: Instant,
+    random: RandomState,
+}
+
+impl WasiState {
+    pub fn new(config: HostConfig) -> Self {
+        let mut fds = HashMap::new();
+        fds.insert(0, FdEntry::Stdin);
+        fds.insert(1, FdEntry::Stdout);
+        fds.insert(2, FdEntry::Stderr);
+        if let Some(dir) = &config.dir {
+            fds.insert(PREOPEN_FD, FdEntry::Dir(dir.clone()));
+        }
+        Self {
+            config,
+            fds,
+            next_fd: PREOPEN_FD + 1,
+            start: Instant::now(),
+            random: RandomState::new(),
+        }
+    }
+
+    fn entry(&mut self, fd: i32) -> Result<&mut FdEntry, Errno> {
+        self.fds.get_mut(&(fd as u32)).ok_or(ERRNO_BADF)
+    }
+
+    fn args_sizes_get(&self, mem: &Guest, argc: i32, buf_size: i32) -> Result<(), Errno> {
+        let strings = self.args();
+        mem.write_u32(argc, strings.len() as u32)?;
+        mem.write_u32(buf_size, strings.iter().map(|s| s.len() as u32).sum())
+    }
+
+    fn args_get(&self, mem: &Guest, argv: i32, argv_buf: i32) -> Result<(), Errno> {
+        mem.write_strings(&self.args(), argv, argv_buf)
+    }
+
+    fn environ_sizes_get(&self, mem: &Guest, count: i32, buf_size: i32) -> Result<(), Errno> {
+        let strings = self.environ();
+        mem.write_u32(count, strings.len() as u32)?;
+        mem.write_u32(buf_size, strings.iter().map(|s| s.len() as u32).sum())
+    }
+
+    fn environ_get(&self, mem: &Guest, environ: i32, environ_buf: i32) -> Result<(), Errno> {
+        mem.write_strings(&self.environ(), environ, environ_buf)
+    }
+
+    /// args as nul terminated strings
+    fn args(&self) -> Vec<Vec<u8>> {
+        self.config
+            .args
+            .iter()
+            .map(|arg| format!("{}\0", arg).into_bytes())
+            .collect()
+    }
+
+    /// environment variables as nul terminated 'KEY=VALUE' strings
+    fn environ(&self) -> Vec<Vec<u8>> {
+        self.config
+            .env
+            .iter()
+            .map(|(key, value)| format!("{}={}\0", key, value).into_bytes())
+            .collect()
+    }
+
+    fn clock_res_get(&self, mem: &Guest, _id: i32, out: i32) -> Result<(), Errno> {
+        mem.write_u64(out, 1000)
+    }
+
+    fn clock_time_get(&self, mem: &Guest, id: i32, _precision: i64, out: i32) -> Result<(), Errno> {
+        // all clocks other than the realtime one (i.e. the monotonic and
+        // cputime clocks) are measured from when the host was created
+        let nanos = if id == CLOCK_REALTIME {
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_err(|_| ERRNO_IO)?
+                .as_nanos()
+        } else {
+            self.start.elapsed().as_nanos()
+        };
+        mem.write_u64(out, nanos as u64)
+    }
+
+    fn random_get(&self, mem: &Guest, buf: i32, len: i32) -> Result<(), Errno> {
+        // not suitable for cryptography, but good enough for
+        // seeding hash tables and the like
+        mem.check(buf, len)?;
+        let mut bytes = Vec::new();
+        while bytes.len() < len as usize {
+            let mut hasher = self.random.build_hasher();
+            hasher.write_usize(bytes.len());
+            hasher.write_u128(self.start.elapsed().as_nanos());
+            bytes.extend(&hasher.finish().to_le_bytes());
+        }
+        bytes.truncate(len as usize);
+        mem.write(buf, &bytes)
+    }
+
+    fn fd_write(
+        &mut self,
+        mem: &Guest,
+        fd: i32,
+        iovs: i32,
+        iovs_len: i32,
+        nwritten: i32,
+    ) -> Result<(), Errno> {
+        let mut data = Vec::new();
+        for (ptr, len) in mem.read_iovecs(iovs, iovs_len)? {
+            data.extend(mem.read(ptr, len)?);
+        }
+        let result = match self.entry(fd)? {
+            FdEntry::Stdout => std::io::stdout().write_all(&data),
+            FdEntry::Stderr => std::io::stderr().write_all(&data),
+            FdEntry::File(file) => file.write_all(&data),
+            FdEntry::Stdin | FdEntry::Dir(_) => return Err(ERRNO_BADF),
+        };
+        result.map_err(io_errno)?;
+        mem.write_u32(nwritten, data.len() as u32)
+    }
+
+    fn fd_read(
+        &mut self,
+        mem: &Guest,
+        fd: i32,
+        iovs: i32,
+        iovs_len: i32,
+        nread: i32,
+    ) -> Result<(), Errno> {
+        let mut total = 0;
+        for (ptr, len) in mem.read_iovecs(iovs, iovs_len)? {
+            mem.check(ptr, len)?;
+            let mut buf = vec![0; len as u32 as usize];
+            let n = match self.entry(fd)? {
+                FdEntry::Stdin => std::io::stdin().read(&mut buf),
+                FdEntry::File(file) => file.read(&mut buf),
+                FdEntry::Stdout | FdEntry::Stderr | FdEntry::Dir(_) => return Err(ERRNO_BADF),
+            }
+            .map_err(io_errno)?;
+            mem.write(ptr, &buf[..n])?;
+            total += n;
+            if n < buf.len() {
+                break;
+            }
+        }
+        mem.write_u32(nread, total as u32)
+    }
+
+    fn fd_seek(
+        &mut self,
+        mem: &Guest,
+        fd: i32,
+        offset: i64,
+        whence: i32,
+        newoffset: i32,
+    ) -> Result<(), Errno> {
+        let pos = match whence {
+            WHENCE_SET if offset >= 0 => SeekFrom::Start(offset as u64),
+            WHENCE_CUR => SeekFrom::Current(offset),
+            WHENCE_END => SeekFrom::End(offset),
+            _ => return Err(ERRNO_INVAL),
+        };
+        let new_pos = match self.entry(fd)? {
+            FdEntry::File(file) => file.seek(pos).map_err(io_errno)?,
+            FdEntry::Dir(_) => return Err(ERRNO_BADF),
+            FdEntry::Stdin | FdEntry::Stdout | FdEntry::Stderr => return Err(ERRNO_SPIPE),
+        };
+        mem.write_u64(newoffset, new_pos)
+    }
+
+    fn fd_close(&mut self, _mem: &Guest, fd: i32) -> Result<(), Errno> {
+        self.fds.remove(&(fd as u32)).ok_or(ERRNO_BADF)?;
+        Ok(())
+    }
+
+    fn fd_fdstat_get(&mut self, mem: &Guest, fd: i32, buf: i32) -> Result<(), Errno> {
+        let filetype = match self.entry(fd)? {
+            FdEntry::Stdin | FdEntry::Stdout | FdEntry::Stderr => FILETYPE_CHARACTER_DEVICE,
+            FdEntry::Dir(_) => FILETYPE_DIRECTORY,
+            FdEntry::File(_) => FILETYPE_REGULAR_FILE,
+        };
+        // [u8 filetype][u16 flags][u64 rights base][u64 rights inheriting]
+        let mut stat = [0u8; 24];
+        stat[0] = filetype;
+        stat[8..16].copy_from_slice(&u64::MAX.to_le_bytes());
+        stat[16..24].copy_from_slice(&u64::MAX.to_le_bytes());
+        mem.write(buf, &stat)
+    }
+
+    fn fd_filestat_get(&mut self, mem: &Guest, fd: i32, buf: i32) -> Result<(), Errno> {
+        let (filetype, size) = match self.entry(fd)? {
+            FdEntry::Stdin | FdEntry::Stdout | FdEntry::Stderr => (FILETYPE_CHARACTER_DEVICE, 0),
+            FdEntry::Dir(_) => (FILETYPE_DIRECTORY, 0),
+            FdEntry::File(file) => (
+                FILETYPE_REGULAR_FILE,
+                file.metadata().map_err(io_errno)?.len(),
+            ),
+        };
+        // [u64 dev][u64 ino][u8 filetype][u64 nlink][u64 size]
+        // [u64 atim][u64 mtim][u64 ctim], of which only the type, the
+        // link count and the size are filled in
+        let mut stat = [0u8; 64];
+        stat[16] = filetype;
+        stat[24..32].copy_from_slice(&1u64.to_le_bytes());
+        stat[32..40].copy_from_slice(&size.to_le_bytes());
+        mem.write(buf, &stat)
+    }
+
+    fn fd_prestat_get(&self, mem: &Guest, fd: i32, buf: i32) -> Result<(), Errno> {
+        if fd as u32 != PREOPEN_FD || self.config.dir.is_none() {
+            return Err(ERRNO_BADF);
+        }
+        // [u8 tag (0 for directories)][u32 name length]
+        mem.write(buf, &[0; 4])?;
+        mem.write_u32(buf + 4, PREOPEN_NAME.len() as u32)
+    }
+
+    fn fd_prestat_dir_name(&self, mem: &Guest, fd: i32, path: i32, len: i32) -> Result<(), Errno> {
+        if fd as u32 != PREOPEN_FD || self.config.dir.is_none() {
+            return Err(ERRNO_BADF);
+        }
+        let name = PREOPEN_NAME.as_bytes();
+        mem.write(path, &name[..std::cmp::min(name.len(), len as usize)])
+    }
+
+    fn path_open(
+        &mut self,
+        mem: &Guest,
+        dirfd: i32,
+        _dirflags: i32,
+        path: i32,
+        path_len: i32,
+        oflags: i32,
+        rights: i64,
+        _rights_inheriting: i64,
+        fdflags: i32,
+        fd_out: i32,
+    ) -> Result<(), Errno> {
+        let dir = match self.entry(dirfd)? {
+            FdEntry::Dir(dir) => dir.clone(),
+            _ => return Err(ERRNO_NOTDIR),
+        };
+        let path = String::from_utf8(mem.read(path, path_len)?).map_err(|_| ERRNO_ILSEQ)?;
+
+        let resolved = resolve_path(&dir, &path)?;
+        let entry = if oflags & OFLAGS_DIRECTORY != 0 || resolved.is_dir() {
+            if oflags & (OFLAGS_CREAT | OFLAGS_TRUNC) != 0 {
+                return Err(ERRNO_ISDIR);
+            }
+            if !resolved.is_dir() {
+                return Err(ERRNO_NOTDIR);
+            }
+            FdEntry::Dir(resolved)
+        } else {
+            let write = rights & RIGHTS_FD_WRITE != 0;
+            let flags = OpenFlags {
+                read: rights & RIGHTS_FD_READ != 0 || !write,
+                write,
+                append: fdflags & FDFLAGS_APPEND != 0,
+                create: oflags & OFLAGS_CREAT != 0,
+                exclusive: oflags & OFLAGS_EXCL != 0,
+                truncate: oflags & OFLAGS_TRUNC != 0,
+            };
+            FdEntry::File(open_path(&dir, &path, &flags)?)
+        };
+        let fd = self.next_fd;
+        self.next_fd += 1;
+        self.fds.insert(fd, entry);
+        mem.write_u32(fd_out, fd)
+    }
+
+    fn sched_yield(&self, _mem: &Guest) -> Result<(), Errno> {
+        Ok(())
+    }
+}
+
+/// Resolves a guest path relative to 'dir' (the preopened directory or
+/// one opened under it), failing with ERRNO_NOTCAPABLE if the path could
+/// lead outside of it: '..', absolute paths and symlinks (even ones that
+/// point back inside, or nowhere) are all refused
+pub fn resolve_path(dir: &Path, path: &str) -> Result<PathBuf, Errno> {
+    let mut resolved = dir.to_owned();
+    let mut exists = true;
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(part) => resolved.push(part),
+            Component::CurDir => continue,
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => {
+                return Err(ERRNO_NOTCAPABLE)
+            }
+        }
+        // (nothing under a missing component can exist either)
+        if exists {
+            match std::fs::symlink_metadata(&resolved) {
+                Ok(meta) if meta.file_type().is_symlink() => return Err(ERRNO_NOTCAPABLE),
+                Ok(_) => {}
+                Err(error) if error.kind() == std::io::ErrorKind::NotFound => exists = false,
+                Err(error) => return Err(io_errno(error)),
+            }
+        }
+    }
+    Ok(resolved)
+}
+
+/// How open_path opens a file
+#[derive(Default)]
+pub struct OpenFlags {
+    pub read: bool,
+    pub write: bool,
+    pub append: bool,
+    pub create: bool,
+    pub exclusive: bool,
+    pub truncate: bool,
+}
+
+/// Opens the file at a guest path relative to 'dir' (see resolve_path).
+///
+/// A symlink could still be swapped in after the path is resolved, so
+/// new files are only ever created exclusively (which fails on symlinks,
+/// dangling or not), and an existing file must be the one at the path
+/// once it is open, before it is truncated.
+pub fn open_path(dir: &Path, path: &str, flags: &OpenFlags) -> Result<File, Errno> {
+    let resolved = resolve_path(dir, path)?;
+    let exists = std::fs::symlink_metadata(&resolved).is_ok();
+    if exists && flags.create && flags.exclusive {
+        return Err(ERRNO_EXIST);
+    }
+    let file = std::fs::OpenOptions::new()
+        .read(flags.read)
+        .write(flags.write)
+        .append(flags.append)
+        .create_new(flags.create && !exists)
+        .open(&resolved)
+        .map_err(io_errno)?;
+    if exists {
+        let opened = file.metadata().map_err(io_errno)?;
+        let found = std::fs::symlink_metadata(&resolved).map_err(io_errno)?;
+        if found.file_type().is_symlink() || !same_file(&opened, &found) {
+            return Err(ERRNO_NOTCAPABLE);
+        }
+        if flags.truncate {
+            file.set_len(0).map_err(io_errno)?;
+        }
+    }
+    Ok(file)
+}
+
+#[cfg(unix)]
+fn same_file(a: &std::fs::Metadata, b: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    (a.dev(), a.ino()) == (b.dev(), b.ino())
+}
+
+#[cfg(not(unix))]
+fn same_file(a: &std::fs::Metadata, b: &std::fs::Metadata) -> bool {
+    a.file_type() == b.file_type() && a.len() == b.len()
+}
+
+fn io_errno(error: std::io::Error) -> Errno {
+    match error.kind() {
+        std::io::ErrorKind::NotFound => ERRNO_NOENT,
+        std::io::ErrorKind::PermissionDenied => ERRNO_ACCES,
+        std::io::ErrorKind::AlreadyExists => ERRNO_EXIST,
+        std::io::ErrorKind::InvalidInput => ERRNO_INVAL,
+        _ => ERRNO_IO,
+    }
+}
+
+/// Bounds checked access to the guest's memory
+struct Guest<'a> {
+    memory: &'a dyn GuestMemory,
+}
+
+impl<'a> Guest<'a> {
+    /// checks that the len bytes at ptr are in bounds (before anything
+    /// is allocated for them, as the guest chooses len)
+    fn check(&self, ptr: i32, len: i32) -> Result<usize, Errno> {
+        let start = ptr as u32 as usize;
+        match start.checked_add(len as u32 as usize) {
+            Some(end) if end <= self.memory.size() => Ok(start),
+            _ => Err(ERRNO_FAULT),
+        }
+    }
+
+    fn read(&self, ptr: i32, len: i32) -> Result<Vec<u8>, Errno> {
+        let start = self.check(ptr, len)?;
+        let mut bytes = vec![0; len as u32 as usize];
+        self.memory.read(start, &mut bytes);
+        Ok(bytes)
+    }
+
+    fn write(&self, ptr: i32, bytes: &[u8]) -> Result<(), Errno> {
+        let start = self.check(ptr, bytes.len() as i32)?;
+        self.memory.write(start, bytes);
+        Ok(())
+    }
+
+    fn read_u32(&self, ptr: i32) -> Result<u32, Errno> {
+        let bytes = self.read(ptr, 4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn write_u32(&self, ptr: i32, value: u32) -> Result<(), Errno> {
+        self.write(ptr, &value.to_le_bytes())
+    }
+
+    fn write_u64(&self, ptr: i32, value: u64) -> Result<(), Errno> {
+        self.write(ptr, &value.to_le_bytes())
+    }
+
+    /// reads an array of (ptr, len) pairs
+    fn read_iovecs(&self, iovs: i32, iovs_len: i32) -> Result<Vec<(i32, i32)>, Errno> {
+        let mut ret = Vec::new();
+        for i in 0..iovs_len {
+            let ptr = self.read_u32(iovs + 8 * i)? as i32;
+            let len = self.read_u32(iovs + 8 * i + 4)? as i32;
+            ret.push((ptr, len));
+        }
+        Ok(ret)
+    }
+
+    /// writes out strings in the layout used by args_get and environ_get
+    fn write_strings(&self, strings: &[Vec<u8>], ptrs: i32, buf: i32) -> Result<(), Errno> {
+        let mut pos = buf;
+        for (i, string) in strings.iter().enumerate() {
+            self.write_u32(ptrs + 4 * i as i32, pos as u32)?;
+            self.write(pos, string)?;
+            pos += string.len() as i32;
+        }
+        Ok(())
+    }
+}
+
+fn errno(result: Result<(), Errno>) -> i32 {
+    match result {
+        Ok(()) => ERRNO_SUCCESS,
+        Err(errno) => errno,
+    }
+}
+
+// each function forwards to the WasiState method of the same name,
+// both in the wasmer namespace and when called by name
+macro_rules! wasi_funcs {
+    ($($name:ident($($arg:ident: $t:ty),*)),* $(,)?) => {
+        /// Creates the wasi_snapshot_preview1 functions, all sharing one WasiState
+        pub fn make_wasi_namespace(config: HostConfig) -> wr::Namespace {
+            let state = Rc::new(RefCell::new(WasiState::new(config)));
+            let mut ns = wr::Namespace::new();
+            $({
+                let state = state.clone();
+                ns.insert(
+                    stringify!($name),
+                    wr::func!(move |ctx: &mut wr::Ctx $(, $arg: $t)*| -> i32 {
+                        let mem = Guest { memory: ctx.memory(0) };
+                        errno(state.borrow_mut().$name(&mem $(, $arg)*))
+                    }),
+                );
+            })*
+            ns.insert(
+                "proc_exit",
+                wr::func!(|_ctx: &mut wr::Ctx, code: i32| -> Result<(), HostStop> {
+                    Err(HostStop::Exit(code))
+                }),
+            );
+            ns
+        }
+
+        impl WasiState {
+            pub const FUNCS: &'static [&'static str] = &[$(stringify!($name),)* "proc_exit"];
+
+            /// Calls the function of the given name, returning None if there
+            /// is no such function or the arguments have the wrong types
+            pub fn call(
+                &mut self,
+                name: &str,
+                memory: &dyn GuestMemory,
+                args: &[Value],
+            ) -> Option<Result<Vec<Value>, HostStop>> {
+                let mem = Guest { memory };
+                match name {
+                    $(stringify!($name) => {
+                        #[allow(unused_mut, unused_variables)]
+                        let mut args = args.iter();
+                        $(let $arg = <$t>::from_value(args.next()?)?;)*
+                        Some(Ok(vec![Value::I32(errno(self.$name(&mem $(, $arg)*)))]))
+                    })*
+                    "proc_exit" => Some(Err(HostStop::Exit(i32::from_value(args.get(0)?)?))),
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+wasi_funcs! {
+    args_get(argv: i32, argv_buf: i32),
+    args_sizes_get(argc: i32, buf_size: i32),
+    environ_get(environ: i32, environ_buf: i32),
+    environ_sizes_get(count: i32, buf_size: i32),
+    clock_res_get(id: i32, out: i32),
+    clock_time_get(id: i32, precision: i64, out: i32),
+    random_get(buf: i32, len: i32),
+    fd_write(fd: i32, iovs: i32, iovs_len: i32, nwritten: i32),
+    fd_read(fd: i32, iovs: i32, iovs_len: i32, nread: i32),
+    fd_seek(fd: i32, offset: i64, whence: i32, newoffset: i32),
+    fd_close(fd: i32),
+    fd_fdstat_get(fd: i32, buf: i32),
+    fd_filestat_get(fd: i32, buf: i32),
+    fd_prestat_get(fd: i32, buf: i32),
+    fd_prestat_dir_name(fd: i32, path: i32, len: i32),
+    path_open(
+        dirfd: i32,
+        dirflags: i32,
+        path: i32,
+        path_len: i32,
+        oflags: i32,
+        rights: i64,
+        rights_inheriting: i64,
+        fdflags: i32,
+        fd_out: i32
+    ),
+    sched_yield(),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_config;
+    use crate::test_sources;
+    use crate::Engine;
+    use crate::EngineError;
+    use crate::RunConfig;
+    use crate::Target;
+    use crate::TEST_BACKENDS;
+
+    #[test]
+    fn paths_stay_in_the_sandbox() {
+        let root = std::env::temp_dir().join(format!("wac-wasi-test-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("sub/a.txt"), "a").unwrap();
+
+        let resolve = |path| resolve_path(&root, path);
+        assert_eq!(resolve("sub/a.txt"), Ok(root.join("sub/a.txt")));
+        assert_eq!(resolve("./new.txt"), Ok(root.join("new.txt")));
+        assert_eq!(resolve("sub/../sub/a.txt"), Err(ERRNO_NOTCAPABLE));
+        assert_eq!(resolve("../outside"), Err(ERRNO_NOTCAPABLE));
+        assert_eq!(resolve("/etc/passwd"), Err(ERRNO_NOTCAPABLE));
+        assert_eq!(
+            resolve_path(&root.join("sub"), "b.txt"),
+            Ok(root.join("sub/b.txt"))
+        );
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::symlink;
+            symlink(std::env::temp_dir(), root.join("link")).unwrap();
+            assert_eq!(resolve("link/x"), Err(ERRNO_NOTCAPABLE));
+            // even links that stay inside are refused
+            symlink(root.join("sub"), root.join("inner")).unwrap();
+            assert_eq!(resolve("inner/a.txt"), Err(ERRNO_NOTCAPABLE));
+
+            // creating a file through a dangling link would create its target
+            let target = root.with_extension("outside");
+            symlink(&target, root.join("dangling")).unwrap();
+            let create = OpenFlags {
+                write: true,
+                create: true,
+                truncate: true,
+                ..OpenFlags::default()
+            };
+            assert_eq!(
+                open_path(&root, "dangling", &create).err(),
+                Some(ERRNO_NOTCAPABLE)
+            );
+            assert!(!target.exists());
+            assert!(open_path(&root, "sub/new.txt", &create).is_ok());
+            assert!(open_path(&root, "sub/a.txt", &create).is_ok());
+            assert_eq!(std::fs::read_to_string(root.join("sub/a.txt")).unwrap(), "");
+        }
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn lengths_are_checked_before_allocating() {
+        let memory = RefCell::new(vec![0u8; 64]);
+        let mut state = WasiState::new(HostConfig::default());
+        let mut call = |name, args: &[i32]| {
+            let args: Vec<_> = args.iter().map(|&arg| Value::I32(arg)).collect();
+            match state.call(name, &memory, &args) {
+                Some(Ok(results)) => results[0],
+                _ => panic!("{} failed", name),
+            }
+        };
+        assert_eq!(call("random_get", &[0, i32::MAX]), Value::I32(ERRNO_FAULT));
+        assert_eq!(call("random_get", &[60, 8]), Value::I32(ERRNO_FAULT));
+        assert_eq!(call("random_get", &[0, 64]), Value::I32(ERRNO_SUCCESS));
+        // an iovec of (8, -1) at 0, read from stdin
+        memory.borrow_mut()[..8].copy_from_slice(&[8, 0, 0, 0, 255, 255, 255, 255]);
+        assert_eq!(call("fd_read", &[0, 0, 1, 16]), Value::I32(ERRNO_FAULT));
+        assert_eq!(call("fd_write", &[1, 0, 1, 16]), Value::I32(ERRNO_FAULT));
+    }
+
+    /// The prelude's I/O functions work on the wasi target as on the wac one
+    #[test]
+    fn prelude_io() {
+        let root = std::env::temp_dir().join(format!("wac-wasi-io-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        // more than a single read of the host's buffer
+        let text = "hello\n".repeat(20000);
+        // longer than a few of the blocks __strlen looks at
+        let long = "x".repeat(5000);
+        std::fs::write(root.join("in.txt"), &text).unwrap();
+
+        let sources = test_sources(
+            r#"
+export fn Arg(i i32) str {
+    return arg(i)
+}
+
+export fn Env(name str) str {
+    return getenv(name)
+}
+
+export fn Copy(from str, to str) {
+    write_file(to, read_file(from))
+}
+"#,
+        );
+        for &backend in TEST_BACKENDS {
+            let engine = Engine::new(RunConfig {
+                target: Target::Wasi,
+                host: HostConfig {
+                    args: vec!["app".into(), "first".into()],
+                    env: vec![
+                        ("AB".into(), "2".into()),
+                        ("A".into(), "1".into()),
+                        ("LONG".into(), long.clone()),
+                    ],
+                    dir: Some(root.clone()),
+                    ..HostConfig::default()
+                },
+                ..test_config(backend)
+            });
+            let module = engine.compile(sources.clone()).unwrap();
+            let mut instance = engine.instantiate(&module).unwrap();
+            let mut call_str = |name: &str, arg: Option<&str>| {
+                let ptr: i32 = match arg {
+                    Some(arg) => {
+                        let arg = instance.alloc_str(arg).unwrap() as i32;
+                        instance.call(name, (arg,))?
+                    }
+                    None => instance.call(name, (1,))?,
+                };
+                instance.memory()?.read_str(ptr as u32)
+            };
+            assert_eq!(call_str("Arg", None).unwrap(), "first");
+            assert_eq!(call_str("Env", Some("A")).unwrap(), "1");
+            assert_eq!(call_str("Env", Some("AB")).unwrap(), "2");
+            assert_eq!(call_str("Env", Some("B")).unwrap(), "");
+            assert_eq!(call_str("Env", Some("LONG")).unwrap(), long);
+
+            let from = instance.alloc_str("in.txt").unwrap() as i32;
+            let to = instance.alloc_str("out.txt").unwrap() as i32;
+            instance.call::<_, ()>("Copy", (from, to)).unwrap();
+            assert_eq!(std::fs::read_to_string(root.join("out.txt")).unwrap(), text);
+            std::fs::remove_file(root.join("out.txt")).unwrap();
+
+            // (the panic's message goes to stdout)
+            match instance.call::<_, i32>("Arg", (2,)) {
+                Err(EngineError::Runtime(_)) => {}
+                other => panic!("{:?}: {:?}", backend, other.map(|_| ())),
+            }
+        }
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
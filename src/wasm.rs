@@ -0,0 +1,1332 @@
+//! An in-memory webassembly module, which is what cgen generates
+//!
+//! Functions, globals, locals and labels are referred to by name, like in
+//! the text format, and are only resolved to indices when the module is
+//! encoded, so that cgen can emit calls to functions it has not seen yet.
+//! A module can be written out as text (e.g. for -c) or encoded directly
+//! into a binary that is ready to be instantiated.
+use crate::Error;
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::Write;
+use std::rc::Rc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ValType {
+    I32,
+    I64,
+    F32,
+    F64,
+}
+
+impl ValType {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::I32 => "i32",
+            Self::I64 => "i64",
+            Self::F32 => "f32",
+            Self::F64 => "f64",
+        }
+    }
+
+    fn code(self) -> u8 {
+        match self {
+            Self::I32 => 0x7f,
+            Self::I64 => 0x7e,
+            Self::F32 => 0x7d,
+            Self::F64 => 0x7c,
+        }
+    }
+}
+
+/// A value of one of the number types, as passed to and from functions
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    I32(i32),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+}
+
+impl Value {
+    pub fn type_(&self) -> ValType {
+        match self {
+            Self::I32(_) => ValType::I32,
+            Self::I64(_) => ValType::I64,
+            Self::F32(_) => ValType::F32,
+            Self::F64(_) => ValType::F64,
+        }
+    }
+
+    /// the zero value of the given type
+    pub fn zero(type_: ValType) -> Self {
+        match type_ {
+            ValType::I32 => Self::I32(0),
+            ValType::I64 => Self::I64(0),
+            ValType::F32 => Self::F32(0.0),
+            ValType::F64 => Self::F64(0.0),
+        }
+    }
+
+    /// the value's bits, zero extended to 64 (e.g. for storing it)
+    pub fn bits(self) -> u64 {
+        match self {
+            Self::I32(x) => x as u32 as u64,
+            Self::I64(x) => x as u64,
+            Self::F32(x) => x.to_bits() as u64,
+            Self::F64(x) => x.to_bits(),
+        }
+    }
+
+    /// the value of the given type with the given bits (the inverse of bits)
+    pub fn from_bits(type_: ValType, bits: u64) -> Self {
+        match type_ {
+            ValType::I32 => Self::I32(bits as i32),
+            ValType::I64 => Self::I64(bits as i64),
+            ValType::F32 => Self::F32(f32::from_bits(bits as u32)),
+            ValType::F64 => Self::F64(f64::from_bits(bits)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FuncType {
+    pub params: Vec<ValType>,
+    pub results: Vec<ValType>,
+}
+
+/// An instruction whose immediates (if any) are described by its kind
+#[derive(Debug)]
+pub struct Op {
+    pub name: &'static str,
+    code: &'static [u8],
+    kind: OpKind,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum OpKind {
+    Plain,
+
+    /// memory.size and memory.grow, which take the memory index (always 0)
+    Memory,
+
+    /// loads and stores, with the log2 of their natural alignment
+    MemArg(u32),
+}
+
+const fn op(name: &'static str, code: &'static [u8], kind: OpKind) -> Op {
+    Op { name, code, kind }
+}
+
+/// Every instruction that is not structured or refers to anything by name
+static OPS: &[Op] = &[
+    op("unreachable", &[0x00], OpKind::Plain),
+    op("nop", &[0x01], OpKind::Plain),
+    op("return", &[0x0f], OpKind::Plain),
+    op("drop", &[0x1a], OpKind::Plain),
+    op("select", &[0x1b], OpKind::Plain),
+    op("i32.load", &[0x28], OpKind::MemArg(2)),
+    op("i64.load", &[0x29], OpKind::MemArg(3)),
+    op("f32.load", &[0x2a], OpKind::MemArg(2)),
+    op("f64.load", &[0x2b], OpKind::MemArg(3)),
+    op("i32.load8_s", &[0x2c], OpKind::MemArg(0)),
+    op("i32.load8_u", &[0x2d], OpKind::MemArg(0)),
+    op("i32.load16_s", &[0x2e], OpKind::MemArg(1)),
+    op("i32.load16_u", &[0x2f], OpKind::MemArg(1)),
+    op("i64.load8_s", &[0x30], OpKind::MemArg(0)),
+    op("i64.load8_u", &[0x31], OpKind::MemArg(0)),
+    op("i64.load16_s", &[0x32], OpKind::MemArg(1)),
+    op("i64.load16_u", &[0x33], OpKind::MemArg(1)),
+    op("i64.load32_s", &[0x34], OpKind::MemArg(2)),
+    op("i64.load32_u", &[0x35], OpKind::MemArg(2)),
+    op("i32.store", &[0x36], OpKind::MemArg(2)),
+    op("i64.store", &[0x37], OpKind::MemArg(3)),
+    op("f32.store", &[0x38], OpKind::MemArg(2)),
+    op("f64.store", &[0x39], OpKind::MemArg(3)),
+    op("i32.store8", &[0x3a], OpKind::MemArg(0)),
+    op("i32.store16", &[0x3b], OpKind::MemArg(1)),
+    op("i64.store8", &[0x3c], OpKind::MemArg(0)),
+    op("i64.store16", &[0x3d], OpKind::MemArg(1)),
+    op("i64.store32", &[0x3e], OpKind::MemArg(2)),
+    op("memory.size", &[0x3f], OpKind::Memory),
+    op("memory.grow", &[0x40], OpKind::Memory),
+    op("i32.eqz", &[0x45], OpKind::Plain),
+    op("i32.eq", &[0x46], OpKind::Plain),
+    op("i32.ne", &[0x47], OpKind::Plain),
+    op("i32.lt_s", &[0x48], OpKind::Plain),
+    op("i32.lt_u", &[0x49], OpKind::Plain),
+    op("i32.gt_s", &[0x4a], OpKind::Plain),
+    op("i32.gt_u", &[0x4b], OpKind::Plain),
+    op("i32.le_s", &[0x4c], OpKind::Plain),
+    op("i32.le_u", &[0x4d], OpKind::Plain),
+    op("i32.ge_s", &[0x4e], OpKind::Plain),
+    op("i32.ge_u", &[0x4f], OpKind::Plain),
+    op("i64.eqz", &[0x50], OpKind::Plain),
+    op("i64.eq", &[0x51], OpKind::Plain),
+    op("i64.ne", &[0x52], OpKind::Plain),
+    op("i64.lt_s", &[0x53], OpKind::Plain),
+    op("i64.lt_u", &[0x54], OpKind::Plain),
+    op("i64.gt_s", &[0x55], OpKind::Plain),
+    op("i64.gt_u", &[0x56], OpKind::Plain),
+    op("i64.le_s", &[0x57], OpKind::Plain),
+    op("i64.le_u", &[0x58], OpKind::Plain),
+    op("i64.ge_s", &[0x59], OpKind::Plain),
+    op("i64.ge_u", &[0x5a], OpKind::Plain),
+    op("f32.eq", &[0x5b], OpKind::Plain),
+    op("f32.ne", &[0x5c], OpKind::Plain),
+    op("f32.lt", &[0x5d], OpKind::Plain),
+    op("f32.gt", &[0x5e], OpKind::Plain),
+    op("f32.le", &[0x5f], OpKind::Plain),
+    op("f32.ge", &[0x60], OpKind::Plain),
+    op("f64.eq", &[0x61], OpKind::Plain),
+    op("f64.ne", &[0x62], OpKind::Plain),
+    op("f64.lt", &[0x63], OpKind::Plain),
+    op("f64.gt", &[0x64], OpKind::Plain),
+    op("f64.le", &[0x65], OpKind::Plain),
+    op("f64.ge", &[0x66], OpKind::Plain),
+    op("i32.clz", &[0x67], OpKind::Plain),
+    op("i32.ctz", &[0x68], OpKind::Plain),
+    op("i32.popcnt", &[0x69], OpKind::Plain),
+    op("i32.add", &[0x6a], OpKind::Plain),
+    op("i32.sub", &[0x6b], OpKind::Plain),
+    op("i32.mul", &[0x6c], OpKind::Plain),
+    op("i32.div_s", &[0x6d], OpKind::Plain),
+    op("i32.div_u", &[0x6e], OpKind::Plain),
+    op("i32.rem_s", &[0x6f], OpKind::Plain),
+    op("i32.rem_u", &[0x70], OpKind::Plain),
+    op("i32.and", &[0x71], OpKind::Plain),
+    op("i32.or", &[0x72], OpKind::Plain),
+    op("i32.xor", &[0x73], OpKind::Plain),
+    op("i32.shl", &[0x74], OpKind::Plain),
+    op("i32.shr_s", &[0x75], OpKind::Plain),
+    op("i32.shr_u", &[0x76], OpKind::Plain),
+    op("i32.rotl", &[0x77], OpKind::Plain),
+    op("i32.rotr", &[0x78], OpKind::Plain),
+    op("i64.clz", &[0x79], OpKind::Plain),
+    op("i64.ctz", &[0x7a], OpKind::Plain),
+    op("i64.popcnt", &[0x7b], OpKind::Plain),
+    op("i64.add", &[0x7c], OpKind::Plain),
+    op("i64.sub", &[0x7d], OpKind::Plain),
+    op("i64.mul", &[0x7e], OpKind::Plain),
+    op("i64.div_s", &[0x7f], OpKind::Plain),
+    op("i64.div_u", &[0x80], OpKind::Plain),
+    op("i64.rem_s", &[0x81], OpKind::Plain),
+    op("i64.rem_u", &[0x82], OpKind::Plain),
+    op("i64.and", &[0x83], OpKind::Plain),
+    op("i64.or", &[0x84], OpKind::Plain),
+    op("i64.xor", &[0x85], OpKind::Plain),
+    op("i64.shl", &[0x86], OpKind::Plain),
+    op("i64.shr_s", &[0x87], OpKind::Plain),
+    op("i64.shr_u", &[0x88], OpKind::Plain),
+    op("i64.rotl", &[0x89], OpKind::Plain),
+    op("i64.rotr", &[0x8a], OpKind::Plain),
+    op("f32.abs", &[0x8b], OpKind::Plain),
+    op("f32.neg", &[0x8c], OpKind::Plain),
+    op("f32.ceil", &[0x8d], OpKind::Plain),
+    op("f32.floor", &[0x8e], OpKind::Plain),
+    op("f32.trunc", &[0x8f], OpKind::Plain),
+    op("f32.nearest", &[0x90], OpKind::Plain),
+    op("f32.sqrt", &[0x91], OpKind::Plain),
+    op("f32.add", &[0x92], OpKind::Plain),
+    op("f32.sub", &[0x93], OpKind::Plain),
+    op("f32.mul", &[0x94], OpKind::Plain),
+    op("f32.div", &[0x95], OpKind::Plain),
+    op("f32.min", &[0x96], OpKind::Plain),
+    op("f32.max", &[0x97], OpKind::Plain),
+    op("f32.copysign", &[0x98], OpKind::Plain),
+    op("f64.abs", &[0x99], OpKind::Plain),
+    op("f64.neg", &[0x9a], OpKind::Plain),
+    op("f64.ceil", &[0x9b], OpKind::Plain),
+    op("f64.floor", &[0x9c], OpKind::Plain),
+    op("f64.trunc", &[0x9d], OpKind::Plain),
+    op("f64.nearest", &[0x9e], OpKind::Plain),
+    op("f64.sqrt", &[0x9f], OpKind::Plain),
+    op("f64.add", &[0xa0], OpKind::Plain),
+    op("f64.sub", &[0xa1], OpKind::Plain),
+    op("f64.mul", &[0xa2], OpKind::Plain),
+    op("f64.div", &[0xa3], OpKind::Plain),
+    op("f64.min", &[0xa4], OpKind::Plain),
+    op("f64.max", &[0xa5], OpKind::Plain),
+    op("f64.copysign", &[0xa6], OpKind::Plain),
+    op("i32.wrap_i64", &[0xa7], OpKind::Plain),
+    op("i32.trunc_f32_s", &[0xa8], OpKind::Plain),
+    op("i32.trunc_f32_u", &[0xa9], OpKind::Plain),
+    op("i32.trunc_f64_s", &[0xaa], OpKind::Plain),
+    op("i32.trunc_f64_u", &[0xab], OpKind::Plain),
+    op("i64.extend_i32_s", &[0xac], OpKind::Plain),
+    op("i64.extend_i32_u", &[0xad], OpKind::Plain),
+    op("i64.trunc_f32_s", &[0xae], OpKind::Plain),
+    op("i64.trunc_f32_u", &[0xaf], OpKind::Plain),
+    op("i64.trunc_f64_s", &[0xb0], OpKind::Plain),
+    op("i64.trunc_f64_u", &[0xb1], OpKind::Plain),
+    op("f32.convert_i32_s", &[0xb2], OpKind::Plain),
+    op("f32.convert_i32_u", &[0xb3], OpKind::Plain),
+    op("f32.convert_i64_s", &[0xb4], OpKind::Plain),
+    op("f32.convert_i64_u", &[0xb5], OpKind::Plain),
+    op("f32.demote_f64", &[0xb6], OpKind::Plain),
+    op("f64.convert_i32_s", &[0xb7], OpKind::Plain),
+    op("f64.convert_i32_u", &[0xb8], OpKind::Plain),
+    op("f64.convert_i64_s", &[0xb9], OpKind::Plain),
+    op("f64.convert_i64_u", &[0xba], OpKind::Plain),
+    op("f64.promote_f32", &[0xbb], OpKind::Plain),
+    op("i32.reinterpret_f32", &[0xbc], OpKind::Plain),
+    op("i64.reinterpret_f64", &[0xbd], OpKind::Plain),
+    op("f32.reinterpret_i32", &[0xbe], OpKind::Plain),
+    op("f64.reinterpret_i64", &[0xbf], OpKind::Plain),
+    op("i32.extend8_s", &[0xc0], OpKind::Plain),
+    op("i32.extend16_s", &[0xc1], OpKind::Plain),
+    op("i64.extend8_s", &[0xc2], OpKind::Plain),
+    op("i64.extend16_s", &[0xc3], OpKind::Plain),
+    op("i64.extend32_s", &[0xc4], OpKind::Plain),
+    op("i32.trunc_sat_f32_s", &[0xfc, 0x00], OpKind::Plain),
+    op("i32.trunc_sat_f32_u", &[0xfc, 0x01], OpKind::Plain),
+    op("i32.trunc_sat_f64_s", &[0xfc, 0x02], OpKind::Plain),
+    op("i32.trunc_sat_f64_u", &[0xfc, 0x03], OpKind::Plain),
+    op("i64.trunc_sat_f32_s", &[0xfc, 0x04], OpKind::Plain),
+    op("i64.trunc_sat_f32_u", &[0xfc, 0x05], OpKind::Plain),
+    op("i64.trunc_sat_f64_s", &[0xfc, 0x06], OpKind::Plain),
+    op("i64.trunc_sat_f64_u", &[0xfc, 0x07], OpKind::Plain),
+];
+
+impl Op {
+    pub fn find(name: &str) -> Option<&'static Op> {
+        OPS.iter().find(|op| op.name == name)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Instr {
+    /// an instruction from the op table, other than a load or store
+    Op(&'static Op),
+
+    /// a load or store, with its offset
+    /// (the alignment is always the natural one)
+    Mem(&'static Op, u32),
+
+    Block(Option<Rc<str>>, Option<ValType>),
+    Loop(Option<Rc<str>>, Option<ValType>),
+    If(Option<ValType>),
+    Else,
+    End,
+    Br(Rc<str>),
+    BrIf(Rc<str>),
+    Call(Rc<str>),
+    LocalGet(Rc<str>),
+    LocalSet(Rc<str>),
+    LocalTee(Rc<str>),
+    GlobalGet(Rc<str>),
+    GlobalSet(Rc<str>),
+    I32Const(i32),
+    I64Const(i64),
+    F32Const(f32),
+    F64Const(f64),
+}
+
+impl Instr {
+    /// The instruction from the op table with the given name,
+    /// which must exist (loads and stores get an offset of 0)
+    pub fn op(name: &str) -> Self {
+        Self::mem(name, 0)
+    }
+
+    pub fn mem(name: &str, offset: u32) -> Self {
+        let op = Op::find(name).unwrap_or_else(|| panic!("Unknown wasm op {}", name));
+        match op.kind {
+            OpKind::MemArg(_) => Self::Mem(op, offset),
+            _ => Self::Op(op),
+        }
+    }
+}
+
+impl fmt::Display for Instr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let block_type = |f: &mut fmt::Formatter<'_>, type_: &Option<ValType>| match type_ {
+            Some(type_) => write!(f, " (result {})", type_.name()),
+            None => Ok(()),
+        };
+        match self {
+            Self::Op(op) => write!(f, "{}", op.name),
+            Self::Mem(op, 0) => write!(f, "{}", op.name),
+            Self::Mem(op, offset) => write!(f, "{} offset={}", op.name, offset),
+            Self::Block(label, type_) | Self::Loop(label, type_) => {
+                let name = if let Self::Block(..) = self {
+                    "block"
+                } else {
+                    "loop"
+                };
+                write!(f, "{}", name)?;
+                if let Some(label) = label {
+                    write!(f, " ${}", label)?;
+                }
+                block_type(f, type_)
+            }
+            Self::If(type_) => {
+                write!(f, "if")?;
+                block_type(f, type_)
+            }
+            Self::Else => write!(f, "else"),
+            Self::End => write!(f, "end"),
+            Self::Br(label) => write!(f, "br ${}", label),
+            Self::BrIf(label) => write!(f, "br_if ${}", label),
+            Self::Call(name) => write!(f, "call ${}", name),
+            Self::LocalGet(name) => write!(f, "local.get ${}", name),
+            Self::LocalSet(name) => write!(f, "local.set ${}", name),
+            Self::LocalTee(name) => write!(f, "local.tee ${}", name),
+            Self::GlobalGet(name) => write!(f, "global.get ${}", name),
+            Self::GlobalSet(name) => write!(f, "global.set ${}", name),
+            Self::I32Const(x) => write!(f, "i32.const {}", x),
+            Self::I64Const(x) => write!(f, "i64.const {}", x),
+            Self::F32Const(x) => write!(f, "f32.const {}", FloatText(*x as f64)),
+            Self::F64Const(x) => write!(f, "f64.const {}", FloatText(*x)),
+        }
+    }
+}
+
+/// A float as the text format spells it
+/// (Rust's Display would give e.g. 'NaN', and lose precision for large values)
+struct FloatText(f64);
+
+impl fmt::Display for FloatText {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let x = self.0;
+        if x.is_nan() {
+            write!(f, "nan")
+        } else if x.is_infinite() {
+            write!(f, "{}inf", if x < 0.0 { "-" } else { "" })
+        } else {
+            write!(f, "{:?}", x)
+        }
+    }
+}
+
+/// Parses a sequence of instructions in the text format, as given
+/// in $asm, e.g. "i32.const 1 i32.add"
+///
+/// Only the flat (non-folded) form is supported, and labels,
+/// functions, locals and globals can only be referred to by name.
+pub fn parse_instrs(code: &str) -> Result<Vec<Instr>, String> {
+    let mut tokens = code.split_whitespace().peekable();
+    let mut instrs = Vec::new();
+    while let Some(token) = tokens.next() {
+        let mut name = || match tokens.next() {
+            Some(name) if name.starts_with('$') => Ok(Rc::from(&name[1..])),
+            Some(name) => Err(format!("Expected a $name after {}, got {}", token, name)),
+            None => Err(format!("Expected a $name after {}", token)),
+        };
+        let instr = match token {
+            "block" | "loop" | "if" => {
+                let label = match tokens.peek() {
+                    Some(label) if label.starts_with('$') && token != "if" => {
+                        Some(Rc::from(&tokens.next().unwrap()[1..]))
+                    }
+                    _ => None,
+                };
+                let type_ = if tokens.peek() == Some(&"(result") {
+                    tokens.next();
+                    let type_ = tokens.next().unwrap_or("");
+                    match type_.strip_suffix(')').and_then(parse_valtype) {
+                        Some(type_) => Some(type_),
+                        None => return Err(format!("Invalid result type {}", type_)),
+                    }
+                } else {
+                    None
+                };
+                match token {
+                    "block" => Instr::Block(label, type_),
+                    "loop" => Instr::Loop(label, type_),
+                    _ => Instr::If(type_),
+                }
+            }
+            "else" => Instr::Else,
+            "end" => Instr::End,
+            "br" => Instr::Br(name()?),
+            "br_if" => Instr::BrIf(name()?),
+            "call" => Instr::Call(name()?),
+            "local.get" => Instr::LocalGet(name()?),
+            "local.set" => Instr::LocalSet(name()?),
+            "local.tee" => Instr::LocalTee(name()?),
+            "global.get" => Instr::GlobalGet(name()?),
+            "global.set" => Instr::GlobalSet(name()?),
+            "i32.const" | "i64.const" | "f32.const" | "f64.const" => {
+                let value = tokens.next().unwrap_or("");
+                let invalid = || format!("Invalid {} value {:?}", token, value);
+                match token {
+                    "i32.const" => {
+                        let x = parse_int(value).ok_or_else(invalid)?;
+                        if x < i32::MIN as i64 || x > u32::MAX as i64 {
+                            return Err(invalid());
+                        }
+                        Instr::I32Const(x as i32)
+                    }
+                    "i64.const" => Instr::I64Const(parse_int(value).ok_or_else(invalid)?),
+                    "f32.const" => Instr::F32Const(value.parse().map_err(|_| invalid())?),
+                    _ => Instr::F64Const(value.parse().map_err(|_| invalid())?),
+                }
+            }
+            _ => match Op::find(token) {
+                Some(op) => match op.kind {
+                    OpKind::MemArg(_) => {
+                        let mut offset = 0;
+                        while let Some(arg) = tokens.peek() {
+                            if arg.starts_with("offset=") {
+                                offset = parse_int(&arg["offset=".len()..])
+                                    .filter(|x| *x >= 0 && *x <= u32::MAX as i64)
+                                    .ok_or_else(|| format!("Invalid {}", arg))?
+                                    as u32;
+                            } else if !arg.starts_with("align=") {
+                                break;
+                            }
+                            tokens.next();
+                        }
+                        Instr::Mem(op, offset)
+                    }
+                    _ => Instr::Op(op),
+                },
+                None => return Err(format!("Unknown wasm instruction {}", token)),
+            },
+        };
+        instrs.push(instr);
+    }
+    Ok(instrs)
+}
+
+fn parse_valtype(name: &str) -> Option<ValType> {
+    match name {
+        "i32" => Some(ValType::I32),
+        "i64" => Some(ValType::I64),
+        "f32" => Some(ValType::F32),
+        "f64" => Some(ValType::F64),
+        _ => None,
+    }
+}
+
+/// parses a decimal or hexadecimal integer literal
+fn parse_int(text: &str) -> Option<i64> {
+    let (negative, text) = match text.strip_prefix('-') {
+        Some(text) => (true, text),
+        None => (false, text),
+    };
+    let text = text.replace('_', "");
+    let value = match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+        None => text.parse::<u64>().ok()?,
+    } as i64;
+    Some(if negative {
+        value.wrapping_neg()
+    } else {
+        value
+    })
+}
+
+#[derive(Default)]
+pub struct Module {
+    /// these come first in each index space
+    pub imports: Vec<Import>,
+
+    /// the memory the module defines (if it does not import one),
+    /// by name and size in pages
+    pub memory: Option<(Rc<str>, Limits)>,
+
+    pub globals: Vec<Global>,
+    pub funcs: Vec<Func>,
+    pub exports: Vec<Export>,
+    pub start: Option<Rc<str>>,
+    pub data: Vec<Data>,
+
+    /// custom sections (by name), which are written after the name
+    /// section, and are left out of the text format as it has no syntax
+    /// for them
+    pub custom: Vec<(Rc<str>, Vec<u8>)>,
+}
+
+pub struct Import {
+    pub module: Rc<str>,
+    pub name: Rc<str>,
+    pub kind: ImportKind,
+}
+
+pub enum ImportKind {
+    Func(Rc<str>, FuncType),
+
+    /// a global, with whether it is mutable
+    Global(Rc<str>, ValType, bool),
+
+    /// a memory, with its size in pages
+    Memory(Rc<str>, Limits),
+}
+
+/// The initial and (optionally) maximum size of a memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub min: u32,
+    pub max: Option<u32>,
+}
+
+impl Limits {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self.max {
+            Some(max) => {
+                out.push(0x01);
+                uleb(out, self.min as u64);
+                uleb(out, max as u64);
+            }
+            None => {
+                out.push(0x00);
+                uleb(out, self.min as u64);
+            }
+        }
+    }
+}
+
+impl fmt::Display for Limits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.max {
+            Some(max) => write!(f, "{} {}", self.min, max),
+            None => write!(f, "{}", self.min),
+        }
+    }
+}
+
+pub struct Global {
+    pub name: Rc<str>,
+    pub type_: ValType,
+    pub mutable: bool,
+
+    /// a constant instruction giving the initial value
+    pub init: Instr,
+}
+
+pub struct Func {
+    pub name: Rc<str>,
+    pub params: Vec<(Rc<str>, ValType)>,
+    pub results: Vec<ValType>,
+
+    /// local variables other than the parameters
+    pub locals: Vec<(Rc<str>, ValType)>,
+
+    /// the instructions of the body, without the final 'end'
+    pub body: Vec<Instr>,
+}
+
+impl Func {
+    pub fn type_(&self) -> FuncType {
+        FuncType {
+            params: self.params.iter().map(|(_, type_)| *type_).collect(),
+            results: self.results.clone(),
+        }
+    }
+}
+
+pub struct Export {
+    pub name: Rc<str>,
+    pub kind: ExportKind,
+}
+
+pub enum ExportKind {
+    Func(Rc<str>),
+    Memory(Rc<str>),
+}
+
+/// Bytes to write into memory at some offset on instantiation
+pub struct Data {
+    pub offset: u32,
+    pub bytes: Vec<u8>,
+}
+
+impl Module {
+    /// Writes out the module in the text format
+    pub fn wat(&self) -> String {
+        let mut out = String::new();
+        self.write_wat(&mut out).unwrap();
+        out
+    }
+
+    fn write_wat(&self, out: &mut String) -> fmt::Result {
+        writeln!(out, "(module")?;
+        for import in &self.imports {
+            write!(
+                out,
+                "(import {} {} ",
+                Quoted(import.module.as_bytes()),
+                Quoted(import.name.as_bytes())
+            )?;
+            match &import.kind {
+                ImportKind::Func(name, type_) => {
+                    write!(out, "(func ${}", name)?;
+                    for param in &type_.params {
+                        write!(out, " (param {})", param.name())?;
+                    }
+                    write_results(out, &type_.results)?;
+                    write!(out, ")")?;
+                }
+                ImportKind::Global(name, type_, mutable) => {
+                    write!(out, "(global ${} {})", name, GlobalType(*type_, *mutable))?
+                }
+                ImportKind::Memory(name, limits) => write!(out, "(memory ${} {})", name, limits)?,
+            }
+            writeln!(out, ")")?;
+        }
+        if let Some((name, limits)) = &self.memory {
+            writeln!(out, "(memory ${} {})", name, limits)?;
+        }
+        for global in &self.globals {
+            writeln!(
+                out,
+                "(global ${} {} ({}))",
+                global.name,
+                GlobalType(global.type_, global.mutable),
+                global.init
+            )?;
+        }
+        for func in &self.funcs {
+            write!(out, "(func ${}", func.name)?;
+            for (name, type_) in &func.params {
+                write!(out, " (param ${} {})", name, type_.name())?;
+            }
+            write_results(out, &func.results)?;
+            writeln!(out)?;
+            for (name, type_) in &func.locals {
+                writeln!(out, "  (local ${} {})", name, type_.name())?;
+            }
+            let mut depth = 1;
+            for instr in &func.body {
+                if let Instr::Else | Instr::End = instr {
+                    depth -= 1;
+                }
+                writeln!(out, "{:width$}{}", "", instr, width = 2 * depth)?;
+                if let Instr::Block(..) | Instr::Loop(..) | Instr::If(..) | Instr::Else = instr {
+                    depth += 1;
+                }
+            }
+            writeln!(out, ")")?;
+        }
+        for export in &self.exports {
+            let (kind, name) = match &export.kind {
+                ExportKind::Func(name) => ("func", name),
+                ExportKind::Memory(name) => ("memory", name),
+            };
+            writeln!(
+                out,
+                "(export {} ({} ${}))",
+                Quoted(export.name.as_bytes()),
+                kind,
+                name
+            )?;
+        }
+        if let Some(start) = &self.start {
+            writeln!(out, "(start ${})", start)?;
+        }
+        for data in &self.data {
+            writeln!(
+                out,
+                "(data (i32.const {}) {})",
+                data.offset,
+                Quoted(&data.bytes)
+            )?;
+        }
+        writeln!(out, ")")
+    }
+}
+
+fn write_results(out: &mut String, results: &[ValType]) -> fmt::Result {
+    for result in results {
+        write!(out, " (result {})", result.name())?;
+    }
+    Ok(())
+}
+
+struct GlobalType(ValType, bool);
+
+impl fmt::Display for GlobalType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.1 {
+            write!(f, "(mut {})", self.0.name())
+        } else {
+            write!(f, "{}", self.0.name())
+        }
+    }
+}
+
+/// A string literal in the text format
+struct Quoted<'a>(&'a [u8]);
+
+impl fmt::Display for Quoted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"")?;
+        for byte in self.0 {
+            match byte {
+                b'"' | b'\\' => write!(f, "\\{}", *byte as char)?,
+                0x20..=0x7e => write!(f, "{}", *byte as char)?,
+                _ => write!(f, "\\{:02x}", byte)?,
+            }
+        }
+        write!(f, "\"")
+    }
+}
+
+const SECTION_CUSTOM: u8 = 0;
+const SECTION_TYPE: u8 = 1;
+const SECTION_IMPORT: u8 = 2;
+const SECTION_FUNCTION: u8 = 3;
+const SECTION_MEMORY: u8 = 5;
+const SECTION_GLOBAL: u8 = 6;
+const SECTION_EXPORT: u8 = 7;
+const SECTION_START: u8 = 8;
+const SECTION_CODE: u8 = 10;
+const SECTION_DATA: u8 = 11;
+
+const KIND_FUNC: u8 = 0;
+const KIND_MEMORY: u8 = 2;
+const KIND_GLOBAL: u8 = 3;
+
+impl Module {
+    /// Encodes the module into the binary format,
+    /// with the names of functions, locals and globals in a name section
+    pub fn encode(&self) -> Result<Vec<u8>, Error> {
+        Ok(self.encode_with_offsets()?.0)
+    }
+
+    /// Like encode, but also gives the offset in the binary of each
+    /// instruction in the body of each function
+    pub fn encode_with_offsets(&self) -> Result<(Vec<u8>, Vec<Vec<u32>>), Error> {
+        let indices = Indices::new(self);
+        let mut types = Types::default();
+        let mut out = b"\0asm\x01\0\0\0".to_vec();
+
+        // the types are only known once the imports and functions are
+        // encoded, so those are encoded first
+        let mut imports = Vec::new();
+        uleb(&mut imports, self.imports.len() as u64);
+        for import in &self.imports {
+            name(&mut imports, &import.module);
+            name(&mut imports, &import.name);
+            match &import.kind {
+                ImportKind::Func(_, type_) => {
+                    imports.push(KIND_FUNC);
+                    uleb(&mut imports, types.index(type_) as u64);
+                }
+                ImportKind::Memory(_, limits) => {
+                    imports.push(KIND_MEMORY);
+                    limits.encode(&mut imports);
+                }
+                ImportKind::Global(_, type_, mutable) => {
+                    imports.extend(&[KIND_GLOBAL, type_.code(), *mutable as u8]);
+                }
+            }
+        }
+        let mut funcs = Vec::new();
+        uleb(&mut funcs, self.funcs.len() as u64);
+        for func in &self.funcs {
+            uleb(&mut funcs, types.index(&func.type_()) as u64);
+        }
+
+        let mut section = Vec::new();
+        uleb(&mut section, types.list.len() as u64);
+        for type_ in &types.list {
+            section.push(0x60);
+            uleb(&mut section, type_.params.len() as u64);
+            section.extend(type_.params.iter().map(|type_| type_.code()));
+            uleb(&mut section, type_.results.len() as u64);
+            section.extend(type_.results.iter().map(|type_| type_.code()));
+        }
+        write_section(&mut out, SECTION_TYPE, &section);
+        write_section(&mut out, SECTION_IMPORT, &imports);
+        write_section(&mut out, SECTION_FUNCTION, &funcs);
+
+        if let Some((_, limits)) = &self.memory {
+            let mut section = vec![1];
+            limits.encode(&mut section);
+            write_section(&mut out, SECTION_MEMORY, &section);
+        }
+
+        let mut section = Vec::new();
+        uleb(&mut section, self.globals.len() as u64);
+        for global in &self.globals {
+            section.extend(&[global.type_.code(), global.mutable as u8]);
+            indices.encode_instr(&mut section, &global.init)?;
+            section.push(0x0b);
+        }
+        write_section(&mut out, SECTION_GLOBAL, &section);
+
+        let mut section = Vec::new();
+        uleb(&mut section, self.exports.len() as u64);
+        for export in &self.exports {
+            name(&mut section, &export.name);
+            match &export.kind {
+                ExportKind::Func(func) => {
+                    section.push(KIND_FUNC);
+                    uleb(&mut section, indices.func(func)? as u64);
+                }
+                ExportKind::Memory(memory) => {
+                    section.push(KIND_MEMORY);
+                    uleb(
+                        &mut section,
+                        indices.get(&indices.memories, "memory", memory)? as u64,
+                    );
+                }
+            }
+        }
+        write_section(&mut out, SECTION_EXPORT, &section);
+
+        if let Some(start) = &self.start {
+            let mut section = Vec::new();
+            uleb(&mut section, indices.func(start)? as u64);
+            write_section(&mut out, SECTION_START, &section);
+        }
+
+        let mut section = Vec::new();
+        let mut offsets = Vec::new();
+        uleb(&mut section, self.funcs.len() as u64);
+        for func in &self.funcs {
+            let (code, mut func_offsets) = indices.encode_func(func)?;
+            uleb(&mut section, code.len() as u64);
+            for offset in &mut func_offsets {
+                *offset += section.len() as u32;
+            }
+            section.extend(code);
+            offsets.push(func_offsets);
+        }
+        write_section(&mut out, SECTION_CODE, &section);
+        let code_start = (out.len() - section.len()) as u32;
+        for offset in offsets.iter_mut().flatten() {
+            *offset += code_start;
+        }
+
+        let mut section = Vec::new();
+        uleb(&mut section, self.data.len() as u64);
+        for data in &self.data {
+            // an active segment for memory 0
+            section.extend(&[0x00, 0x41]);
+            sleb(&mut section, data.offset as i32 as i64);
+            section.push(0x0b);
+            uleb(&mut section, data.bytes.len() as u64);
+            section.extend(&data.bytes);
+        }
+        write_section(&mut out, SECTION_DATA, &section);
+
+        write_section(&mut out, SECTION_CUSTOM, &self.name_section());
+        for (name, content) in &self.custom {
+            write_custom_section(&mut out, name, content);
+        }
+        Ok((out, offsets))
+    }
+
+    /// The "name" custom section, with the names of functions, locals and globals
+    fn name_section(&self) -> Vec<u8> {
+        let mut func_names = Vec::new();
+        let mut local_names = Vec::new();
+        let mut global_names = Vec::new();
+        let imported = self.imports.iter().filter_map(|import| match &import.kind {
+            ImportKind::Func(name, _) => Some(name),
+            _ => None,
+        });
+        let names: Vec<_> = imported
+            .chain(self.funcs.iter().map(|func| &func.name))
+            .collect();
+        uleb(&mut func_names, names.len() as u64);
+        for (i, func) in names.iter().enumerate() {
+            uleb(&mut func_names, i as u64);
+            name(&mut func_names, func);
+        }
+
+        let first = names.len() - self.funcs.len();
+        uleb(&mut local_names, self.funcs.len() as u64);
+        for (i, func) in self.funcs.iter().enumerate() {
+            uleb(&mut local_names, (first + i) as u64);
+            uleb(
+                &mut local_names,
+                (func.params.len() + func.locals.len()) as u64,
+            );
+            for (j, (local, _)) in func.params.iter().chain(&func.locals).enumerate() {
+                uleb(&mut local_names, j as u64);
+                name(&mut local_names, local);
+            }
+        }
+
+        let imported = self.imports.iter().filter_map(|import| match &import.kind {
+            ImportKind::Global(name, ..) => Some(name),
+            _ => None,
+        });
+        let names: Vec<_> = imported
+            .chain(self.globals.iter().map(|global| &global.name))
+            .collect();
+        uleb(&mut global_names, names.len() as u64);
+        for (i, global) in names.iter().enumerate() {
+            uleb(&mut global_names, i as u64);
+            name(&mut global_names, global);
+        }
+
+        // subsections must be in order of their ids
+        // (global names are from the extended name section proposal)
+        let mut section = Vec::new();
+        name(&mut section, "name");
+        write_section(&mut section, 1, &func_names);
+        write_section(&mut section, 2, &local_names);
+        write_section(&mut section, 7, &global_names);
+        section
+    }
+}
+
+/// The function types used by a module, each with its index
+#[derive(Default)]
+struct Types {
+    list: Vec<FuncType>,
+    map: HashMap<FuncType, usize>,
+}
+
+impl Types {
+    fn index(&mut self, type_: &FuncType) -> usize {
+        let list = &mut self.list;
+        *self.map.entry(type_.clone()).or_insert_with(|| {
+            list.push(type_.clone());
+            list.len() - 1
+        })
+    }
+}
+
+/// The indices of everything that can be referred to by name
+struct Indices<'a> {
+    funcs: HashMap<&'a str, u32>,
+    globals: HashMap<&'a str, u32>,
+    memories: HashMap<&'a str, u32>,
+}
+
+impl<'a> Indices<'a> {
+    fn new(module: &'a Module) -> Self {
+        let mut funcs = HashMap::new();
+        let mut globals = HashMap::new();
+        let mut memories = HashMap::new();
+        let add = |map: &mut HashMap<&'a str, u32>, name: &'a str| {
+            let index = map.len() as u32;
+            map.insert(name, index);
+        };
+        for import in &module.imports {
+            match &import.kind {
+                ImportKind::Func(name, _) => add(&mut funcs, name),
+                ImportKind::Global(name, ..) => add(&mut globals, name),
+                ImportKind::Memory(name, _) => add(&mut memories, name),
+            }
+        }
+        if let Some((name, _)) = &module.memory {
+            add(&mut memories, name);
+        }
+        for global in &module.globals {
+            add(&mut globals, &global.name);
+        }
+        for func in &module.funcs {
+            add(&mut funcs, &func.name);
+        }
+        Self {
+            funcs,
+            globals,
+            memories,
+        }
+    }
+
+    fn get(&self, map: &HashMap<&str, u32>, kind: &str, name: &str) -> Result<u32, Error> {
+        map.get(name).cloned().ok_or_else(|| Error {
+            span: vec![],
+            message: format!("Unknown {} ${}", kind, name),
+        })
+    }
+
+    fn func(&self, name: &str) -> Result<u32, Error> {
+        self.get(&self.funcs, "function", name)
+    }
+
+    /// The body of a function in the code section (without its size),
+    /// and the offset of each instruction in it
+    fn encode_func(&self, func: &Func) -> Result<(Vec<u8>, Vec<u32>), Error> {
+        let mut out = Vec::new();
+
+        // locals are declared in runs of the same type
+        let mut runs: Vec<(u32, ValType)> = Vec::new();
+        for (_, type_) in &func.locals {
+            match runs.last_mut() {
+                Some((count, last)) if last == type_ => *count += 1,
+                _ => runs.push((1, *type_)),
+            }
+        }
+        uleb(&mut out, runs.len() as u64);
+        for (count, type_) in runs {
+            uleb(&mut out, count as u64);
+            out.push(type_.code());
+        }
+
+        let mut locals = HashMap::new();
+        for (i, (name, _)) in func.params.iter().chain(&func.locals).enumerate() {
+            locals.insert(&name[..], i as u32);
+        }
+
+        // the labels of the enclosing blocks, innermost last
+        let mut labels: Vec<Option<&Rc<str>>> = Vec::new();
+        let mut offsets = Vec::new();
+        for instr in &func.body {
+            offsets.push(out.len() as u32);
+            match instr {
+                Instr::LocalGet(name) | Instr::LocalSet(name) | Instr::LocalTee(name) => {
+                    out.push(match instr {
+                        Instr::LocalGet(_) => 0x20,
+                        Instr::LocalSet(_) => 0x21,
+                        _ => 0x22,
+                    });
+                    uleb(&mut out, self.get(&locals, "local", name)? as u64);
+                }
+                Instr::Br(label) | Instr::BrIf(label) => {
+                    let depth = labels
+                        .iter()
+                        .rev()
+                        .position(|l| *l == Some(label))
+                        .ok_or_else(|| Error {
+                            span: vec![],
+                            message: format!("Unknown label ${} in ${}", label, func.name),
+                        })?;
+                    out.push(if let Instr::Br(_) = instr { 0x0c } else { 0x0d });
+                    uleb(&mut out, depth as u64);
+                }
+                _ => self.encode_instr(&mut out, instr)?,
+            }
+            match instr {
+                Instr::Block(label, _) | Instr::Loop(label, _) => labels.push(label.as_ref()),
+                Instr::If(_) => labels.push(None),
+                Instr::End => {
+                    if labels.pop().is_none() {
+                        return Err(Error {
+                            span: vec![],
+                            message: format!("Unbalanced 'end' in ${}", func.name),
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+        if !labels.is_empty() {
+            return Err(Error {
+                span: vec![],
+                message: format!("Unterminated block in ${}", func.name),
+            });
+        }
+        out.push(0x0b);
+        Ok((out, offsets))
+    }
+
+    /// Encodes an instruction that does not refer to locals or labels
+    fn encode_instr(&self, out: &mut Vec<u8>, instr: &Instr) -> Result<(), Error> {
+        let block_type = |out: &mut Vec<u8>, type_: &Option<ValType>| {
+            out.push(type_.map(ValType::code).unwrap_or(0x40));
+        };
+        match instr {
+            Instr::Op(op) => {
+                out.extend(op.code);
+                if let OpKind::Memory = op.kind {
+                    out.push(0x00);
+                }
+            }
+            Instr::Mem(op, offset) => {
+                out.extend(op.code);
+                if let OpKind::MemArg(align) = op.kind {
+                    uleb(out, align as u64);
+                }
+                uleb(out, *offset as u64);
+            }
+            Instr::Block(_, type_) => {
+                out.push(0x02);
+                block_type(out, type_);
+            }
+            Instr::Loop(_, type_) => {
+                out.push(0x03);
+                block_type(out, type_);
+            }
+            Instr::If(type_) => {
+                out.push(0x04);
+                block_type(out, type_);
+            }
+            Instr::Else => out.push(0x05),
+            Instr::End => out.push(0x0b),
+            Instr::Call(name) => {
+                out.push(0x10);
+                uleb(out, self.func(name)? as u64);
+            }
+            Instr::GlobalGet(name) | Instr::GlobalSet(name) => {
+                out.push(if let Instr::GlobalGet(_) = instr {
+                    0x23
+                } else {
+                    0x24
+                });
+                uleb(out, self.get(&self.globals, "global", name)? as u64);
+            }
+            Instr::I32Const(x) => {
+                out.push(0x41);
+                sleb(out, *x as i64);
+            }
+            Instr::I64Const(x) => {
+                out.push(0x42);
+                sleb(out, *x);
+            }
+            Instr::F32Const(x) => {
+                out.push(0x43);
+                out.extend(&x.to_le_bytes());
+            }
+            Instr::F64Const(x) => {
+                out.push(0x44);
+                out.extend(&x.to_le_bytes());
+            }
+            Instr::LocalGet(_)
+            | Instr::LocalSet(_)
+            | Instr::LocalTee(_)
+            | Instr::Br(_)
+            | Instr::BrIf(_) => {
+                return Err(Error {
+                    span: vec![],
+                    message: format!("'{}' is only allowed in a function body", instr),
+                })
+            }
+        }
+        Ok(())
+    }
+}
+
+fn write_section(out: &mut Vec<u8>, id: u8, content: &[u8]) {
+    out.push(id);
+    uleb(out, content.len() as u64);
+    out.extend(content);
+}
+
+/// Appends a custom section to an encoded module
+/// (custom sections may come after any other section)
+pub fn write_custom_section(out: &mut Vec<u8>, section_name: &str, content: &[u8]) {
+    let mut section = Vec::new();
+    name(&mut section, section_name);
+    section.extend(content);
+    write_section(out, SECTION_CUSTOM, &section);
+}
+
+/// writes a string, prefixed by its length
+pub(crate) fn name(out: &mut Vec<u8>, name: &str) {
+    uleb(out, name.len() as u64);
+    out.extend(name.as_bytes());
+}
+
+/// writes an unsigned LEB128 number
+pub(crate) fn uleb(out: &mut Vec<u8>, mut x: u64) {
+    loop {
+        let byte = (x & 0x7f) as u8;
+        x >>= 7;
+        if x == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+/// writes a signed LEB128 number
+fn sleb(out: &mut Vec<u8>, mut x: i64) {
+    loop {
+        let byte = (x & 0x7f) as u8;
+        x >>= 7;
+        if (x == 0 && byte & 0x40 == 0) || (x == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leb128() {
+        let encode = |f: fn(&mut Vec<u8>, i64), x| {
+            let mut out = vec![];
+            f(&mut out, x);
+            out
+        };
+        let uleb = |out: &mut Vec<u8>, x| uleb(out, x as u64);
+        assert_eq!(encode(uleb, 0), [0x00]);
+        assert_eq!(encode(uleb, 624485), [0xe5, 0x8e, 0x26]);
+        assert_eq!(encode(sleb, -1), [0x7f]);
+        assert_eq!(encode(sleb, 64), [0xc0, 0x00]);
+        assert_eq!(encode(sleb, -123456), [0xc0, 0xbb, 0x78]);
+    }
+
+    #[test]
+    fn asm_text() {
+        let instrs = parse_instrs("i32.const 0x10 i32.load8_u offset=4 align=1 memory.grow").ok();
+        let text: Vec<_> = instrs.unwrap().iter().map(Instr::to_string).collect();
+        assert_eq!(
+            text,
+            ["i32.const 16", "i32.load8_u offset=4", "memory.grow"]
+        );
+        assert!(parse_instrs("i32.bogus").is_err());
+        assert!(parse_instrs("i32.const").is_err());
+        assert!(parse_instrs("call 3").is_err());
+    }
+
+    /// Compiles the samples with the prelude, and checks that wabt accepts
+    /// the binary, and assembles the text into the same code
+    #[cfg(feature = "wabt")]
+    #[test]
+    fn same_code_as_wat2wasm() {
+        use crate::RunConfig;
+        use crate::Target;
+        let mut sources = vec![];
+        crate::add_sources(&mut sources, "prelude").unwrap();
+        crate::add_sources(&mut sources, "samples").unwrap();
+        for &target in &[Target::Wac, Target::Wasi] {
+            for &debug in &[false, true] {
+                let config = RunConfig {
+                    target,
+                    debug,
+                    ..RunConfig::default()
+                };
+                let module = match crate::translate_to_module(sources.clone(), &config) {
+                    Ok((module, _)) => module,
+                    Err(error) => panic!("{}", error.format()),
+                };
+                let wasm = module.encode().ok().unwrap();
+                wabt::wasm2wat(&wasm).unwrap();
+                let expected = wabt::Wat2Wasm::new().convert(module.wat()).unwrap();
+                assert_eq!(code_bodies(&wasm), code_bodies(expected.as_ref()));
+            }
+        }
+    }
+
+    /// the function bodies in the code section
+    /// (whose sizes wabt might encode differently)
+    #[cfg(feature = "wabt")]
+    fn code_bodies(wasm: &[u8]) -> Vec<&[u8]> {
+        let read_uleb = |pos: &mut usize| {
+            let mut result = 0;
+            let mut shift = 0;
+            loop {
+                let byte = wasm[*pos];
+                *pos += 1;
+                result |= ((byte & 0x7f) as usize) << shift;
+                shift += 7;
+                if byte & 0x80 == 0 {
+                    return result;
+                }
+            }
+        };
+        let mut pos = 8;
+        let mut bodies = vec![];
+        while pos < wasm.len() {
+            let id = wasm[pos];
+            pos += 1;
+            let size = read_uleb(&mut pos);
+            if id == SECTION_CODE {
+                for _ in 0..read_uleb(&mut pos) {
+                    let size = read_uleb(&mut pos);
+                    bodies.push(&wasm[pos..pos + size]);
+                    pos += size;
+                }
+                break;
+            }
+            pos += size;
+        }
+        bodies
+    }
+}